@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE mounts (
+  username TEXT NOT NULL,
+  prefix TEXT NOT NULL,
+  target TEXT NOT NULL,
+  read_only BOOLEAN NOT NULL,
+  PRIMARY KEY (username, prefix)
+)
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE trash (
+  id TEXT NOT NULL PRIMARY KEY,
+  username TEXT NOT NULL,
+  original_path TEXT NOT NULL,
+  deleted_at BIGINT NOT NULL,
+  is_dir BOOLEAN NOT NULL,
+  size BIGINT NOT NULL
+)
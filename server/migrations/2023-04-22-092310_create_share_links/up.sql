@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE share_links (
+  id TEXT NOT NULL PRIMARY KEY,
+  username TEXT NOT NULL,
+  path TEXT NOT NULL,
+  password TEXT,
+  expires_at BIGINT,
+  max_downloads INTEGER,
+  download_count INTEGER NOT NULL DEFAULT 0,
+  allow_upload BOOLEAN NOT NULL DEFAULT FALSE,
+  created_at BIGINT NOT NULL
+)
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE versioning_policies;
+DROP TABLE file_versions
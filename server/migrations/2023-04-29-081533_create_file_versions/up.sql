@@ -0,0 +1,19 @@
+-- Your SQL goes here
+CREATE TABLE file_versions (
+  id TEXT NOT NULL PRIMARY KEY,
+  username TEXT NOT NULL,
+  path TEXT NOT NULL,
+  hash TEXT NOT NULL,
+  size BIGINT NOT NULL,
+  created_at BIGINT NOT NULL
+);
+
+CREATE INDEX file_versions_username_path ON file_versions (username, path);
+
+CREATE TABLE versioning_policies (
+  username TEXT NOT NULL,
+  dir TEXT NOT NULL,
+  keep_versions INTEGER,
+  keep_days INTEGER,
+  PRIMARY KEY (username, dir)
+)
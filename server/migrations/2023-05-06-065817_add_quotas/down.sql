@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE storage_usage;
+
+ALTER TABLE groups DROP COLUMN quota;
+
+ALTER TABLE users DROP COLUMN quota
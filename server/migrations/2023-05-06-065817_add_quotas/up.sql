@@ -0,0 +1,10 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN quota BIGINT;
+
+ALTER TABLE groups ADD COLUMN quota BIGINT;
+
+CREATE TABLE storage_usage (
+  username TEXT NOT NULL PRIMARY KEY,
+  used BIGINT NOT NULL,
+  updated_at BIGINT NOT NULL
+)
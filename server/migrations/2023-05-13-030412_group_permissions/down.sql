@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+UPDATE groups SET permissions = 'none' WHERE name = 'guest' AND permissions = 'fs_read,fs_write,kv';
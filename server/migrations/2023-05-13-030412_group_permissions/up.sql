@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- permissions were not enforced before, keep guests able to use their files
+UPDATE groups SET permissions = 'fs_read,fs_write,kv' WHERE name = 'guest' AND permissions = 'none';
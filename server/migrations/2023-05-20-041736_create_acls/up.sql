@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE acls (
+  id TEXT NOT NULL PRIMARY KEY,
+  owner TEXT NOT NULL,
+  path TEXT NOT NULL,
+  username TEXT,
+  group_name TEXT,
+  read_only BOOLEAN NOT NULL DEFAULT TRUE,
+  created_at BIGINT NOT NULL
+);
+
+CREATE INDEX acls_owner ON acls (owner);
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE sessions (
+  id TEXT NOT NULL PRIMARY KEY,
+  state TEXT NOT NULL,
+  expires_at BIGINT NOT NULL
+);
+
+CREATE INDEX sessions_expires_at ON sessions (expires_at);
@@ -0,0 +1,14 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX sessions_username;
+
+ALTER TABLE sessions DROP COLUMN last_seen;
+
+ALTER TABLE sessions DROP COLUMN created_at;
+
+ALTER TABLE sessions DROP COLUMN ip;
+
+ALTER TABLE sessions DROP COLUMN user_agent;
+
+ALTER TABLE sessions DROP COLUMN device_id;
+
+ALTER TABLE sessions DROP COLUMN username
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+ALTER TABLE sessions ADD COLUMN username TEXT;
+
+ALTER TABLE sessions ADD COLUMN device_id TEXT;
+
+ALTER TABLE sessions ADD COLUMN user_agent TEXT;
+
+ALTER TABLE sessions ADD COLUMN ip TEXT;
+
+ALTER TABLE sessions ADD COLUMN created_at BIGINT;
+
+ALTER TABLE sessions ADD COLUMN last_seen BIGINT;
+
+CREATE INDEX sessions_username ON sessions (username);
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE web_authn_credentials (
+  id TEXT NOT NULL PRIMARY KEY,
+  username TEXT NOT NULL,
+  name TEXT NOT NULL,
+  passkey TEXT NOT NULL,
+  created_at BIGINT NOT NULL,
+  last_used_at BIGINT
+);
+
+CREATE INDEX web_authn_credentials_username ON web_authn_credentials (username);
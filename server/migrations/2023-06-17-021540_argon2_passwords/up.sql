@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN must_change_password BOOLEAN NOT NULL DEFAULT 0;
+
+-- the default admin account still using password "admin"
+UPDATE users SET must_change_password = 1
+WHERE username = 'admin' AND password = '8c6976e5b5410415bde908bd4dee15dfb167a9c873fc4bb8a81f6f2ab448a918';
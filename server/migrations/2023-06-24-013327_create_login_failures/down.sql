@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX login_failures_created_at;
+
+DROP TABLE login_failures;
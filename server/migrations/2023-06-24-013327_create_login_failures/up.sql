@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE login_failures (
+  id TEXT NOT NULL PRIMARY KEY,
+  action TEXT NOT NULL,
+  username TEXT,
+  ip TEXT NOT NULL,
+  reason TEXT NOT NULL,
+  created_at BIGINT NOT NULL
+);
+
+CREATE INDEX login_failures_created_at ON login_failures (created_at);
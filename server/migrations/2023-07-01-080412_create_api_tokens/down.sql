@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX api_tokens_username;
+
+DROP TABLE api_tokens;
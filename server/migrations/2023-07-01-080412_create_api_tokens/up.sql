@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE api_tokens (
+  id TEXT NOT NULL PRIMARY KEY,
+  username TEXT NOT NULL,
+  name TEXT NOT NULL,
+  token_hash TEXT NOT NULL UNIQUE,
+  scopes TEXT NOT NULL,
+  expires_at BIGINT,
+  created_at BIGINT NOT NULL,
+  last_used_at BIGINT
+);
+
+CREATE INDEX api_tokens_username ON api_tokens (username);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX oidc_identities_username;
+
+DROP TABLE oidc_identities;
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE oidc_identities (
+  issuer TEXT NOT NULL,
+  subject TEXT NOT NULL,
+  username TEXT NOT NULL,
+  email TEXT,
+  created_at BIGINT NOT NULL,
+  last_login BIGINT NOT NULL,
+  PRIMARY KEY (issuer, subject)
+);
+
+CREATE INDEX oidc_identities_username ON oidc_identities (username);
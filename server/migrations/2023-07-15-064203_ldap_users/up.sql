@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT 0;
+
+ALTER TABLE users ADD COLUMN ldap_dn TEXT;
@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX mail_queue_next_attempt_at;
+
+DROP TABLE mail_queue;
+
+DROP INDEX email_tokens_username;
+
+DROP TABLE email_tokens;
+
+ALTER TABLE users DROP COLUMN email_verified;
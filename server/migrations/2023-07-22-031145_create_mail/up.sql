@@ -0,0 +1,27 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT 0;
+
+CREATE TABLE email_tokens (
+  id TEXT NOT NULL PRIMARY KEY,
+  username TEXT NOT NULL,
+  purpose TEXT NOT NULL,
+  email TEXT NOT NULL,
+  expires_at BIGINT NOT NULL,
+  created_at BIGINT NOT NULL
+);
+
+CREATE INDEX email_tokens_username ON email_tokens (username);
+
+CREATE TABLE mail_queue (
+  id TEXT NOT NULL PRIMARY KEY,
+  recipient TEXT NOT NULL,
+  subject TEXT NOT NULL,
+  body TEXT NOT NULL,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  next_attempt_at BIGINT NOT NULL,
+  last_error TEXT,
+  created_at BIGINT NOT NULL,
+  sent_at BIGINT
+);
+
+CREATE INDEX mail_queue_next_attempt_at ON mail_queue (next_attempt_at);
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE dynamic_config (
+  key TEXT NOT NULL PRIMARY KEY,
+  value TEXT NOT NULL,
+  updated_at BIGINT NOT NULL,
+  updated_by TEXT NOT NULL
+);
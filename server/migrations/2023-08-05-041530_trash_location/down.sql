@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE trash DROP COLUMN indexed_path;
+
+ALTER TABLE trash DROP COLUMN mount;
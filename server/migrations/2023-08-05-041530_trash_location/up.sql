@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE trash ADD COLUMN mount TEXT;
+
+ALTER TABLE trash ADD COLUMN indexed_path TEXT;
@@ -0,0 +1,358 @@
+/// Admin subcommands
+///
+/// They work on the database and files of config.toml directly, so they also help when
+/// nobody can log in anymore. Run them while the server is stopped, `restore` requires it.
+use std::{
+  io::{BufRead, Write},
+  path::{Path, PathBuf},
+};
+
+use clap::Subcommand;
+use diesel::{prelude::*, sql_types::Text, SqliteConnection};
+
+use crate::{
+  config,
+  config::DYNAMIC_CONFIG,
+  connect_db,
+  db::SHARED_DB_CONN,
+  models::{NewGroup, NewUser, User},
+  run_migrations,
+  schedulers::update_file_index::JOB_UPDATE_GALLERY,
+  utils::{
+    auth::{find_user, set_disabled, set_password},
+    crypto::hash_pwd,
+    dynamic_config,
+    error::AppError,
+    ldap, permission, storage,
+  },
+};
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+  /// start the server, the default without a subcommand
+  Serve,
+  /// manage users
+  User {
+    #[command(subcommand)]
+    command: UserCommand,
+  },
+  /// manage groups
+  Group {
+    #[command(subcommand)]
+    command: GroupCommand,
+  },
+  /// run pending database migrations
+  Migrate,
+  /// rebuild the file index and the search index
+  Reindex,
+  /// write a consistent copy of the database to `path`
+  Backup { path: PathBuf },
+  /// replace the database with a backup, the server must be stopped
+  Restore { path: PathBuf },
+  /// check the runtime config stored in the database, config.toml, env and flags
+  /// are checked on every start
+  CheckConfig,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UserCommand {
+  /// create a user, the password is read from stdin if not given
+  Add {
+    username: String,
+    #[arg(long)]
+    password: Option<String>,
+    #[arg(long, default_value = "guest")]
+    group: String,
+    #[arg(long, default_value = "")]
+    email: String,
+    /// ask for a new password on first login
+    #[arg(long)]
+    must_change_password: bool,
+  },
+  /// list users
+  List,
+  /// set the password of a user, it is read from stdin if not given
+  Passwd {
+    username: String,
+    #[arg(long)]
+    password: Option<String>,
+    /// ask for a new password on next login
+    #[arg(long)]
+    must_change_password: bool,
+  },
+  /// link a local user to its directory entry, it logs in with its directory password then
+  LinkLdap { username: String },
+  /// disable a user and revoke its sessions
+  Disable {
+    username: String,
+    /// enable the user again
+    #[arg(long)]
+    enable: bool,
+  },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum GroupCommand {
+  /// create a group
+  Add {
+    name: String,
+    #[arg(long, default_value = "")]
+    desc: String,
+    /// `all`, `none` or a comma separated list like `fs_read,fs_write,kv`
+    #[arg(long, default_value = "none")]
+    permissions: String,
+  },
+}
+
+fn fail(msg: &str) -> AppError {
+  AppError::new(msg)
+}
+
+/// run a subcommand other than `serve`
+pub fn run(command: Command) -> Result<(), AppError> {
+  match command {
+    Command::Serve => Ok(()),
+    Command::Migrate => {
+      let mut conn = connect_db();
+      run_migrations(&mut conn);
+      println!("database is up to date");
+      Ok(())
+    }
+    Command::Backup { path } => backup(&path),
+    Command::Restore { path } => restore(&path),
+    command => {
+      // everything else needs the current schema
+      run_migrations(&mut connect_db());
+      match command {
+        Command::User { command } => user(command),
+        Command::Group { command } => group(command),
+        Command::Reindex => reindex(),
+        Command::CheckConfig => check_config(),
+        _ => unreachable!(),
+      }
+    }
+  }
+}
+
+fn read_password(password: Option<String>) -> Result<String, AppError> {
+  if let Some(password) = password {
+    return Ok(password);
+  }
+  eprint!("password: ");
+  std::io::stderr().flush()?;
+  let mut line = String::new();
+  std::io::stdin().lock().read_line(&mut line)?;
+  let password = line.trim_end_matches(['\r', '\n']).to_owned();
+  if password.is_empty() {
+    return Err(fail("password is empty"));
+  }
+  Ok(password)
+}
+
+fn user(command: UserCommand) -> Result<(), AppError> {
+  match command {
+    UserCommand::Add {
+      username,
+      password,
+      group,
+      email,
+      must_change_password,
+    } => {
+      if find_user(&username)?.is_some() {
+        return Err(fail(&format!("user {username} already exists")));
+      }
+      if !group_exists(&group)? {
+        return Err(fail(&format!("group {group} not found")));
+      }
+      let hashed = hash_pwd(&read_password(password)?)?;
+      let mut conn = SHARED_DB_CONN.lock().unwrap();
+      diesel::insert_into(crate::schema::users::table)
+        .values(NewUser {
+          username: &username,
+          password: &hashed,
+          email: &email,
+          user_type: 1,
+          user_root: "",
+          group_name: &group,
+          must_change_password,
+        })
+        .execute(&mut *conn)?;
+      println!("created user {username} in group {group}");
+    }
+    UserCommand::List => {
+      let list = {
+        use crate::schema::users::dsl::*;
+        let mut conn = SHARED_DB_CONN.lock().unwrap();
+        users.order(username.asc()).load::<User>(&mut *conn)?
+      };
+      println!(
+        "{:<24} {:<16} {:<32} {}",
+        "USERNAME", "GROUP", "EMAIL", "STATE"
+      );
+      for u in list {
+        let mut state = vec![];
+        if u.disabled {
+          state.push("disabled");
+        }
+        if u.ldap_dn.is_some() {
+          state.push("ldap");
+        }
+        if u.otp_secret.is_some() {
+          state.push("otp");
+        }
+        if u.must_change_password {
+          state.push("must_change_password");
+        }
+        println!(
+          "{:<24} {:<16} {:<32} {}",
+          u.username,
+          u.group_name,
+          u.email,
+          state.join(",")
+        );
+      }
+    }
+    UserCommand::Passwd {
+      username,
+      password,
+      must_change_password,
+    } => {
+      let found =
+        find_user(&username)?.ok_or_else(|| fail(&format!("user {username} not found")))?;
+      if found.ldap_dn.is_some() {
+        return Err(fail(&format!(
+          "the password of {username} is managed by the directory"
+        )));
+      }
+      set_password(&username, &read_password(password)?, must_change_password)?;
+      println!("password of {username} is changed");
+    }
+    UserCommand::LinkLdap { username } => {
+      let dn = ldap::link_local_user(&username)?;
+      println!("user {username} is linked to {dn}");
+    }
+    UserCommand::Disable { username, enable } => {
+      if !set_disabled(&username, !enable)? {
+        return Err(fail(&format!("user {username} not found")));
+      }
+      let state = if enable { "enabled" } else { "disabled" };
+      println!("user {username} is {state}");
+    }
+  }
+  Ok(())
+}
+
+fn group_exists(group: &str) -> Result<bool, AppError> {
+  use crate::schema::groups::dsl::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = groups
+    .filter(name.eq(group))
+    .count()
+    .get_result::<i64>(&mut *conn)?;
+  Ok(r > 0)
+}
+
+fn group(command: GroupCommand) -> Result<(), AppError> {
+  match command {
+    GroupCommand::Add {
+      name,
+      desc,
+      permissions,
+    } => {
+      if group_exists(&name)? {
+        return Err(fail(&format!("group {name} already exists")));
+      }
+      let permissions = permission::format(&permission::parse(&permissions)?);
+      let mut conn = SHARED_DB_CONN.lock().unwrap();
+      diesel::insert_into(crate::schema::groups::table)
+        .values(NewGroup {
+          name: name.clone(),
+          desc,
+          permissions: permissions.clone(),
+        })
+        .execute(&mut *conn)?;
+      println!("created group {name} with permissions {permissions}");
+    }
+  }
+  Ok(())
+}
+
+fn reindex() -> Result<(), AppError> {
+  let file_root = std::env::current_dir()?.join(config!(file_root));
+  storage::init_storage_backends()?;
+  let mut job = JOB_UPDATE_GALLERY.lock().unwrap();
+  job.set_file_root(&file_root);
+  println!("indexing {}", file_root.display());
+  job.update_blocking()?;
+  println!("file index is rebuilt");
+  Ok(())
+}
+
+/// path of the sqlite file of `database_url`
+fn database_path() -> PathBuf {
+  let url = config!(database_url);
+  PathBuf::from(url.strip_prefix("sqlite://").unwrap_or(&url))
+}
+
+fn backup(path: &Path) -> Result<(), AppError> {
+  if path.exists() {
+    return Err(fail(&format!("{} already exists", path.display())));
+  }
+  let mut conn = connect_db();
+  // unlike copying the file, this is consistent while the database is written
+  diesel::sql_query("VACUUM INTO ?")
+    .bind::<Text, _>(path.to_string_lossy())
+    .execute(&mut conn)?;
+  println!("database is written to {}", path.display());
+  Ok(())
+}
+
+fn restore(path: &Path) -> Result<(), AppError> {
+  let mut backup = SqliteConnection::establish(&path.to_string_lossy())
+    .map_err(|err| fail(&format!("can not open {}: {err}", path.display())))?;
+  diesel::sql_query("PRAGMA quick_check").execute(&mut backup)?;
+  drop(backup);
+
+  let target = database_path();
+  if target.exists() {
+    let previous = target.with_extension("db.before-restore");
+    std::fs::copy(&target, &previous)?;
+    println!("previous database is kept as {}", previous.display());
+  }
+  std::fs::copy(path, &target)?;
+  // a journal of the replaced database must not be applied to the restored one
+  for suffix in ["-wal", "-shm"] {
+    let journal = PathBuf::from(format!("{}{suffix}", target.display()));
+    if journal.exists() {
+      std::fs::remove_file(journal)?;
+    }
+  }
+  run_migrations(&mut connect_db());
+  println!("database is restored from {}", path.display());
+  Ok(())
+}
+
+fn check_config() -> Result<(), AppError> {
+  let mut problems = vec![];
+  if let Err(err) = storage::init_storage_backends() {
+    problems.push(format!("storage: {err}"));
+  }
+  if let Err(err) = dynamic_config::load() {
+    problems.push(format!("runtime config: {err}"));
+  }
+  let conf = DYNAMIC_CONFIG.lock().unwrap().clone();
+  let keys = dynamic_config::get()?.keys().cloned().collect::<Vec<_>>();
+  for key in keys {
+    if let Err(err) = dynamic_config::validate(&conf, &key) {
+      problems.push(format!("{key}: {err}"));
+    }
+  }
+  if problems.is_empty() {
+    println!("config is valid");
+    return Ok(());
+  }
+  for problem in &problems {
+    eprintln!("{problem}");
+  }
+  Err(fail(&format!("{} problems found", problems.len())))
+}
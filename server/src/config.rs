@@ -1,20 +1,51 @@
-use std::{path::PathBuf, sync::Mutex};
+use std::{collections::HashMap, sync::Mutex};
 
 use clap::Parser;
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+use crate::cli::Command;
+
+pub mod loader;
+
+/// Settings an admin can change at runtime, read from config.toml and overridden by
+/// the values stored in `dynamic_config`
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct DynamicConfig {
-  smtp: Option<SMTPConfig>,
+  pub smtp: Option<SMTPConfig>,
+  /// url users reach the server at, used for links in emails
+  pub public_url: Option<String>,
+  /// shell started by terminals, overrides `shell`
+  pub shell: Option<String>,
+  /// defaults of video transcoding, declared as `[transcode]`
+  pub transcode: Option<TranscodeConfig>,
+  /// daily time the file index is rebuilt, "HH:MM", not scheduled if unset
+  pub index_update_time: Option<String>,
+  /// who may create accounts, "admin" (default) or "open" to let anyone sign up
+  pub registration: Option<String>,
+  /// group of users who signed up themselves, defaults to "guest"
+  pub registration_group: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TranscodeConfig {
+  /// height of transcoded videos, defaults to 720
+  pub resize: Option<u32>,
+  /// kbit/s of transcoded videos, defaults to 2000
+  pub bitrate: Option<u32>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct SMTPConfig {
-  user: String,
-  secret: String,
-  host: String,
-  port: u32,
+  /// no login if empty
+  pub user: String,
+  pub secret: String,
+  pub host: String,
+  pub port: u32,
+  /// sender address, defaults to `user`
+  pub from: Option<String>,
+  /// "tls", "starttls" or "none", defaults to "starttls", "none" suits a local smtp sink
+  pub security: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -32,72 +63,179 @@ pub struct AppConfig {
   pub static_dir: Option<String>,
   pub shell: Option<String>,
   pub log_path: Option<String>,
+  pub storage: Option<HashMap<String, StorageConfig>>,
+  /// days before deleted files are purged from trash, 0 keeps them forever
+  pub trash_retention_days: Option<i32>,
+  /// watch `file_root` for changes made outside of the server and update file index
+  pub fs_watcher: Option<bool>,
+  /// quiet period before collected file changes are applied to file index
+  pub fs_watcher_debounce_ms: Option<i32>,
+  /// single sign-on with an OpenID Connect provider, declared as `[oidc]`
+  pub oidc: Option<OidcConfig>,
+  /// directory server used when `authentication` is "ldap", declared as `[ldap]`
+  pub ldap: Option<LdapConfig>,
+  /// serve https instead of http, declared as `[tls]`
+  pub tls: Option<TlsConfig>,
+  /// file with the base64 encoded key of session cookies, at least 64 bytes,
+  /// generated on first start if missing, a new key logs every user out
+  pub session_key_path: Option<String>,
+  /// addresses of reverse proxies whose `X-Forwarded-For` is believed, the peer address is
+  /// used as client ip otherwise, e.g. `["127.0.0.1"]` behind a local nginx
+  pub trusted_proxies: Option<Vec<String>>,
 }
 
-/// Simple program to greet a person
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-  #[arg(short, long)]
-  config: Option<String>,
+/// Certificate of the https listener, reloaded when the files change
+#[derive(Deserialize, Debug, Serialize, Clone)]
+pub struct TlsConfig {
+  /// pem file with the certificate chain, leaf first
+  pub cert_path: String,
+  /// pem file with the private key, pkcs8, rsa or ec
+  pub key_path: String,
+  /// port of a plain http listener redirecting to https, none if unset
+  pub redirect_http_port: Option<i32>,
+  /// max-age of the Strict-Transport-Security header, 0 leaves it out, defaults to a year
+  pub hsts_max_age: Option<u64>,
 }
 
-impl AppConfig {
-  pub fn init(&mut self) {
-    let args = Args::parse();
-    let mut config_file = None;
-    if let Some(ref config) = args.config {
-      config_file = Some(config.to_owned());
-    } else {
-      if PathBuf::from("./config.toml").exists() {
-        config_file = Some("./config.toml".to_owned());
-      }
-    }
-    if let Some(config_file) = config_file {
-      let content = std::fs::read_to_string(config_file).unwrap();
-      *self = toml::from_str(&content).unwrap();
-      println!(
-        "app config:\n{}",
-        serde_json::to_string_pretty(self).unwrap()
-      );
-    }
-  }
+/// A directory server, users log in by binding as their entry
+#[derive(Deserialize, Debug, Serialize, Clone)]
+pub struct LdapConfig {
+  /// e.g. "ldap://127.0.0.1:389" or "ldaps://ldap.example.com"
+  pub url: String,
+  pub starttls: Option<bool>,
+  /// account used to search users, anonymous if not set
+  pub bind_dn: Option<String>,
+  pub bind_password: Option<String>,
+  pub base_dn: String,
+  /// `{username}` is replaced by the escaped username, defaults to "(uid={username})"
+  pub user_filter: Option<String>,
+  /// defaults to "mail"
+  pub email_attribute: Option<String>,
+  /// defaults to "memberOf"
+  pub group_attribute: Option<String>,
+  /// group dns or cns to group names, the first match sets the group on login and sync
+  pub group_mapping: Option<HashMap<String, String>>,
+  /// group of new users when no directory group is mapped, defaults to "guest"
+  pub default_group: Option<String>,
+  /// minutes between syncs with the directory, 0 turns sync off, defaults to 60
+  pub sync_interval_minutes: Option<u32>,
 }
 
-macro_rules! default_str {
-  ($env_var:expr, $v: expr) => {
-    std::env::var($env_var).map_or(Some($v), |v| Some(v))
-  };
+/// An OpenID Connect provider, users log in with the authorization code flow and PKCE
+#[derive(Deserialize, Debug, Serialize, Clone)]
+pub struct OidcConfig {
+  /// name of the provider shown on the login page
+  pub display_name: Option<String>,
+  /// issuer url, `<issuer>/.well-known/openid-configuration` must be reachable
+  pub issuer: String,
+  pub client_id: String,
+  pub client_secret: Option<String>,
+  /// `<server url>/auth/oidc/callback`, registered at the provider
+  pub redirect_url: String,
+  /// defaults to "openid email profile"
+  pub scopes: Option<String>,
+  /// claim used as username of new users, defaults to "preferred_username"
+  pub username_claim: Option<String>,
+  /// group of new users when no claim maps to a group, defaults to "guest"
+  pub default_group: Option<String>,
+  /// claim holding a group name or a list of them, e.g. "groups"
+  pub group_claim: Option<String>,
+  /// values of `group_claim` to group names, the first match sets the group on every login
+  pub group_mapping: Option<HashMap<String, String>>,
+  /// log in to an existing account with the same verified email, defaults to true
+  pub link_by_email: Option<bool>,
 }
 
-macro_rules! default_int {
-  ($env_var:expr, $v: expr) => {
-    std::env::var($env_var).map_or(Some($v), |v| Some(v.parse::<i32>().unwrap()))
-  };
+/// A named storage backend declared as `[storage.<name>]` in config.toml,
+/// a user is pointed to it by setting its user root to `<name>://<path>`
+#[derive(Deserialize, Debug, Serialize, Clone)]
+pub struct StorageConfig {
+  /// one of "local", "s3" or "memory"
+  #[serde(rename = "type")]
+  pub kind: String,
+  /// root directory of a local backend
+  pub root: Option<String>,
+  pub endpoint: Option<String>,
+  pub region: Option<String>,
+  pub bucket: Option<String>,
+  pub access_key: Option<String>,
+  pub secret_key: Option<String>,
 }
 
-macro_rules! default_bool {
-  ($env_var:expr, $v: expr) => {
-    std::env::var($env_var).map_or(Some($v), |v| Some(v.parse::<bool>().unwrap()))
-  };
+/// webby.os server, starts the server without a subcommand
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+  #[arg(short, long)]
+  pub config: Option<String>,
+  /// print the effective config and where each value comes from, then exit
+  #[arg(long)]
+  pub print_config: bool,
+  #[arg(long)]
+  pub host: Option<String>,
+  #[arg(long)]
+  pub port: Option<i32>,
+  #[arg(long)]
+  pub file_root: Option<String>,
+  #[arg(long)]
+  pub database_url: Option<String>,
+  #[arg(long)]
+  pub static_dir: Option<String>,
+  #[arg(long)]
+  pub log_path: Option<String>,
+  #[command(subcommand)]
+  pub command: Option<Command>,
+}
+
+/// load defaults, config file, env and flags into `APP_CONFIG` and `DYNAMIC_CONFIG`,
+/// exits listing every problem if the config is invalid
+pub fn init(args: &Args) {
+  let loaded = loader::load(args);
+  if args.print_config {
+    loaded.print();
+  }
+  for warning in &loaded.warnings {
+    eprintln!("config warning: {warning}");
+  }
+  if !loaded.errors.is_empty() {
+    eprintln!("invalid config, {} problems found:", loaded.errors.len());
+    for error in &loaded.errors {
+      eprintln!("  {error}");
+    }
+    std::process::exit(1);
+  }
+  if args.print_config {
+    std::process::exit(0);
+  }
+  *APP_CONFIG.lock().unwrap() = loaded.config;
+  *DYNAMIC_CONFIG.lock().unwrap() = loaded.dynamic;
 }
 
 impl Default for AppConfig {
   fn default() -> Self {
     Self {
-      host: default_str!("HOST", "127.0.0.1".to_owned()),
-      port: default_int!("PORT", 7001),
-      file_root: default_str!("FILE_ROOT", "./files".to_owned()),
-      database_url: default_str!("DATABASE_URL", "sqlite://./app.db".to_owned()),
-      upload_temp_dir: default_str!("UPLOAD_TEMP_DIR", "./files/upload_temp".to_owned()),
-      use_ffmpeg_trancode: default_bool!("USE_FFMPEG_TRANSCODE", false),
-      ffmpeg_bin_path: default_str!("FFMPEG_BIN_PATH", "ffmpeg".to_owned()),
-      indexing_follow_link: default_bool!("INDEXING_FOLLOW_LINK", true),
+      host: Some("127.0.0.1".to_owned()),
+      port: Some(7001),
+      file_root: Some("./files".to_owned()),
+      database_url: Some("sqlite://./app.db".to_owned()),
+      upload_temp_dir: Some("./files/upload_temp".to_owned()),
+      use_ffmpeg_trancode: Some(false),
+      ffmpeg_bin_path: Some("ffmpeg".to_owned()),
+      indexing_follow_link: Some(true),
       search_index_path: Some("index".to_owned()),
-      authentication: default_str!("AUTHENTICATION", "user".to_owned()),
-      static_dir: default_str!("STATIC_DIR", "./static".to_owned()),
-      shell: default_str!("SHELL", "zsh".to_owned()),
-      log_path: default_str!("LOG_PATH", "log/system.log".to_owned()),
+      authentication: Some("user".to_owned()),
+      static_dir: Some("./static".to_owned()),
+      shell: Some("zsh".to_owned()),
+      log_path: Some("log/system.log".to_owned()),
+      storage: Some(HashMap::new()),
+      trash_retention_days: Some(30),
+      fs_watcher: Some(true),
+      fs_watcher_debounce_ms: Some(2000),
+      oidc: None,
+      ldap: None,
+      tls: None,
+      session_key_path: Some("./session.key".to_owned()),
+      trusted_proxies: Some(vec![]),
     }
   }
 }
@@ -108,7 +246,7 @@ lazy_static! {
     Mutex::new(default_config)
   };
   pub static ref DYNAMIC_CONFIG: Mutex<DynamicConfig> = {
-    let default_config = DynamicConfig { smtp: None };
+    let default_config = DynamicConfig::default();
     Mutex::new(default_config)
   };
 }
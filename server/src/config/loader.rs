@@ -0,0 +1,443 @@
+/// Layered config loading
+///
+/// Every key is taken from, in increasing priority, the defaults, the config file, an
+/// environment variable and a command line flag. Problems are collected instead of
+/// panicking on the first one, so a single start lists all of them, and the source of each
+/// value is kept for `--print-config`.
+use std::{
+  collections::BTreeMap,
+  fmt,
+  net::{IpAddr, SocketAddr},
+  path::{Path, PathBuf},
+  str::FromStr,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+
+use super::{AppConfig, Args, DynamicConfig};
+use crate::middlewares::session::read_key;
+use crate::utils::{dynamic_config, storage, tls};
+
+#[derive(Debug, Clone)]
+pub enum Source {
+  Default,
+  File(PathBuf),
+  Env(&'static str),
+  Flag(&'static str),
+}
+
+impl fmt::Display for Source {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Default => write!(f, "default"),
+      Self::File(path) => write!(f, "file {}", path.display()),
+      Self::Env(var) => write!(f, "env {var}"),
+      Self::Flag(flag) => write!(f, "flag --{flag}"),
+    }
+  }
+}
+
+/// environment variable of each key
+const ENV_VARS: [(&str, &str); 18] = [
+  ("host", "HOST"),
+  ("port", "PORT"),
+  ("file_root", "FILE_ROOT"),
+  ("database_url", "DATABASE_URL"),
+  ("upload_temp_dir", "UPLOAD_TEMP_DIR"),
+  ("use_ffmpeg_trancode", "USE_FFMPEG_TRANSCODE"),
+  ("ffmpeg_bin_path", "FFMPEG_BIN_PATH"),
+  ("indexing_follow_link", "INDEXING_FOLLOW_LINK"),
+  ("search_index_path", "SEARCH_INDEX_PATH"),
+  ("authentication", "AUTHENTICATION"),
+  ("static_dir", "STATIC_DIR"),
+  // SHELL is set by every login shell, it would always win over the file
+  ("shell", "WEBBYOS_SHELL"),
+  ("log_path", "LOG_PATH"),
+  ("trash_retention_days", "TRASH_RETENTION_DAYS"),
+  ("fs_watcher", "FS_WATCHER"),
+  ("fs_watcher_debounce_ms", "FS_WATCHER_DEBOUNCE_MS"),
+  ("session_key_path", "SESSION_KEY_PATH"),
+  // a json list, e.g. TRUSTED_PROXIES='["127.0.0.1"]'
+  ("trusted_proxies", "TRUSTED_PROXIES"),
+];
+
+/// keys of the dynamic config checked without a database
+const DYNAMIC_KEYS: [&str; 5] = [
+  "smtp",
+  "public_url",
+  "transcode",
+  "index_update_time",
+  "registration",
+];
+
+pub struct LoadedConfig {
+  pub config: AppConfig,
+  pub dynamic: DynamicConfig,
+  /// keys not listed come from the defaults
+  pub sources: BTreeMap<String, Source>,
+  /// the server does not start with any of these
+  pub errors: Vec<String>,
+  /// problems the server works around
+  pub warnings: Vec<String>,
+}
+
+fn to_map<T: Serialize>(value: &T) -> Map<String, Value> {
+  match serde_json::to_value(value) {
+    Ok(Value::Object(map)) => map,
+    _ => Map::new(),
+  }
+}
+
+/// set `key` of `map` to `value` if `T` accepts it there
+fn merge_key<T: Serialize + DeserializeOwned>(
+  map: &mut Map<String, Value>,
+  key: &str,
+  value: Value,
+) -> Result<(), String> {
+  let mut single = Map::new();
+  single.insert(key.to_owned(), value.clone());
+  serde_json::from_value::<T>(Value::Object(single)).map_err(|err| err.to_string())?;
+  map.insert(key.to_owned(), value);
+  Ok(())
+}
+
+/// an environment variable is tried as json first, so numbers and booleans keep their type
+fn merge_env<T: Serialize + DeserializeOwned>(
+  map: &mut Map<String, Value>,
+  key: &str,
+  raw: &str,
+) -> Result<(), String> {
+  if let Ok(parsed) = serde_json::from_str::<Value>(raw) {
+    if merge_key::<T>(map, key, parsed).is_ok() {
+      return Ok(());
+    }
+  }
+  merge_key::<T>(map, key, Value::String(raw.to_owned()))
+}
+
+fn config_file(args: &Args) -> Option<PathBuf> {
+  if let Some(path) = &args.config {
+    return Some(PathBuf::from(path));
+  }
+  Some(PathBuf::from("./config.toml")).filter(|path| path.exists())
+}
+
+pub fn load(args: &Args) -> LoadedConfig {
+  let mut errors = vec![];
+  let mut sources = BTreeMap::new();
+  let mut app = to_map(&AppConfig::default());
+  let mut dynamic = to_map(&DynamicConfig::default());
+
+  if let Some(path) = config_file(args) {
+    let table = std::fs::read_to_string(&path)
+      .map_err(|err| err.to_string())
+      .and_then(|content| toml::from_str::<toml::Value>(&content).map_err(|err| err.to_string()))
+      .map(|value| to_map(&value));
+    match table {
+      Ok(table) => {
+        for (key, value) in table {
+          let (in_app, in_dynamic) = (app.contains_key(&key), dynamic.contains_key(&key));
+          if !in_app && !in_dynamic {
+            errors.push(format!("{key}: unknown key in {}", path.display()));
+            continue;
+          }
+          let mut result = Ok(());
+          if in_app {
+            result = result.and(merge_key::<AppConfig>(&mut app, &key, value.clone()));
+          }
+          if in_dynamic {
+            result = result.and(merge_key::<DynamicConfig>(&mut dynamic, &key, value));
+          }
+          match result {
+            Ok(_) => {
+              sources.insert(key, Source::File(path.clone()));
+            }
+            Err(err) => errors.push(format!("{key}: {err} in {}", path.display())),
+          }
+        }
+      }
+      Err(err) => errors.push(format!("{}: {err}", path.display())),
+    }
+  }
+
+  for (key, var) in ENV_VARS {
+    if let Ok(raw) = std::env::var(var) {
+      match merge_env::<AppConfig>(&mut app, key, &raw) {
+        Ok(_) => {
+          sources.insert(key.to_owned(), Source::Env(var));
+        }
+        Err(err) => errors.push(format!("{key}: {err} in env {var}")),
+      }
+    }
+  }
+
+  let flags = [
+    ("host", "host", args.host.clone().map(Value::from)),
+    ("port", "port", args.port.map(Value::from)),
+    (
+      "file_root",
+      "file-root",
+      args.file_root.clone().map(Value::from),
+    ),
+    (
+      "database_url",
+      "database-url",
+      args.database_url.clone().map(Value::from),
+    ),
+    (
+      "static_dir",
+      "static-dir",
+      args.static_dir.clone().map(Value::from),
+    ),
+    (
+      "log_path",
+      "log-path",
+      args.log_path.clone().map(Value::from),
+    ),
+  ];
+  for (key, flag, value) in flags {
+    if let Some(value) = value {
+      match merge_key::<AppConfig>(&mut app, key, value) {
+        Ok(_) => {
+          sources.insert(key.to_owned(), Source::Flag(flag));
+        }
+        Err(err) => errors.push(format!("{key}: {err} in flag --{flag}")),
+      }
+    }
+  }
+
+  let config = serde_json::from_value::<AppConfig>(Value::Object(app)).unwrap_or_else(|err| {
+    errors.push(err.to_string());
+    AppConfig::default()
+  });
+  let dynamic =
+    serde_json::from_value::<DynamicConfig>(Value::Object(dynamic)).unwrap_or_else(|err| {
+      errors.push(err.to_string());
+      DynamicConfig::default()
+    });
+  let mut loaded = LoadedConfig {
+    config,
+    dynamic,
+    sources,
+    errors,
+    warnings: vec![],
+  };
+  loaded.check();
+  loaded
+}
+
+/// a directory that exists or can be created
+fn check_dir(path: &str) -> Result<(), String> {
+  let path = Path::new(path);
+  if path.exists() {
+    if !path.is_dir() {
+      return Err(format!("{} is not a directory", path.display()));
+    }
+    return Ok(());
+  }
+  let parent = path
+    .ancestors()
+    .skip(1)
+    .find(|p| p.as_os_str().is_empty() || p.exists())
+    .filter(|p| !p.as_os_str().is_empty())
+    .unwrap_or(Path::new("."));
+  if !parent.is_dir() {
+    return Err(format!(
+      "{} can not be created, {} is not a directory",
+      path.display(),
+      parent.display()
+    ));
+  }
+  if parent
+    .metadata()
+    .map_or(true, |m| m.permissions().readonly())
+  {
+    return Err(format!(
+      "{} can not be created, {} is read only",
+      path.display(),
+      parent.display()
+    ));
+  }
+  Ok(())
+}
+
+fn which_binary(bin: &str) -> Result<PathBuf, String> {
+  which::which(bin).map_err(|err| format!("{bin}: {err}"))
+}
+
+impl LoadedConfig {
+  fn check(&mut self) {
+    let conf = &self.config;
+    let defaults = AppConfig::default();
+    let mut errors = vec![];
+    let mut warnings = vec![];
+
+    let port = conf.port.or(defaults.port).unwrap_or_default();
+    if !(1..=65535).contains(&port) {
+      errors.push(format!("port: {port} is not between 1 and 65535"));
+    } else {
+      let host = conf.host.clone().or(defaults.host).unwrap_or_default();
+      if SocketAddr::from_str(&format!("{host}:{port}")).is_err() {
+        errors.push(format!("host: {host} is not an ip address"));
+      }
+    }
+
+    let authentication = conf.authentication.clone().or(defaults.authentication);
+    match authentication.as_deref() {
+      Some("none") | Some("user") => {}
+      Some("ldap") if conf.ldap.is_none() => {
+        errors.push("ldap: authentication is ldap but [ldap] is not configured".to_owned())
+      }
+      Some("ldap") => {}
+      other => errors.push(format!(
+        "authentication: {} is not none, user or ldap",
+        other.unwrap_or_default()
+      )),
+    }
+
+    if conf.trash_retention_days.map_or(false, |v| v < 0) {
+      errors.push("trash_retention_days: must not be negative".to_owned());
+    }
+    if conf.fs_watcher_debounce_ms.map_or(false, |v| v < 0) {
+      errors.push("fs_watcher_debounce_ms: must not be negative".to_owned());
+    }
+
+    let dirs = [
+      ("file_root", conf.file_root.clone()),
+      ("upload_temp_dir", conf.upload_temp_dir.clone()),
+      ("search_index_path", conf.search_index_path.clone()),
+    ];
+    for (key, dir) in dirs {
+      if let Err(err) = check_dir(&dir.unwrap_or_default()) {
+        errors.push(format!("{key}: {err}"));
+      }
+    }
+    let log_path = PathBuf::from(conf.log_path.clone().unwrap_or_default());
+    if let Some(parent) = log_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+      if let Err(err) = check_dir(&parent.to_string_lossy()) {
+        errors.push(format!("log_path: {err}"));
+      }
+    }
+    let database_url = conf.database_url.clone().unwrap_or_default();
+    let database = PathBuf::from(
+      database_url
+        .strip_prefix("sqlite://")
+        .unwrap_or(&database_url),
+    );
+    if let Some(parent) = database.parent().filter(|p| !p.as_os_str().is_empty()) {
+      // sqlite creates the file but not its directory
+      if !parent.is_dir() {
+        errors.push(format!(
+          "database_url: directory {} does not exist",
+          parent.display()
+        ));
+      }
+    }
+    for proxy in conf.trusted_proxies.clone().unwrap_or_default() {
+      if proxy.parse::<IpAddr>().is_err() {
+        errors.push(format!("trusted_proxies: {proxy} is not an ip address"));
+      }
+    }
+    let session_key_path = PathBuf::from(conf.session_key_path.clone().unwrap_or_default());
+    match read_key(&session_key_path) {
+      Ok(Some(_)) => {}
+      Ok(None) => {
+        // generated on start
+        if let Some(parent) = session_key_path
+          .parent()
+          .filter(|p| !p.as_os_str().is_empty())
+        {
+          if let Err(err) = check_dir(&parent.to_string_lossy()) {
+            errors.push(format!("session_key_path: {err}"));
+          }
+        }
+      }
+      Err(err) => errors.push(format!("session_key_path: {err}")),
+    }
+    let static_dir = conf.static_dir.clone().unwrap_or_default();
+    if !Path::new(&static_dir).is_dir() {
+      warnings.push(format!(
+        "static_dir: {static_dir} does not exist, the desktop is not served"
+      ));
+    }
+
+    if conf.use_ffmpeg_trancode.unwrap_or(false) {
+      if let Err(err) = which_binary(&conf.ffmpeg_bin_path.clone().unwrap_or_default()) {
+        errors.push(format!("ffmpeg_bin_path: {err}"));
+      }
+    }
+    let shell = self
+      .dynamic
+      .shell
+      .clone()
+      .or(conf.shell.clone())
+      .unwrap_or_default();
+    if let Err(err) = which_binary(&shell) {
+      warnings.push(format!(
+        "shell: {err}, terminals fall back to another shell"
+      ));
+    }
+
+    if let Some(tls_conf) = &conf.tls {
+      if let Err(err) = tls::load_certified_key(tls_conf) {
+        errors.push(err.to_string());
+      }
+      if let Some(redirect_port) = tls_conf.redirect_http_port {
+        if !(1..=65535).contains(&redirect_port) || redirect_port == port {
+          errors.push(format!(
+            "tls.redirect_http_port: {redirect_port} is not a free port between 1 and 65535"
+          ));
+        }
+      }
+    }
+
+    for (name, storage_conf) in conf.storage.clone().unwrap_or_default() {
+      if let Err(err) = storage::create_backend(&name, &storage_conf) {
+        errors.push(format!("storage.{name}: {err}"));
+      }
+    }
+
+    for key in DYNAMIC_KEYS {
+      if let Err(err) = dynamic_config::validate(&self.dynamic, key) {
+        errors.push(format!("{key}: {err}"));
+      }
+    }
+
+    self.errors.append(&mut errors);
+    self.warnings.append(&mut warnings);
+  }
+
+  /// effective values and their source, secrets are masked
+  pub fn print(&self) {
+    let dynamic = to_map(&self.dynamic);
+    let mut values = to_map(&self.config);
+    for (key, value) in dynamic {
+      values.entry(key).or_insert(value);
+    }
+    for (key, mut value) in values {
+      if value.is_null() {
+        continue;
+      }
+      mask_secrets(&mut value);
+      let source = self
+        .sources
+        .get(&key)
+        .map_or_else(|| Source::Default.to_string(), |s| s.to_string());
+      println!("{key} = {value}  # {source}");
+    }
+  }
+}
+
+fn mask_secrets(value: &mut Value) {
+  if let Value::Object(map) = value {
+    for (key, v) in map.iter_mut() {
+      let secret = key.contains("secret") || key.contains("password");
+      if secret && v.as_str().map_or(false, |s| !s.is_empty()) {
+        *v = Value::from("********");
+      } else {
+        mask_secrets(v);
+      }
+    }
+  }
+}
@@ -25,11 +25,13 @@ mod routers;
 mod schedulers;
 pub mod schema;
 mod utils;
+use clap::Parser;
 use diesel::prelude::*;
 use diesel::sqlite::SqliteConnection;
 
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("./migrations");
+mod cli;
 mod config;
 mod db;
 
@@ -41,6 +43,9 @@ pub struct UserSessionData {
   user_root: String,
   csrf_token: String,
   ip: String,
+  /// only changing the password is allowed until it is done
+  #[serde(default)]
+  must_change_password: bool,
 }
 
 impl UserSessionData {
@@ -56,6 +61,7 @@ impl UserSessionData {
       user_root: user_root.to_string(),
       csrf_token: uuid::Uuid::new_v4().to_string(),
       ip: "unknown".to_owned(),
+      must_change_password: false,
     }
   }
 }
@@ -85,7 +91,20 @@ pub type AppData = Arc<RwLock<AppState>>;
 
 #[actix_web::main]
 async fn main() -> Result<(), AppError> {
+  let args = config::Args::parse();
+  config::init(&args);
+  match args.command {
+    None | Some(cli::Command::Serve) => serve().await,
+    Some(command) => cli::run(command),
+  }
+}
+
+async fn serve() -> Result<(), AppError> {
   init_log();
+  info!(
+    "app config:\n{}",
+    serde_json::to_string_pretty(&*APP_CONFIG.lock().unwrap()).unwrap()
+  );
 
   let app_state = init();
   let app_state = Arc::new(RwLock::new(app_state));
@@ -96,9 +115,7 @@ async fn main() -> Result<(), AppError> {
   let addr = SocketAddr::from_str(format!("{host}:{port}").as_str()).unwrap();
   drop(state);
 
-  info!("server start on {addr:?}");
-
-  HttpServer::new(move || {
+  let server = HttpServer::new(move || {
     let upload_temp_dir = config!(upload_temp_dir);
     utils::vfs::ensure_dir_sync(&upload_temp_dir).unwrap();
     let awmp_config = awmp::PartsConfig::default().with_temp_dir(&upload_temp_dir);
@@ -114,27 +131,47 @@ async fn main() -> Result<(), AppError> {
       .service(routers::system_info::system_info_routers())
       .service(routers::shell::shell_routers())
       .service(routers::fs::file_routers())
+      .service(routers::mount::mount_routers())
+      .service(routers::acl::acl_routers())
+      .service(routers::quota::quota_routers())
+      .service(routers::dav::dav_routers())
+      .service(routers::share::share_routers())
+      .service(routers::share::public_share_routers())
       .service(routers::log::log_routers())
       .service(routers::auth::auth_routers())
+      .service(routers::admin::admin_routers())
       .service(routers::gallery::gallery_routers())
       .service(routers::index::index_routers())
       .wrap(middlewares::guard::guard_mw())
       .wrap(middlewares::static_server::static_server())
       .wrap(middlewares::csrf::csrf_token())
       .wrap(middlewares::session::session())
-  })
-  .bind(addr)?
-  .run()
-  .await
-  .unwrap();
+      .wrap(middlewares::https::https())
+  });
+
+  let server = match utils::tls::server_config()? {
+    Some(tls_config) => {
+      info!("server start on https://{addr:?}");
+      let mut server = server.bind_rustls_021(addr, tls_config)?;
+      if let Some(port) = utils::tls::config().and_then(|c| c.redirect_http_port) {
+        let redirect_addr = SocketAddr::new(addr.ip(), port as u16);
+        info!("redirect http://{redirect_addr:?} to https");
+        server = server.bind(redirect_addr)?;
+      }
+      server
+    }
+    None => {
+      info!("server start on {addr:?}");
+      server.bind(addr)?
+    }
+  };
+  server.run().await.unwrap();
 
   Ok(())
 }
 
 // init global static config, database connection etc.
 fn init() -> AppState {
-  APP_CONFIG.lock().unwrap().init();
-
   let port: i32 = config!(port);
 
   let host = config!(host);
@@ -145,12 +182,72 @@ fn init() -> AppState {
   abs_file_root.push(file_root);
   abs_static_root.push(static_root);
   fs::create_dir_all(&abs_file_root).unwrap();
+  utils::storage::init_storage_backends().unwrap();
 
   let mut conn = connect_db();
   run_migrations(&mut conn);
 
   auto_create_user_group(&mut conn);
   auto_create_user(&mut conn);
+  utils::dynamic_config::load().unwrap();
+  lazy_static::initialize(&middlewares::session::COOKIE_KEY);
+
+  schedulers::purge_trash::JOB_PURGE_TRASH
+    .lock()
+    .unwrap()
+    .init(&abs_file_root)
+    .unwrap();
+  schedulers::purge_versions::JOB_PURGE_VERSIONS
+    .lock()
+    .unwrap()
+    .init(&abs_file_root)
+    .unwrap();
+  schedulers::purge_sessions::JOB_PURGE_SESSIONS
+    .lock()
+    .unwrap()
+    .init()
+    .unwrap();
+  schedulers::purge_uploads::JOB_PURGE_UPLOADS
+    .lock()
+    .unwrap()
+    .init()
+    .unwrap();
+  schedulers::send_mail::JOB_SEND_MAIL
+    .lock()
+    .unwrap()
+    .init()
+    .unwrap();
+  schedulers::reload_cert::JOB_RELOAD_CERT
+    .lock()
+    .unwrap()
+    .init()
+    .unwrap();
+  schedulers::ldap_sync::JOB_LDAP_SYNC
+    .lock()
+    .unwrap()
+    .init()
+    .unwrap();
+  schedulers::update_file_index::JOB_UPDATE_GALLERY
+    .lock()
+    .unwrap()
+    .set_file_root(&abs_file_root);
+  schedulers::update_file_index::JOB_UPDATE_GALLERY
+    .lock()
+    .unwrap()
+    .apply_schedule()
+    .unwrap();
+  utils::dynamic_config::listen("index_update_time", |_| {
+    schedulers::update_file_index::JOB_UPDATE_GALLERY
+      .lock()
+      .unwrap()
+      .apply_schedule()
+      .unwrap_or_else(|err| tracing::error!("schedule file index update failed: {err}"));
+  });
+  schedulers::fs_watcher::JOB_FS_WATCHER
+    .lock()
+    .unwrap()
+    .init(&abs_file_root)
+    .unwrap();
 
   let state = AppState {
     config: AppConfig {
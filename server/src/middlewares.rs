@@ -1,4 +1,5 @@
 pub mod session;
 pub mod guard;
+pub mod https;
 pub mod static_server;
 pub mod csrf;
\ No newline at end of file
@@ -5,15 +5,17 @@ use actix_web::{
   body::BoxBody,
   cookie::Cookie,
   dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-  http::Method,
+  http::{header, Method},
   Error, HttpResponse,
 };
 use futures_util::future::LocalBoxFuture;
 
 use crate::utils::{
+  api_token,
   error::AppError,
   response::{create_resp, EmptyResponseData},
   session::SessionUtils,
+  tls,
 };
 
 // There are two steps in middleware processing.
@@ -63,7 +65,17 @@ where
       false
     };
 
-    if is_post {
+    // share links are public and do not rely on session
+    let is_public = req.path().starts_with("/s/");
+    // browsers never send api tokens by themselves, the guard checks them
+    let has_api_token = req
+      .headers()
+      .get(header::AUTHORIZATION)
+      .and_then(|v| v.to_str().ok())
+      .and_then(api_token::parse_bearer)
+      .is_some();
+
+    if is_post && !is_public && !has_api_token {
       let req = req.request();
       let mut sess = req.get_session();
       let csrf_token = req
@@ -110,6 +122,7 @@ fn refresh_token(resp: &mut HttpResponse, sess: &mut Session) -> Result<(), AppE
   let cookie = Cookie::build("csrf_token", &csrf_token)
     .max_age(time::Duration::seconds(3600 * 24 * 30))
     .http_only(false)
+    .secure(tls::is_enabled())
     .path("/")
     .finish();
   resp.add_cookie(&cookie)?;
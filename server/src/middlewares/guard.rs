@@ -6,7 +6,8 @@ use std::future::{ready, Ready};
 use actix_web::{
   body::BoxBody,
   dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-  Error,
+  http::{header, Method, StatusCode},
+  Error, HttpResponse, ResponseError,
 };
 use futures_util::future::LocalBoxFuture;
 use lazy_static::lazy_static;
@@ -14,19 +15,36 @@ use regex::Regex;
 
 use crate::{
   config,
-  routers::auth::login_fake_user,
+  routers::{auth::login_fake_user, dav::DAV_PREFIX},
   utils::{
-    auth::ONETIME_TOKENS,
+    api_token,
+    auth::{is_otp_enabled, parse_basic_auth, verify_password, ONETIME_TOKENS},
     error::AppError,
+    permission::{self, Permission},
+    rate_limit::{self, Action},
     response::{create_resp, EmptyResponseData},
-    session::SessionUtils,
+    session::{client_ip, user_agent, SessionUtils, TRANSIENT_KEY},
   },
+  UserSessionData,
 };
 
 lazy_static! {
-  pub static ref IGNORE_PATHS: Vec<Regex> = vec![Regex::new(r#"^/static/.+"#).unwrap()];
+  pub static ref IGNORE_PATHS: Vec<Regex> = vec![
+    Regex::new(r#"^/static/.+"#).unwrap(),
+    // public share links
+    Regex::new(r#"^/s/.+"#).unwrap(),
+  ];
   pub static ref ALLOW_PATHS: HashSet<&'static str> = vec![
     "/auth/login",
+    "/auth/web_authn_start_login",
+    "/auth/web_authn_finish_login",
+    "/auth/oidc/info",
+    "/auth/oidc/login",
+    "/auth/oidc/callback",
+    "/auth/forgot_password",
+    "/auth/reset_password_with_token",
+    "/auth/verify_email",
+    "/auth/sign_up",
     "/login",
     "/",
     // "/asset-manifest.json",
@@ -35,6 +53,78 @@ lazy_static! {
   ]
   .into_iter()
   .collect();
+  /// routes still usable by a user who has to change its password
+  pub static ref PASSWORD_CHANGE_PATHS: HashSet<&'static str> = vec![
+    "/auth/reset_password",
+    "/auth/logout",
+  ]
+  .into_iter()
+  .collect();
+  /// permission required by each route scope, the first match wins,
+  /// other routes only require login
+  pub static ref ROUTE_PERMISSIONS: Vec<(Regex, Permission)> = vec![
+    // shell
+    (r#"^/websocket/shell/"#, Permission::Shell),
+    // tunnel
+    (r#"^/tunnel/"#, Permission::Tunnel),
+    (r#"^/websocket/websockify/"#, Permission::Tunnel),
+    // auth
+    (
+      r#"^/auth/(register|delete_user|set_user_info|set_user_disabled|get_all_users|get_all_groups|set_group_permissions|get_session_state|delete_session_state|sessions/all|sessions/revoke_any|lockouts/list|lockouts/clear|lockouts/audit)$"#,
+      Permission::UserAdmin,
+    ),
+    // mount
+    (r#"^/mount/(add|remove)$"#, Permission::UserAdmin),
+    (r#"^/mount/"#, Permission::FsRead),
+    // quota
+    (r#"^/quota/(list|set_user|set_group)$"#, Permission::UserAdmin),
+    // log
+    (r#"^/log/"#, Permission::LogRead),
+    // admin
+    (r#"^/admin/config/"#, Permission::Config),
+    // system info
+    (r#"^/system_info/"#, Permission::SystemInfo),
+    // kv storage
+    (r#"^/kv_storage/"#, Permission::Kv),
+    (r#"^/websocket/kv_storage/"#, Permission::Kv),
+    // file
+    (
+      r#"^/file/(move|copy|upload|resumable/.*|delete_batch|trash/(restore|empty)|versions/(restore|policy/(set|remove))|extract|compress|create_dir|delete)$"#,
+      Permission::FsWrite,
+    ),
+    (r#"^/file/"#, Permission::FsRead),
+    // share
+    (r#"^/share/"#, Permission::FsRead),
+    (r#"^/acl/"#, Permission::FsRead),
+    // gallery
+    (r#"^/gallery/update_index$"#, Permission::FsWrite),
+    (r#"^/gallery/"#, Permission::FsRead),
+  ]
+  .into_iter()
+  .map(|(re, permission)| (Regex::new(re).unwrap(), permission))
+  .collect();
+}
+
+/// permission required to call `path` with `method`
+pub fn required_permission(method: &Method, path: &str) -> Option<Permission> {
+  if path.starts_with(DAV_PREFIX) {
+    return match method.as_str() {
+      "OPTIONS" | "PROPFIND" | "GET" | "HEAD" => Some(Permission::FsRead),
+      _ => Some(Permission::FsWrite),
+    };
+  }
+  ROUTE_PERMISSIONS
+    .iter()
+    .find(|(re, _)| re.is_match(path))
+    .map(|(_, permission)| *permission)
+}
+
+/// fails with 403 when `user` can not call the requested route
+fn check_permission(req: &ServiceRequest, user: &str) -> Result<bool, AppError> {
+  if let Some(required) = required_permission(req.method(), req.path()) {
+    permission::require(user, required)?;
+  }
+  Ok(true)
 }
 
 pub fn guard(req: &ServiceRequest) -> Result<bool, AppError> {
@@ -47,12 +137,18 @@ pub fn guard(req: &ServiceRequest) -> Result<bool, AppError> {
   let query = qstring::QString::from(r.query_string());
   let one_time_token = query.get("one_time_token");
   if let Some(token) = one_time_token {
-    let tokens = ONETIME_TOKENS.lock().unwrap();
-    let exist = tokens.get(token);
-    if let Some(token) = exist {
-      if r.path().starts_with(&token.module_prefix) {
-        return Ok(true);
-      }
+    let ip = client_ip(req.request());
+    rate_limit::check(Action::OneTimeToken, &ip, None)?;
+    let create_user = ONETIME_TOKENS
+      .lock()
+      .unwrap()
+      .get(token)
+      .filter(|token| r.path().starts_with(&token.module_prefix))
+      .map(|token| token.create_user.clone());
+    match create_user {
+      // the token acts on behalf of the user who created it
+      Some(create_user) => return check_permission(req, &create_user),
+      None => rate_limit::fail(Action::OneTimeToken, &ip, None, "invalid one time token")?,
     }
   }
   let p = r.path();
@@ -64,8 +160,101 @@ pub fn guard(req: &ServiceRequest) -> Result<bool, AppError> {
   if ALLOW_PATHS.contains(p) {
     return Ok(true);
   }
+  let bearer = req
+    .headers()
+    .get(header::AUTHORIZATION)
+    .and_then(|v| v.to_str().ok())
+    .and_then(api_token::parse_bearer);
+  if let Some(token) = bearer {
+    return api_token_auth(req, token);
+  }
   let sess = r.get_session();
-  sess.is_login()
+  if p.starts_with(DAV_PREFIX) && !sess.is_login()? && !dav_basic_auth(req)? {
+    return Ok(false);
+  }
+  if !sess.is_login()? {
+    return Ok(false);
+  }
+  let user_data = sess.get_user_data()?;
+  if user_data.must_change_password && !PASSWORD_CHANGE_PATHS.contains(p) {
+    return Err(
+      AppError::new("password must be changed before continuing")
+        .with_status(StatusCode::FORBIDDEN),
+    );
+  }
+  check_permission(req, &user_data.username)?;
+  sess.touch_device(&user_agent(req.request()))?;
+  Ok(true)
+}
+
+/// requests with an api token get a session of the token user which is never stored,
+/// the token scopes limit the permissions of the user
+fn api_token_auth(req: &ServiceRequest, token: &str) -> Result<bool, AppError> {
+  let (token, user) = match api_token::authenticate(token)? {
+    Some(found) => found,
+    None => return Ok(false),
+  };
+  if user.must_change_password || user.disabled {
+    return Ok(false);
+  }
+  if req.path().starts_with("/auth/api_tokens/") {
+    return Err(
+      AppError::new("api tokens can not manage api tokens").with_status(StatusCode::FORBIDDEN),
+    );
+  }
+  check_permission(req, &user.username)?;
+  if let Some(required) = required_permission(req.method(), req.path()) {
+    if !api_token::scopes(&token).contains(&required) {
+      return Err(
+        AppError::new(&format!(
+          "permission denied: {} not in token scopes",
+          required.name()
+        ))
+        .with_status(StatusCode::FORBIDDEN),
+      );
+    }
+  }
+  let mut user_data = UserSessionData::new(&user.username, &user.user_root);
+  user_data.ip = client_ip(req.request());
+  let sess = req.get_session();
+  sess.insert("user", user_data)?;
+  sess.insert(api_token::SESSION_KEY, &token.id)?;
+  Ok(true)
+}
+
+/// DAV clients can not use the login page, they send credentials with basic auth instead,
+/// users with otp enabled can only use DAV after logging in on the web. Like api tokens,
+/// basic auth is checked on every request and its session is never stored.
+fn dav_basic_auth(req: &ServiceRequest) -> Result<bool, AppError> {
+  let credentials = req
+    .headers()
+    .get(header::AUTHORIZATION)
+    .and_then(|v| v.to_str().ok())
+    .and_then(parse_basic_auth);
+  let (name, pwd) = match credentials {
+    Some(credentials) => credentials,
+    None => return Ok(false),
+  };
+  let ip = client_ip(req.request());
+  rate_limit::check(Action::Login, &ip, Some(&name))?;
+  let user = match verify_password(&name, &pwd)? {
+    Some(user) if !user.must_change_password => user,
+    Some(_) => return Ok(false),
+    None => {
+      rate_limit::fail(Action::Login, &ip, Some(&name), "wrong password or unknown user")?;
+      return Ok(false);
+    }
+  };
+  rate_limit::succeed(Action::Login, &name);
+  if is_otp_enabled(&user.username)? {
+    return Ok(false);
+  }
+  let mut user_data = UserSessionData::new(&user.username, &user.user_root);
+  user_data.ip = ip;
+  let sess = req.get_session();
+  sess.insert("user", user_data)?;
+  sess.insert(TRANSIENT_KEY, true)?;
+  Ok(true)
 }
 
 pub struct Guard;
@@ -105,14 +294,24 @@ where
   forward_ready!(service);
 
   fn call(&self, req: ServiceRequest) -> Self::Future {
-    let ip = req
-      .request()
-      .connection_info()
-      .realip_remote_addr()
-      .map_or("unknown ip".to_owned(), |v| v.to_owned());
+    let ip = client_ip(req.request());
     let path = req.path().to_owned();
 
     let ret = guard(&req);
+    if let Err(err) = &ret {
+      if err.status_code == StatusCode::FORBIDDEN
+        || err.status_code == StatusCode::TOO_MANY_REQUESTS
+      {
+        tracing::info!(
+          "Permission Error - CLIENT IP: {}, PATH: {}, {}",
+          ip,
+          path,
+          err
+        );
+        let r = ServiceResponse::new(req.request().clone(), err.error_response());
+        return Box::pin(async move { Ok(r) });
+      }
+    }
     if let Ok(is_valid_request) = ret {
       if is_valid_request {
         let fut = self.service.call(req);
@@ -135,6 +334,12 @@ where
 
     return Box::pin(async move {
       tracing::info!("Auth Error - CLIENT IP: {}, PATH: {}", ip, path);
+      if path.starts_with(DAV_PREFIX) {
+        let resp = HttpResponse::Unauthorized()
+          .insert_header((header::WWW_AUTHENTICATE, r#"Basic realm="webby.os""#))
+          .finish();
+        return Ok(ServiceResponse::new(req.request().clone(), resp));
+      }
       let resp = create_resp(false, EmptyResponseData::new(), "authentication error");
       let r = ServiceResponse::new(req.request().clone(), resp);
       Ok(r)
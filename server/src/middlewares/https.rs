@@ -0,0 +1,105 @@
+use std::future::{ready, Ready};
+
+use actix_web::{
+  body::BoxBody,
+  dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+  http::header::{self, HeaderValue},
+  Error, HttpResponse,
+};
+use futures_util::future::LocalBoxFuture;
+
+use crate::{config, utils::tls};
+
+/// With tls, requests to the plain http listener are redirected to https and https
+/// responses tell browsers to stay on https. Does nothing without tls.
+pub struct Https {
+  /// port of the https listener and max-age of the hsts header
+  tls: Option<(i32, u64)>,
+}
+
+impl<S> Transform<S, ServiceRequest> for Https
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error>,
+  S::Future: 'static,
+{
+  type Response = ServiceResponse<BoxBody>;
+  type Error = Error;
+  type InitError = ();
+  type Transform = HttpsMiddleware<S>;
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(HttpsMiddleware {
+      service,
+      tls: self.tls,
+    }))
+  }
+}
+
+pub struct HttpsMiddleware<S> {
+  service: S,
+  tls: Option<(i32, u64)>,
+}
+
+impl<S> Service<ServiceRequest> for HttpsMiddleware<S>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error>,
+  S::Future: 'static,
+{
+  type Response = ServiceResponse<BoxBody>;
+  type Error = actix_web::Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+  forward_ready!(service);
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    let (port, hsts_max_age) = match self.tls {
+      Some(tls) => tls,
+      None => return Box::pin(self.service.call(req)),
+    };
+
+    if !req.app_config().secure() {
+      let host = req.connection_info().host().to_owned();
+      // the host header names the port of the plain listener
+      let host = match host.rsplit_once(':') {
+        Some((name, p)) if p.chars().all(|c| c.is_ascii_digit()) => name.to_owned(),
+        _ => host,
+      };
+      let authority = if port == 443 {
+        host
+      } else {
+        format!("{host}:{port}")
+      };
+      let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+      let location = format!("https://{authority}{path}");
+      let resp = HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, location))
+        .finish();
+      return Box::pin(async move { Ok(req.into_response(resp)) });
+    }
+
+    let fut = self.service.call(req);
+    Box::pin(async move {
+      let mut res = fut.await?;
+      if hsts_max_age > 0 {
+        let value = format!("max-age={hsts_max_age}; includeSubDomains");
+        if let Ok(value) = HeaderValue::from_str(&value) {
+          res
+            .headers_mut()
+            .insert(header::STRICT_TRANSPORT_SECURITY, value);
+        }
+      }
+      Ok(res)
+    })
+  }
+}
+
+pub fn https() -> Https {
+  let tls = tls::config().map(|conf| {
+    (
+      config!(port),
+      conf.hsts_max_age.unwrap_or(tls::DEFAULT_HSTS_MAX_AGE),
+    )
+  });
+  Https { tls }
+}
@@ -4,33 +4,95 @@ use actix_session::{
   SessionMiddleware,
 };
 use actix_web::{cookie::Key, http::header::InvalidHeaderValue};
-use chrono::Utc;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::Engine;
+use chrono::{TimeZone, Utc};
+use lazy_static::lazy_static;
 use serde::{Serialize, ser::SerializeStruct};
-use std::{collections::HashMap, sync::RwLock};
+use std::{collections::HashMap, io::Write, path::Path};
 use time::Duration;
 
-use crate::{utils::error::AppError, conv_err};
+use crate::{
+  config,
+  conv_err,
+  db::SHARED_DB_CONN,
+  models::StoredSession,
+  utils::{
+    api_token,
+    error::AppError,
+    session::{DeviceInfo, DEVICE_KEY, TRANSIENT_KEY},
+    tls,
+  },
+  UserSessionData,
+};
 
 conv_err!(InvalidHeaderValue);
 
-pub fn session() -> SessionMiddleware<MemorySessionStore> {
+lazy_static! {
+  /// signs and encrypts session cookies, shared by every worker and kept across restarts
+  pub static ref COOKIE_KEY: Key = load_or_create_key(Path::new(&config!(session_key_path)))
+    .unwrap_or_else(|err| panic!("session key: {err}"));
+}
+
+/// key stored base64 encoded in `path`, None if the file does not exist
+pub fn read_key(path: &Path) -> Result<Option<Key>, AppError> {
+  if !path.exists() {
+    return Ok(None);
+  }
+  let content = std::fs::read_to_string(path)?;
+  let bytes = base64::engine::general_purpose::STANDARD
+    .decode(content.trim())
+    .map_err(|err| AppError::new(&format!("{}: {err}", path.display())))?;
+  let key = Key::try_from(bytes.as_slice()).map_err(|_| {
+    AppError::new(&format!(
+      "{}: a key needs at least 64 bytes, found {}",
+      path.display(),
+      bytes.len()
+    ))
+  })?;
+  Ok(Some(key))
+}
+
+/// read the key in `path`, or generate one and store it there on first start
+fn load_or_create_key(path: &Path) -> Result<Key, AppError> {
+  if let Some(key) = read_key(path)? {
+    return Ok(key);
+  }
+  let mut bytes = [0u8; 64];
+  OsRng.fill_bytes(&mut bytes);
+  if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+    std::fs::create_dir_all(parent)?;
+  }
+  let mut options = std::fs::OpenOptions::new();
+  options.write(true).create_new(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+  let mut file = options.open(path)?;
+  file.write_all(
+    base64::engine::general_purpose::STANDARD
+      .encode(bytes)
+      .as_bytes(),
+  )?;
+  tracing::info!("generated session key in {}", path.display());
+  Ok(Key::from(&bytes))
+}
+
+pub fn session() -> SessionMiddleware<SqliteSessionStore> {
   let session_ttl = PersistentSession::default();
   let session_ttl = session_ttl.session_ttl(actix_web::cookie::time::Duration::days(30));
-  let store = MemorySessionStore::new();
-  SessionMiddleware::builder(store, Key::from(&[0; 64]))
-    .cookie_secure(false)
+  let store = SqliteSessionStore::new();
+  SessionMiddleware::builder(store, COOKIE_KEY.clone())
+    .cookie_secure(tls::is_enabled())
     .cookie_content_security(CookieContentSecurity::Private)
     .session_lifecycle(session_ttl)
     .build()
 }
 
-pub struct MemorySessionStore {}
-
-lazy_static::lazy_static! {
-  pub static ref SESSION_STATE: RwLock<HashMap<String, InternalState>> = {
-    RwLock::new(HashMap::new())
-  };
-}
+/// Sessions stored in the `sessions` table, so users stay logged in across restarts.
+///
+/// Rows are keyed by the hash of the session key, a leaked database does not leak
+/// usable session cookies. Expired rows are removed by the `purge_sessions` job.
+pub struct SqliteSessionStore {}
 
 #[derive(Clone)]
 pub struct InternalState {
@@ -50,25 +112,214 @@ impl Serialize for InternalState {
   }
 }
 
-impl InternalState {
-  fn new(ttl: &Duration, state: SessionState) -> Self {
-    let expires = ttl_to_expires(ttl);
-    Self {
-      ttl: expires,
-      state,
-    }
-  }
-}
-
 type SessionState = HashMap<String, String>;
 
-impl MemorySessionStore {
+impl SqliteSessionStore {
   pub fn new() -> Self {
     Self {}
   }
 }
 
-impl SessionStore for MemorySessionStore {
+fn hash_key(key: &str) -> String {
+  sha256::digest(key.to_string())
+}
+
+fn now_secs() -> i64 {
+  Utc::now().timestamp()
+}
+
+/// row of a session, user and device columns are copied from the state
+fn to_row(key: &str, session_state: &SessionState, ttl: &Duration) -> Result<StoredSession, AppError> {
+  let user = session_state
+    .get("user")
+    .and_then(|v| serde_json::from_str::<UserSessionData>(v).ok())
+    .filter(|user| user.is_login);
+  let device = session_state
+    .get(DEVICE_KEY)
+    .and_then(|v| serde_json::from_str::<DeviceInfo>(v).ok());
+  Ok(StoredSession {
+    id: hash_key(key),
+    state: serde_json::to_string(session_state)?,
+    expires_at: ttl_to_expires(ttl).timestamp(),
+    username: user.as_ref().map(|user| user.username.clone()),
+    device_id: device.as_ref().map(|device| device.id.clone()),
+    user_agent: device.as_ref().map(|device| device.user_agent.clone()),
+    ip: user.map(|user| user.ip),
+    created_at: device.as_ref().map(|device| device.created_at),
+    last_seen: device.map(|device| device.last_seen),
+  })
+}
+
+fn save_session(key: &str, session_state: &SessionState, ttl: &Duration) -> Result<(), AppError> {
+  let row = to_row(key, session_state, ttl)?;
+  use crate::schema::sessions::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::replace_into(sessions)
+    .values(&row)
+    .execute(&mut *conn)?;
+  Ok(())
+}
+
+fn load_session(key: &str) -> Result<Option<SessionState>, AppError> {
+  let row = {
+    use crate::schema::sessions::dsl::*;
+    use diesel::prelude::*;
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    sessions
+      .filter(id.eq(hash_key(key)).and(expires_at.gt(now_secs())))
+      .select(state)
+      .first::<String>(&mut *conn)
+      .optional()?
+  };
+  match row {
+    Some(row) => Ok(Some(serde_json::from_str(&row)?)),
+    None => Ok(None),
+  }
+}
+
+/// returns false when the session does not exist any more
+fn update_session(
+  key: &str,
+  session_state: Option<&SessionState>,
+  ttl: &Duration,
+) -> Result<bool, AppError> {
+  use crate::schema::sessions::dsl::*;
+  use diesel::prelude::*;
+  let expires = ttl_to_expires(ttl).timestamp();
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  // an expired session is not extended, it may only be purged
+  let target = sessions.filter(id.eq(hash_key(key)).and(expires_at.gt(now_secs())));
+  let r = match session_state {
+    Some(session_state) => diesel::update(target)
+      .set(&to_row(key, session_state, ttl)?)
+      .execute(&mut *conn)?,
+    None => diesel::update(target)
+      .set(expires_at.eq(expires))
+      .execute(&mut *conn)?,
+  };
+  Ok(r > 0)
+}
+
+/// every live session, keyed by session id
+pub fn list_sessions() -> Result<HashMap<String, InternalState>, AppError> {
+  use crate::schema::sessions::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let rows = sessions
+    .filter(expires_at.gt(now_secs()))
+    .load::<StoredSession>(&mut *conn)?;
+  let mut r = HashMap::new();
+  for row in rows {
+    let ttl = Utc
+      .timestamp_opt(row.expires_at, 0)
+      .single()
+      .unwrap_or_else(Utc::now);
+    r.insert(
+      row.id,
+      InternalState {
+        ttl,
+        state: serde_json::from_str(&row.state)?,
+      },
+    );
+  }
+  Ok(r)
+}
+
+/// remove a session by its id, the user of the session is logged out
+pub fn delete_session(session_id: &str) -> Result<bool, AppError> {
+  use crate::schema::sessions::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = diesel::delete(sessions.filter(id.eq(session_id))).execute(&mut *conn)?;
+  Ok(r > 0)
+}
+
+/// a logged in session as shown to users
+#[derive(Debug, Serialize)]
+pub struct SessionDevice {
+  pub id: String,
+  pub username: String,
+  pub user_agent: String,
+  pub ip: String,
+  pub created_at: i64,
+  pub last_seen: i64,
+  pub expires_at: i64,
+}
+
+/// logged in sessions of `user`, or of every user
+pub fn list_devices(user: Option<&str>) -> Result<Vec<SessionDevice>, AppError> {
+  use crate::schema::sessions::dsl::*;
+  use diesel::prelude::*;
+  let mut query = sessions
+    .filter(expires_at.gt(now_secs()))
+    .filter(username.is_not_null().and(device_id.is_not_null()))
+    .into_boxed();
+  if let Some(user) = user {
+    query = query.filter(username.eq(user));
+  }
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let rows = query
+    .order(last_seen.desc())
+    .load::<StoredSession>(&mut *conn)?;
+  let r = rows
+    .into_iter()
+    .map(|row| SessionDevice {
+      id: row.device_id.unwrap_or_default(),
+      username: row.username.unwrap_or_default(),
+      user_agent: row.user_agent.unwrap_or_default(),
+      ip: row.ip.unwrap_or_default(),
+      created_at: row.created_at.unwrap_or_default(),
+      last_seen: row.last_seen.unwrap_or_default(),
+      expires_at: row.expires_at,
+    })
+    .collect();
+  Ok(r)
+}
+
+/// log out sessions of `user` by device id, or every session of `user` when `device` is None,
+/// returns how many were removed
+pub fn revoke_devices(user: &str, device: Option<&str>) -> Result<usize, AppError> {
+  use crate::schema::sessions::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = match device {
+    Some(device) => diesel::delete(sessions.filter(username.eq(user).and(device_id.eq(device))))
+      .execute(&mut *conn)?,
+    None => diesel::delete(sessions.filter(username.eq(user))).execute(&mut *conn)?,
+  };
+  Ok(r)
+}
+
+/// remove a session by device id, whoever it belongs to
+pub fn revoke_device(device: &str) -> Result<bool, AppError> {
+  use crate::schema::sessions::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = diesel::delete(sessions.filter(device_id.eq(device))).execute(&mut *conn)?;
+  Ok(r > 0)
+}
+
+/// remove expired sessions, returns how many were removed
+pub fn purge_expired() -> Result<usize, AppError> {
+  use crate::schema::sessions::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = diesel::delete(sessions.filter(expires_at.le(now_secs()))).execute(&mut *conn)?;
+  Ok(r)
+}
+
+/// requests with an api token or basic auth are authenticated one by one, their sessions are
+/// never stored
+fn is_transient(session_state: &SessionState) -> bool {
+  session_state.contains_key(api_token::SESSION_KEY) || session_state.contains_key(TRANSIENT_KEY)
+}
+
+fn other_err(err: AppError) -> anyhow::Error {
+  anyhow::anyhow!(err.to_string())
+}
+
+impl SessionStore for SqliteSessionStore {
   fn save<'life0, 'life1, 'async_trait>(
     &'life0 self,
     session_state: SessionState,
@@ -86,21 +337,11 @@ impl SessionStore for MemorySessionStore {
   {
     Box::pin(async move {
       let key = uuid::Uuid::new_v4().to_string();
-      let mut state = SESSION_STATE.write().unwrap();
-
-      state.insert(key.clone(), InternalState::new(ttl, session_state.clone()));
-
-      let now = chrono::Utc::now();
-      let mut to_delete = vec![];
-      for s in state.iter() {
-        if s.1.ttl < now {
-          to_delete.push(s.0.clone());
-        }
+      if is_transient(&session_state) {
+        return Ok(key.try_into().unwrap());
       }
-      for key in to_delete {
-        state.remove(&key);
-      }
-
+      save_session(&key, &session_state, ttl)
+        .map_err(|err| storage::SaveError::Other(other_err(err)))?;
       let sess_key: SessionKey = key.try_into().unwrap();
       Ok(sess_key)
     })
@@ -121,12 +362,7 @@ impl SessionStore for MemorySessionStore {
     Self: 'async_trait,
   {
     Box::pin(async move {
-      let state = SESSION_STATE.read().unwrap();
-      let internal_state = state.get(session_key.as_ref());
-      if let Some(internal_state) = internal_state {
-        return Ok(Some(internal_state.state.clone()));
-      }
-      Ok(None)
+      load_session(session_key.as_ref()).map_err(|err| storage::LoadError::Other(other_err(err)))
     })
   }
 
@@ -142,21 +378,7 @@ impl SessionStore for MemorySessionStore {
     Self: 'async_trait,
   {
     Box::pin(async move {
-      let mut state = SESSION_STATE.write().unwrap();
-
-      state.remove(session_key.as_ref());
-
-      let now = chrono::Utc::now();
-      let mut to_delete = vec![];
-      for s in state.iter() {
-        if s.1.ttl > now {
-          to_delete.push(s.0.clone());
-        }
-      }
-      for key in to_delete {
-        state.remove(&key);
-      }
-
+      delete_session(&hash_key(session_key.as_ref())).map_err(other_err)?;
       Ok(())
     })
   }
@@ -175,15 +397,18 @@ impl SessionStore for MemorySessionStore {
     Self: 'async_trait,
   {
     Box::pin(async move {
-      let key = session_key.as_ref();
-      let mut state = SESSION_STATE.write().unwrap();
-      let internal_state = state.entry(key.to_owned());
-      let expires = ttl_to_expires(ttl);
-      internal_state.and_modify(|s| {
-        s.state = session_state;
-        s.ttl = expires;
-      });
-      Ok(session_key)
+      if is_transient(&session_state) {
+        return Ok(session_key);
+      }
+      let updated = update_session(session_key.as_ref(), Some(&session_state), ttl)
+        .map_err(|err| storage::UpdateError::Other(other_err(err)))?;
+      if updated {
+        return Ok(session_key);
+      }
+      // the session was revoked or expired during the request and must not come back,
+      // the client gets the key of no stored session and starts over anonymously
+      let sess_key: SessionKey = uuid::Uuid::new_v4().to_string().try_into().unwrap();
+      Ok(sess_key)
     })
   }
 
@@ -201,11 +426,7 @@ impl SessionStore for MemorySessionStore {
     Self: 'async_trait,
   {
     Box::pin(async move {
-      let key = session_key.as_ref();
-      let mut state = SESSION_STATE.write().unwrap();
-      let internal_state = state.entry(key.to_owned());
-      let expires = ttl_to_expires(ttl);
-      internal_state.and_modify(|s| s.ttl = expires);
+      update_session(session_key.as_ref(), None, ttl).map_err(other_err)?;
       Ok(())
     })
   }
@@ -217,3 +438,40 @@ fn ttl_to_expires(ttl: &Duration) -> chrono::DateTime<Utc> {
   let expires = chrono::Utc::now() + ttl;
   expires
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::test_utils::init_db;
+
+  fn logged_in(name: &str) -> SessionState {
+    let user = UserSessionData::new(name, "");
+    let mut state = SessionState::new();
+    state.insert("user".to_owned(), serde_json::to_string(&user).unwrap());
+    state
+  }
+
+  #[tokio::test]
+  async fn revoked_session_is_not_saved_again() {
+    init_db();
+    let store = SqliteSessionStore::new();
+    let ttl = Duration::days(1);
+    let key = store
+      .save(logged_in("session-revoked"), &ttl)
+      .await
+      .unwrap();
+    assert!(store.load(&key).await.unwrap().is_some());
+
+    let id = hash_key(key.as_ref());
+    delete_session(&id).unwrap();
+    let new_key = store
+      .update(key, logged_in("session-revoked"), &ttl)
+      .await
+      .unwrap();
+    assert!(store.load(&new_key).await.unwrap().is_none());
+    assert!(!list_sessions().unwrap().contains_key(&id));
+    assert!(!list_sessions()
+      .unwrap()
+      .contains_key(&hash_key(new_key.as_ref())));
+  }
+}
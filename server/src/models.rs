@@ -14,6 +14,16 @@ pub struct User {
   pub group_name: String,
   pub otp_secret: Option<String>,
   pub web_authn_id: Option<String>,
+  /// bytes allowed in user root, overrides the quota of the group
+  pub quota: Option<i64>,
+  /// set for accounts created with a default password
+  pub must_change_password: bool,
+  /// disabled users can not log in
+  pub disabled: bool,
+  /// entry of the user in the directory, set for users authenticated by ldap
+  pub ldap_dn: Option<String>,
+  /// the user opened the verification link sent to `email`
+  pub email_verified: bool,
 }
 
 #[derive(Serialize, Queryable)]
@@ -22,6 +32,8 @@ pub struct Group {
   pub name: String,
   pub desc: String,
   pub permissions: String,
+  /// bytes allowed in the root of each user of the group
+  pub quota: Option<i64>,
 }
 
 #[derive(Insertable)]
@@ -41,6 +53,7 @@ pub struct NewUser<'a> {
   pub user_type: i32,
   pub user_root: &'a str,
   pub group_name: &'a str,
+  pub must_change_password: bool,
 }
 
 #[derive(Insertable, Debug)]
@@ -122,3 +135,199 @@ pub struct KvStorageDocNoValue {
   pub is_private: bool,
 }
 
+
+#[derive(Queryable, Debug, Serialize, Insertable, Clone)]
+#[diesel(table_name = mounts)]
+pub struct Mount {
+  pub username: String,
+  pub prefix: String,
+  pub target: String,
+  pub read_only: bool,
+}
+
+#[derive(Queryable, Debug, Serialize, Insertable, Clone)]
+#[diesel(table_name = trash)]
+pub struct TrashItem {
+  pub id: String,
+  pub username: String,
+  pub original_path: String,
+  pub deleted_at: i64,
+  pub is_dir: bool,
+  pub size: i64,
+  /// prefix of the mount the file was deleted from, its trash is in the mount
+  pub mount: Option<String>,
+  /// path in file index, the entry is removed when the file is purged
+  #[serde(skip)]
+  pub indexed_path: Option<String>,
+}
+
+/// access granted by `owner` on `path` in its root to a user or to every user of a group
+#[derive(Queryable, Debug, Serialize, Insertable, Clone)]
+#[diesel(table_name = acls)]
+pub struct Acl {
+  pub id: String,
+  pub owner: String,
+  pub path: String,
+  pub username: Option<String>,
+  pub group_name: Option<String>,
+  pub read_only: bool,
+  pub created_at: i64,
+}
+
+/// a login session, `id` is the hash of the session key sent in the cookie,
+/// the other columns are copied from the state to query sessions by user
+#[derive(Queryable, Debug, Insertable, AsChangeset, Clone)]
+#[diesel(table_name = sessions, treat_none_as_null = true)]
+pub struct StoredSession {
+  pub id: String,
+  /// json encoded session state
+  pub state: String,
+  /// unix timestamp in seconds
+  pub expires_at: i64,
+  /// set while a user is logged in
+  pub username: Option<String>,
+  pub device_id: Option<String>,
+  pub user_agent: Option<String>,
+  pub ip: Option<String>,
+  pub created_at: Option<i64>,
+  pub last_seen: Option<i64>,
+}
+
+#[derive(Queryable, Debug, Serialize, Insertable, Clone)]
+#[diesel(table_name = share_links)]
+pub struct ShareLink {
+  pub id: String,
+  pub username: String,
+  pub path: String,
+  #[serde(skip_serializing)]
+  pub password: Option<String>,
+  pub expires_at: Option<i64>,
+  pub max_downloads: Option<i32>,
+  pub download_count: i32,
+  pub allow_upload: bool,
+  pub created_at: i64,
+}
+
+#[derive(Queryable, Debug, Serialize, Insertable, Clone)]
+#[diesel(table_name = file_versions)]
+pub struct FileVersion {
+  pub id: String,
+  pub username: String,
+  pub path: String,
+  pub hash: String,
+  pub size: i64,
+  pub created_at: i64,
+}
+
+#[derive(Queryable, Debug, Serialize, Insertable, Clone)]
+#[diesel(table_name = versioning_policies)]
+pub struct VersioningPolicy {
+  pub username: String,
+  pub dir: String,
+  pub keep_versions: Option<i32>,
+  pub keep_days: Option<i32>,
+}
+
+#[derive(Queryable, Debug, Serialize, Insertable, Clone)]
+#[diesel(table_name = storage_usage)]
+pub struct StorageUsage {
+  pub username: String,
+  pub used: i64,
+  pub updated_at: i64,
+}
+
+/// a passkey registered by a user, `id` is the base64url encoded credential id
+#[derive(Queryable, Debug, Serialize, Insertable, Clone)]
+#[diesel(table_name = web_authn_credentials)]
+pub struct WebAuthnCredential {
+  pub id: String,
+  pub username: String,
+  /// name given by the user to tell keys apart
+  pub name: String,
+  /// json encoded `webauthn_rs::prelude::Passkey`
+  #[serde(skip)]
+  pub passkey: String,
+  pub created_at: i64,
+  pub last_used_at: Option<i64>,
+}
+
+/// a failed authentication attempt, kept for auditing
+#[derive(Queryable, Debug, Serialize, Insertable, Clone)]
+#[diesel(table_name = login_failures)]
+pub struct LoginFailure {
+  pub id: String,
+  /// what was attempted, `login`, `enable_otp`, `one_time_token` or `reset_password`
+  pub action: String,
+  pub username: Option<String>,
+  pub ip: String,
+  pub reason: String,
+  pub created_at: i64,
+}
+
+/// a personal access token, only the sha256 of the token is stored
+#[derive(Queryable, Debug, Serialize, Insertable, Clone)]
+#[diesel(table_name = api_tokens)]
+pub struct ApiToken {
+  pub id: String,
+  pub username: String,
+  pub name: String,
+  #[serde(skip)]
+  pub token_hash: String,
+  /// permissions the token is limited to, in the format of `groups.permissions`
+  pub scopes: String,
+  pub expires_at: Option<i64>,
+  pub created_at: i64,
+  pub last_used_at: Option<i64>,
+}
+
+/// an account of the OpenID Connect provider linked to a user
+#[derive(Queryable, Debug, Serialize, Insertable, Clone)]
+#[diesel(table_name = oidc_identities)]
+pub struct OidcIdentity {
+  pub issuer: String,
+  /// `sub` claim, stable for an account of the provider
+  pub subject: String,
+  pub username: String,
+  pub email: Option<String>,
+  pub created_at: i64,
+  pub last_login: i64,
+}
+
+/// a single use token sent by email, `id` is the sha256 of the token
+#[derive(Queryable, Debug, Insertable, Clone)]
+#[diesel(table_name = email_tokens)]
+pub struct EmailToken {
+  pub id: String,
+  pub username: String,
+  /// `reset_password` or `verify_email`
+  pub purpose: String,
+  /// address the token was sent to
+  pub email: String,
+  pub expires_at: i64,
+  pub created_at: i64,
+}
+
+/// an email waiting to be sent, kept for a while after it is sent
+#[derive(Queryable, Debug, Serialize, Insertable, Clone)]
+#[diesel(table_name = mail_queue)]
+pub struct QueuedMail {
+  pub id: String,
+  pub recipient: String,
+  pub subject: String,
+  pub body: String,
+  pub attempts: i32,
+  pub next_attempt_at: i64,
+  pub last_error: Option<String>,
+  pub created_at: i64,
+  pub sent_at: Option<i64>,
+}
+
+/// a runtime setting set by an admin, `value` is the json of a key of `DynamicConfig`
+#[derive(Queryable, Debug, Insertable, Clone)]
+#[diesel(table_name = dynamic_config)]
+pub struct DynamicConfigEntry {
+  pub key: String,
+  pub value: String,
+  pub updated_at: i64,
+  pub updated_by: String,
+}
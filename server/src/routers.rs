@@ -1,10 +1,16 @@
+pub mod admin;
 pub mod auth;
+pub mod dav;
 pub mod fs;
 pub mod gallery;
 pub mod index;
 pub mod kv_storage;
 pub mod tunnel;
 pub mod log;
+pub mod mount;
+pub mod acl;
+pub mod quota;
+pub mod share;
 pub mod system_info;
 
 #[cfg(target_os="windows")]
@@ -0,0 +1,95 @@
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Scope};
+use serde::{Deserialize, Serialize};
+
+use crate::models::Acl;
+use crate::utils::acl::{self, NewAcl, SHARED_DIR};
+use crate::utils::error::AppError;
+use crate::utils::response::{create_resp, EmptyResponseData};
+use crate::utils::session::SessionUtils;
+use crate::AppData;
+
+#[derive(Deserialize)]
+pub struct GrantReq {
+  /// directory in the root of current user
+  file: String,
+  username: Option<String>,
+  group: Option<String>,
+  read_only: Option<bool>,
+}
+
+/// share a directory with a user or a group, read only unless `read_only` is false
+pub async fn grant(
+  body: web::Json<GrantReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root.clone();
+  let user = &sess.get_user_data()?;
+  let body = body.into_inner();
+  let acl = acl::grant(
+    file_root,
+    user,
+    NewAcl {
+      path: body.file,
+      username: body.username,
+      group_name: body.group,
+      read_only: body.read_only.unwrap_or(true),
+    },
+  )
+  .await?;
+  Ok(create_resp(true, acl, "done"))
+}
+
+/// directories current user has shared
+pub async fn list(sess: Session) -> Result<HttpResponse, AppError> {
+  let user = sess.get_user_data()?;
+  let acls = acl::list_owned(&user.username)?;
+  Ok(create_resp(true, acls, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct RevokeReq {
+  id: String,
+}
+
+pub async fn revoke(body: web::Json<RevokeReq>, sess: Session) -> Result<HttpResponse, AppError> {
+  let user = sess.get_user_data()?;
+  if acl::revoke(&user.username, &body.id)? {
+    return Ok(create_resp(true, EmptyResponseData::new(), "done"));
+  }
+  Ok(create_resp(
+    false,
+    EmptyResponseData::new(),
+    "acl not found",
+  ))
+}
+
+#[derive(Serialize)]
+pub struct SharedWithMeResp {
+  #[serde(flatten)]
+  acl: Acl,
+  /// where the directory shows up for current user
+  file: String,
+}
+
+/// directories shared with current user
+pub async fn shared_with_me(sess: Session) -> Result<HttpResponse, AppError> {
+  let user = sess.get_user_data()?;
+  let r = acl::grants_for(&user.username)?
+    .into_iter()
+    .map(|acl| SharedWithMeResp {
+      file: format!("{SHARED_DIR}/{}/{}", acl.owner, acl::share_name(&acl)),
+      acl,
+    })
+    .collect::<Vec<_>>();
+  Ok(create_resp(true, r, "done"))
+}
+
+pub fn acl_routers() -> Scope {
+  web::scope("/acl")
+    .route("/grant", web::post().to(grant))
+    .route("/list", web::post().to(list))
+    .route("/revoke", web::post().to(revoke))
+    .route("/shared_with_me", web::post().to(shared_with_me))
+}
@@ -0,0 +1,34 @@
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Scope};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::utils::{dynamic_config, error::AppError, response::create_resp, session::SessionUtils};
+
+pub async fn get_config() -> Result<HttpResponse, AppError> {
+  let config = dynamic_config::get()?;
+  Ok(create_resp(true, config, "done"))
+}
+
+#[derive(Serialize)]
+pub struct SetConfigResp {
+  changed: Vec<String>,
+  config: Map<String, Value>,
+}
+
+/// body holds the keys to change, `null` clears a key
+pub async fn set_config(
+  body: web::Json<Map<String, Value>>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let user_data = sess.get_user_data()?;
+  let changed = dynamic_config::set(body.into_inner(), &user_data.username)?;
+  let config = dynamic_config::get()?;
+  Ok(create_resp(true, SetConfigResp { changed, config }, "done"))
+}
+
+pub fn admin_routers() -> Scope {
+  web::scope("/admin")
+    .route("/config/get", web::post().to(get_config))
+    .route("/config/set", web::post().to(set_config))
+}
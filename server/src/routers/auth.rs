@@ -5,22 +5,36 @@ use std::{
   time::{SystemTime, UNIX_EPOCH},
 };
 
-use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Scope};
-use serde::Deserialize;
+use actix_web::{
+  http::{header, StatusCode},
+  web, HttpRequest, HttpResponse, Scope,
+};
+use serde::{Deserialize, Serialize};
 
 use crate::{
   db::SHARED_DB_CONN,
-  middlewares::session::SESSION_STATE,
+  middlewares::session::{
+    delete_session, list_devices, list_sessions, revoke_device, revoke_devices, SessionDevice,
+  },
   models::NewUser,
   models::User as TUser,
   schema,
   utils::{
     self,
-    auth::{create_one_time_token, verify_otp},
+    api_token,
+    auth::{
+      create_one_time_token, create_user_root, find_resettable_user, find_user, set_email_verified,
+      set_password, verify_otp, verify_password,
+    },
     crypto::hash_pwd,
+    dynamic_config, email_token,
     error::AppError,
+    mail::{self, Template},
+    oidc, permission,
+    rate_limit::{self, Action},
     response::{create_resp, EmptyResponseData},
-    session::SessionUtils,
+    session::{client_ip, user_agent, SessionUtils},
+    web_authn,
   },
   AppData, UserSessionData,
 };
@@ -59,72 +73,205 @@ pub fn login_fake_user(sess: Session) -> Result<bool, AppError> {
   Ok(true)
 }
 
-pub async fn login(
-  body: web::Json<User>,
-  data: web::Data<AppData>,
-  sess: Session,
-  req: HttpRequest,
-) -> Result<HttpResponse, AppError> {
-  use crate::schema::users::dsl::*;
-
-  let ip = req
-    .connection_info()
-    .realip_remote_addr()
-    .map_or_else(|| "unknown".to_owned(), |v| v.to_owned());
-
-  let name = &body.borrow().name;
-  let pwd = &body.borrow().password;
-  let hashed_pwd = hash_pwd(pwd);
-  let state = data.borrow().write().unwrap();
-
-  let mut db_mutex = state.db.lock().await;
-
-  let db = &mut *db_mutex;
-
-  let user = users
-    .filter(username.eq(name).and(password.eq(hashed_pwd)))
-    .load::<TUser>(db)?;
-
-  drop(db_mutex);
-
-  if user.len() == 0 {
-    return Ok(create_resp(
-      false,
-      EmptyResponseData::new(),
-      "password error or user not exists",
-    ));
-  }
+/// mark the session as logged in as `user` and record the device it comes from
+fn start_user_session(sess: &Session, user: &TUser, req: &HttpRequest) -> Result<(), AppError> {
+  let ip = client_ip(req);
+  // a key set before login, e.g. by someone else on a shared computer, must not be logged in
+  sess.renew();
 
   let user_data = sess.get::<UserSessionData>("user")?;
 
-  let user = user.get(0).unwrap();
-
-  let otp_code = body.otp_code.clone().map_or(String::new(), |v| v);
-  let success = verify_otp(&user.username, &otp_code)?;
-  if !success {
-    return Ok(create_resp(false, false, "otp error"));
-  }
-
   match user_data {
     Some(mut user_data) => {
       user_data.is_login = true;
       user_data.user_root = user.user_root.clone();
-      user_data.username = name.clone();
+      user_data.username = user.username.clone();
       user_data.last_login = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs();
       user_data.ip = ip;
+      user_data.must_change_password = user.must_change_password;
       sess.insert("user", user_data)?;
     }
     None => {
       let mut new_user_data = UserSessionData::new(&user.username, &user.user_root);
       new_user_data.ip = ip;
+      new_user_data.must_change_password = user.must_change_password;
       sess.insert("user", new_user_data)?;
     }
   }
+  let agent = user_agent(req);
+  notify_new_device(user, &ip, &agent).unwrap_or_else(|err| {
+    tracing::error!("new device mail to {} failed: {err}", user.username);
+  });
+  sess.start_device(&agent)?;
+  Ok(())
+}
 
-  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+/// mail the user when it logs in from a user agent none of its sessions has
+fn notify_new_device(user: &TUser, ip: &str, agent: &str) -> Result<(), AppError> {
+  if !mail::is_enabled() || !user.email_verified {
+    return Ok(());
+  }
+  let known = list_devices(Some(&user.username))?
+    .iter()
+    .any(|d| d.user_agent == agent);
+  if known {
+    return Ok(());
+  }
+  let time = chrono::Utc::now().to_rfc2822();
+  mail::queue(
+    &user.email,
+    Template::NewDevice,
+    &[
+      ("username", &user.username),
+      ("time", &time),
+      ("ip", ip),
+      ("user_agent", agent),
+    ],
+  )
+}
+
+/// mail a link verifying `address` to `user`
+fn send_verification(user: &str, address: &str) -> Result<(), AppError> {
+  let token = email_token::create(user, email_token::VERIFY_EMAIL, address, 24 * 60 * 60)?;
+  let link = mail::link(&format!("/auth/verify_email?token={token}"))?;
+  mail::queue(
+    address,
+    Template::VerifyEmail,
+    &[("username", user), ("link", &link)],
+  )
+}
+
+#[derive(Serialize)]
+pub struct LoginResp {
+  /// the client should ask for a new password, other requests fail until it is changed
+  must_change_password: bool,
+}
+
+pub async fn login(
+  body: web::Json<User>,
+  sess: Session,
+  req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+  let name = &body.borrow().name;
+  let pwd = &body.borrow().password;
+  let ip = client_ip(&req);
+  rate_limit::check(Action::Login, &ip, Some(name))?;
+
+  let user = match verify_password(name, pwd)? {
+    Some(user) => user,
+    None => {
+      rate_limit::fail(Action::Login, &ip, Some(name), "wrong password or unknown user")?;
+      return Ok(create_resp(
+        false,
+        EmptyResponseData::new(),
+        "password error or user not exists",
+      ));
+    }
+  };
+
+  let otp_code = body.otp_code.clone().map_or(String::new(), |v| v);
+  let success = verify_otp(&user.username, &otp_code)?;
+  if !success {
+    rate_limit::fail(Action::Login, &ip, Some(name), "wrong otp code")?;
+    return Ok(create_resp(false, false, "otp error"));
+  }
+
+  rate_limit::succeed(Action::Login, name);
+  start_user_session(&sess, &user, &req)?;
+
+  Ok(create_resp(
+    true,
+    LoginResp {
+      must_change_password: user.must_change_password,
+    },
+    "done",
+  ))
+}
+
+#[derive(Serialize)]
+pub struct OidcInfoResp {
+  enabled: bool,
+  display_name: Option<String>,
+}
+
+/// tells the login page whether to show single sign-on
+pub async fn oidc_info() -> Result<HttpResponse, AppError> {
+  let display_name = oidc::config().ok().and_then(|c| c.display_name);
+  Ok(create_resp(
+    true,
+    OidcInfoResp {
+      enabled: oidc::is_enabled(),
+      display_name,
+    },
+    "done",
+  ))
+}
+
+/// key of the oidc login in progress in session state
+const OIDC_STATE_KEY: &str = "oidc_state";
+
+#[derive(Deserialize)]
+pub struct OidcLoginQuery {
+  /// path to go to after login
+  redirect: Option<String>,
+}
+
+/// send the browser to the provider
+pub async fn oidc_login(
+  sess: Session,
+  query: web::Query<OidcLoginQuery>,
+) -> Result<HttpResponse, AppError> {
+  let (url, state) = oidc::start_login(query.redirect.as_deref()).await?;
+  sess.insert(OIDC_STATE_KEY, state)?;
+  Ok(
+    HttpResponse::Found()
+      .insert_header((header::LOCATION, url))
+      .finish(),
+  )
+}
+
+#[derive(Deserialize)]
+pub struct OidcCallbackQuery {
+  code: Option<String>,
+  state: Option<String>,
+  error: Option<String>,
+  error_description: Option<String>,
+}
+
+/// the provider sends the browser back here with a code
+pub async fn oidc_callback(
+  sess: Session,
+  query: web::Query<OidcCallbackQuery>,
+  req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+  let session_state = sess
+    .remove_as::<String>(OIDC_STATE_KEY)
+    .and_then(|r| r.ok());
+  if let Some(error) = &query.error {
+    return Err(
+      AppError::new(&format!(
+        "oidc: {error} {}",
+        query.error_description.as_deref().unwrap_or("")
+      ))
+      .with_status(StatusCode::UNAUTHORIZED),
+    );
+  }
+  let (code, state) = match (&query.code, &query.state) {
+    (Some(code), Some(state)) => (code, state),
+    _ => {
+      return Err(AppError::new("oidc: code or state missing").with_status(StatusCode::BAD_REQUEST))
+    }
+  };
+  let (user, redirect) = oidc::finish_login(state, session_state.as_deref(), code).await?;
+  start_user_session(&sess, &user, &req)?;
+  Ok(
+    HttpResponse::Found()
+      .insert_header((header::LOCATION, redirect))
+      .finish(),
+  )
 }
 
 #[derive(Deserialize)]
@@ -136,16 +283,24 @@ pub struct EnableOtpReq {
 pub async fn enable_otp(
   body: web::Json<EnableOtpReq>,
   sess: Session,
+  req: HttpRequest,
 ) -> Result<HttpResponse, AppError> {
   let user_data = sess
     .get::<UserSessionData>("user")?
     .ok_or_else(|| AppError::new("no use session data"))?;
   let username = user_data.username;
+  let ip = client_ip(&req);
+  rate_limit::check(Action::EnableOtp, &ip, Some(&username))?;
 
   let secret = &body.secret;
   let code = &body.code;
 
   let result = utils::auth::enable_otp(&username, secret, code)?;
+  if result {
+    rate_limit::succeed(Action::EnableOtp, &username);
+  } else {
+    rate_limit::fail(Action::EnableOtp, &ip, Some(&username), "wrong otp code")?;
+  }
   Ok(create_resp(true, result, "done"))
 }
 
@@ -178,45 +333,48 @@ pub struct ResetPasswordReq {
 
 pub async fn reset_password(
   body: web::Json<ResetPasswordReq>,
-  data: web::Data<AppData>,
   sess: Session,
 ) -> Result<HttpResponse, AppError> {
-  use crate::schema::users::dsl::*;
-
   let old_pwd = &body.borrow().old_password;
   let pwd = &body.borrow().new_password;
-  let hashed_old_pwd = hash_pwd(old_pwd);
-  let hashed_pwd = hash_pwd(pwd);
-  let state = data.borrow().write().unwrap();
   let user_data = sess.get_user_data()?;
   let name = &user_data.username;
 
-  let mut db_mutex = state.db.lock().await;
-
-  let db = &mut *db_mutex;
-
-  let user = users
-    .filter(username.eq(name).and(password.eq(&hashed_old_pwd)))
-    .load::<TUser>(db)?;
-
-  if user.len() == 0 {
+  let user = match verify_password(name, old_pwd)? {
+    Some(user) => user,
+    None => {
+      return Ok(create_resp(
+        false,
+        EmptyResponseData::new(),
+        "old password error",
+      ));
+    }
+  };
+  if user.ldap_dn.is_some() {
     return Ok(create_resp(
       false,
       EmptyResponseData::new(),
-      "old password error",
+      "password is managed by the directory",
+    ));
+  }
+  if user_data.must_change_password && pwd == old_pwd {
+    return Ok(create_resp(
+      false,
+      EmptyResponseData::new(),
+      "new password must be different",
     ));
   }
 
-  diesel::update(users.filter(username.eq(name).and(password.eq(&hashed_old_pwd))))
-    .set(password.eq(hashed_pwd))
-    .execute(db)?;
+  set_password(name, pwd, false)?;
+  // other devices may be logged in by whoever knew the old password
+  revoke_devices(name, None)?;
 
   return logout(sess).await;
 }
 
 pub async fn get_session_state() -> Result<HttpResponse, AppError> {
-  let state = SESSION_STATE.read().unwrap();
-  let resp = create_resp(true, state.clone(), "done");
+  let state = list_sessions()?;
+  let resp = create_resp(true, state, "done");
   Ok(resp)
 }
 
@@ -228,10 +386,9 @@ pub struct DeleteSessionDataReq {
 pub async fn delete_session_state(
   body: web::Json<DeleteSessionDataReq>,
 ) -> Result<HttpResponse, AppError> {
-  let key = body.key.clone();
-  let mut state = SESSION_STATE.write().unwrap();
-  state.remove(&key);
-  let resp = create_resp(true, state.clone(), "done");
+  delete_session(&body.key)?;
+  let state = list_sessions()?;
+  let resp = create_resp(true, state, "done");
   Ok(resp)
 }
 
@@ -258,7 +415,7 @@ pub async fn register(
 ) -> Result<HttpResponse, AppError> {
   let name = &body.borrow().username;
   let pwd = &body.borrow().password;
-  let hashed_pwd = hash_pwd(pwd);
+  let hashed_pwd = hash_pwd(pwd)?;
   let email = &body.borrow().email;
   let group = &body.borrow().group;
   let state = data.borrow().write().unwrap();
@@ -274,9 +431,218 @@ pub async fn register(
       user_type: 1,
       user_root: "",
       group_name: group,
+      must_change_password: false,
     })
     .execute(conn)?;
 
+  if mail::is_enabled() && !email.is_empty() {
+    send_verification(name, email).unwrap_or_else(|err| {
+      tracing::error!("verification mail to {name} failed: {err}");
+    });
+  }
+
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+#[derive(Deserialize)]
+pub struct SignUpReq {
+  username: String,
+  password: String,
+  email: String,
+}
+
+/// create an account without an admin, only when registration is open
+pub async fn sign_up(body: web::Json<SignUpReq>) -> Result<HttpResponse, AppError> {
+  let group = dynamic_config::open_registration_group()
+    .ok_or_else(|| AppError::new("registration is closed").with_status(StatusCode::FORBIDDEN))?;
+  let name = body.username.trim();
+  if name.is_empty() || body.password.is_empty() {
+    return Ok(create_resp(
+      false,
+      EmptyResponseData::new(),
+      "username or password is empty",
+    ));
+  }
+  if find_user(name)?.is_some() {
+    return Ok(create_resp(
+      false,
+      EmptyResponseData::new(),
+      "username is taken",
+    ));
+  }
+  let hashed_pwd = hash_pwd(&body.password)?;
+  let root = create_user_root(name)?;
+  {
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    diesel::insert_into(schema::users::table)
+      .values(NewUser {
+        username: name,
+        password: &hashed_pwd,
+        email: &body.email,
+        user_type: 1,
+        user_root: &root,
+        group_name: &group,
+        must_change_password: false,
+      })
+      .execute(&mut *conn)?;
+  }
+
+  if mail::is_enabled() && !body.email.is_empty() {
+    send_verification(name, &body.email).unwrap_or_else(|err| {
+      tracing::error!("verification mail to {name} failed: {err}");
+    });
+  }
+
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordReq {
+  /// username or verified email
+  pub name: String,
+}
+
+/// mail a reset link, the response is the same whether the user exists or not
+pub async fn forgot_password(
+  body: web::Json<ForgotPasswordReq>,
+  req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+  let ip = client_ip(&req);
+  let name = body.name.trim();
+  rate_limit::check(Action::ResetPassword, &ip, Some(name))?;
+  // every request counts, not only failed ones, each may send a mail
+  rate_limit::fail(
+    Action::ResetPassword,
+    &ip,
+    Some(name),
+    "password reset requested",
+  )?;
+  if !mail::is_enabled() {
+    return Err(
+      AppError::new("password reset by mail is not available")
+        .with_status(StatusCode::SERVICE_UNAVAILABLE),
+    );
+  }
+  if let Some(user) = find_resettable_user(name)? {
+    let name = &user.username;
+    // one mail a minute, the link of an earlier mail stops working on every request
+    let recent =
+      email_token::last_created(name, email_token::RESET_PASSWORD)?.map_or(false, |ago| ago < 60);
+    if !recent {
+      let token = email_token::create(name, email_token::RESET_PASSWORD, &user.email, 60 * 60)?;
+      let link = mail::link(&format!("/?reset_password_token={token}"))?;
+      mail::queue(
+        &user.email,
+        Template::ResetPassword,
+        &[("username", name), ("link", &link)],
+      )?;
+    }
+  }
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordWithTokenReq {
+  pub token: String,
+  pub new_password: String,
+}
+
+/// set a new password with the token of a reset link, every session of the user is revoked
+pub async fn reset_password_with_token(
+  body: web::Json<ResetPasswordWithTokenReq>,
+  req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+  let ip = client_ip(&req);
+  rate_limit::check(Action::ResetPassword, &ip, None)?;
+  if body.new_password.is_empty() {
+    return Ok(create_resp(
+      false,
+      EmptyResponseData::new(),
+      "new password is empty",
+    ));
+  }
+  let token = match email_token::consume(&body.token, email_token::RESET_PASSWORD)? {
+    Some(token) => token,
+    None => {
+      rate_limit::fail(Action::ResetPassword, &ip, None, "invalid reset token")?;
+      return Ok(create_resp(
+        false,
+        EmptyResponseData::new(),
+        "invalid or expired link",
+      ));
+    }
+  };
+  // the user may have been disabled or moved to the directory since
+  let allowed = find_user(&token.username)?.map_or(false, |u| {
+    !u.disabled && u.ldap_dn.is_none() && u.email == token.email
+  });
+  if !allowed {
+    return Ok(create_resp(
+      false,
+      EmptyResponseData::new(),
+      "invalid or expired link",
+    ));
+  }
+  set_password(&token.username, &body.new_password, false)?;
+  revoke_devices(&token.username, None)?;
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailQuery {
+  pub token: String,
+}
+
+/// opened from the link of a verification mail, redirects to the desktop
+pub async fn verify_email(
+  query: web::Query<VerifyEmailQuery>,
+  req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+  let ip = client_ip(&req);
+  rate_limit::check(Action::ResetPassword, &ip, None)?;
+  let verified = match email_token::consume(&query.token, email_token::VERIFY_EMAIL)? {
+    Some(token) => set_email_verified(&token.username, &token.email)?,
+    None => {
+      rate_limit::fail(
+        Action::ResetPassword,
+        &ip,
+        None,
+        "invalid verification token",
+      )?;
+      false
+    }
+  };
+  Ok(
+    HttpResponse::Found()
+      .insert_header((header::LOCATION, format!("/?email_verified={verified}")))
+      .finish(),
+  )
+}
+
+/// mail a new verification link to the current user
+pub async fn send_verification_email(sess: Session) -> Result<HttpResponse, AppError> {
+  let name = sess.get_user_data()?.username;
+  let user = find_user(&name)?.ok_or_else(|| AppError::new("user not found"))?;
+  if user.email.is_empty() {
+    return Ok(create_resp(false, EmptyResponseData::new(), "no email"));
+  }
+  if user.email_verified {
+    return Ok(create_resp(
+      false,
+      EmptyResponseData::new(),
+      "email is already verified",
+    ));
+  }
+  let recent =
+    email_token::last_created(&name, email_token::VERIFY_EMAIL)?.map_or(false, |ago| ago < 60);
+  if recent {
+    return Ok(create_resp(
+      false,
+      EmptyResponseData::new(),
+      "a mail was sent less than a minute ago",
+    ));
+  }
+  send_verification(&name, &user.email)?;
   Ok(create_resp(true, EmptyResponseData::new(), "done"))
 }
 
@@ -289,11 +655,21 @@ pub async fn delete_user(body: web::Json<DeleteUserReq>) -> Result<HttpResponse,
   let _username = &body.borrow().username;
 
   use crate::schema::users::dsl::*;
-  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
-
-  let r = diesel::delete(users.filter(username.eq(_username))).execute(&mut *conn)?;
+  let r = {
+    let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+    diesel::delete(users.filter(username.eq(_username))).execute(&mut *conn)?
+  };
 
   if r > 0 {
+    revoke_devices(_username, None)?;
+    utils::acl::remove_user(_username)?;
+    api_token::remove_user(_username)?;
+    oidc::remove_user(_username)?;
+    web_authn::remove_user(_username)?;
+    utils::mount::remove_user(_username)?;
+    utils::quota::remove_user(_username)?;
+    utils::trash::remove_user(_username)?;
+    utils::versions::remove_user(_username)?;
     return Ok(create_resp(true, EmptyResponseData::new(), "done"));
   }
   return Ok(create_resp(false, EmptyResponseData::new(), "fail"));
@@ -330,6 +706,26 @@ pub struct SetUserInfoReq {
   file_root: String,
 }
 
+#[derive(Deserialize)]
+pub struct SetUserDisabledReq {
+  username: String,
+  disabled: bool,
+}
+
+/// users removed from the directory are disabled by ldap sync, admins enable them again here
+pub async fn set_user_disabled(
+  body: web::Json<SetUserDisabledReq>,
+) -> Result<HttpResponse, AppError> {
+  if utils::auth::set_disabled(&body.username, body.disabled)? {
+    return Ok(create_resp(true, EmptyResponseData::new(), "done"));
+  }
+  Ok(create_resp(
+    false,
+    EmptyResponseData::new(),
+    "user not found",
+  ))
+}
+
 pub async fn set_user_info(body: web::Json<SetUserInfoReq>) -> Result<HttpResponse, AppError> {
   use crate::schema::users::dsl::*;
   let conn = &mut *SHARED_DB_CONN.lock().unwrap();
@@ -366,6 +762,135 @@ async fn user_info(sess: Session) -> Result<HttpResponse, AppError> {
   ))
 }
 
+/// permissions of current user
+async fn permissions(sess: Session) -> Result<HttpResponse, AppError> {
+  let user_data = sess.get_user_data()?;
+  let r = permission::of_user(&user_data.username)?;
+  Ok(create_resp(true, r, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct SetGroupPermissionsReq {
+  group: String,
+  permissions: Vec<String>,
+}
+
+pub async fn set_group_permissions(
+  body: web::Json<SetGroupPermissionsReq>,
+) -> Result<HttpResponse, AppError> {
+  let permissions = permission::parse(&body.permissions.join(","))?;
+  if permission::set_group_permissions(&body.group, &permissions)? {
+    return Ok(create_resp(true, EmptyResponseData::new(), "done"));
+  }
+  Ok(create_resp(
+    false,
+    EmptyResponseData::new(),
+    "group not found",
+  ))
+}
+
+#[derive(Serialize)]
+pub struct SessionDeviceResp {
+  #[serde(flatten)]
+  device: SessionDevice,
+  /// the session of this request
+  current: bool,
+}
+
+/// logged in sessions of current user
+pub async fn list_own_sessions(sess: Session) -> Result<HttpResponse, AppError> {
+  let user_data = sess.get_user_data()?;
+  let current = sess.get_device()?.map(|device| device.id);
+  let r = list_devices(Some(&user_data.username))?
+    .into_iter()
+    .map(|device| SessionDeviceResp {
+      current: Some(&device.id) == current.as_ref(),
+      device,
+    })
+    .collect::<Vec<_>>();
+  Ok(create_resp(true, r, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct RevokeSessionReq {
+  id: String,
+}
+
+/// log out one session of current user
+pub async fn revoke_own_session(
+  body: web::Json<RevokeSessionReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let user_data = sess.get_user_data()?;
+  if revoke_devices(&user_data.username, Some(&body.id))? == 0 {
+    return Ok(create_resp(
+      false,
+      EmptyResponseData::new(),
+      "session not found",
+    ));
+  }
+  if sess
+    .get_device()?
+    .map_or(false, |device| device.id == body.id)
+  {
+    sess.purge();
+  }
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+#[derive(Deserialize)]
+pub struct RevokeAllSessionsReq {
+  /// keep the session of this request logged in
+  keep_current: Option<bool>,
+}
+
+/// log out everywhere
+pub async fn revoke_all_sessions(
+  body: web::Json<RevokeAllSessionsReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let user_data = sess.get_user_data()?;
+  let current = sess.get_device()?;
+  let keep_current = body.keep_current.unwrap_or(false);
+  let revoked = match (keep_current, current) {
+    (true, Some(current)) => list_devices(Some(&user_data.username))?
+      .into_iter()
+      .filter(|device| device.id != current.id)
+      .map(|device| revoke_devices(&user_data.username, Some(&device.id)))
+      .sum::<Result<usize, AppError>>()?,
+    _ => {
+      let revoked = revoke_devices(&user_data.username, None)?;
+      sess.purge();
+      revoked
+    }
+  };
+  Ok(create_resp(true, revoked, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct ListSessionsReq {
+  username: Option<String>,
+}
+
+/// logged in sessions of a user, or of every user
+pub async fn list_all_sessions(body: web::Json<ListSessionsReq>) -> Result<HttpResponse, AppError> {
+  let r = list_devices(body.username.as_deref())?;
+  Ok(create_resp(true, r, "done"))
+}
+
+pub async fn revoke_any_session(
+  body: web::Json<RevokeSessionReq>,
+) -> Result<HttpResponse, AppError> {
+  if revoke_device(&body.id)? {
+    return Ok(create_resp(true, EmptyResponseData::new(), "done"));
+  }
+  Ok(create_resp(
+    false,
+    EmptyResponseData::new(),
+    "session not found",
+  ))
+}
+
 #[derive(Debug, Deserialize)]
 pub struct OneTimeTokenReq {
   pub module_prefix: String,
@@ -374,7 +899,10 @@ pub struct OneTimeTokenReq {
 pub async fn request_one_time_token(
   sess: Session,
   body: web::Json<OneTimeTokenReq>,
+  req: HttpRequest,
 ) -> Result<HttpResponse, AppError> {
+  // an ip locked out for guessing tokens gets no new ones either
+  rate_limit::check(Action::OneTimeToken, &client_ip(&req), None)?;
   let user_data = sess.get::<UserSessionData>("user")?.unwrap();
   let module_prefix = &body.module_prefix;
   let token = create_one_time_token(&user_data.username.clone(), module_prefix, 60 * 5);
@@ -386,45 +914,238 @@ pub struct EnableWebAuthnReq {
   pub url: String,
 }
 
+fn request_host(req: &HttpRequest) -> String {
+  req.connection_info().host().to_owned()
+}
+
 pub async fn web_authn_start_register(
   sess: Session,
   body: web::Json<EnableWebAuthnReq>,
   req: HttpRequest,
 ) -> Result<HttpResponse, AppError> {
-  use webauthn_rs::prelude::*;
+  let user_data = sess.get_user_data()?;
+  let web_authn = web_authn::build(&body.url, &request_host(&req))?;
+  let ccr = web_authn::start_register(&web_authn, &user_data.username)?;
+  Ok(create_resp(true, ccr, "done"))
+}
 
-  let user_data = sess.get::<UserSessionData>("user")?.unwrap();
-  let url = &body.url;
-  let rp_origin = Url::parse(url).expect("Invalid URL");
-  let rp_id = rp_origin.domain().expect("Invalid host");
-  println!("rp_id: {} {:#?} {}", rp_id, rp_origin, url);
-  let builder = WebauthnBuilder::new(rp_id, &rp_origin).expect("Invalid configuration");
-  let builder = builder.rp_name("webby_os");
-  let web_authn = builder.build().expect("Invalid configuration");
-  let exclude_credentials = Option::None;
-
-  let user_unique_id = Uuid::new_v4();
-  let (ccr, reg_state) = web_authn
-    .start_passkey_registration(user_unique_id, &user_data.username, &user_data.username, exclude_credentials)
-    .unwrap();
-  let user_data = sess.get::<UserSessionData>("user")?.unwrap();
+#[derive(Deserialize)]
+pub struct FinishWebAuthnRegisterReq {
+  url: String,
+  /// name of the key shown in the key list
+  name: String,
+  credential: webauthn_rs::prelude::RegisterPublicKeyCredential,
+}
 
-  println!("user_data: {:#?} {:#?} {:#?}", user_data, ccr, reg_state);
-  Ok(create_resp(true, ccr, "done"))
+pub async fn web_authn_finish_register(
+  sess: Session,
+  body: web::Json<FinishWebAuthnRegisterReq>,
+  req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+  let user_data = sess.get_user_data()?;
+  let web_authn = web_authn::build(&body.url, &request_host(&req))?;
+  let key = web_authn::finish_register(
+    &web_authn,
+    &user_data.username,
+    &body.name,
+    &body.credential,
+  )?;
+  Ok(create_resp(true, key, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct StartWebAuthnLoginReq {
+  url: String,
+  name: String,
+}
+
+/// key of the passkey login in progress in session state
+const WEB_AUTHN_LOGIN_KEY: &str = "web_authn_login";
+
+pub async fn web_authn_start_login(
+  sess: Session,
+  body: web::Json<StartWebAuthnLoginReq>,
+  req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+  let web_authn = web_authn::build(&body.url, &request_host(&req))?;
+  let (rcr, login_id) = web_authn::start_login(&web_authn, &body.name)?;
+  sess.insert(WEB_AUTHN_LOGIN_KEY, login_id)?;
+  Ok(create_resp(true, rcr, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct FinishWebAuthnLoginReq {
+  url: String,
+  credential: webauthn_rs::prelude::PublicKeyCredential,
+}
+
+pub async fn web_authn_finish_login(
+  sess: Session,
+  body: web::Json<FinishWebAuthnLoginReq>,
+  req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+  let login_id = sess
+    .remove_as::<String>(WEB_AUTHN_LOGIN_KEY)
+    .and_then(|r| r.ok())
+    .ok_or_else(|| AppError::new("no login in progress").with_status(StatusCode::BAD_REQUEST))?;
+  let web_authn = web_authn::build(&body.url, &request_host(&req))?;
+  let name = web_authn::finish_login(&web_authn, &login_id, &body.credential)?;
+
+  use crate::schema::users::dsl::*;
+  let user = {
+    let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+    users.filter(username.eq(&name)).first::<TUser>(conn)?
+  };
+  if user.disabled {
+    return Err(AppError::new("user is disabled").with_status(StatusCode::FORBIDDEN));
+  }
+  start_user_session(&sess, &user, &req)?;
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+/// passkeys of current user
+pub async fn web_authn_list_keys(sess: Session) -> Result<HttpResponse, AppError> {
+  let user_data = sess.get_user_data()?;
+  let keys = web_authn::list(&user_data.username)?;
+  Ok(create_resp(true, keys, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct DeleteWebAuthnKeyReq {
+  id: String,
+}
+
+pub async fn web_authn_delete_key(
+  sess: Session,
+  body: web::Json<DeleteWebAuthnKeyReq>,
+) -> Result<HttpResponse, AppError> {
+  let user_data = sess.get_user_data()?;
+  if web_authn::delete(&user_data.username, &body.id)? {
+    return Ok(create_resp(true, EmptyResponseData::new(), "done"));
+  }
+  Ok(create_resp(
+    false,
+    EmptyResponseData::new(),
+    "passkey not found",
+  ))
+}
+
+#[derive(Deserialize)]
+pub struct CreateApiTokenReq {
+  name: String,
+  /// permissions the token can use, e.g. `["fs_read"]`
+  scopes: Vec<permission::Permission>,
+  expires_at: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct CreateApiTokenResp {
+  /// only shown once
+  token: String,
+  info: crate::models::ApiToken,
+}
+
+pub async fn create_api_token(
+  sess: Session,
+  body: web::Json<CreateApiTokenReq>,
+) -> Result<HttpResponse, AppError> {
+  let user_data = sess.get_user_data()?;
+  let scopes = body.scopes.iter().copied().collect();
+  let (info, token) =
+    api_token::create(&user_data.username, &body.name, &scopes, body.expires_at)?;
+  Ok(create_resp(true, CreateApiTokenResp { token, info }, "done"))
+}
+
+pub async fn list_api_tokens(sess: Session) -> Result<HttpResponse, AppError> {
+  let user_data = sess.get_user_data()?;
+  let tokens = api_token::list(&user_data.username)?;
+  Ok(create_resp(true, tokens, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct RevokeApiTokenReq {
+  id: String,
+}
+
+pub async fn revoke_api_token(
+  sess: Session,
+  body: web::Json<RevokeApiTokenReq>,
+) -> Result<HttpResponse, AppError> {
+  let user_data = sess.get_user_data()?;
+  if api_token::revoke(&user_data.username, &body.id)? {
+    return Ok(create_resp(true, EmptyResponseData::new(), "done"));
+  }
+  Ok(create_resp(
+    false,
+    EmptyResponseData::new(),
+    "token not found",
+  ))
+}
+
+pub async fn list_lockouts() -> Result<HttpResponse, AppError> {
+  Ok(create_resp(true, rate_limit::lockouts(), "done"))
+}
+
+#[derive(Deserialize)]
+pub struct ClearLockoutsReq {
+  /// clear every lockout if not set
+  key: Option<String>,
+}
+
+pub async fn clear_lockouts(body: web::Json<ClearLockoutsReq>) -> Result<HttpResponse, AppError> {
+  let cleared = rate_limit::clear(body.key.as_deref());
+  Ok(create_resp(true, cleared, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct LoginAuditReq {
+  username: Option<String>,
+  limit: Option<i64>,
+}
+
+/// failed authentication attempts, most recent first
+pub async fn login_audit(body: web::Json<LoginAuditReq>) -> Result<HttpResponse, AppError> {
+  let failures = rate_limit::audit(body.username.as_deref(), body.limit.unwrap_or(100))?;
+  Ok(create_resp(true, failures, "done"))
 }
 
 pub fn auth_routers() -> Scope {
   web::scope("/auth")
     .route("/login", web::post().to(login))
+    .route("/oidc/info", web::post().to(oidc_info))
+    .route("/oidc/login", web::get().to(oidc_login))
+    .route("/oidc/callback", web::get().to(oidc_callback))
     .route("/enable_otp", web::post().to(enable_otp))
     .route("/disable_otp", web::post().to(disbale_otp))
     .route(
       "/web_authn_start_register",
       web::post().to(web_authn_start_register),
     )
+    .route(
+      "/web_authn_finish_register",
+      web::post().to(web_authn_finish_register),
+    )
+    .route("/web_authn_start_login", web::post().to(web_authn_start_login))
+    .route(
+      "/web_authn_finish_login",
+      web::post().to(web_authn_finish_login),
+    )
+    .route("/web_authn_list_keys", web::post().to(web_authn_list_keys))
+    .route("/web_authn_delete_key", web::post().to(web_authn_delete_key))
     .route("/is_otp_enabled", web::post().to(is_otp_enabled))
     .route("/reset_password", web::post().to(reset_password))
+    .route("/forgot_password", web::post().to(forgot_password))
+    .route(
+      "/reset_password_with_token",
+      web::post().to(reset_password_with_token),
+    )
+    .route("/verify_email", web::get().to(verify_email))
+    .route(
+      "/send_verification_email",
+      web::post().to(send_verification_email),
+    )
     .route("/register", web::post().to(register))
+    .route("/sign_up", web::post().to(sign_up))
     .route("/delete_user", web::post().to(delete_user))
     .route("/get_session_state", web::post().to(get_session_state))
     .route(
@@ -432,12 +1153,194 @@ pub fn auth_routers() -> Scope {
       web::post().to(delete_session_state),
     )
     .route("/logout", web::post().to(logout))
+    .route("/sessions/list", web::post().to(list_own_sessions))
+    .route("/sessions/revoke", web::post().to(revoke_own_session))
+    .route("/sessions/revoke_all", web::post().to(revoke_all_sessions))
+    .route("/sessions/all", web::post().to(list_all_sessions))
+    .route("/sessions/revoke_any", web::post().to(revoke_any_session))
+    .route("/api_tokens/create", web::post().to(create_api_token))
+    .route("/api_tokens/list", web::post().to(list_api_tokens))
+    .route("/api_tokens/revoke", web::post().to(revoke_api_token))
+    .route("/lockouts/list", web::post().to(list_lockouts))
+    .route("/lockouts/clear", web::post().to(clear_lockouts))
+    .route("/lockouts/audit", web::post().to(login_audit))
     .route("/get_all_users", web::post().to(get_all_users))
     .route("/get_all_groups", web::post().to(get_all_groups))
     .route("/set_user_info", web::post().to(set_user_info))
+    .route("/set_user_disabled", web::post().to(set_user_disabled))
     .route("/user_info", web::post().to(user_info))
+    .route("/permissions", web::post().to(permissions))
+    .route(
+      "/set_group_permissions",
+      web::post().to(set_group_permissions),
+    )
     .route(
       "/request_one_time_token",
       web::post().to(request_one_time_token),
     )
 }
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::mpsc,
+    time::Duration,
+  };
+
+  use actix_web::test;
+
+  use super::*;
+  use crate::config::{SMTPConfig, DYNAMIC_CONFIG};
+  use crate::models::NewGroup;
+  use crate::utils::test_utils::init_db;
+
+  /// talk smtp on one connection, the recipient and data of every mail go to `tx`
+  fn serve_smtp(stream: TcpStream, tx: &mpsc::Sender<(String, String)>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    writer.write_all(b"220 sink\r\n")?;
+    let (mut recipient, mut line) = (String::new(), String::new());
+    loop {
+      line.clear();
+      if reader.read_line(&mut line)? == 0 {
+        return Ok(());
+      }
+      let command = line.trim_end().to_ascii_uppercase();
+      if command.starts_with("RCPT TO:") {
+        recipient = line.trim_end()[8..]
+          .trim_matches(&['<', '>', ' '][..])
+          .to_owned();
+      }
+      if command == "QUIT" {
+        return writer.write_all(b"221 bye\r\n");
+      }
+      if command == "DATA" {
+        writer.write_all(b"354 go on\r\n")?;
+        let mut data = String::new();
+        loop {
+          line.clear();
+          if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+          }
+          if line == ".\r\n" {
+            break;
+          }
+          data.push_str(&line);
+        }
+        let _ = tx.send((recipient.clone(), data));
+      }
+      writer.write_all(b"250 ok\r\n")?;
+    }
+  }
+
+  /// a local smtp server accepting every mail, returns its port
+  fn smtp_sink() -> (u16, mpsc::Receiver<(String, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+      for stream in listener.incoming().flatten() {
+        let _ = serve_smtp(stream, &tx);
+      }
+    });
+    (port, rx)
+  }
+
+  fn add_user(name: &str, address: &str) {
+    init_db();
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    diesel::insert_into(schema::groups::table)
+      .values(NewGroup {
+        name: name.to_owned(),
+        desc: String::new(),
+        permissions: String::new(),
+      })
+      .execute(&mut *conn)
+      .unwrap();
+    diesel::insert_into(schema::users::table)
+      .values(NewUser {
+        username: name,
+        password: &hash_pwd("secret").unwrap(),
+        email: address,
+        user_type: 0,
+        user_root: "",
+        group_name: name,
+        must_change_password: false,
+      })
+      .execute(&mut *conn)
+      .unwrap();
+    diesel::update(schema::users::table.filter(schema::users::username.eq(name)))
+      .set(schema::users::email_verified.eq(true))
+      .execute(&mut *conn)
+      .unwrap();
+  }
+
+  fn from_ip(ip: &str) -> HttpRequest {
+    test::TestRequest::default()
+      .peer_addr(SocketAddr::new(ip.parse().unwrap(), 50000))
+      .to_http_request()
+  }
+
+  #[actix_web::test]
+  async fn reset_link_is_mailed_and_requests_are_limited() {
+    let (name, address) = ("forgot-password", "forgot-password@example.com");
+    add_user(name, address);
+    let (port, mails) = smtp_sink();
+    {
+      let mut conf = DYNAMIC_CONFIG.lock().unwrap();
+      conf.smtp = Some(SMTPConfig {
+        user: String::new(),
+        secret: String::new(),
+        host: "127.0.0.1".to_owned(),
+        port: port as u32,
+        from: Some("webbyos@example.com".to_owned()),
+        security: Some("none".to_owned()),
+      });
+      conf.public_url = Some("http://webby.test".to_owned());
+    }
+    let forgot = |ip: &str| {
+      forgot_password(
+        web::Json(ForgotPasswordReq {
+          name: address.to_owned(),
+        }),
+        from_ip(ip),
+      )
+    };
+
+    forgot("10.6.0.1").await.unwrap();
+    let (sent, _) = mail::send_due().unwrap();
+    assert!(sent >= 1);
+    let data = loop {
+      let (to, data) = mails.recv_timeout(Duration::from_secs(10)).unwrap();
+      if to == address {
+        break data;
+      }
+    };
+    assert!(data.contains("Subject: Reset your webby.os password"));
+    // long lines of the body are quoted-printable
+    let body = data.replace("=\r\n", "").replace("=3D", "=");
+    let token = body.split("reset_password_token=").nth(1).unwrap()[..43].to_owned();
+    reset_password_with_token(
+      web::Json(ResetPasswordWithTokenReq {
+        token,
+        new_password: "new secret".to_owned(),
+      }),
+      from_ip("10.6.0.2"),
+    )
+    .await
+    .unwrap();
+    assert!(verify_password(name, "new secret").unwrap().is_some());
+
+    // every request counts, whether a mail is sent or not
+    let mut status = StatusCode::OK;
+    for i in 0..10 {
+      if let Err(err) = forgot(&format!("10.6.1.{i}")).await {
+        status = err.status_code;
+        break;
+      }
+    }
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+  }
+}
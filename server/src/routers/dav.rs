@@ -0,0 +1,974 @@
+/// WebDAV (RFC 4918) access to the files of user, so the storage can be
+/// mounted as a network drive by desktop file managers and sync tools.
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use actix_session::Session;
+use actix_web::{
+  http::{header, StatusCode},
+  web, HttpRequest, HttpResponse, Scope,
+};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use futures::StreamExt;
+use lazy_static::lazy_static;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use regex::Regex;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::utils::error::AppError;
+use crate::utils::parser::parse_range;
+use crate::utils::response::create_stream_resp;
+use crate::utils::session::SessionUtils;
+use crate::utils::storage::resolve;
+use crate::utils::quota;
+use crate::utils::versions;
+use crate::utils::vfs::{self, FSHookType};
+use crate::{AppData, UserSessionData};
+
+pub const DAV_PREFIX: &str = "/dav";
+
+/// lock body is small xml, anything larger is rejected
+const MAX_LOCK_BODY: usize = 64 * 1024;
+const DEFAULT_LOCK_TIMEOUT: u64 = 3600;
+
+const HREF: &AsciiSet = &CONTROLS
+  .add(b' ')
+  .add(b'"')
+  .add(b'#')
+  .add(b'%')
+  .add(b'<')
+  .add(b'>')
+  .add(b'?')
+  .add(b'`')
+  .add(b'{')
+  .add(b'}')
+  .add(b'[')
+  .add(b']')
+  .add(b'\\')
+  .add(b'^')
+  .add(b'|')
+  .add(b'&');
+
+#[derive(Clone)]
+struct DavLock {
+  token: String,
+  username: String,
+  path: String,
+  depth_infinity: bool,
+  exclusive: bool,
+  owner: Option<String>,
+  timeout: u64,
+  expires_at: Instant,
+}
+
+lazy_static! {
+  /// active locks keyed by lock token, locks are kept in memory and lost on restart
+  static ref DAV_LOCKS: Mutex<HashMap<String, DavLock>> = Mutex::new(HashMap::new());
+}
+
+fn status(code: StatusCode) -> HttpResponse {
+  HttpResponse::build(code).finish()
+}
+
+fn decode_path(p: &str) -> Result<String, AppError> {
+  let p = percent_decode_str(p)
+    .decode_utf8()
+    .map_err(|_| AppError::new("dav: invalid path").with_status(StatusCode::BAD_REQUEST))?;
+  Ok(p.trim_matches('/').to_string())
+}
+
+fn href(file: &str, is_dir: bool) -> String {
+  let mut href = format!("{DAV_PREFIX}/{}", utf8_percent_encode(file, HREF));
+  if is_dir && !file.is_empty() {
+    href.push('/');
+  }
+  href
+}
+
+fn join(dir: &str, name: &str) -> String {
+  if dir.is_empty() {
+    return name.to_string();
+  }
+  format!("{dir}/{name}")
+}
+
+fn escape_xml(s: &str) -> String {
+  s.replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+fn get_header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+  req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+/// `a` is `b` or one of its parent directories
+fn is_ancestor(a: &str, b: &str) -> bool {
+  a == b || a.is_empty() || b.starts_with(&format!("{a}/"))
+}
+
+async fn exists(file_root: &PathBuf, user: &UserSessionData, file: &str) -> Option<vfs::FileStat> {
+  vfs::stat(file_root, user, file).await.ok()
+}
+
+async fn parent_exists(file_root: &PathBuf, user: &UserSessionData, file: &str) -> bool {
+  match Path::new(file).parent() {
+    Some(parent) => exists(file_root, user, &parent.to_string_lossy())
+      .await
+      .map_or(false, |s| s.is_dir),
+    None => true,
+  }
+}
+
+/// lock tokens submitted in `If` header
+fn submitted_tokens(req: &HttpRequest) -> Vec<String> {
+  lazy_static! {
+    static ref RE: Regex = Regex::new(r#"<(opaquelocktoken:[^>]+)>"#).unwrap();
+  }
+  get_header(req, "if").map_or(vec![], |v| {
+    RE.captures_iter(v)
+      .map(|c| c.get(1).unwrap().as_str().to_string())
+      .collect()
+  })
+}
+
+fn active_locks() -> std::sync::MutexGuard<'static, HashMap<String, DavLock>> {
+  let mut locks = DAV_LOCKS.lock().unwrap();
+  let now = Instant::now();
+  locks.retain(|_, l| l.expires_at > now);
+  locks
+}
+
+/// fails with 423 when `file` or anything below it is locked by a token not submitted
+fn check_lock(user: &UserSessionData, file: &str, req: &HttpRequest) -> Result<(), AppError> {
+  let tokens = submitted_tokens(req);
+  let locks = active_locks();
+  let locked = locks.values().any(|l| {
+    l.username == user.username
+      && ((l.depth_infinity && is_ancestor(&l.path, file))
+        || l.path == file
+        || is_ancestor(file, &l.path))
+      && !tokens.contains(&l.token)
+  });
+  if locked {
+    return Err(AppError::new("dav: resource is locked").with_status(StatusCode::LOCKED));
+  }
+  Ok(())
+}
+
+fn http_date(millis: u128) -> String {
+  Utc
+    .timestamp_millis_opt(millis as i64)
+    .single()
+    .map_or(String::new(), |t| {
+      t.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+    })
+}
+
+fn rfc3339_date(millis: u128) -> String {
+  Utc
+    .timestamp_millis_opt(millis as i64)
+    .single()
+    .map_or(String::new(), |t| {
+      t.to_rfc3339_opts(SecondsFormat::Secs, true)
+    })
+}
+
+fn active_lock_xml(lock: &DavLock) -> String {
+  format!(
+    "<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope>{}</D:lockscope>\
+    <D:depth>{}</D:depth>{}<D:timeout>Second-{}</D:timeout>\
+    <D:locktoken><D:href>{}</D:href></D:locktoken>\
+    <D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+    if lock.exclusive {
+      "<D:exclusive/>"
+    } else {
+      "<D:shared/>"
+    },
+    if lock.depth_infinity { "infinity" } else { "0" },
+    lock
+      .owner
+      .as_ref()
+      .map_or(String::new(), |o| format!("<D:owner>{o}</D:owner>")),
+    lock.timeout,
+    lock.token,
+    href(&lock.path, false),
+  )
+}
+
+fn prop_response(file: &str, stat: &vfs::FileStat, locks: &[DavLock]) -> String {
+  let name = Path::new(file)
+    .file_name()
+    .map_or(String::new(), |n| n.to_string_lossy().to_string());
+  let mut props = format!(
+    "<D:displayname>{}</D:displayname>\
+    <D:creationdate>{}</D:creationdate>\
+    <D:getlastmodified>{}</D:getlastmodified>\
+    <D:getetag>\"{:x}-{:x}\"</D:getetag>",
+    escape_xml(&name),
+    rfc3339_date(stat.created),
+    http_date(stat.modified),
+    stat.modified,
+    stat.size,
+  );
+  if stat.is_dir {
+    props += "<D:resourcetype><D:collection/></D:resourcetype>";
+  } else {
+    let mime = mime_guess::from_path(file).first_or_octet_stream();
+    props += &format!(
+      "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>\
+      <D:getcontenttype>{}</D:getcontenttype>",
+      stat.size, mime
+    );
+  }
+  props += "<D:supportedlock>\
+    <D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+    <D:lockentry><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+    </D:supportedlock>";
+  let discovery = locks
+    .iter()
+    .filter(|l| l.path == file)
+    .map(active_lock_xml)
+    .collect::<String>();
+  props += &format!("<D:lockdiscovery>{discovery}</D:lockdiscovery>");
+  format!(
+    "<D:response><D:href>{}</D:href><D:propstat><D:prop>{props}</D:prop>\
+    <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+    href(file, stat.is_dir)
+  )
+}
+
+fn options() -> HttpResponse {
+  HttpResponse::Ok()
+    .insert_header(("DAV", "1, 2"))
+    .insert_header((
+      "Allow",
+      "OPTIONS, PROPFIND, MKCOL, GET, HEAD, PUT, DELETE, MOVE, COPY, LOCK, UNLOCK",
+    ))
+    .insert_header(("MS-Author-Via", "DAV"))
+    .finish()
+}
+
+async fn propfind(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  req: &HttpRequest,
+) -> Result<HttpResponse, AppError> {
+  let file_stat = match exists(file_root, user, file).await {
+    Some(file_stat) => file_stat,
+    None => return Ok(status(StatusCode::NOT_FOUND)),
+  };
+  // a request without depth means infinity, walking a whole tree is refused as RFC 4918 allows
+  let depth = get_header(req, "depth").unwrap_or("infinity");
+  if depth.eq_ignore_ascii_case("infinity") {
+    return Ok(
+      HttpResponse::build(StatusCode::FORBIDDEN)
+        .content_type("application/xml; charset=utf-8")
+        .body(
+          r#"<?xml version="1.0" encoding="utf-8"?><D:error xmlns:D="DAV:"><D:propfind-finite-depth/></D:error>"#,
+        ),
+    );
+  }
+  let locks = active_locks()
+    .values()
+    .filter(|l| l.username == user.username)
+    .cloned()
+    .collect::<Vec<_>>();
+
+  let mut body =
+    String::from(r#"<?xml version="1.0" encoding="utf-8"?><D:multistatus xmlns:D="DAV:">"#);
+  body += &prop_response(file, &file_stat, &locks);
+  if file_stat.is_dir && depth != "0" {
+    for f in vfs::read_dir(file_root, user, file).await? {
+      let child_stat = vfs::FileStat {
+        is_dir: f.is_dir,
+        is_file: f.is_file,
+        file_type: f.file_type,
+        size: f.size,
+        created: f.created,
+        modified: f.modified,
+        accessed: f.accessed,
+      };
+      body += &prop_response(&join(file, &f.name), &child_stat, &locks);
+    }
+  }
+  body += "</D:multistatus>";
+  Ok(
+    HttpResponse::build(StatusCode::MULTI_STATUS)
+      .content_type("application/xml; charset=utf-8")
+      .body(body),
+  )
+}
+
+async fn get(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  req: &HttpRequest,
+) -> Result<HttpResponse, AppError> {
+  let file_stat = match exists(file_root, user, file).await {
+    Some(file_stat) => file_stat,
+    None => return Ok(status(StatusCode::NOT_FOUND)),
+  };
+  if file_stat.is_dir {
+    return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+  }
+  let mime = mime_guess::from_path(file)
+    .first_or_octet_stream()
+    .to_string();
+  let etag = vfs::etag(file_root, user, file).await?.to_string();
+  let last_modified = http_date(file_stat.modified);
+  if file_stat.size == 0 {
+    return Ok(
+      HttpResponse::Ok()
+        .content_type(mime)
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::LAST_MODIFIED, last_modified))
+        .finish(),
+    );
+  }
+  let size = file_stat.size;
+  let (start, end, is_range) = parse_range(req.headers(), size)?;
+  let end = end.min(size - 1);
+  if start > end {
+    return Ok(
+      HttpResponse::build(StatusCode::RANGE_NOT_SATISFIABLE)
+        .insert_header((header::CONTENT_RANGE, format!("bytes */{size}")))
+        .finish(),
+    );
+  }
+  let stream = vfs::read_file_stream(file_root, user, file, (start, end)).await?;
+  let mut resp = create_stream_resp(stream, Some(mime), None, (start, end), size, is_range, None);
+  resp
+    .headers_mut()
+    .insert(header::ETAG, header::HeaderValue::from_str(&etag)?);
+  resp.headers_mut().insert(
+    header::LAST_MODIFIED,
+    header::HeaderValue::from_str(&last_modified)?,
+  );
+  Ok(resp)
+}
+
+/// write `payload` at the start of `Content-Range`, only files on local storage can be partially updated
+async fn put_range(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  content_range: &str,
+  content_length: Option<u64>,
+  mut payload: web::Payload,
+) -> Result<(), AppError> {
+  lazy_static! {
+    static ref RE: Regex = Regex::new(r#"^bytes (\d+)-(\d+)/(\d+|\*)$"#).unwrap();
+  }
+  let caps = RE.captures(content_range.trim()).ok_or_else(|| {
+    AppError::new("dav: invalid Content-Range header").with_status(StatusCode::BAD_REQUEST)
+  })?;
+  let start = caps.get(1).unwrap().as_str().parse::<u64>()?;
+  let end = caps.get(2).unwrap().as_str().parse::<u64>()?;
+  let total = caps.get(3).unwrap().as_str().parse::<u64>().ok();
+  if end < start || total.map_or(false, |total| end >= total) {
+    return Err(
+      AppError::new("dav: invalid Content-Range header").with_status(StatusCode::BAD_REQUEST),
+    );
+  }
+  let expected = end - start + 1;
+  let length_mismatch = || {
+    AppError::new(&format!(
+      "dav: body length does not match Content-Range of {expected} bytes"
+    ))
+    .with_status(StatusCode::BAD_REQUEST)
+  };
+  if content_length.map_or(false, |len| len != expected) {
+    return Err(length_mismatch());
+  }
+  let target = resolve(file_root, user, file)?.writable()?;
+  let before = quota::size_of(file_root, user, file).await?;
+  quota::check(file_root, user, file, (end + 1).saturating_sub(before)).await?;
+  // a partial upload is kept as one version, taken before its first chunk
+  if start == 0 {
+    versions::snapshot(file_root, user, file).await?;
+  }
+  let local = target.backend.local_path(&target.path).ok_or_else(|| {
+    AppError::new("dav: partial update is only supported on local storage")
+      .with_status(StatusCode::NOT_IMPLEMENTED)
+  })?;
+  let mut f = tokio::fs::OpenOptions::new()
+    .write(true)
+    .create(true)
+    .open(local)
+    .await?;
+  f.seek(std::io::SeekFrom::Start(start)).await?;
+  let mut written = 0;
+  while let Some(bytes) = payload.next().await {
+    let bytes = bytes.map_err(|e| AppError::new(&e.to_string()))?;
+    written += bytes.len() as u64;
+    if written > expected {
+      break;
+    }
+    f.write_all(&bytes).await?;
+  }
+  f.flush().await?;
+  quota::track(file_root, user, file, before).await?;
+  if written != expected {
+    return Err(length_mismatch());
+  }
+  Ok(())
+}
+
+async fn put(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  req: &HttpRequest,
+  payload: web::Payload,
+) -> Result<HttpResponse, AppError> {
+  check_lock(user, file, req)?;
+  let existing = exists(file_root, user, file).await;
+  if existing.as_ref().map_or(false, |s| s.is_dir) {
+    return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+  }
+  if !parent_exists(file_root, user, file).await {
+    return Ok(status(StatusCode::CONFLICT));
+  }
+  let content_length = get_header(req, "content-length").and_then(|v| v.parse::<u64>().ok());
+  if let Some(content_range) = get_header(req, "content-range") {
+    put_range(
+      file_root,
+      user,
+      file,
+      content_range,
+      content_length,
+      payload,
+    )
+    .await?;
+  } else {
+    if let Some(len) = content_length {
+      let before = existing.as_ref().map_or(0, |s| s.size);
+      quota::check(file_root, user, file, len.saturating_sub(before)).await?;
+    }
+    vfs::write_stream(file_root, user, file, payload).await?;
+  }
+  vfs::emit_fs_hook(file_root, user, FSHookType::AddFile, file)?;
+  Ok(status(if existing.is_some() {
+    StatusCode::NO_CONTENT
+  } else {
+    StatusCode::CREATED
+  }))
+}
+
+async fn mkcol(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  req: &HttpRequest,
+) -> Result<HttpResponse, AppError> {
+  check_lock(user, file, req)?;
+  if exists(file_root, user, file).await.is_some() {
+    return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+  }
+  if !parent_exists(file_root, user, file).await {
+    return Ok(status(StatusCode::CONFLICT));
+  }
+  vfs::create_dir(file_root, user, file).await?;
+  vfs::emit_fs_hook(file_root, user, FSHookType::AddFile, file)?;
+  Ok(status(StatusCode::CREATED))
+}
+
+async fn delete(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  req: &HttpRequest,
+) -> Result<HttpResponse, AppError> {
+  check_lock(user, file, req)?;
+  if exists(file_root, user, file).await.is_none() {
+    return Ok(status(StatusCode::NOT_FOUND));
+  }
+  vfs::delete(file_root, user, file).await?;
+  active_locks().retain(|_, l| !(l.username == user.username && is_ancestor(file, &l.path)));
+  Ok(status(StatusCode::NO_CONTENT))
+}
+
+#[async_recursion::async_recursion(?Send)]
+async fn copy_tree(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  from: &str,
+  to: &str,
+) -> Result<(), AppError> {
+  let file_stat = vfs::stat(file_root, user, from).await?;
+  if file_stat.is_dir {
+    vfs::create_dir(file_root, user, to).await?;
+    for f in vfs::read_dir(file_root, user, from).await? {
+      copy_tree(file_root, user, &join(from, &f.name), &join(to, &f.name)).await?;
+    }
+    return Ok(());
+  }
+  vfs::copy_file(file_root, user, from, to).await?;
+  Ok(())
+}
+
+/// path in `Destination` header, which is an absolute url or an absolute path
+fn destination(req: &HttpRequest) -> Result<String, AppError> {
+  let bad_request =
+    || AppError::new("dav: invalid Destination header").with_status(StatusCode::BAD_REQUEST);
+  let dest = get_header(req, "destination").ok_or_else(bad_request)?;
+  let dest = match url::Url::parse(dest) {
+    Ok(url) => url.path().to_string(),
+    Err(_) => dest.to_string(),
+  };
+  let dest = dest.strip_prefix(DAV_PREFIX).ok_or_else(bad_request)?;
+  decode_path(dest)
+}
+
+async fn move_or_copy(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  req: &HttpRequest,
+  is_move: bool,
+) -> Result<HttpResponse, AppError> {
+  let dest = destination(req)?;
+  if is_ancestor(file, &dest) || (is_move && is_ancestor(&dest, file)) {
+    return Ok(status(StatusCode::FORBIDDEN));
+  }
+  if exists(file_root, user, file).await.is_none() {
+    return Ok(status(StatusCode::NOT_FOUND));
+  }
+  if is_move {
+    check_lock(user, file, req)?;
+  }
+  check_lock(user, &dest, req)?;
+
+  let overwrite = get_header(req, "overwrite").map_or(true, |v| !v.eq_ignore_ascii_case("F"));
+  let dest_exists = exists(file_root, user, &dest).await.is_some();
+  if dest_exists {
+    if !overwrite {
+      return Ok(status(StatusCode::PRECONDITION_FAILED));
+    }
+    vfs::delete(file_root, user, &dest).await?;
+  }
+  if !parent_exists(file_root, user, &dest).await {
+    return Ok(status(StatusCode::CONFLICT));
+  }
+
+  if is_move {
+    vfs::move_file(file_root, user, file, &dest).await?;
+    active_locks().retain(|_, l| !(l.username == user.username && is_ancestor(file, &l.path)));
+    vfs::emit_fs_hook(file_root, user, FSHookType::DeleteFile, file)?;
+  } else {
+    copy_tree(file_root, user, file, &dest).await?;
+  }
+  vfs::emit_fs_hook(file_root, user, FSHookType::AddFile, &dest)?;
+  Ok(status(if dest_exists {
+    StatusCode::NO_CONTENT
+  } else {
+    StatusCode::CREATED
+  }))
+}
+
+fn lock_response(lock: &DavLock, code: StatusCode) -> HttpResponse {
+  let body = format!(
+    r#"<?xml version="1.0" encoding="utf-8"?><D:prop xmlns:D="DAV:"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>"#,
+    active_lock_xml(lock)
+  );
+  HttpResponse::build(code)
+    .content_type("application/xml; charset=utf-8")
+    .insert_header(("Lock-Token", format!("<{}>", lock.token)))
+    .body(body)
+}
+
+async fn lock(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  req: &HttpRequest,
+  mut payload: web::Payload,
+) -> Result<HttpResponse, AppError> {
+  lazy_static! {
+    static ref OWNER_RE: Regex =
+      Regex::new(r#"(?s)<(?:\w+:)?owner[^>]*>(.*?)</(?:\w+:)?owner>"#).unwrap();
+    static ref TIMEOUT_RE: Regex = Regex::new(r#"Second-(\d+)"#).unwrap();
+  }
+  let mut body = vec![];
+  while let Some(bytes) = payload.next().await {
+    let bytes = bytes.map_err(|e| AppError::new(&e.to_string()))?;
+    if body.len() + bytes.len() > MAX_LOCK_BODY {
+      return Ok(status(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+    body.extend_from_slice(&bytes);
+  }
+  let body = String::from_utf8_lossy(&body);
+  let timeout = get_header(req, "timeout")
+    .and_then(|v| TIMEOUT_RE.captures(v))
+    .and_then(|c| c.get(1).unwrap().as_str().parse::<u64>().ok())
+    .unwrap_or(DEFAULT_LOCK_TIMEOUT)
+    .min(7 * 24 * 3600);
+
+  // a lock request without body refreshes an existing lock
+  if body.trim().is_empty() {
+    let tokens = submitted_tokens(req);
+    let mut locks = active_locks();
+    let lock = tokens
+      .iter()
+      .find_map(|t| locks.get_mut(t))
+      .filter(|l| l.username == user.username && is_ancestor(&l.path, file));
+    return Ok(match lock {
+      Some(lock) => {
+        lock.timeout = timeout;
+        lock.expires_at = Instant::now() + Duration::from_secs(timeout);
+        lock_response(lock, StatusCode::OK)
+      }
+      None => status(StatusCode::PRECONDITION_FAILED),
+    });
+  }
+
+  let exclusive = !body.contains("shared");
+  let depth_infinity = get_header(req, "depth").map_or(true, |d| d != "0");
+  {
+    let locks = active_locks();
+    // paths are relative to the root of each user, locks of others are on other files
+    let conflict = locks.values().any(|l| {
+      l.username == user.username
+        && (l.exclusive || exclusive)
+        && ((l.depth_infinity && is_ancestor(&l.path, file))
+          || l.path == file
+          || (depth_infinity && is_ancestor(file, &l.path)))
+    });
+    if conflict {
+      return Ok(status(StatusCode::LOCKED));
+    }
+  }
+
+  // locking an unmapped url creates an empty file
+  let mut code = StatusCode::OK;
+  if exists(file_root, user, file).await.is_none() {
+    if !parent_exists(file_root, user, file).await {
+      return Ok(status(StatusCode::CONFLICT));
+    }
+    vfs::create(file_root, user, file, vec![]).await?;
+    code = StatusCode::CREATED;
+  }
+
+  let lock = DavLock {
+    token: format!("opaquelocktoken:{}", uuid::Uuid::new_v4()),
+    username: user.username.clone(),
+    path: file.to_string(),
+    depth_infinity,
+    exclusive,
+    owner: OWNER_RE
+      .captures(&body)
+      .map(|c| c.get(1).unwrap().as_str().to_string()),
+    timeout,
+    expires_at: Instant::now() + Duration::from_secs(timeout),
+  };
+  active_locks().insert(lock.token.clone(), lock.clone());
+  Ok(lock_response(&lock, code))
+}
+
+fn unlock(user: &UserSessionData, file: &str, req: &HttpRequest) -> Result<HttpResponse, AppError> {
+  let token = get_header(req, "lock-token")
+    .map(|t| {
+      t.trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .to_string()
+    })
+    .ok_or_else(|| {
+      AppError::new("dav: Lock-Token header is required").with_status(StatusCode::BAD_REQUEST)
+    })?;
+  let mut locks = active_locks();
+  match locks.get(&token) {
+    Some(l) if l.username == user.username && is_ancestor(&l.path, file) => {
+      locks.remove(&token);
+      Ok(status(StatusCode::NO_CONTENT))
+    }
+    _ => Ok(status(StatusCode::CONFLICT)),
+  }
+}
+
+pub async fn dav_handler(
+  req: HttpRequest,
+  payload: web::Payload,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root.clone();
+  let user = &sess.get_user_data()?;
+  let file = &decode_path(req.path().strip_prefix(DAV_PREFIX).unwrap_or(""))?;
+
+  match req.method().as_str() {
+    "OPTIONS" => Ok(options()),
+    "PROPFIND" => propfind(file_root, user, file, &req).await,
+    "GET" | "HEAD" => get(file_root, user, file, &req).await,
+    "PUT" => put(file_root, user, file, &req, payload).await,
+    "MKCOL" => mkcol(file_root, user, file, &req).await,
+    "DELETE" => delete(file_root, user, file, &req).await,
+    "MOVE" => move_or_copy(file_root, user, file, &req, true).await,
+    "COPY" => move_or_copy(file_root, user, file, &req, false).await,
+    "LOCK" => lock(file_root, user, file, &req, payload).await,
+    "UNLOCK" => unlock(user, file, &req),
+    _ => Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+  }
+}
+
+pub fn dav_routers() -> Scope {
+  web::scope(DAV_PREFIX).default_service(web::to(dav_handler))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+  };
+
+  use actix_session::SessionMiddleware;
+  use actix_web::{cookie::Key, http::Method, test, App};
+  use base64::Engine;
+  use diesel::{Connection, RunQueryDsl, SqliteConnection};
+
+  use super::*;
+  use crate::middlewares::{guard::guard_mw, session::SqliteSessionStore};
+  use crate::models::{NewGroup, NewUser};
+  use crate::utils::crypto::hash_pwd;
+  use crate::utils::test_utils::{init_db, temp_dir};
+  use crate::{db::SHARED_DB_CONN, AppConfig, AppSession, AppState};
+
+  /// a user allowed to read and write files, its root is a host directory outside of
+  /// `file_root`, so nothing is sent to the file index
+  fn setup(name: &str) -> (web::Data<AppData>, PathBuf) {
+    init_db();
+    let dir = temp_dir(name);
+    let user_root = dir.join("root");
+    std::fs::create_dir_all(&user_root).unwrap();
+    {
+      let mut conn = SHARED_DB_CONN.lock().unwrap();
+      diesel::insert_into(crate::schema::groups::table)
+        .values(NewGroup {
+          name: name.to_owned(),
+          desc: String::new(),
+          permissions: "fs_read,fs_write".to_owned(),
+        })
+        .execute(&mut *conn)
+        .unwrap();
+      diesel::insert_into(crate::schema::users::table)
+        .values(NewUser {
+          username: name,
+          password: &hash_pwd("secret").unwrap(),
+          email: "",
+          user_type: 0,
+          user_root: &user_root.to_string_lossy(),
+          group_name: name,
+          must_change_password: false,
+        })
+        .execute(&mut *conn)
+        .unwrap();
+    }
+    let state = AppState {
+      config: AppConfig {
+        file_root: dir.join("files"),
+        static_root: dir.join("static"),
+        port: 0,
+        host: "127.0.0.1".to_owned(),
+      },
+      session: AppSession {
+        users: HashMap::new(),
+      },
+      db: Mutex::new(SqliteConnection::establish(":memory:").unwrap()),
+    };
+    (web::Data::new(Arc::new(RwLock::new(state))), user_root)
+  }
+
+  fn basic(name: &str, pwd: &str) -> (header::HeaderName, String) {
+    let credentials = base64::engine::general_purpose::STANDARD.encode(format!("{name}:{pwd}"));
+    (header::AUTHORIZATION, format!("Basic {credentials}"))
+  }
+
+  /// a request of a DAV client from its own address, so rate limits of tests do not mix
+  fn dav(method: &str, path: &str, name: &str, ip: &str) -> test::TestRequest {
+    test::TestRequest::default()
+      .method(Method::from_bytes(method.as_bytes()).unwrap())
+      .uri(&format!("{DAV_PREFIX}{path}"))
+      .peer_addr(SocketAddr::new(ip.parse().unwrap(), 50000))
+      .insert_header(basic(name, "secret"))
+  }
+
+  macro_rules! dav_service {
+    ($state:expr) => {
+      test::init_service(
+        App::new()
+          .app_data($state.clone())
+          .service(dav_routers())
+          .wrap(guard_mw())
+          .wrap(
+            SessionMiddleware::builder(SqliteSessionStore::new(), Key::generate())
+              .cookie_secure(false)
+              .build(),
+          ),
+      )
+      .await
+    };
+  }
+
+  #[actix_web::test]
+  async fn files_round_trip() {
+    let name = "dav-round-trip";
+    let ip = "10.5.0.1";
+    let (state, user_root) = setup(name);
+    let app = dav_service!(state);
+
+    let resp = test::call_service(&app, dav("MKCOL", "/docs", name, ip).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let resp = test::call_service(
+      &app,
+      dav("PUT", "/docs/a%20b.txt", name, ip)
+        .insert_header((header::CONTENT_LENGTH, 5))
+        .set_payload("hello")
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert_eq!(
+      std::fs::read_to_string(user_root.join("docs/a b.txt")).unwrap(),
+      "hello"
+    );
+
+    let resp = test::call_service(
+      &app,
+      dav("PROPFIND", "/docs", name, ip)
+        .insert_header(("Depth", "1"))
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+    assert!(
+      body.contains("<D:href>/dav/docs/a%20b.txt</D:href>"),
+      "{body}"
+    );
+
+    let resp = test::call_service(&app, dav("GET", "/docs/a%20b.txt", name, ip).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(test::read_body(resp).await, "hello");
+
+    let resp = test::call_service(
+      &app,
+      dav("MOVE", "/docs/a%20b.txt", name, ip)
+        .insert_header(("Destination", "http://localhost/dav/b.txt"))
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(!user_root.join("docs/a b.txt").exists());
+    assert_eq!(
+      std::fs::read_to_string(user_root.join("b.txt")).unwrap(),
+      "hello"
+    );
+
+    let resp = test::call_service(&app, dav("DELETE", "/b.txt", name, ip).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let resp = test::call_service(&app, dav("GET", "/b.txt", name, ip).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+  }
+
+  #[actix_web::test]
+  async fn locked_file_needs_the_lock_token() {
+    let name = "dav-lock";
+    let ip = "10.5.0.2";
+    let (state, _) = setup(name);
+    let app = dav_service!(state);
+
+    let resp = test::call_service(
+      &app,
+      dav("LOCK", "/a.txt", name, ip)
+        .set_payload(
+          r#"<?xml version="1.0"?><D:lockinfo xmlns:D="DAV:"><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockinfo>"#,
+        )
+        .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success(), "{}", resp.status());
+    let token = resp
+      .headers()
+      .get("Lock-Token")
+      .unwrap()
+      .to_str()
+      .unwrap()
+      .to_owned();
+
+    let resp = test::call_service(
+      &app,
+      dav("PUT", "/a.txt", name, ip)
+        .set_payload("hello")
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::LOCKED);
+    let resp = test::call_service(
+      &app,
+      dav("PUT", "/a.txt", name, ip)
+        .insert_header(("If", format!("({token})")))
+        .set_payload("hello")
+        .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success(), "{}", resp.status());
+
+    let resp = test::call_service(
+      &app,
+      dav("UNLOCK", "/a.txt", name, ip)
+        .insert_header(("Lock-Token", token))
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+  }
+
+  #[actix_web::test]
+  async fn basic_auth_is_checked_on_every_request() {
+    let name = "dav-basic-auth";
+    let ip = "10.5.0.3";
+    let (state, _) = setup(name);
+    let app = dav_service!(state);
+
+    let resp = test::call_service(
+      &app,
+      dav("PROPFIND", "/", name, ip)
+        .insert_header(basic(name, "wrong"))
+        .insert_header(("Depth", "0"))
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(resp.headers().contains_key(header::WWW_AUTHENTICATE));
+
+    let resp = test::call_service(
+      &app,
+      dav("PROPFIND", "/", name, ip)
+        .insert_header(("Depth", "0"))
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    // the session of a basic auth request is not stored, its cookie does not log in
+    let cookie = resp.response().cookies().next().map(|c| c.into_owned());
+    let mut req = test::TestRequest::default()
+      .method(Method::from_bytes(b"PROPFIND").unwrap())
+      .uri(&format!("{DAV_PREFIX}/"))
+      .peer_addr(SocketAddr::new(ip.parse().unwrap(), 50000))
+      .insert_header(("Depth", "0"));
+    if let Some(cookie) = cookie {
+      req = req.cookie(cookie);
+    }
+    let resp = test::call_service(&app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+  }
+}
@@ -1,20 +1,28 @@
+use crate::models::{FileIndexSizeCount, VersioningPolicy};
+use crate::utils::archive;
 use crate::utils::error::AppError;
 use crate::utils::parser::parse_range;
 use crate::utils::response::{
   create_binary_resp, create_stream_resp, create_unsized_stream_resp, EmptyResponseData,
 };
+use crate::utils::quota;
 use crate::utils::session::SessionUtils;
+use crate::utils::storage::resolve;
+use crate::utils::trash;
+use crate::utils::upload::{self, UploadOffset};
+use crate::utils::versions;
 use crate::utils::vfs::{
-  ensure_parent_dir_sync, normailze_path, read_file_stream, read_to_zip_stream, FileStatWithName,
+  ensure_parent_dir_sync, read_file_stream, read_to_zip_stream, FileStatWithName,
 };
 use crate::utils::{response::create_resp, vfs};
-use crate::AppData;
+use crate::{AppData, UserSessionData};
 use actix_session::Session;
-use actix_web::http::{header, StatusCode};
+use actix_web::http::{header, header::HeaderMap, StatusCode};
 use actix_web::{web, HttpRequest, HttpResponse, Scope};
 use reqwest::header::HeaderValue;
 use serde::{Deserialize, Serialize};
 use std::borrow::Borrow;
+use std::path::PathBuf;
 use std::str::FromStr;
 use tokio_util::io::ReaderStream;
 
@@ -69,13 +77,13 @@ pub async fn fs_actions(
   query: GetFilesOfDirReq,
 ) -> Result<HttpResponse, AppError> {
   let file_root = &state.read().unwrap().config.file_root;
-  let user_root = &sess.get_user_root()?;
+  let user = &sess.get_user_data()?;
   let action = path.into_inner().0;
   let headers = req_raw.headers();
 
   match action.as_str() {
     "read_dir" => {
-      let files = vfs::read_dir(file_root, user_root, file).await.unwrap();
+      let files = vfs::read_dir(file_root, user, file).await.unwrap();
 
       let resp = GetFilesOfDirResp { files };
 
@@ -83,13 +91,13 @@ pub async fn fs_actions(
     }
 
     "create_dir" => {
-      vfs::create_dir(file_root, user_root, file).await.unwrap();
+      vfs::create_dir(file_root, user, file).await.unwrap();
 
       Ok(create_resp(true, EmptyResponseData::new(), ""))
     }
 
     "read_zip_entries" => {
-      let tree = vfs::read_entries_in_zip(file_root, user_root, file)
+      let tree = vfs::read_entries_in_zip(file_root, user, file)
         .await
         .unwrap();
 
@@ -97,7 +105,7 @@ pub async fn fs_actions(
     }
 
     "read_compression" => {
-      let stream = read_to_zip_stream(file_root, user_root, file).await?;
+      let stream = read_to_zip_stream(file_root, user, file).await?;
       let resp = create_unsized_stream_resp(
         stream,
         Some("application/zip".to_string()),
@@ -107,75 +115,109 @@ pub async fn fs_actions(
     }
 
     "read" => {
-      let header_etag = req_raw.headers().get(header::IF_NONE_MATCH);
-      if header_etag.is_some() {
-        let meta = vfs::metadata(file_root, user_root, file).await?;
-        let tag = etag::EntityTag::from_file_meta(&meta);
-        let header_tag = header_etag.unwrap().to_str()?.to_string();
-        let header_tag = etag::EntityTag::from_str(&header_tag)?;
-        let matched = tag.weak_eq(&header_tag);
-        if matched {
-          return Ok(HttpResponse::NotModified().append_header((header::ETAG, tag.to_string())).finish());
-        }
-      }
-
-      let file_stat = vfs::stat(file_root, user_root, file).await?;
-      let (range_start, range_end, is_range) = parse_range(headers, file_stat.size)?;
-      let stream = read_file_stream(file_root, user_root, file, (range_start, range_end)).await?;
       let mime = mime_guess::from_path(file.to_owned())
         .first()
         .map(|m| m.to_string());
 
-      let mut resp =
-        if query.param_resize.is_some() && mime.clone().map_or(false, |v| v.contains("image")) {
-          let thumbnail =
-            vfs::create_thumbnail(file_root, user_root, file, query.param_resize.unwrap())?;
-          create_binary_resp(thumbnail, mime.clone(), query.param_expires)
-        } else if is_download {
-          create_stream_resp(
-            stream,
-            mime,
-            Some(file),
-            (range_start, range_end),
-            file_stat.size,
-            is_range,
-            query.param_expires,
-          )
-        } else {
-          create_stream_resp(
-            stream,
-            mime,
-            None,
-            (range_start, range_end),
-            file_stat.size,
-            is_range,
-            query.param_expires,
-          )
-        };
-
-      if let Some(_) = query.param_expires {
-        let meta = vfs::metadata(file_root, user_root, file).await?;
-        let tag = etag::EntityTag::from_file_meta(&meta);
-        resp
-          .headers_mut()
-          .insert(header::ETAG, HeaderValue::from_str(&tag.to_string())?);
+      if query.param_resize.is_some() && mime.clone().map_or(false, |v| v.contains("image")) {
+        if let Some(resp) = not_modified(file_root, user, file, headers).await? {
+          return Ok(resp);
+        }
+        let thumbnail = vfs::create_thumbnail(file_root, user, file, query.param_resize.unwrap())?;
+        let resp = create_binary_resp(thumbnail, mime, query.param_expires);
+        return with_etag(resp, file_root, user, file, query.param_expires).await;
       }
-      Ok(resp)
+
+      let download_name = if is_download { Some(file) } else { None };
+      read_file_resp(file_root, user, file, headers, download_name, query.param_expires).await
     }
 
     "delete" => {
-      vfs::delete(file_root, user_root, file).await?;
+      vfs::delete(file_root, user, file).await?;
       Ok(create_resp(true, EmptyResponseData::new(), "done"))
     }
 
     "stat" => {
-      let file_stat = vfs::stat(file_root, user_root, file).await?;
+      let file_stat = vfs::stat(file_root, user, file).await?;
       Ok(create_resp(true, file_stat, ""))
     }
     _ => Ok(create_resp(false, EmptyResponseData::new(), "error action")),
   }
 }
 
+/// 304 response when `If-None-Match` header matches current etag of file
+async fn not_modified(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  headers: &HeaderMap,
+) -> Result<Option<HttpResponse>, AppError> {
+  let header_etag = headers.get(header::IF_NONE_MATCH);
+  if let Some(header_etag) = header_etag {
+    let tag = vfs::etag(file_root, user, file).await?;
+    let header_tag = header_etag.to_str()?.to_string();
+    let header_tag = etag::EntityTag::from_str(&header_tag)?;
+    if tag.weak_eq(&header_tag) {
+      return Ok(Some(
+        HttpResponse::NotModified()
+          .append_header((header::ETAG, tag.to_string()))
+          .finish(),
+      ));
+    }
+  }
+  Ok(None)
+}
+
+/// cached responses carry an etag so they can be revalidated
+async fn with_etag(
+  mut resp: HttpResponse,
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  expires: Option<u32>,
+) -> Result<HttpResponse, AppError> {
+  if expires.is_some() {
+    let tag = vfs::etag(file_root, user, file).await?;
+    resp
+      .headers_mut()
+      .insert(header::ETAG, HeaderValue::from_str(&tag.to_string())?);
+  }
+  Ok(resp)
+}
+
+/// stream a file honoring `Range` and `If-None-Match` headers,
+/// the file is sent as an attachment when `download_name` is given
+pub async fn read_file_resp(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  headers: &HeaderMap,
+  download_name: Option<&str>,
+  expires: Option<u32>,
+) -> Result<HttpResponse, AppError> {
+  if let Some(resp) = not_modified(file_root, user, file, headers).await? {
+    return Ok(resp);
+  }
+
+  let file_stat = vfs::stat(file_root, user, file).await?;
+  let (range_start, range_end, is_range) = parse_range(headers, file_stat.size)?;
+  let stream = read_file_stream(file_root, user, file, (range_start, range_end)).await?;
+  let mime = mime_guess::from_path(file.to_owned())
+    .first()
+    .map(|m| m.to_string());
+
+  let resp = create_stream_resp(
+    stream,
+    mime,
+    download_name,
+    (range_start, range_end),
+    file_stat.size,
+    is_range,
+    expires,
+  );
+  with_etag(resp, file_root, user, file, expires).await
+}
+
 #[derive(Deserialize)]
 pub struct DeleteFilesOfDirReq {
   files: Option<Vec<String>>,
@@ -187,13 +229,13 @@ pub async fn delete_batch(
   sess: Session,
 ) -> Result<HttpResponse, AppError> {
   let file_root = &state.read().unwrap().config.file_root;
-  let user_root = &sess.get_user_root()?;
+  let user = &sess.get_user_data()?;
   let files = query
     .borrow()
     .files
     .clone()
     .ok_or(AppError::new("delete: query params error").with_status(StatusCode::BAD_REQUEST))?;
-  vfs::delete_batch(file_root, user_root, files).await?;
+  vfs::delete_batch(file_root, user, files).await?;
   Ok(create_resp(true, EmptyResponseData::new(), ""))
 }
 
@@ -208,12 +250,12 @@ pub async fn move_file(
   sess: Session,
 ) -> Result<HttpResponse, AppError> {
   let file_root = &state.read().unwrap().config.file_root;
-  let user_root = &sess.get_user_root()?;
+  let user = &sess.get_user_data()?;
 
   let from_file = body.borrow().from_file.clone();
   let to_file = body.borrow().to_file.clone();
 
-  vfs::move_file(file_root, user_root, &from_file, &to_file).await?;
+  vfs::move_file(file_root, user, &from_file, &to_file).await?;
   Ok(create_resp(true, EmptyResponseData::new(), "done"))
 }
 
@@ -223,40 +265,65 @@ pub async fn copy_file(
   sess: Session,
 ) -> Result<HttpResponse, AppError> {
   let file_root = &state.read().unwrap().config.file_root;
-  let user_root = &sess.get_user_root()?;
+  let user = &sess.get_user_data()?;
 
   let from_file = body.borrow().from_file.clone();
   let to_file = body.borrow().to_file.clone();
 
-  vfs::copy_file(file_root, user_root, &from_file, &to_file).await?;
+  vfs::copy_file(file_root, user, &from_file, &to_file).await?;
   Ok(create_resp(true, EmptyResponseData::new(), "done"))
 }
+/// save a file of a multipart upload, `filename` is the form field name holding its path
+pub async fn save_upload(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  filename: &str,
+  file: awmp::File,
+) -> Result<(), AppError> {
+  let target = resolve(file_root, user, filename)?.writable()?;
+  // the file is saved with its sanitized name in the directory of `filename`
+  let name = file.sanitized_file_name().to_owned();
+  let dest = PathBuf::from(filename).with_file_name(&name);
+  let dest = dest.to_string_lossy();
+  let temp_file = file.into_inner();
+  let size = tokio::fs::metadata(temp_file.path()).await?.len();
+  let before = quota::size_of(file_root, user, &dest).await?;
+  quota::check(file_root, user, &dest, size.saturating_sub(before)).await?;
+  versions::snapshot(file_root, user, &dest).await?;
+  if let Some(file_path) = target.backend.local_path(&target.path) {
+    web::block(move || -> Result<(), AppError> {
+      ensure_parent_dir_sync(&file_path)?;
+      let file_path = file_path.with_file_name(&name);
+      // rename fails when upload_temp_dir is on another device
+      if let Err(err) = temp_file.persist(&file_path) {
+        std::fs::copy(err.file.path(), &file_path)?;
+      }
+      Ok(())
+    })
+    .await??;
+  } else {
+    let file_path = target.path.with_file_name(&name);
+    let reader = tokio::fs::File::open(temp_file.path()).await?;
+    target.backend.write(&file_path, Box::pin(reader)).await?;
+  }
+  quota::track(file_root, user, &dest, before).await?;
+  Ok(())
+}
+
 pub async fn upload(
   parts: awmp::Parts,
   state: web::Data<AppData>,
   sess: Session,
 ) -> Result<HttpResponse, AppError> {
   let file_root = &state.read().unwrap().config.file_root;
-  let user_root = &sess.get_user_root()?;
-
-  let file_root = file_root.clone();
-  let user_root = user_root.clone();
-  web::block(move || -> Result<(), AppError> {
-    let files = parts.files.into_inner();
-    for (filename, file) in files {
-      if let Ok(file) = file {
-        let file_path = normailze_path(&file_root, &user_root, &filename)?;
-        println!("{file_path:#?}");
-        ensure_parent_dir_sync(&file_path)?;
-        let parent_dir = file_path.parent();
-        if let Some(parent_dir) = parent_dir {
-          file.persist_in(parent_dir).unwrap();
-        }
-      }
+  let user = &sess.get_user_data()?;
+
+  let files = parts.files.into_inner();
+  for (filename, file) in files {
+    if let Ok(file) = file {
+      save_upload(file_root, user, &filename, file).await?;
     }
-    Ok(())
-  })
-  .await??;
+  }
 
   Ok(create_resp(
     true,
@@ -265,6 +332,352 @@ pub async fn upload(
   ))
 }
 
+#[derive(Deserialize)]
+pub struct ResumableCreateReq {
+  file: String,
+  size: u64,
+  sha256: Option<String>,
+}
+
+pub async fn resumable_create(
+  body: web::Json<ResumableCreateReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root.clone();
+  let user = &sess.get_user_data()?;
+  let body = body.into_inner();
+  let session =
+    upload::create_session(file_root, user, &body.file, body.size, body.sha256).await?;
+  let resp = UploadOffset {
+    id: session.id,
+    offset: 0,
+    size: session.size,
+  };
+  Ok(create_resp(true, resp, "done"))
+}
+
+fn with_upload_offset(mut resp: HttpResponse, offset: u64) -> Result<HttpResponse, AppError> {
+  resp.headers_mut().insert(
+    header::HeaderName::from_static("upload-offset"),
+    HeaderValue::from_str(&offset.to_string())?,
+  );
+  Ok(resp)
+}
+
+/// current offset of an upload, also returned in `Upload-Offset` header for HEAD requests
+pub async fn resumable_offset(
+  path: web::Path<(String,)>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let user = &sess.get_user_data()?;
+  let (session, offset) = upload::get_session(user, &path.into_inner().0).await?;
+  let resp = UploadOffset {
+    id: session.id,
+    offset,
+    size: session.size,
+  };
+  with_upload_offset(create_resp(true, resp, "done"), offset)
+}
+
+/// append request body to an upload, the `Upload-Offset` header must match current offset
+pub async fn resumable_patch(
+  path: web::Path<(String,)>,
+  req: HttpRequest,
+  payload: web::Payload,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let user = &sess.get_user_data()?;
+  let id = path.into_inner().0;
+  let offset = req
+    .headers()
+    .get("upload-offset")
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.parse::<u64>().ok())
+    .ok_or_else(|| {
+      AppError::new("upload: Upload-Offset header is required").with_status(StatusCode::BAD_REQUEST)
+    })?;
+  let offset = upload::write_chunk(user, &id, offset, payload).await?;
+  let (session, _) = upload::get_session(user, &id).await?;
+  let resp = UploadOffset {
+    id,
+    offset,
+    size: session.size,
+  };
+  with_upload_offset(create_resp(true, resp, "done"), offset)
+}
+
+#[derive(Deserialize)]
+pub struct ResumableFinalizeReq {
+  id: String,
+  sha256: Option<String>,
+}
+
+pub async fn resumable_finalize(
+  body: web::Json<ResumableFinalizeReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root;
+  let user = &sess.get_user_data()?;
+  let body = body.into_inner();
+  let session = upload::finalize(file_root, user, &body.id, body.sha256).await?;
+  let file_stat = vfs::stat(file_root, user, &session.file).await?;
+  Ok(create_resp(true, file_stat, "upload file successfully"))
+}
+
+pub async fn resumable_abort(
+  path: web::Path<(String,)>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let user = &sess.get_user_data()?;
+  upload::abort(user, &path.into_inner().0).await?;
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+pub async fn trash_list(sess: Session) -> Result<HttpResponse, AppError> {
+  let user = sess.get_user_data()?;
+  let items = trash::list(&user.username)?;
+  Ok(create_resp(true, items, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct TrashRestoreReq {
+  id: String,
+}
+
+pub async fn trash_restore(
+  body: web::Json<TrashRestoreReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root;
+  let user = &sess.get_user_data()?;
+  let item = trash::restore(file_root, user, &body.id).await?;
+  Ok(create_resp(true, item, "done"))
+}
+
+pub async fn trash_empty(
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root;
+  let user = &sess.get_user_data()?;
+  let count = trash::empty(file_root, user).await?;
+  Ok(create_resp(true, count, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct VersionsListReq {
+  file: String,
+}
+
+pub async fn versions_list(
+  body: web::Json<VersionsListReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let user = sess.get_user_data()?;
+  let items = versions::list(&user.username, &body.file)?;
+  Ok(create_resp(true, items, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct VersionReq {
+  id: String,
+}
+
+/// content of a version, served like `/file/read` with `Range` support
+pub async fn versions_read(
+  query: web::Query<VersionReq>,
+  req: HttpRequest,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root.clone();
+  let user = &sess.get_user_data()?;
+  let version = versions::get(&user.username, &query.id)?;
+  let name = PathBuf::from(&version.path)
+    .file_name()
+    .map_or("download".to_owned(), |n| n.to_string_lossy().to_string());
+  let size = version.size as u64;
+  let (range_start, range_end, is_range) = parse_range(req.headers(), size)?;
+  let stream = versions::read_stream(file_root, user, &version, (range_start, range_end)).await?;
+  let mime = mime_guess::from_path(&version.path)
+    .first()
+    .map(|m| m.to_string());
+  Ok(create_stream_resp(
+    stream,
+    mime,
+    Some(&name),
+    (range_start, range_end),
+    size,
+    is_range,
+    None,
+  ))
+}
+
+pub async fn versions_restore(
+  body: web::Json<VersionReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root.clone();
+  let user = &sess.get_user_data()?;
+  let version = versions::restore(file_root, user, &body.id).await?;
+  Ok(create_resp(true, version, "done"))
+}
+
+pub async fn versions_policy_list(sess: Session) -> Result<HttpResponse, AppError> {
+  let user = sess.get_user_data()?;
+  let policies = versions::list_policies(&user.username)?;
+  Ok(create_resp(true, policies, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct VersionsPolicyReq {
+  dir: String,
+  keep_versions: Option<i32>,
+  keep_days: Option<i32>,
+}
+
+/// turn on versioning for a directory and its children
+pub async fn versions_policy_set(
+  body: web::Json<VersionsPolicyReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let user = sess.get_user_data()?;
+  let body = body.into_inner();
+  let policy = versions::set_policy(VersioningPolicy {
+    username: user.username,
+    dir: body.dir,
+    keep_versions: body.keep_versions,
+    keep_days: body.keep_days,
+  })?;
+  Ok(create_resp(true, policy, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct VersionsPolicyRemoveReq {
+  dir: String,
+}
+
+pub async fn versions_policy_remove(
+  body: web::Json<VersionsPolicyRemoveReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let user = sess.get_user_data()?;
+  if versions::remove_policy(&user.username, &body.dir)? {
+    return Ok(create_resp(true, EmptyResponseData::new(), "done"));
+  }
+  Ok(create_resp(
+    false,
+    EmptyResponseData::new(),
+    "versioning policy not found",
+  ))
+}
+
+#[derive(Deserialize)]
+pub struct ExtractReq {
+  file: String,
+  /// directory to extract into, defaults to the archive path without extension
+  dest: Option<String>,
+}
+
+/// start extracting an archive, progress is read with `/file/archive/job`
+pub async fn extract(
+  body: web::Json<ExtractReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root.clone();
+  let user = &sess.get_user_data()?;
+  let body = body.into_inner();
+  let job = archive::extract(file_root, user, &body.file, body.dest).await?;
+  Ok(create_resp(true, job, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct CompressReq {
+  files: Vec<String>,
+  /// path of the new archive, its extension selects the format
+  dest: String,
+}
+
+pub async fn compress(
+  body: web::Json<CompressReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root.clone();
+  let user = &sess.get_user_data()?;
+  let body = body.into_inner();
+  let job = archive::compress(file_root, user, body.files, &body.dest).await?;
+  Ok(create_resp(true, job, "done"))
+}
+
+pub async fn archive_jobs(sess: Session) -> Result<HttpResponse, AppError> {
+  let user = sess.get_user_data()?;
+  Ok(create_resp(true, archive::list_jobs(&user.username), "done"))
+}
+
+#[derive(Deserialize)]
+pub struct ArchiveJobReq {
+  id: String,
+}
+
+pub async fn archive_job(
+  body: web::Json<ArchiveJobReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let user = sess.get_user_data()?;
+  let job = archive::get_job(&user.username, &body.id)?;
+  Ok(create_resp(true, job, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct ArchiveEntriesReq {
+  file: String,
+}
+
+pub async fn archive_entries(
+  body: web::Json<ArchiveEntriesReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root.clone();
+  let user = &sess.get_user_data()?;
+  let entries = archive::list_entries(file_root, user, &body.file).await?;
+  Ok(create_resp(true, entries, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct ArchiveEntryReq {
+  file: String,
+  entry: String,
+}
+
+/// download a single entry of an archive
+pub async fn archive_read_entry(
+  query: web::Query<ArchiveEntryReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root.clone();
+  let user = &sess.get_user_data()?;
+  let stream = archive::read_entry(file_root, user, &query.file, &query.entry).await?;
+  let mime = mime_guess::from_path(&query.entry)
+    .first()
+    .map(|m| m.to_string());
+  let name = PathBuf::from(&query.entry)
+    .file_name()
+    .map_or("download".to_owned(), |n| n.to_string_lossy().to_string());
+  Ok(create_unsized_stream_resp(
+    ReaderStream::new(stream),
+    mime,
+    Some(&name),
+  ))
+}
+
 #[derive(Deserialize)]
 pub struct ReadImageReq {
   pub file: Option<String>,
@@ -318,9 +731,9 @@ pub async fn search_files(
   let kw = &body.keyword;
   let dir = &body.dir;
   let file_root = &state.read().unwrap().config.file_root;
-  let user_root = &sess.get_user_root()?;
+  let user = &sess.get_user_data()?;
 
-  let files = vfs::search_files(file_root, user_root, dir, kw)?;
+  let files = vfs::search_files(file_root, user, dir, kw)?;
   Ok(create_resp(true, files, "done"))
 }
 
@@ -331,19 +744,13 @@ pub async fn read_image(
   sess: Session,
 ) -> Result<HttpResponse, AppError> {
   let file_root = &state.read().unwrap().config.file_root;
-  let user_root = sess.get_user_root()?;
+  let user = &sess.get_user_data()?;
 
   let mime = mime_guess::from_path(file.to_owned())
     .first()
     .map(|m| m.to_string());
 
-  let img = vfs::read_image(
-    file_root.clone(),
-    user_root.clone(),
-    file.to_string(),
-    resize,
-  )
-  .await?;
+  let img = vfs::read_image(file_root, user, file, resize).await?;
 
   Ok(create_binary_resp(img, mime, None))
 }
@@ -360,16 +767,10 @@ pub async fn read_video_transcode_get(
   let resize = query.resize.clone();
   let bitrate = query.bitrate.clone();
   let file_root = &state.read().unwrap().config.file_root;
-  let user_root = sess.get_user_root()?;
+  let user = &sess.get_user_data()?;
 
-  let video_stream = vfs::read_video_transform_stream(
-    file_root.clone(),
-    user_root.clone(),
-    file.to_string(),
-    resize,
-    bitrate,
-  )
-  .await?;
+  let video_stream =
+    vfs::read_video_transform_stream(file_root, user, &file, resize, bitrate).await?;
 
   let reader = ReaderStream::new(video_stream);
   Ok(create_unsized_stream_resp(
@@ -398,14 +799,23 @@ pub async fn search_content(body: web::Json<SearchFilesReq>) -> Result<HttpRespo
   Ok(create_resp(true, r, "done"))
 }
 
-pub async fn storage_info() -> Result<HttpResponse, AppError> {
-  let r = vfs::storage_info_group_by_file_mime("").await?;
+pub async fn storage_info(sess: Session) -> Result<HttpResponse, AppError> {
+  let user = sess.get_user_data()?;
+  let mut r = vfs::storage_info_group_by_file_mime(&user.username).await?;
+  // stored versions are not in file index, they are reported as a group of their own
+  r.push(FileIndexSizeCount {
+    size: versions::usage(&user.username)?,
+    username: user.username,
+    format: Some(versions::VERSIONS_DIR.to_owned()),
+    is_dir: false,
+  });
 
   Ok(create_resp(true, r, "done"))
 }
 
-pub async fn index_updated_at() -> Result<HttpResponse, AppError> {
-  let r = vfs::file_index_last_updated_time("").await?;
+pub async fn index_updated_at(sess: Session) -> Result<HttpResponse, AppError> {
+  let user = sess.get_user_data()?;
+  let r = vfs::file_index_last_updated_time(&user.username).await?;
   Ok(create_resp(true, r, "done"))
 }
 
@@ -414,10 +824,31 @@ pub fn file_routers() -> Scope {
     .route("/move", web::post().to(move_file))
     .route("/copy", web::post().to(copy_file))
     .route("/upload", web::post().to(upload))
+    .route("/resumable/create", web::post().to(resumable_create))
+    .route("/resumable/finalize", web::post().to(resumable_finalize))
+    .route("/resumable/{id}", web::head().to(resumable_offset))
+    .route("/resumable/{id}", web::get().to(resumable_offset))
+    .route("/resumable/{id}", web::patch().to(resumable_patch))
+    .route("/resumable/{id}", web::delete().to(resumable_abort))
     .route("/search", web::post().to(search))
     .route("/search_files", web::post().to(search_files))
     .route("/search_content", web::post().to(search_content))
     .route("/delete_batch", web::post().to(delete_batch))
+    .route("/trash/list", web::post().to(trash_list))
+    .route("/trash/restore", web::post().to(trash_restore))
+    .route("/trash/empty", web::post().to(trash_empty))
+    .route("/versions/list", web::post().to(versions_list))
+    .route("/versions/read", web::get().to(versions_read))
+    .route("/versions/restore", web::post().to(versions_restore))
+    .route("/versions/policy/list", web::post().to(versions_policy_list))
+    .route("/versions/policy/set", web::post().to(versions_policy_set))
+    .route("/versions/policy/remove", web::post().to(versions_policy_remove))
+    .route("/extract", web::post().to(extract))
+    .route("/compress", web::post().to(compress))
+    .route("/archive/jobs", web::post().to(archive_jobs))
+    .route("/archive/job", web::post().to(archive_job))
+    .route("/archive/entries", web::post().to(archive_entries))
+    .route("/archive/read_entry", web::get().to(archive_read_entry))
     .route("/read_image", web::post().to(read_image_post))
     .route("/read_image", web::get().to(read_image_get))
     .route("/storage_info", web::post().to(storage_info))
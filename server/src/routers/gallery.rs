@@ -3,17 +3,20 @@ use crate::utils::error::AppError;
 use crate::utils::gallery;
 use crate::utils::response::create_resp;
 use crate::utils::response::EmptyResponseData;
+use crate::utils::session::SessionUtils;
 use crate::AppData;
+use actix_session::Session;
 use actix_web::{web, HttpResponse, Scope};
 use std::borrow::Borrow;
 
-pub async fn list(state: web::Data<AppData>) -> Result<HttpResponse, AppError> {
+pub async fn list(sess: Session, state: web::Data<AppData>) -> Result<HttpResponse, AppError> {
+  let user = sess.get_user_data()?;
   let state = state.borrow().write().unwrap();
 
   let mut db_mutex = state.db.lock().await;
 
   let db = &mut *db_mutex;
-  let images = gallery::get_all_images(db, "")?;
+  let images = gallery::get_all_images(db, &user.username)?;
   let resp = create_resp(true, images, "done");
   Ok(resp)
 }
@@ -0,0 +1,81 @@
+use std::borrow::Borrow;
+
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Scope};
+use serde::Deserialize;
+
+use crate::models::Mount;
+use crate::utils::error::AppError;
+use crate::utils::mount::{add_mount, get_mounts, remove_mount};
+use crate::utils::permission::{self, Permission};
+use crate::utils::response::{create_resp, EmptyResponseData};
+use crate::utils::session::SessionUtils;
+
+#[derive(Deserialize)]
+pub struct ListMountsReq {
+  username: Option<String>,
+}
+
+/// list mounts of the given user, defaults to current user,
+/// mounts of others contain host paths and are only listed to user admins
+pub async fn list_mounts(
+  body: web::Json<ListMountsReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let current = sess.get_user_data()?.username;
+  let user = match body.borrow().username.clone() {
+    Some(user) if user != current => {
+      permission::require(&current, Permission::UserAdmin)?;
+      user
+    }
+    _ => current,
+  };
+  let mounts = get_mounts(&user)?;
+  Ok(create_resp(true, mounts, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct AddMountReq {
+  username: String,
+  prefix: String,
+  target: String,
+  read_only: Option<bool>,
+}
+
+/// a mount target may be any directory of the host, so only user admins manage mounts
+pub async fn add(body: web::Json<AddMountReq>, sess: Session) -> Result<HttpResponse, AppError> {
+  permission::require(&sess.get_user_data()?.username, Permission::UserAdmin)?;
+  let body = body.into_inner();
+  add_mount(Mount {
+    username: body.username,
+    prefix: body.prefix,
+    target: body.target,
+    read_only: body.read_only.unwrap_or(false),
+  })?;
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+#[derive(Deserialize)]
+pub struct RemoveMountReq {
+  username: String,
+  prefix: String,
+}
+
+pub async fn remove(
+  body: web::Json<RemoveMountReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  permission::require(&sess.get_user_data()?.username, Permission::UserAdmin)?;
+  let removed = remove_mount(&body.username, &body.prefix)?;
+  if removed {
+    return Ok(create_resp(true, EmptyResponseData::new(), "done"));
+  }
+  Ok(create_resp(false, EmptyResponseData::new(), "mount not found"))
+}
+
+pub fn mount_routers() -> Scope {
+  web::scope("/mount")
+    .route("/list", web::post().to(list_mounts))
+    .route("/add", web::post().to(add))
+    .route("/remove", web::post().to(remove))
+}
@@ -0,0 +1,83 @@
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Scope};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::error::AppError;
+use crate::utils::quota;
+use crate::utils::response::{create_resp, EmptyResponseData};
+use crate::utils::session::SessionUtils;
+use crate::AppData;
+
+#[derive(Serialize)]
+pub struct UsageResp {
+  used: i64,
+  quota: Option<i64>,
+}
+
+/// usage and quota of current user
+pub async fn usage(state: web::Data<AppData>, sess: Session) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root.clone();
+  let user = &sess.get_user_data()?;
+  let used = quota::usage(file_root, user).await?;
+  let quota = quota::limit(&user.username)?;
+  Ok(create_resp(true, UsageResp { used, quota }, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct ListUsageReq {
+  /// walk user roots again instead of using tracked usage
+  refresh: Option<bool>,
+}
+
+pub async fn list(
+  body: web::Json<ListUsageReq>,
+  state: web::Data<AppData>,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root.clone();
+  let r = quota::list_usage(file_root, body.refresh.unwrap_or(false)).await?;
+  Ok(create_resp(true, r, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct SetUserQuotaReq {
+  username: String,
+  /// bytes, no limit when null
+  quota: Option<i64>,
+}
+
+pub async fn set_user(body: web::Json<SetUserQuotaReq>) -> Result<HttpResponse, AppError> {
+  if quota::set_user_quota(&body.username, body.quota)? {
+    return Ok(create_resp(true, EmptyResponseData::new(), "done"));
+  }
+  Ok(create_resp(
+    false,
+    EmptyResponseData::new(),
+    "user not found",
+  ))
+}
+
+#[derive(Deserialize)]
+pub struct SetGroupQuotaReq {
+  group: String,
+  /// bytes, no limit when null
+  quota: Option<i64>,
+}
+
+pub async fn set_group(body: web::Json<SetGroupQuotaReq>) -> Result<HttpResponse, AppError> {
+  if quota::set_group_quota(&body.group, body.quota)? {
+    return Ok(create_resp(true, EmptyResponseData::new(), "done"));
+  }
+  Ok(create_resp(
+    false,
+    EmptyResponseData::new(),
+    "group not found",
+  ))
+}
+
+pub fn quota_routers() -> Scope {
+  web::scope("/quota")
+    .route("/usage", web::post().to(usage))
+    .route("/list", web::post().to(list))
+    .route("/set_user", web::post().to(set_user))
+    .route("/set_group", web::post().to(set_group))
+}
@@ -0,0 +1,250 @@
+use std::path::{Path, PathBuf};
+
+use actix_session::Session;
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Scope};
+use serde::{Deserialize, Serialize};
+
+use crate::models::ShareLink;
+use crate::routers::fs::{read_file_resp, save_upload};
+use crate::utils::api_token;
+use crate::utils::error::AppError;
+use crate::utils::parser::parse_range;
+use crate::utils::permission::Permission;
+use crate::utils::response::{create_resp, create_unsized_stream_resp, EmptyResponseData};
+use crate::utils::session::{client_ip, SessionUtils};
+use crate::utils::share::{self, NewShareLink};
+use crate::utils::vfs::{self, FileStatWithName};
+use crate::{AppData, UserSessionData};
+
+#[derive(Deserialize)]
+pub struct CreateShareLinkReq {
+  file: String,
+  password: Option<String>,
+  /// unix timestamp in seconds
+  expires_at: Option<i64>,
+  max_downloads: Option<i32>,
+  allow_upload: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct ShareLinkResp {
+  #[serde(flatten)]
+  link: ShareLink,
+  has_password: bool,
+}
+
+impl From<ShareLink> for ShareLinkResp {
+  fn from(link: ShareLink) -> Self {
+    let has_password = link.password.is_some();
+    Self { link, has_password }
+  }
+}
+
+pub async fn create_link(
+  body: web::Json<CreateShareLinkReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root.clone();
+  let user = &sess.get_user_data()?;
+  let body = body.into_inner();
+  // the guard only requires fs_read for share routes, anyone may upload through this link
+  if body.allow_upload == Some(true) {
+    api_token::require(&sess, Permission::FsWrite)?;
+  }
+  let link = share::create(
+    file_root,
+    user,
+    NewShareLink {
+      path: body.file,
+      password: body.password,
+      expires_at: body.expires_at,
+      max_downloads: body.max_downloads,
+      allow_upload: body.allow_upload.unwrap_or(false),
+    },
+  )
+  .await?;
+  Ok(create_resp(true, ShareLinkResp::from(link), "done"))
+}
+
+pub async fn list_links(sess: Session) -> Result<HttpResponse, AppError> {
+  let user = sess.get_user_data()?;
+  let links = share::list(&user.username)?
+    .into_iter()
+    .map(ShareLinkResp::from)
+    .collect::<Vec<_>>();
+  Ok(create_resp(true, links, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct DeleteShareLinkReq {
+  id: String,
+}
+
+pub async fn delete_link(
+  body: web::Json<DeleteShareLinkReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let user = sess.get_user_data()?;
+  if share::delete(&user.username, &body.id)? {
+    return Ok(create_resp(true, EmptyResponseData::new(), "done"));
+  }
+  Ok(create_resp(
+    false,
+    EmptyResponseData::new(),
+    "share link not found",
+  ))
+}
+
+pub fn share_routers() -> Scope {
+  web::scope("/share")
+    .route("/create", web::post().to(create_link))
+    .route("/list", web::post().to(list_links))
+    .route("/delete", web::post().to(delete_link))
+}
+
+#[derive(Deserialize)]
+pub struct PublicShareReq {
+  /// path relative to the shared folder
+  file: Option<String>,
+  password: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PublicShareInfo {
+  name: String,
+  is_dir: bool,
+  size: u64,
+  modified: u128,
+  allow_upload: bool,
+  expires_at: Option<i64>,
+}
+
+/// open the link in path with the password in `x-share-password` header or query,
+/// returns the link, a session of its owner and the requested path in owner root
+async fn open_link(
+  id: &str,
+  query: &PublicShareReq,
+  req: &HttpRequest,
+) -> Result<(ShareLink, UserSessionData, String), AppError> {
+  let pwd = req
+    .headers()
+    .get("x-share-password")
+    .and_then(|v| v.to_str().ok())
+    .or(query.password.as_deref());
+  let (link, owner) = share::open(id, pwd, &client_ip(req)).await?;
+  let file = share::file_in_link(&link, query.file.as_deref().unwrap_or(""))?;
+  Ok((link, owner, file))
+}
+
+pub async fn share_info(
+  path: web::Path<(String,)>,
+  query: web::Query<PublicShareReq>,
+  req: HttpRequest,
+  state: web::Data<AppData>,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root.clone();
+  let (link, owner, file) = open_link(&path.into_inner().0, &query, &req).await?;
+  let file_stat = vfs::stat(file_root, &owner, &file).await?;
+  let name = Path::new(&link.path)
+    .file_name()
+    .map_or(String::new(), |n| n.to_string_lossy().to_string());
+  let info = PublicShareInfo {
+    name,
+    is_dir: file_stat.is_dir,
+    size: file_stat.size,
+    modified: file_stat.modified,
+    allow_upload: link.allow_upload,
+    expires_at: link.expires_at,
+  };
+  Ok(create_resp(true, info, "done"))
+}
+
+pub async fn share_list(
+  path: web::Path<(String,)>,
+  query: web::Query<PublicShareReq>,
+  req: HttpRequest,
+  state: web::Data<AppData>,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root.clone();
+  let (_, owner, file) = open_link(&path.into_inner().0, &query, &req).await?;
+  let files: Vec<FileStatWithName> = vfs::read_dir(file_root, &owner, &file).await?;
+  Ok(create_resp(true, files, "done"))
+}
+
+pub async fn share_download(
+  path: web::Path<(String,)>,
+  query: web::Query<PublicShareReq>,
+  req: HttpRequest,
+  state: web::Data<AppData>,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root.clone();
+  let (link, owner, file) = open_link(&path.into_inner().0, &query, &req).await?;
+  let file_stat = vfs::stat(file_root, &owner, &file).await?;
+  let name = Path::new(&file)
+    .file_name()
+    .map_or("download".to_owned(), |n| n.to_string_lossy().to_string());
+
+  if file_stat.is_dir {
+    share::count_download(&link)?;
+    let stream = vfs::read_to_zip_stream(file_root, &owner, &file).await?;
+    return Ok(create_unsized_stream_resp(
+      stream,
+      Some("application/zip".to_string()),
+      Some(&(name + ".zip")),
+    ));
+  }
+
+  // resumed downloads send a range not starting at 0 and are not counted again
+  let (range_start, _, _) = parse_range(req.headers(), file_stat.size.max(1))?;
+  if range_start == 0 {
+    share::count_download(&link)?;
+  }
+  read_file_resp(file_root, &owner, &file, req.headers(), Some(&name), None).await
+}
+
+pub async fn share_upload(
+  path: web::Path<(String,)>,
+  query: web::Query<PublicShareReq>,
+  req: HttpRequest,
+  parts: awmp::Parts,
+  state: web::Data<AppData>,
+) -> Result<HttpResponse, AppError> {
+  let file_root = &state.read().unwrap().config.file_root.clone();
+  let (link, owner, dir) = open_link(&path.into_inner().0, &query, &req).await?;
+  if !link.allow_upload {
+    return Err(
+      AppError::new("share link: upload is not allowed").with_status(StatusCode::FORBIDDEN),
+    );
+  }
+  let files = parts.files.into_inner();
+  for (_, file) in files {
+    if let Ok(file) = file {
+      let filename = file.sanitized_file_name().to_owned();
+      let dest = PathBuf::from(&dir).join(filename);
+      let dest = dest.to_string_lossy();
+      // visitors may add files but never replace files of the owner
+      if vfs::stat(file_root, &owner, &dest).await.is_ok() {
+        return Err(
+          AppError::new(&format!("share link: file exists: {dest}"))
+            .with_status(StatusCode::CONFLICT),
+        );
+      }
+      save_upload(file_root, &owner, &dest, file).await?;
+    }
+  }
+  Ok(create_resp(
+    true,
+    EmptyResponseData::new(),
+    "upload file successfully",
+  ))
+}
+
+/// routes of share links, served without login
+pub fn public_share_routers() -> Scope {
+  web::scope("/s")
+    .route("/{id}", web::get().to(share_info))
+    .route("/{id}/list", web::get().to(share_list))
+    .route("/{id}/download", web::get().to(share_download))
+    .route("/{id}/upload", web::post().to(share_upload))
+}
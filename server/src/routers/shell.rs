@@ -13,7 +13,7 @@ use actix_web_actors::ws;
 use ptyprocess::PtyProcess;
 use serde::{Deserialize, Serialize};
 
-use crate::{config, utils::error::AppError};
+use crate::utils::{dynamic_config, error::AppError};
 
 /// Define HTTP actor
 struct MyWs {
@@ -99,7 +99,7 @@ impl<T: Serialize> ToString for ShellErrorMsg<T> {
 }
 
 fn find_shell() -> Result<String, AppError> {
-  let default_shell = config!(shell);
+  let default_shell = dynamic_config::shell();
   if let Ok(shell) = which::which(default_shell) {
     return Ok(shell.to_string_lossy().to_string());
   }
@@ -10,7 +10,7 @@ use actix_web_actors::ws;
 use serde::Deserialize;
 use std::ffi::OsString;
 
-use crate::{utils::error::AppError, config};
+use crate::utils::{error::AppError, dynamic_config};
 
 /// Define HTTP actor
 struct MyWs {
@@ -74,7 +74,7 @@ struct SetTTYSizePayload {
 }
 
 fn find_shell() -> Result<OsString, AppError> {
-  let default_shell = config!(shell);
+  let default_shell = dynamic_config::shell();
   if let Ok(shell) = which::which(default_shell) {
     return Ok(OsString::from_str(shell.to_str().unwrap()).unwrap());
   }
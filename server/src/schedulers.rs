@@ -1 +1,9 @@
-pub mod update_file_index;
\ No newline at end of file
+pub mod update_file_index;
+pub mod purge_trash;
+pub mod purge_versions;
+pub mod purge_sessions;
+pub mod purge_uploads;
+pub mod ldap_sync;
+pub mod send_mail;
+pub mod reload_cert;
+pub mod fs_watcher;
@@ -0,0 +1,193 @@
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use lazy_static::lazy_static;
+use notify::{
+  event::{AccessKind, AccessMode},
+  Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use std::{
+  collections::HashSet,
+  env,
+  path::PathBuf,
+  sync::{Arc, Mutex},
+  thread,
+  time::{Duration, Instant},
+};
+use tracing::{error, info, warn};
+use walkdir::WalkDir;
+
+use crate::{
+  config, conv_err,
+  utils::error::AppError,
+};
+
+use super::update_file_index::{is_unindexed_dir, UpdateGalleryJob, JOB_UPDATE_GALLERY};
+
+lazy_static! {
+  pub static ref JOB_FS_WATCHER: Arc<Mutex<FsWatcherJob>> =
+    Arc::new(Mutex::new(FsWatcherJob::new()));
+}
+
+conv_err!(notify::Error);
+
+/// Watches `file_root` with inotify (or the native api of other platforms) and applies
+/// debounced, incremental updates to file index, so files changed outside of the api
+/// are searchable without a full walk. A full walk is only run when events may be lost:
+/// on start and when the kernel event queue overflows.
+pub struct FsWatcherJob {
+  watcher: Option<RecommendedWatcher>,
+}
+
+/// changes collected during a debounce window
+#[derive(Default)]
+struct Changes {
+  paths: HashSet<PathBuf>,
+  rescan: bool,
+}
+
+impl FsWatcherJob {
+  pub fn new() -> Self {
+    Self { watcher: None }
+  }
+
+  /// stop watching, the worker thread exits once the watcher is dropped
+  #[allow(unused)]
+  pub fn stop(&mut self) {
+    self.watcher.take();
+  }
+
+  pub fn init(&mut self, file_root: &PathBuf) -> Result<(), AppError> {
+    self.stop();
+    if !config!(fs_watcher) {
+      return Ok(());
+    }
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let mut watcher = RecommendedWatcher::new(
+      move |ev: notify::Result<Event>| {
+        let _ = tx.send(ev);
+      },
+      Config::default(),
+    )?;
+    watcher.watch(file_root, RecursiveMode::Recursive)?;
+    self.watcher = Some(watcher);
+
+    let file_root = file_root.clone();
+    thread::spawn(move || Self::run(rx, &file_root));
+    // changes made while the server was down are only found by a walk
+    JOB_UPDATE_GALLERY.lock().unwrap().update_immediate();
+    info!("watching file changes in {file_root:?}");
+    Ok(())
+  }
+
+  fn run(rx: Receiver<notify::Result<Event>>, file_root: &PathBuf) {
+    let debounce = Duration::from_millis(config!(fs_watcher_debounce_ms).max(0) as u64);
+    // a file written without pause must still be indexed from time to time
+    let max_wait = debounce * 10;
+    let ignored = Self::ignored_dirs();
+    loop {
+      let mut changes = Changes::default();
+      match rx.recv() {
+        Ok(ev) => Self::collect(&mut changes, ev, &ignored),
+        Err(_) => return,
+      }
+      let started = Instant::now();
+      while started.elapsed() < max_wait {
+        match rx.recv_timeout(debounce) {
+          Ok(ev) => Self::collect(&mut changes, ev, &ignored),
+          Err(RecvTimeoutError::Timeout) => break,
+          Err(RecvTimeoutError::Disconnected) => {
+            Self::apply(changes, file_root);
+            return;
+          }
+        }
+      }
+      Self::apply(changes, file_root);
+    }
+  }
+
+  /// temporary files of uploads are not indexed until they are moved to their destination
+  fn ignored_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    if let Ok(cwd) = env::current_dir() {
+      dirs.push(cwd.join(config!(upload_temp_dir)));
+      dirs.push(cwd.join(config!(search_index_path)));
+    }
+    dirs
+  }
+
+  fn collect(changes: &mut Changes, ev: notify::Result<Event>, ignored: &Vec<PathBuf>) {
+    let ev = match ev {
+      Ok(ev) => ev,
+      Err(err) => {
+        warn!("file watcher error: {err}");
+        changes.rescan = true;
+        return;
+      }
+    };
+    if ev.need_rescan() {
+      changes.rescan = true;
+    }
+    match ev.kind {
+      EventKind::Access(AccessKind::Close(AccessMode::Write)) => (),
+      EventKind::Access(_) => return,
+      _ => (),
+    }
+    for path in ev.paths {
+      let skip = ignored.iter().any(|dir| path.starts_with(dir))
+        || path.components().any(|c| is_unindexed_dir(c.as_os_str()));
+      if !skip {
+        changes.paths.insert(path);
+      }
+    }
+  }
+
+  fn apply(changes: Changes, file_root: &PathBuf) {
+    if changes.rescan {
+      warn!("file watcher lost events, rebuilding file index");
+      JOB_UPDATE_GALLERY.lock().unwrap().update_immediate();
+      return;
+    }
+    if changes.paths.is_empty() {
+      return;
+    }
+    Self::update(changes.paths, file_root).unwrap_or_else(|err| {
+      error!("update file index failed: {err}");
+    });
+  }
+
+  fn update(paths: HashSet<PathBuf>, file_root: &PathBuf) -> Result<(), AppError> {
+    let follow_link = config!(indexing_follow_link);
+    let mut updated = HashSet::new();
+    let mut deleted = vec![];
+    for path in paths {
+      let file = match path.strip_prefix(file_root) {
+        Ok(file) if file.components().next().is_some() => file.to_path_buf(),
+        _ => continue,
+      };
+      if !path.exists() {
+        deleted.push(file.to_string_lossy().to_string());
+        continue;
+      }
+      if path.is_dir() {
+        // a directory moved in comes with its whole content and a single event
+        let walker = WalkDir::new(&path)
+          .follow_links(follow_link)
+          .into_iter()
+          .filter_entry(|e| !is_unindexed_dir(e.file_name()));
+        for entry in walker {
+          let entry = entry?;
+          let file = entry.path().strip_prefix(file_root)?;
+          updated.insert(file.to_string_lossy().to_string());
+        }
+      } else {
+        updated.insert(file.to_string_lossy().to_string());
+      }
+    }
+    if !deleted.is_empty() {
+      UpdateGalleryJob::delete_file_indices_under(deleted)?;
+    }
+    if !updated.is_empty() {
+      UpdateGalleryJob::update_file_indices(updated.into_iter().collect(), file_root)?;
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,61 @@
+use clokwerk::{ScheduleHandle, Scheduler, TimeUnits};
+use lazy_static::lazy_static;
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+use tracing::{error, info};
+
+use crate::utils::{error::AppError, ldap};
+
+lazy_static! {
+  pub static ref JOB_LDAP_SYNC: Arc<Mutex<LdapSyncJob>> = Arc::new(Mutex::new(LdapSyncJob::new()));
+}
+
+/// keeps users linked to the directory in sync, disables the ones removed from it
+pub struct LdapSyncJob {
+  schedule_handle: Option<ScheduleHandle>,
+}
+
+impl LdapSyncJob {
+  pub fn new() -> Self {
+    Self {
+      schedule_handle: None,
+    }
+  }
+
+  #[allow(unused)]
+  pub fn stop(&mut self) {
+    if let Some(s) = self.schedule_handle.take() {
+      s.stop();
+    }
+  }
+
+  fn sync() -> Result<(), AppError> {
+    let r = ldap::sync()?;
+    info!(
+      "ldap sync: {} users updated, {} users disabled",
+      r.updated, r.disabled
+    );
+    Ok(())
+  }
+
+  pub fn init(&mut self) -> Result<(), AppError> {
+    self.stop();
+    if !ldap::is_enabled() {
+      return Ok(());
+    }
+    let minutes = ldap::config()?.sync_interval_minutes.unwrap_or(60);
+    if minutes == 0 {
+      return Ok(());
+    }
+    let mut scheduler = Scheduler::new();
+    scheduler.every(minutes.minutes()).run(|| {
+      Self::sync().unwrap_or_else(|err| {
+        error!("ldap sync failed: {err}");
+      });
+    });
+    self.schedule_handle = Some(scheduler.watch_thread(Duration::from_millis(1000)));
+    Ok(())
+  }
+}
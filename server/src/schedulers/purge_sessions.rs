@@ -0,0 +1,64 @@
+use clokwerk::{ScheduleHandle, Scheduler, TimeUnits};
+use lazy_static::lazy_static;
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+use tracing::{error, info};
+
+use crate::{
+  middlewares::session::purge_expired,
+  utils::{error::AppError, rate_limit},
+};
+
+lazy_static! {
+  pub static ref JOB_PURGE_SESSIONS: Arc<Mutex<PurgeSessionsJob>> =
+    Arc::new(Mutex::new(PurgeSessionsJob::new()));
+}
+
+/// removes expired login sessions from the session store and forgotten login failures
+pub struct PurgeSessionsJob {
+  schedule_handle: Option<ScheduleHandle>,
+}
+
+impl PurgeSessionsJob {
+  pub fn new() -> Self {
+    Self {
+      schedule_handle: None,
+    }
+  }
+
+  #[allow(unused)]
+  pub fn stop(&mut self) {
+    if let Some(s) = self.schedule_handle.take() {
+      s.stop();
+    }
+  }
+
+  fn purge() -> Result<(), AppError> {
+    let purged = purge_expired()?;
+    if purged > 0 {
+      info!("purged {purged} expired sessions");
+    }
+    let purged = rate_limit::purge_expired()?;
+    if purged > 0 {
+      info!("purged {purged} old login failures");
+    }
+    Ok(())
+  }
+
+  pub fn init(&mut self) -> Result<(), AppError> {
+    self.stop();
+    let mut scheduler = Scheduler::new();
+    let run = || {
+      Self::purge().unwrap_or_else(|err| {
+        error!("purge sessions failed: {err}");
+      });
+    };
+    // sessions expired while the server was down
+    run();
+    scheduler.every(1.hours()).run(run);
+    self.schedule_handle = Some(scheduler.watch_thread(Duration::from_millis(1000)));
+    Ok(())
+  }
+}
@@ -0,0 +1,57 @@
+use clokwerk::{ScheduleHandle, Scheduler, TimeUnits};
+use lazy_static::lazy_static;
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+use tracing::{error, info};
+
+use crate::utils::{error::AppError, upload::purge_stale};
+
+lazy_static! {
+  pub static ref JOB_PURGE_UPLOADS: Arc<Mutex<PurgeUploadsJob>> =
+    Arc::new(Mutex::new(PurgeUploadsJob::new()));
+}
+
+/// removes abandoned resumable uploads from `upload_temp_dir`
+pub struct PurgeUploadsJob {
+  schedule_handle: Option<ScheduleHandle>,
+}
+
+impl PurgeUploadsJob {
+  pub fn new() -> Self {
+    Self {
+      schedule_handle: None,
+    }
+  }
+
+  #[allow(unused)]
+  pub fn stop(&mut self) {
+    if let Some(s) = self.schedule_handle.take() {
+      s.stop();
+    }
+  }
+
+  fn purge() -> Result<(), AppError> {
+    let purged = purge_stale()?;
+    if purged > 0 {
+      info!("purged {purged} abandoned uploads");
+    }
+    Ok(())
+  }
+
+  pub fn init(&mut self) -> Result<(), AppError> {
+    self.stop();
+    let mut scheduler = Scheduler::new();
+    let run = || {
+      Self::purge().unwrap_or_else(|err| {
+        error!("purge uploads failed: {err}");
+      });
+    };
+    // uploads abandoned while the server was down
+    run();
+    scheduler.every(1.hours()).run(run);
+    self.schedule_handle = Some(scheduler.watch_thread(Duration::from_millis(1000)));
+    Ok(())
+  }
+}
@@ -0,0 +1,60 @@
+use clokwerk::{ScheduleHandle, Scheduler, TimeUnits};
+use lazy_static::lazy_static;
+use std::{
+  path::PathBuf,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+use tracing::{error, info};
+
+use crate::utils::{error::AppError, versions::purge_expired};
+
+lazy_static! {
+  pub static ref JOB_PURGE_VERSIONS: Arc<Mutex<PurgeVersionsJob>> =
+    Arc::new(Mutex::new(PurgeVersionsJob::new()));
+}
+
+/// removes file versions older than `keep_days` of their versioning policy
+pub struct PurgeVersionsJob {
+  schedule_handle: Option<ScheduleHandle>,
+}
+
+impl PurgeVersionsJob {
+  pub fn new() -> Self {
+    Self {
+      schedule_handle: None,
+    }
+  }
+
+  #[allow(unused)]
+  pub fn stop(&mut self) {
+    if let Some(s) = self.schedule_handle.take() {
+      s.stop();
+    }
+  }
+
+  fn purge(file_root: &PathBuf) -> Result<(), AppError> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+      .enable_all()
+      .build()?;
+    let purged = rt.block_on(purge_expired(file_root))?;
+    if purged > 0 {
+      info!("purged {purged} file versions");
+    }
+    Ok(())
+  }
+
+  pub fn init(&mut self, file_root: &PathBuf) -> Result<(), AppError> {
+    self.stop();
+    let mut scheduler = Scheduler::new();
+    let file_root = file_root.clone();
+    let run = move || {
+      Self::purge(&file_root).unwrap_or_else(|err| {
+        error!("purge file versions failed: {err}");
+      });
+    };
+    scheduler.every(1.hours()).run(run);
+    self.schedule_handle = Some(scheduler.watch_thread(Duration::from_millis(1000)));
+    Ok(())
+  }
+}
@@ -0,0 +1,52 @@
+use clokwerk::{ScheduleHandle, Scheduler, TimeUnits};
+use lazy_static::lazy_static;
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+use tracing::{error, info};
+
+use crate::utils::{error::AppError, tls};
+
+lazy_static! {
+  pub static ref JOB_RELOAD_CERT: Arc<Mutex<ReloadCertJob>> =
+    Arc::new(Mutex::new(ReloadCertJob::new()));
+}
+
+/// picks up a renewed tls certificate, the files are checked instead of watched since
+/// they are often symlinks into another directory, e.g. with certbot
+pub struct ReloadCertJob {
+  schedule_handle: Option<ScheduleHandle>,
+}
+
+impl ReloadCertJob {
+  pub fn new() -> Self {
+    Self {
+      schedule_handle: None,
+    }
+  }
+
+  #[allow(unused)]
+  pub fn stop(&mut self) {
+    if let Some(s) = self.schedule_handle.take() {
+      s.stop();
+    }
+  }
+
+  pub fn init(&mut self) -> Result<(), AppError> {
+    self.stop();
+    if !tls::is_enabled() {
+      return Ok(());
+    }
+    let mut scheduler = Scheduler::new();
+    scheduler
+      .every(10.seconds())
+      .run(|| match tls::reload_if_changed() {
+        Ok(true) => info!("tls certificate reloaded"),
+        Ok(false) => {}
+        Err(err) => error!("reload tls certificate failed, the old one stays in use: {err}"),
+      });
+    self.schedule_handle = Some(scheduler.watch_thread(Duration::from_millis(1000)));
+    Ok(())
+  }
+}
@@ -0,0 +1,53 @@
+use clokwerk::{ScheduleHandle, Scheduler, TimeUnits};
+use lazy_static::lazy_static;
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+use tracing::{error, info};
+
+use crate::utils::{error::AppError, mail};
+
+lazy_static! {
+  pub static ref JOB_SEND_MAIL: Arc<Mutex<SendMailJob>> = Arc::new(Mutex::new(SendMailJob::new()));
+}
+
+/// delivers queued mails, failed ones are retried later
+pub struct SendMailJob {
+  schedule_handle: Option<ScheduleHandle>,
+}
+
+impl SendMailJob {
+  pub fn new() -> Self {
+    Self {
+      schedule_handle: None,
+    }
+  }
+
+  #[allow(unused)]
+  pub fn stop(&mut self) {
+    if let Some(s) = self.schedule_handle.take() {
+      s.stop();
+    }
+  }
+
+  fn send() -> Result<(), AppError> {
+    let (sent, failed) = mail::send_due()?;
+    if sent > 0 || failed > 0 {
+      info!("mail queue: {sent} mails sent, {failed} failed");
+    }
+    Ok(())
+  }
+
+  pub fn init(&mut self) -> Result<(), AppError> {
+    self.stop();
+    let mut scheduler = Scheduler::new();
+    scheduler.every(10.seconds()).run(|| {
+      Self::send().unwrap_or_else(|err| {
+        error!("send mail failed: {err}");
+      });
+    });
+    self.schedule_handle = Some(scheduler.watch_thread(Duration::from_millis(1000)));
+    Ok(())
+  }
+}
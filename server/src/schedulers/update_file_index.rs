@@ -17,11 +17,21 @@ use crate::{
   models::{FileIndex, NewFileIndex},
   utils::{
     doc_parser::try_parse_sync,
+    dynamic_config,
     error::AppError,
+    quota,
     search_engine::{self, insert_docs, Doc},
+    trash::TRASH_DIR,
+    versions::VERSIONS_DIR,
   }, conv_err,
 };
 
+/// stored versions are counted by `versions::usage` and deleted files wait in trash,
+/// both are kept out of the index and search
+pub fn is_unindexed_dir(name: &std::ffi::OsStr) -> bool {
+  name == VERSIONS_DIR || name == TRASH_DIR
+}
+
 lazy_static! {
   pub static ref JOB_UPDATE_GALLERY: Arc<Mutex<UpdateGalleryJob>> =
     Arc::new(Mutex::new(UpdateGalleryJob::new()));
@@ -52,7 +62,6 @@ impl UpdateGalleryJob {
     }
   }
 
-  #[allow(unused)]
   pub fn set_file_root(&mut self, file_root: &PathBuf) {
     self.file_root = Some(file_root.clone());
   }
@@ -99,10 +108,51 @@ impl UpdateGalleryJob {
       .duration_since(UNIX_EPOCH)?
       .as_millis()
       .to_string();
+    // entries of changed files are replaced instead of being added next to the old ones
+    Self::remove_entries(&files)?;
     Self::insert_files_into_db(files, now, file_root).unwrap();
     Ok(())
   }
 
+  fn remove_entries(files: &Vec<String>) -> Result<(), AppError> {
+    use crate::schema::file_index::dsl::*;
+    use crate::schema::file_index::table;
+    use diesel::prelude::*;
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    let conn = &mut *conn;
+    let indexed = file_index
+      .filter(file_path.eq_any(files))
+      .select(file_path)
+      .load::<String>(conn)?;
+    if indexed.is_empty() {
+      return Ok(());
+    }
+    diesel::delete(table.filter(file_path.eq_any(&indexed))).execute(conn)?;
+    search_engine::delete_paths(&indexed)?;
+    Ok(())
+  }
+
+  /// remove entries of deleted files, and of their children for directories
+  pub fn delete_file_indices_under(files: Vec<String>) -> Result<(), AppError> {
+    use crate::schema::file_index::dsl::*;
+    use crate::schema::file_index::table;
+    use diesel::prelude::*;
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    let conn = &mut *conn;
+    for f in files.iter() {
+      let children = format!(
+        "{}/%",
+        f.replace('\\', "\\\\")
+          .replace('%', "\\%")
+          .replace('_', "\\_")
+      );
+      diesel::delete(table.filter(file_path.eq(f).or(file_path.like(children).escape('\\'))))
+        .execute(conn)?;
+    }
+    search_engine::delete_paths(&files)?;
+    Ok(())
+  }
+
   fn insert_files_into_db(
     images: Vec<String>,
     now: String,
@@ -112,6 +162,8 @@ impl UpdateGalleryJob {
     use crate::schema::file_index::table;
     use diesel::prelude::*;
 
+    // entries belong to the user whose root holds them, storage info is reported per user
+    let roots = quota::user_roots()?;
     let mut conn = SHARED_DB_CONN.lock().unwrap();
     let conn = &mut *conn;
     let exists = file_index
@@ -170,7 +222,7 @@ impl UpdateGalleryJob {
         file_path: f,
         size: meta.len() as i64,
         format: Some(mime_joined),
-        username: "".to_owned(),
+        username: quota::find_owner(&roots, &f).map_or(String::new(), |(name, _)| name.clone()),
         created_at: created_at_,
         modified_at: modified_at_,
         updated_at: now.clone(),
@@ -197,6 +249,15 @@ impl UpdateGalleryJob {
     });
   }
 
+  /// update on the current thread, used by the `reindex` command
+  pub fn update_blocking(&self) -> Result<(), AppError> {
+    let file_root = self
+      .file_root
+      .clone()
+      .ok_or_else(|| AppError::new("file root is not set"))?;
+    Self::update(self.status.clone(), &file_root)
+  }
+
   fn update(status: Arc<RwLock<JobStatus>>, file_root: &PathBuf) -> Result<(), AppError> {
     let mut status_lock = status.write().unwrap();
     match *status_lock {
@@ -215,7 +276,11 @@ impl UpdateGalleryJob {
       .to_string();
     let mut images = vec![];
     let follow_link = config!(indexing_follow_link);
-    for entry in WalkDir::new(&file_root).follow_links(follow_link) {
+    let walker = WalkDir::new(&file_root)
+      .follow_links(follow_link)
+      .into_iter()
+      .filter_entry(|e| !is_unindexed_dir(e.file_name()));
+    for entry in walker {
       let entry = entry?;
       let dir = entry.path().strip_prefix(file_root.clone())?;
       images.push(dir.to_string_lossy().to_string());
@@ -244,6 +309,18 @@ impl UpdateGalleryJob {
     Ok(())
   }
 
+  /// schedule the daily update at `index_update_time` of the dynamic config,
+  /// called again whenever it changes
+  pub fn apply_schedule(&mut self) -> Result<(), AppError> {
+    match dynamic_config::index_update_time() {
+      Some(at_time) => self.init(at_time),
+      None => {
+        self.stop();
+        Ok(())
+      }
+    }
+  }
+
   #[allow(unused)]
   pub fn init(&mut self, at_time: NaiveTime) -> Result<(), AppError> {
     self.stop();
@@ -1,5 +1,50 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    acls (id) {
+        id -> Text,
+        owner -> Text,
+        path -> Text,
+        username -> Nullable<Text>,
+        group_name -> Nullable<Text>,
+        read_only -> Bool,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    api_tokens (id) {
+        id -> Text,
+        username -> Text,
+        name -> Text,
+        token_hash -> Text,
+        scopes -> Text,
+        expires_at -> Nullable<BigInt>,
+        created_at -> BigInt,
+        last_used_at -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    dynamic_config (key) {
+        key -> Text,
+        value -> Text,
+        updated_at -> BigInt,
+        updated_by -> Text,
+    }
+}
+
+diesel::table! {
+    email_tokens (id) {
+        id -> Text,
+        username -> Text,
+        purpose -> Text,
+        email -> Text,
+        expires_at -> BigInt,
+        created_at -> BigInt,
+    }
+}
+
 diesel::table! {
     file_index (file_path, updated_at) {
         file_name -> Text,
@@ -14,11 +59,23 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    file_versions (id) {
+        id -> Text,
+        username -> Text,
+        path -> Text,
+        hash -> Text,
+        size -> BigInt,
+        created_at -> BigInt,
+    }
+}
+
 diesel::table! {
     groups (name) {
         name -> Text,
         desc -> Text,
         permissions -> Text,
+        quota -> Nullable<BigInt>,
     }
 }
 
@@ -32,6 +89,100 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    login_failures (id) {
+        id -> Text,
+        action -> Text,
+        username -> Nullable<Text>,
+        ip -> Text,
+        reason -> Text,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    mail_queue (id) {
+        id -> Text,
+        recipient -> Text,
+        subject -> Text,
+        body -> Text,
+        attempts -> Integer,
+        next_attempt_at -> BigInt,
+        last_error -> Nullable<Text>,
+        created_at -> BigInt,
+        sent_at -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    mounts (username, prefix) {
+        username -> Text,
+        prefix -> Text,
+        target -> Text,
+        read_only -> Bool,
+    }
+}
+
+diesel::table! {
+    oidc_identities (issuer, subject) {
+        issuer -> Text,
+        subject -> Text,
+        username -> Text,
+        email -> Nullable<Text>,
+        created_at -> BigInt,
+        last_login -> BigInt,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Text,
+        state -> Text,
+        expires_at -> BigInt,
+        username -> Nullable<Text>,
+        device_id -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        ip -> Nullable<Text>,
+        created_at -> Nullable<BigInt>,
+        last_seen -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    share_links (id) {
+        id -> Text,
+        username -> Text,
+        path -> Text,
+        password -> Nullable<Text>,
+        expires_at -> Nullable<BigInt>,
+        max_downloads -> Nullable<Integer>,
+        download_count -> Integer,
+        allow_upload -> Bool,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    storage_usage (username) {
+        username -> Text,
+        used -> BigInt,
+        updated_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    trash (id) {
+        id -> Text,
+        username -> Text,
+        original_path -> Text,
+        deleted_at -> BigInt,
+        is_dir -> Bool,
+        size -> BigInt,
+        mount -> Nullable<Text>,
+        indexed_path -> Nullable<Text>,
+    }
+}
+
 diesel::table! {
     users (username) {
         username -> Text,
@@ -42,14 +193,54 @@ diesel::table! {
         group_name -> Text,
         otp_secret -> Nullable<Text>,
         web_authn_id -> Nullable<Text>,
+        quota -> Nullable<BigInt>,
+        must_change_password -> Bool,
+        disabled -> Bool,
+        ldap_dn -> Nullable<Text>,
+        email_verified -> Bool,
     }
 }
 
 diesel::joinable!(users -> groups (group_name));
 
+diesel::table! {
+    versioning_policies (username, dir) {
+        username -> Text,
+        dir -> Text,
+        keep_versions -> Nullable<Integer>,
+        keep_days -> Nullable<Integer>,
+    }
+}
+
+diesel::table! {
+    web_authn_credentials (id) {
+        id -> Text,
+        username -> Text,
+        name -> Text,
+        passkey -> Text,
+        created_at -> BigInt,
+        last_used_at -> Nullable<BigInt>,
+    }
+}
+
 diesel::allow_tables_to_appear_in_same_query!(
+    acls,
+    api_tokens,
+    dynamic_config,
+    email_tokens,
     file_index,
+    file_versions,
     groups,
     kv_storage,
+    login_failures,
+    mail_queue,
+    mounts,
+    oidc_identities,
+    sessions,
+    share_links,
+    storage_usage,
+    trash,
     users,
+    versioning_policies,
+    web_authn_credentials,
 );
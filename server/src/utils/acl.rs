@@ -0,0 +1,471 @@
+/// Access control lists of shared folders
+///
+/// An owner grants a user, or every user of a group, read-only or read-write access to a
+/// directory in its root. Granted directories show up for the grantee under
+/// `Shared with me/<owner>/<directory name>`, and `storage::resolve` maps paths in there
+/// to the root of the owner, so every `vfs` call honours the grant.
+use std::{
+  collections::BTreeSet,
+  path::{Component, PathBuf},
+  sync::RwLock,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::http::StatusCode;
+use lazy_static::lazy_static;
+
+use crate::{db::SHARED_DB_CONN, models::Acl, UserSessionData};
+
+use super::error::AppError;
+use super::path::secure_join;
+use super::storage::{resolve, ResolvedPath, SharedOrigin};
+use super::trash::TRASH_DIR;
+use super::versions::VERSIONS_DIR;
+use super::vfs::{self, FileStat, FileStatWithName};
+
+pub const SHARED_DIR: &str = "Shared with me";
+
+lazy_static! {
+  /// every acl, loaded from database on first use
+  static ref ACL_TABLE: RwLock<Option<Vec<Acl>>> = RwLock::new(None);
+}
+
+fn now_secs() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn all() -> Result<Vec<Acl>, AppError> {
+  if let Some(cached) = ACL_TABLE.read().unwrap().as_ref() {
+    return Ok(cached.clone());
+  }
+  // loaded under the write lock, rows read before an invalidation can not be cached after it
+  let mut table = ACL_TABLE.write().unwrap();
+  if let Some(cached) = table.as_ref() {
+    return Ok(cached.clone());
+  }
+  use crate::schema::acls::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let result = acls.order(created_at.asc()).load::<Acl>(&mut *conn)?;
+  *table = Some(result.clone());
+  Ok(result)
+}
+
+/// must be called with the database unlocked, `all` locks the table before the database
+fn invalidate() {
+  ACL_TABLE.write().unwrap().take();
+}
+
+/// root and group of a user
+fn find_user(name: &str) -> Result<Option<(String, String)>, AppError> {
+  use crate::schema::users::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = users
+    .filter(username.eq(name))
+    .select((user_root, group_name))
+    .first::<(String, String)>(&mut *conn)
+    .optional()?;
+  Ok(r)
+}
+
+/// name of a granted directory in `Shared with me/<owner>`
+pub fn share_name(acl: &Acl) -> String {
+  PathBuf::from(&acl.path)
+    .file_name()
+    .map_or(String::new(), |name| name.to_string_lossy().to_string())
+}
+
+/// acls granted to `user`, the ones granted to the user come before the ones granted to its group
+pub fn grants_for(user: &str) -> Result<Vec<Acl>, AppError> {
+  let group = find_user(user)?.map(|(_, group)| group);
+  let mut r = all()?
+    .into_iter()
+    .filter(|acl| {
+      acl.owner != user
+        && (acl.username.as_deref() == Some(user)
+          || (acl.group_name.is_some() && acl.group_name == group))
+    })
+    .collect::<Vec<_>>();
+  r.sort_by_key(|acl| acl.username.is_none());
+  Ok(r)
+}
+
+pub fn list_owned(owner: &str) -> Result<Vec<Acl>, AppError> {
+  Ok(
+    all()?
+      .into_iter()
+      .filter(|acl| acl.owner == owner)
+      .collect(),
+  )
+}
+
+pub struct NewAcl {
+  pub path: String,
+  pub username: Option<String>,
+  pub group_name: Option<String>,
+  pub read_only: bool,
+}
+
+fn bad_request(msg: &str) -> AppError {
+  AppError::new(msg).with_status(StatusCode::BAD_REQUEST)
+}
+
+/// grant access to `new.path` in the root of `user`, a grant to the same user or group is updated
+pub async fn grant(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  new: NewAcl,
+) -> Result<Acl, AppError> {
+  let file = secure_join(&PathBuf::new(), &PathBuf::from(&new.path))?;
+  match file.components().next() {
+    None => return Err(bad_request("can not share user root")),
+    Some(Component::Normal(first))
+      if first == SHARED_DIR || first == TRASH_DIR || first == VERSIONS_DIR =>
+    {
+      return Err(bad_request(&format!("can not share {}", new.path)))
+    }
+    _ => (),
+  }
+  if !vfs::stat(file_root, user, &new.path).await?.is_dir {
+    return Err(bad_request("only directories can be shared"));
+  }
+  match (&new.username, &new.group_name) {
+    (Some(name), None) => {
+      if name == &user.username {
+        return Err(bad_request("can not share with yourself"));
+      }
+      if find_user(name)?.is_none() {
+        return Err(AppError::new("user not found").with_status(StatusCode::NOT_FOUND));
+      }
+    }
+    (None, Some(group)) => {
+      use crate::schema::groups::dsl::*;
+      use diesel::prelude::*;
+      let mut conn = SHARED_DB_CONN.lock().unwrap();
+      let found = groups
+        .filter(name.eq(group))
+        .count()
+        .get_result::<i64>(&mut *conn)?;
+      if found == 0 {
+        return Err(AppError::new("group not found").with_status(StatusCode::NOT_FOUND));
+      }
+    }
+    _ => return Err(bad_request("share with either a user or a group")),
+  }
+
+  let owned = list_owned(&user.username)?;
+  let acl = Acl {
+    id: uuid::Uuid::new_v4().to_string(),
+    owner: user.username.clone(),
+    path: file.to_string_lossy().to_string(),
+    username: new.username,
+    group_name: new.group_name,
+    read_only: new.read_only,
+    created_at: now_secs(),
+  };
+  // grantees find shared directories by name
+  if owned
+    .iter()
+    .any(|a| a.path != acl.path && share_name(a) == share_name(&acl))
+  {
+    return Err(
+      AppError::new(&format!(
+        "another directory named {} is already shared",
+        share_name(&acl)
+      ))
+      .with_status(StatusCode::CONFLICT),
+    );
+  }
+  let existing = owned
+    .into_iter()
+    .find(|a| a.path == acl.path && a.username == acl.username && a.group_name == acl.group_name);
+  let acl = save(acl, existing)?;
+  invalidate();
+  Ok(acl)
+}
+
+fn save(acl: Acl, existing: Option<Acl>) -> Result<Acl, AppError> {
+  use crate::schema::acls::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  match existing {
+    Some(mut existing) => {
+      diesel::update(acls.filter(id.eq(&existing.id)))
+        .set(read_only.eq(acl.read_only))
+        .execute(&mut *conn)?;
+      existing.read_only = acl.read_only;
+      Ok(existing)
+    }
+    None => {
+      diesel::insert_into(acls).values(&acl).execute(&mut *conn)?;
+      Ok(acl)
+    }
+  }
+}
+
+pub fn revoke(user: &str, acl_id: &str) -> Result<bool, AppError> {
+  use crate::schema::acls::dsl::*;
+  use diesel::prelude::*;
+  let r = {
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    diesel::delete(acls.filter(owner.eq(user).and(id.eq(acl_id)))).execute(&mut *conn)?
+  };
+  invalidate();
+  Ok(r > 0)
+}
+
+/// remove acls of a user, as owner and as grantee
+pub fn remove_user(user: &str) -> Result<(), AppError> {
+  use crate::schema::acls::dsl::*;
+  use diesel::prelude::*;
+  {
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    diesel::delete(acls.filter(owner.eq(user).or(username.eq(user)))).execute(&mut *conn)?;
+  }
+  invalidate();
+  Ok(())
+}
+
+/// `Shared with me` and `Shared with me/<owner>` only exist in listings
+pub fn is_virtual(file: &PathBuf) -> bool {
+  let mut components = file.components();
+  matches!(components.next(), Some(Component::Normal(first)) if first == SHARED_DIR)
+    && components.count() < 2
+}
+
+fn virtual_stat() -> FileStat {
+  FileStat {
+    is_dir: true,
+    is_file: false,
+    file_type: "".to_string(),
+    size: 0,
+    created: 0,
+    modified: 0,
+    accessed: 0,
+  }
+}
+
+/// stat of a virtual directory, None for other paths
+pub fn stat_virtual(user: &UserSessionData, file: &PathBuf) -> Result<Option<FileStat>, AppError> {
+  if !is_virtual(file) {
+    return Ok(None);
+  }
+  let grants = grants_for(&user.username)?;
+  let owner = file.components().nth(1);
+  let found = match owner {
+    None => true,
+    Some(owner) => grants
+      .iter()
+      .any(|acl| owner.as_os_str() == acl.owner.as_str()),
+  };
+  if !found {
+    return Err(super::storage::not_found(file));
+  }
+  Ok(Some(virtual_stat()))
+}
+
+/// entries of a virtual directory, None for other paths
+pub async fn read_virtual_dir(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  dir: &PathBuf,
+) -> Result<Option<Vec<FileStatWithName>>, AppError> {
+  if stat_virtual(user, dir)?.is_none() {
+    return Ok(None);
+  }
+  let grants = grants_for(&user.username)?;
+  let mut names = BTreeSet::new();
+  let mut files = vec![];
+  match dir.components().nth(1) {
+    None => {
+      for acl in grants {
+        if names.insert(acl.owner.clone()) {
+          files.push(FileStatWithName::new(&virtual_stat(), &acl.owner));
+        }
+      }
+    }
+    Some(owner) => {
+      for acl in grants {
+        let name = share_name(&acl);
+        if owner.as_os_str() != acl.owner.as_str() || !names.insert(name.clone()) {
+          continue;
+        }
+        let file = dir.join(&name).to_string_lossy().to_string();
+        // the owner may have removed the directory
+        if let Ok(file_stat) = vfs::stat(file_root, user, &file).await {
+          files.push(FileStatWithName::new(&file_stat, &name));
+        }
+      }
+    }
+  }
+  Ok(Some(files))
+}
+
+/// resolve a path under `Shared with me/<owner>/<directory name>` in the root of the owner,
+/// None for other paths
+pub fn resolve_shared(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &PathBuf,
+) -> Result<Option<ResolvedPath>, AppError> {
+  let mut components = file.components();
+  match components.next() {
+    Some(Component::Normal(first)) if first == SHARED_DIR => (),
+    _ => return Ok(None),
+  }
+  let (owner, name) = match (components.next(), components.next()) {
+    (Some(owner), Some(name)) => (owner.as_os_str(), name.as_os_str()),
+    _ => {
+      return Err(
+        AppError::new(&format!("{SHARED_DIR} is a virtual directory"))
+          .with_status(StatusCode::FORBIDDEN),
+      )
+    }
+  };
+  let rest = components.as_path().to_path_buf();
+  let acl = grants_for(&user.username)?
+    .into_iter()
+    .find(|acl| owner == acl.owner.as_str() && name == share_name(acl).as_str())
+    .ok_or_else(|| super::storage::not_found(file))?;
+  let (owner_root, _) = find_user(&acl.owner)?.ok_or_else(|| super::storage::not_found(file))?;
+  let owner = UserSessionData::new(&acl.owner, &owner_root);
+  let shared = PathBuf::from(&acl.path);
+  if matches!(shared.components().next(), Some(Component::Normal(first)) if first == SHARED_DIR) {
+    // shares of shares could loop
+    return Err(super::storage::not_found(file));
+  }
+  let owner_file = shared.join(&rest).to_string_lossy().to_string();
+  let target = resolve(file_root, &owner, &owner_file)?;
+  Ok(Some(ResolvedPath {
+    // the shared directory itself can not be renamed, replaced or deleted by grantees
+    read_only: acl.read_only || target.read_only || rest.components().next().is_none(),
+    mount: Some(
+      PathBuf::from(SHARED_DIR)
+        .join(owner.username)
+        .join(name)
+        .to_string_lossy()
+        .to_string(),
+    ),
+    shared: Some(SharedOrigin {
+      owner: acl.owner.clone(),
+      owner_root,
+      file: owner_file,
+    }),
+    ..target
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::models::NewUser;
+  use crate::utils::test_utils::{init_db, temp_dir};
+
+  fn add_user(name: &str, user_root: &PathBuf) -> UserSessionData {
+    use crate::schema::users::dsl::*;
+    use diesel::prelude::*;
+    let root = user_root.to_string_lossy().to_string();
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    diesel::insert_into(users)
+      .values(&NewUser {
+        username: name,
+        password: "",
+        email: "",
+        user_type: 1,
+        user_root: &root,
+        group_name: "test",
+        must_change_password: false,
+      })
+      .execute(&mut *conn)
+      .unwrap();
+    UserSessionData::new(name, &root)
+  }
+
+  /// `owner` shares `docs` with `grantee`, both roots are host paths outside of `file_root`
+  async fn setup(name: &str, read_only: bool) -> (PathBuf, PathBuf, UserSessionData, String) {
+    init_db();
+    let dir = temp_dir(name);
+    let owner_root = dir.join("owner");
+    std::fs::create_dir_all(owner_root.join("docs")).unwrap();
+    std::fs::write(owner_root.join("docs/a.txt"), "hello").unwrap();
+    let owner = add_user(&format!("{name}-owner"), &owner_root);
+    let grantee = add_user(&format!("{name}-grantee"), &dir.join("grantee"));
+    let file_root = dir.join("files");
+    grant(
+      &file_root,
+      &owner,
+      NewAcl {
+        path: "docs".to_owned(),
+        username: Some(grantee.username.clone()),
+        group_name: None,
+        read_only,
+      },
+    )
+    .await
+    .unwrap();
+    let shared = format!("{SHARED_DIR}/{}/docs", owner.username);
+    (file_root, owner_root, grantee, shared)
+  }
+
+  #[tokio::test]
+  async fn read_only_grant_refuses_writes() {
+    let (file_root, owner_root, grantee, shared) = setup("acl-read-only", true).await;
+    let a = format!("{shared}/a.txt");
+    assert_eq!(
+      vfs::read_to_end(&file_root, &grantee, &a).await.unwrap(),
+      b"hello"
+    );
+
+    let new_file = format!("{shared}/new.txt");
+    let err = vfs::create(&file_root, &grantee, &new_file, b"new".to_vec())
+      .await
+      .unwrap_err();
+    assert_eq!(err.status_code, StatusCode::FORBIDDEN);
+    let err = vfs::delete(&file_root, &grantee, &a).await.unwrap_err();
+    assert_eq!(err.status_code, StatusCode::FORBIDDEN);
+    let err = vfs::move_file(&file_root, &grantee, &a, &new_file)
+      .await
+      .unwrap_err();
+    assert_eq!(err.status_code, StatusCode::FORBIDDEN);
+    assert!(!owner_root.join("docs/new.txt").exists());
+    assert!(owner_root.join("docs/a.txt").exists());
+  }
+
+  #[tokio::test]
+  async fn read_write_grant_writes_in_owner_root() {
+    let (file_root, owner_root, grantee, shared) = setup("acl-read-write", false).await;
+    vfs::create(
+      &file_root,
+      &grantee,
+      &format!("{shared}/new.txt"),
+      b"new".to_vec(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+      std::fs::read_to_string(owner_root.join("docs/new.txt")).unwrap(),
+      "new"
+    );
+    // the shared directory itself stays read only
+    let err = vfs::delete(&file_root, &grantee, &shared)
+      .await
+      .unwrap_err();
+    assert_eq!(err.status_code, StatusCode::FORBIDDEN);
+  }
+
+  #[tokio::test]
+  async fn revoked_grant_hides_files() {
+    let (file_root, _, grantee, shared) = setup("acl-revoke", true).await;
+    let owner = "acl-revoke-owner";
+    for acl in list_owned(owner).unwrap() {
+      assert!(revoke(owner, &acl.id).unwrap());
+    }
+    let err = vfs::stat(&file_root, &grantee, &format!("{shared}/a.txt"))
+      .await
+      .unwrap_err();
+    assert_eq!(err.status_code, StatusCode::NOT_FOUND);
+  }
+}
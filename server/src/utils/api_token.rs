@@ -0,0 +1,202 @@
+/// Personal access tokens
+///
+/// Scripts send `Authorization: Bearer <token>` instead of logging in. A token is limited to
+/// its scopes, which are permissions of the `permission` module, and to the permissions
+/// of its user at the time of the request.
+use std::{
+  collections::BTreeSet,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_session::Session;
+use actix_web::http::StatusCode;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::Engine;
+
+use crate::{
+  db::SHARED_DB_CONN,
+  models::{ApiToken, User},
+};
+
+use super::error::AppError;
+use super::permission::{self, Permission};
+use super::session::SessionUtils;
+
+/// set in the session of a request authenticated by a token, such sessions are not stored
+pub const SESSION_KEY: &str = "api_token";
+
+const TOKEN_PREFIX: &str = "wos_";
+
+/// last used time is written at most this often
+const TOUCH_INTERVAL_SECS: i64 = 60;
+
+fn now_secs() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// tokens are random, a fast hash is enough
+fn hash_token(token: &str) -> String {
+  sha256::digest(token.to_string())
+}
+
+/// token from an `Authorization` header value
+pub fn parse_bearer(value: &str) -> Option<&str> {
+  value
+    .strip_prefix("Bearer ")
+    .map(|token| token.trim())
+    .filter(|token| token.starts_with(TOKEN_PREFIX))
+}
+
+pub fn scopes(token: &ApiToken) -> BTreeSet<Permission> {
+  permission::parse(&token.scopes).unwrap_or_default()
+}
+
+/// create a token of `user`, the token itself is only returned here
+pub fn create(
+  user: &str,
+  name: &str,
+  scopes: &BTreeSet<Permission>,
+  expires_at: Option<i64>,
+) -> Result<(ApiToken, String), AppError> {
+  let bad_request = |msg: &str| AppError::new(msg).with_status(StatusCode::BAD_REQUEST);
+  if name.trim().is_empty() {
+    return Err(bad_request("token name is empty"));
+  }
+  if scopes.is_empty() {
+    return Err(bad_request("token scopes are empty"));
+  }
+  let granted = permission::of_user(user)?;
+  if let Some(p) = scopes.iter().find(|p| !granted.contains(p)) {
+    return Err(bad_request(&format!(
+      "can not grant {} to a token without having it",
+      p.name()
+    )));
+  }
+  let now = now_secs();
+  if matches!(expires_at, Some(t) if t <= now) {
+    return Err(bad_request("token expires in the past"));
+  }
+
+  let mut bytes = [0u8; 32];
+  OsRng.fill_bytes(&mut bytes);
+  let token = format!(
+    "{TOKEN_PREFIX}{}",
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+  );
+  let api_token = ApiToken {
+    id: uuid::Uuid::new_v4().to_string(),
+    username: user.to_owned(),
+    name: name.trim().to_owned(),
+    token_hash: hash_token(&token),
+    scopes: permission::format(scopes),
+    expires_at,
+    created_at: now,
+    last_used_at: None,
+  };
+  insert(&api_token)?;
+  Ok((api_token, token))
+}
+
+fn insert(api_token: &ApiToken) -> Result<(), AppError> {
+  use crate::schema::api_tokens::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::insert_into(api_tokens)
+    .values(api_token)
+    .execute(&mut *conn)?;
+  Ok(())
+}
+
+pub fn list(user: &str) -> Result<Vec<ApiToken>, AppError> {
+  use crate::schema::api_tokens::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = api_tokens
+    .filter(username.eq(user))
+    .order(created_at.asc())
+    .load::<ApiToken>(&mut *conn)?;
+  Ok(r)
+}
+
+pub fn revoke(user: &str, token_id: &str) -> Result<bool, AppError> {
+  use crate::schema::api_tokens::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = diesel::delete(api_tokens.filter(username.eq(user).and(id.eq(token_id))))
+    .execute(&mut *conn)?;
+  Ok(r > 0)
+}
+
+pub fn remove_user(user: &str) -> Result<(), AppError> {
+  use crate::schema::api_tokens::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::delete(api_tokens.filter(username.eq(user))).execute(&mut *conn)?;
+  Ok(())
+}
+
+/// the unexpired token and its user, None if the token is unknown
+pub fn authenticate(token: &str) -> Result<Option<(ApiToken, User)>, AppError> {
+  use crate::schema::{api_tokens, users};
+  use diesel::prelude::*;
+  let found = {
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    api_tokens::table
+      .inner_join(users::table.on(users::username.eq(api_tokens::username)))
+      .filter(api_tokens::token_hash.eq(hash_token(token)))
+      .first::<(ApiToken, User)>(&mut *conn)
+      .optional()?
+  };
+  let now = now_secs();
+  let found = found.filter(|(t, _)| t.expires_at.map_or(true, |expires_at| expires_at > now));
+  if let Some((t, _)) = &found {
+    if t
+      .last_used_at
+      .map_or(true, |at| now - at >= TOUCH_INTERVAL_SECS)
+    {
+      touch(&t.id, now)?;
+    }
+  }
+  Ok(found)
+}
+
+fn touch(token_id: &str, now: i64) -> Result<(), AppError> {
+  use crate::schema::api_tokens::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::update(api_tokens.filter(id.eq(token_id)))
+    .set(last_used_at.eq(now))
+    .execute(&mut *conn)?;
+  Ok(())
+}
+
+/// fails with 403 when the user of `sess` lacks `permission`, for checks depending on the
+/// request body which the guard can not see, a token session is also limited to its scopes
+pub fn require(sess: &Session, required: Permission) -> Result<(), AppError> {
+  permission::require(&sess.get_user_data()?.username, required)?;
+  let token_id = match sess.get::<String>(SESSION_KEY)? {
+    Some(token_id) => token_id,
+    None => return Ok(()),
+  };
+  let token = {
+    use crate::schema::api_tokens::dsl::*;
+    use diesel::prelude::*;
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    api_tokens
+      .filter(id.eq(&token_id))
+      .first::<ApiToken>(&mut *conn)
+      .optional()?
+  };
+  if token.map_or(false, |token| scopes(&token).contains(&required)) {
+    return Ok(());
+  }
+  Err(
+    AppError::new(&format!(
+      "permission denied: {} not in token scopes",
+      required.name()
+    ))
+    .with_status(StatusCode::FORBIDDEN),
+  )
+}
@@ -0,0 +1,604 @@
+/// Archive extraction and creation
+///
+/// Extracting and compressing run as background jobs on local storage, their progress is
+/// kept in memory until the server restarts. Supported formats are zip (deflate), tar,
+/// tar.gz and tar.zst.
+use std::{
+  collections::HashMap,
+  fs::{self, File},
+  io::{self, BufReader, BufWriter, Read, Write},
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  thread,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::http::StatusCode;
+use flate2::{read::GzDecoder, write::GzEncoder};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::io::{duplex, AsyncWriteExt, DuplexStream};
+use tracing::error;
+use walkdir::WalkDir;
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{conv_err, UserSessionData};
+
+use super::error::AppError;
+use super::path::secure_join;
+use super::quota;
+use super::storage::resolve;
+use super::vfs::{index_path, normailze_path, FSHookPayload, FSHookType, FS_HOOK};
+
+conv_err!(zip::result::ZipError);
+
+/// finished jobs are forgotten after a day
+const JOB_TTL_SECS: i64 = 24 * 3600;
+
+lazy_static! {
+  static ref ARCHIVE_JOBS: Mutex<HashMap<String, ArchiveJob>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ArchiveFormat {
+  #[serde(rename = "zip")]
+  Zip,
+  #[serde(rename = "tar")]
+  Tar,
+  #[serde(rename = "tar.gz")]
+  TarGz,
+  #[serde(rename = "tar.zst")]
+  TarZst,
+}
+
+impl ArchiveFormat {
+  /// guess format from file extension
+  pub fn from_name(name: &str) -> Option<Self> {
+    let name = name.to_lowercase();
+    if name.ends_with(".zip") {
+      Some(Self::Zip)
+    } else if name.ends_with(".tar") {
+      Some(Self::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+      Some(Self::TarGz)
+    } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+      Some(Self::TarZst)
+    } else {
+      None
+    }
+  }
+
+  fn strip_extension(self, name: &str) -> String {
+    let lower = name.to_lowercase();
+    let exts: &[&str] = match self {
+      Self::Zip => &[".zip"],
+      Self::Tar => &[".tar"],
+      Self::TarGz => &[".tar.gz", ".tgz"],
+      Self::TarZst => &[".tar.zst", ".tzst"],
+    };
+    for ext in exts {
+      if lower.ends_with(ext) {
+        return name[..name.len() - ext.len()].to_string();
+      }
+    }
+    name.to_string()
+  }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveJobKind {
+  Extract,
+  Compress,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveJobStatus {
+  Running,
+  Done,
+  Failed(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveJob {
+  pub id: String,
+  #[serde(skip)]
+  username: String,
+  pub kind: ArchiveJobKind,
+  pub format: ArchiveFormat,
+  pub sources: Vec<String>,
+  pub dest: String,
+  pub status: ArchiveJobStatus,
+  /// bytes processed, of the archive when extracting a tar and of the files otherwise
+  pub processed: u64,
+  pub total: u64,
+  pub created_at: i64,
+  #[serde(skip)]
+  progress: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArchiveEntry {
+  pub name: String,
+  pub size: u64,
+  pub is_dir: bool,
+}
+
+fn now_secs() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn snapshot(job: &ArchiveJob) -> ArchiveJob {
+  let mut job = job.clone();
+  job.processed = job.progress.load(Ordering::Relaxed);
+  job
+}
+
+pub fn list_jobs(user: &str) -> Vec<ArchiveJob> {
+  let jobs = ARCHIVE_JOBS.lock().unwrap();
+  let mut jobs = jobs
+    .values()
+    .filter(|j| j.username == user)
+    .map(snapshot)
+    .collect::<Vec<_>>();
+  jobs.sort_by_key(|j| -j.created_at);
+  jobs
+}
+
+pub fn get_job(user: &str, id: &str) -> Result<ArchiveJob, AppError> {
+  let jobs = ARCHIVE_JOBS.lock().unwrap();
+  jobs
+    .get(id)
+    .filter(|j| j.username == user)
+    .map(snapshot)
+    .ok_or_else(|| AppError::new("archive job not found").with_status(StatusCode::NOT_FOUND))
+}
+
+/// register a job and run `work` in a thread, `work` returns files to add to file index
+fn spawn_job<F>(mut job: ArchiveJob, work: F) -> ArchiveJob
+where
+  F: FnOnce(&ArchiveJob) -> Result<Vec<String>, AppError> + Send + 'static,
+{
+  job.id = uuid::Uuid::new_v4().to_string();
+  job.created_at = now_secs();
+  {
+    let mut jobs = ARCHIVE_JOBS.lock().unwrap();
+    let expired_at = now_secs() - JOB_TTL_SECS;
+    jobs.retain(|_, j| matches!(j.status, ArchiveJobStatus::Running) || j.created_at > expired_at);
+    jobs.insert(job.id.clone(), job.clone());
+  }
+  let running = job.clone();
+  thread::spawn(move || {
+    let status = match work(&running) {
+      Ok(added) => {
+        if !added.is_empty() {
+          FS_HOOK
+            .lock()
+            .unwrap()
+            .emit(FSHookType::AddFile, FSHookPayload(added));
+        }
+        ArchiveJobStatus::Done
+      }
+      Err(err) => {
+        error!("archive job {} failed: {err}", running.id);
+        ArchiveJobStatus::Failed(err.to_string())
+      }
+    };
+    if let Some(job) = ARCHIVE_JOBS.lock().unwrap().get_mut(&running.id) {
+      job.status = status;
+      if matches!(job.status, ArchiveJobStatus::Done) {
+        job.progress.store(job.total, Ordering::Relaxed);
+      }
+    }
+  });
+  job
+}
+
+/// counts bytes passing through a reader or writer
+struct Counting<T> {
+  inner: T,
+  count: Arc<AtomicU64>,
+}
+
+impl<T: Read> Read for Counting<T> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = self.inner.read(buf)?;
+    self.count.fetch_add(n as u64, Ordering::Relaxed);
+    Ok(n)
+  }
+}
+
+impl<T: Write> Write for Counting<T> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let n = self.inner.write(buf)?;
+    self.count.fetch_add(n as u64, Ordering::Relaxed);
+    Ok(n)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+fn open_tar(
+  format: ArchiveFormat,
+  reader: impl Read + 'static,
+) -> Result<tar::Archive<Box<dyn Read>>, AppError> {
+  let reader: Box<dyn Read> = match format {
+    ArchiveFormat::Tar => Box::new(reader),
+    ArchiveFormat::TarGz => Box::new(GzDecoder::new(reader)),
+    ArchiveFormat::TarZst => Box::new(zstd::Decoder::new(reader)?),
+    ArchiveFormat::Zip => unreachable!(),
+  };
+  Ok(tar::Archive::new(reader))
+}
+
+fn format_of(file: &str) -> Result<ArchiveFormat, AppError> {
+  ArchiveFormat::from_name(file).ok_or_else(|| {
+    AppError::new(&format!("unsupported archive format: {file}"))
+      .with_status(StatusCode::BAD_REQUEST)
+  })
+}
+
+fn conflict(file: &str) -> AppError {
+  AppError::new(&format!("file exists: {file}")).with_status(StatusCode::CONFLICT)
+}
+
+/// local path of `file` for writing, with its path in file index and the user charged for it
+fn writable_local(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+) -> Result<(PathBuf, Option<String>, Option<String>), AppError> {
+  let target = resolve(file_root, user, file)?.writable()?;
+  let indexed = index_path(file_root, &target);
+  let local = normailze_path(file_root, user, file)?;
+  let owner = quota::owner(file_root, user, file)?.map(|owner| owner.username);
+  Ok((local, indexed, owner))
+}
+
+fn indexed_child(base: &Option<String>, name: &Path) -> Option<String> {
+  base
+    .as_ref()
+    .map(|base| PathBuf::from(base).join(name).to_string_lossy().to_string())
+}
+
+/// extract `file` into directory `dest`, which defaults to the archive name without extension
+pub async fn extract(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  dest: Option<String>,
+) -> Result<ArchiveJob, AppError> {
+  let format = format_of(file)?;
+  let source = normailze_path(file_root, user, file)?;
+  let dest = dest.unwrap_or_else(|| format.strip_extension(file));
+  let (dest_dir, indexed, owner) = writable_local(file_root, user, &dest)?;
+  if dest_dir.exists() {
+    return Err(conflict(&dest));
+  }
+  let total = match format {
+    ArchiveFormat::Zip => {
+      let mut zip = ZipArchive::new(BufReader::new(File::open(source)?))?;
+      let mut total = 0;
+      for i in 0..zip.len() {
+        total += zip.by_index(i)?.size();
+      }
+      total
+    }
+    _ => fs::metadata(&source)?.len(),
+  };
+  // compressed size of tar archives is only a lower bound
+  quota::check(file_root, user, &dest, total).await?;
+
+  let job = ArchiveJob {
+    id: String::new(),
+    username: user.username.clone(),
+    kind: ArchiveJobKind::Extract,
+    format,
+    sources: vec![file.to_string()],
+    dest,
+    status: ArchiveJobStatus::Running,
+    processed: 0,
+    total,
+    created_at: 0,
+    progress: Arc::new(AtomicU64::new(0)),
+  };
+  Ok(spawn_job(job, move |job| {
+    fs::create_dir_all(&dest_dir)?;
+    let mut added = indexed.iter().cloned().collect::<Vec<_>>();
+    let r = extract_into(format, &source, &dest_dir, &indexed, &mut added, job);
+    if let Some(owner) = &owner {
+      quota::add(owner, quota::local_tree_size(&dest_dir) as i64)?;
+    }
+    r?;
+    Ok(added)
+  }))
+}
+
+fn extract_into(
+  format: ArchiveFormat,
+  source: &PathBuf,
+  dest_dir: &PathBuf,
+  indexed: &Option<String>,
+  added: &mut Vec<String>,
+  job: &ArchiveJob,
+) -> Result<(), AppError> {
+  if format == ArchiveFormat::Zip {
+    let mut zip = ZipArchive::new(BufReader::new(File::open(source)?))?;
+    for i in 0..zip.len() {
+      let mut entry = zip.by_index(i)?;
+      let name = PathBuf::from(entry.name());
+      // zip-slip: entries must stay inside the destination
+      let out = secure_join(dest_dir, &name)?;
+      if entry.is_dir() {
+        fs::create_dir_all(&out)?;
+      } else {
+        if let Some(parent) = out.parent() {
+          fs::create_dir_all(parent)?;
+        }
+        let mut w = Counting {
+          inner: File::create(&out)?,
+          count: job.progress.clone(),
+        };
+        io::copy(&mut entry, &mut w)?;
+      }
+      added.extend(indexed_child(indexed, &name));
+    }
+    return Ok(());
+  }
+
+  let reader = Counting {
+    inner: BufReader::new(File::open(source)?),
+    count: job.progress.clone(),
+  };
+  let mut archive = open_tar(format, reader)?;
+  for entry in archive.entries()? {
+    let mut entry = entry?;
+    let name = entry.path()?.to_path_buf();
+    let out = secure_join(dest_dir, &name)?;
+    match entry.header().entry_type() {
+      tar::EntryType::Directory => fs::create_dir_all(&out)?,
+      tar::EntryType::Regular | tar::EntryType::Continuous => {
+        if let Some(parent) = out.parent() {
+          fs::create_dir_all(parent)?;
+        }
+        io::copy(&mut entry, &mut File::create(&out)?)?;
+      }
+      // links could point outside of the destination
+      _ => continue,
+    }
+    added.extend(indexed_child(indexed, &name));
+  }
+  Ok(())
+}
+
+/// pack `files` into a new archive `dest`, the format follows the extension of `dest`
+pub async fn compress(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  files: Vec<String>,
+  dest: &str,
+) -> Result<ArchiveJob, AppError> {
+  let format = format_of(dest)?;
+  if files.is_empty() {
+    return Err(AppError::new("nothing to compress").with_status(StatusCode::BAD_REQUEST));
+  }
+  let (dest_file, indexed, owner) = writable_local(file_root, user, dest)?;
+  if dest_file.exists() {
+    return Err(conflict(dest));
+  }
+  let mut sources = vec![];
+  let mut total = 0;
+  for f in files.iter() {
+    let local = normailze_path(file_root, user, f)?;
+    let name = local.file_name().map(PathBuf::from).ok_or_else(|| {
+      AppError::new("can not compress user root").with_status(StatusCode::BAD_REQUEST)
+    })?;
+    for entry in WalkDir::new(&local) {
+      let entry = entry?;
+      if entry.file_type().is_file() {
+        total += entry.metadata()?.len();
+      }
+    }
+    sources.push((local, name));
+  }
+  // the archive is at most about as large as its files
+  quota::check(file_root, user, dest, total).await?;
+
+  let job = ArchiveJob {
+    id: String::new(),
+    username: user.username.clone(),
+    kind: ArchiveJobKind::Compress,
+    format,
+    sources: files,
+    dest: dest.to_string(),
+    status: ArchiveJobStatus::Running,
+    processed: 0,
+    total,
+    created_at: 0,
+    progress: Arc::new(AtomicU64::new(0)),
+  };
+  Ok(spawn_job(job, move |job| {
+    let r = write_archive(format, &sources, &dest_file, &job.progress);
+    if r.is_err() {
+      fs::remove_file(&dest_file).ok();
+    }
+    r?;
+    if let Some(owner) = &owner {
+      quota::add(owner, fs::metadata(&dest_file)?.len() as i64)?;
+    }
+    Ok(indexed.into_iter().collect())
+  }))
+}
+
+fn write_archive(
+  format: ArchiveFormat,
+  sources: &Vec<(PathBuf, PathBuf)>,
+  dest: &PathBuf,
+  progress: &Arc<AtomicU64>,
+) -> Result<(), AppError> {
+  let out = BufWriter::new(File::create(dest)?);
+  if format == ArchiveFormat::Zip {
+    let mut zip = ZipWriter::new(out);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (local, name) in sources {
+      for entry in WalkDir::new(local) {
+        let entry = entry?;
+        let rel = name.join(entry.path().strip_prefix(local)?);
+        let rel = rel.to_string_lossy().replace('\\', "/");
+        if entry.file_type().is_dir() {
+          zip.add_directory(rel, options)?;
+        } else if entry.file_type().is_file() {
+          zip.start_file(rel, options)?;
+          let mut r = Counting {
+            inner: File::open(entry.path())?,
+            count: progress.clone(),
+          };
+          io::copy(&mut r, &mut zip)?;
+        }
+      }
+    }
+    zip.finish()?.flush()?;
+    return Ok(());
+  }
+
+  let out: Box<dyn Write> = match format {
+    ArchiveFormat::Tar => Box::new(out),
+    ArchiveFormat::TarGz => Box::new(GzEncoder::new(out, flate2::Compression::default())),
+    ArchiveFormat::TarZst => Box::new(zstd::Encoder::new(out, 0)?.auto_finish()),
+    ArchiveFormat::Zip => unreachable!(),
+  };
+  let mut builder = tar::Builder::new(out);
+  for (local, name) in sources {
+    for entry in WalkDir::new(local) {
+      let entry = entry?;
+      let rel = name.join(entry.path().strip_prefix(local)?);
+      if entry.file_type().is_dir() {
+        builder.append_dir(&rel, entry.path())?;
+      } else if entry.file_type().is_file() {
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&entry.metadata()?);
+        let r = Counting {
+          inner: File::open(entry.path())?,
+          count: progress.clone(),
+        };
+        builder.append_data(&mut header, &rel, r)?;
+      }
+    }
+  }
+  // finishing the builder drops the encoders, which writes their trailers
+  builder.into_inner()?.flush()?;
+  Ok(())
+}
+
+/// entries of an archive of any supported format
+pub async fn list_entries(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+) -> Result<Vec<ArchiveEntry>, AppError> {
+  let format = format_of(file)?;
+  let source = normailze_path(file_root, user, file)?;
+  actix_web::web::block(move || -> Result<Vec<ArchiveEntry>, AppError> {
+    let mut entries = vec![];
+    if format == ArchiveFormat::Zip {
+      let mut zip = ZipArchive::new(BufReader::new(File::open(source)?))?;
+      for i in 0..zip.len() {
+        let entry = zip.by_index(i)?;
+        entries.push(ArchiveEntry {
+          name: entry.name().to_string(),
+          size: entry.size(),
+          is_dir: entry.is_dir(),
+        });
+      }
+      return Ok(entries);
+    }
+    let mut archive = open_tar(format, BufReader::new(File::open(source)?))?;
+    for entry in archive.entries()? {
+      let entry = entry?;
+      entries.push(ArchiveEntry {
+        name: entry.path()?.to_string_lossy().to_string(),
+        size: entry.size(),
+        is_dir: entry.header().entry_type().is_dir(),
+      });
+    }
+    Ok(entries)
+  })
+  .await?
+}
+
+/// stream a single entry of an archive without extracting it
+pub async fn read_entry(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  entry_name: &str,
+) -> Result<DuplexStream, AppError> {
+  let format = format_of(file)?;
+  let source = normailze_path(file_root, user, file)?;
+  let entry_name = PathBuf::from(entry_name.trim_start_matches('/'));
+
+  let (mut w, r) = duplex(512 * 1024);
+  // the entry is located before responding, so a missing entry is a 404
+  let (found_tx, found_rx) = tokio::sync::oneshot::channel::<Result<(), AppError>>();
+  let handle = tokio::runtime::Handle::current();
+  tokio::task::spawn_blocking(move || {
+    let mut found_tx = Some(found_tx);
+    let mut send = |entry: &mut dyn Read| -> Result<(), AppError> {
+      if let Some(tx) = found_tx.take() {
+        tx.send(Ok(())).ok();
+      }
+      let mut buf = vec![0u8; 64 * 1024];
+      loop {
+        let n = entry.read(&mut buf)?;
+        if n == 0 {
+          break;
+        }
+        handle.block_on(w.write_all(&buf[..n]))?;
+      }
+      handle.block_on(w.shutdown())?;
+      Ok(())
+    };
+    let r = (|| -> Result<(), AppError> {
+      if format == ArchiveFormat::Zip {
+        let mut zip = ZipArchive::new(BufReader::new(File::open(source)?))?;
+        let name = entry_name.to_string_lossy().replace('\\', "/");
+        if let Ok(mut entry) = zip.by_name(&name) {
+          return send(&mut entry);
+        }
+        return Ok(());
+      }
+      let mut archive = open_tar(format, BufReader::new(File::open(source)?))?;
+      for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_file() && entry.path()? == entry_name {
+          return send(&mut entry);
+        }
+      }
+      Ok(())
+    })();
+    if let Err(err) = r {
+      error!("read archive entry failed: {err}");
+      if let Some(tx) = found_tx.take() {
+        tx.send(Err(err)).ok();
+      }
+    } else if let Some(tx) = found_tx.take() {
+      tx.send(Err(
+        AppError::new(&format!("entry not found in archive: {:?}", entry_name))
+          .with_status(StatusCode::NOT_FOUND),
+      ))
+      .ok();
+    }
+  });
+  found_rx
+    .await
+    .map_err(|_| AppError::new("read archive entry failed"))??;
+  Ok(r)
+}
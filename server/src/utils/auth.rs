@@ -1,9 +1,11 @@
+use actix_web::http::StatusCode;
 use diesel::prelude::*;
 use diesel::{RunQueryDsl, SqliteConnection};
 use lazy_static::lazy_static;
 use serde::Serialize;
 use std::{
   collections::HashMap,
+  path::PathBuf,
   sync::{Arc, Mutex},
   time::Duration,
 };
@@ -22,12 +24,13 @@ lazy_static! {
     Arc::new(Mutex::new(HashMap::new()));
 }
 
-use crate::conv_err;
+use crate::{config, conv_err};
 use crate::{
   db::SHARED_DB_CONN,
+  middlewares::session::revoke_devices,
   models::{Group, NewGroup, NewUser, User},
   schema,
-  utils::crypto::hash_pwd,
+  utils::crypto::{hash_pwd, is_legacy_hash, verify_dummy, verify_pwd},
 };
 
 use super::error::AppError;
@@ -53,6 +56,73 @@ pub fn enable_otp(user: &str, secret: &str, code: &str) -> Result<bool, AppError
   Ok(success)
 }
 
+/// check password of a user, returns the user when it matches and is not disabled.
+/// a legacy sha256 hash is replaced with an argon2id one once the password is known to match
+pub fn verify_password(name: &str, pwd: &str) -> Result<Option<User>, AppError> {
+  if super::ldap::is_enabled() {
+    match super::ldap::verify_password(name, pwd) {
+      Ok(Some(user)) => return Ok(Some(user)),
+      Ok(None) => (),
+      // the directory accepted the password of an entry named like an unlinked local user
+      Err(err) if err.status_code == StatusCode::CONFLICT => return Err(err),
+      // local users can still log in while the directory is down
+      Err(err) => tracing::error!("ldap: {err}"),
+    }
+  }
+  let user = {
+    use crate::schema::users::dsl::*;
+    let mut db_mutex = SHARED_DB_CONN.lock().unwrap();
+    let db = &mut *db_mutex;
+    users
+      .filter(username.eq(name))
+      .first::<User>(db)
+      .optional()?
+  };
+  let user = match user {
+    // users of the directory only log in with their directory password
+    Some(user) if user.ldap_dn.is_none() => user,
+    _ => {
+      verify_dummy(pwd);
+      return Ok(None);
+    }
+  };
+  if !verify_pwd(pwd, &user.password) {
+    return Ok(None);
+  }
+  if user.disabled {
+    return Ok(None);
+  }
+  if is_legacy_hash(&user.password) {
+    set_password(&user.username, pwd, user.must_change_password)?;
+  }
+  Ok(Some(user))
+}
+
+/// store a new password of a user, hashed with argon2id
+pub fn set_password(name: &str, pwd: &str, must_change: bool) -> Result<bool, AppError> {
+  use crate::schema::users::dsl::*;
+
+  let hashed = hash_pwd(pwd)?;
+  let mut db_mutex = SHARED_DB_CONN.lock().unwrap();
+  let db = &mut *db_mutex;
+  let effected = diesel::update(users.filter(username.eq(name)))
+    .set((password.eq(hashed), must_change_password.eq(must_change)))
+    .execute(db)?;
+  Ok(effected > 0)
+}
+
+/// username and password in the value of an `Authorization: Basic` header
+pub fn parse_basic_auth(value: &str) -> Option<(String, String)> {
+  use base64::Engine;
+  let encoded = value.strip_prefix("Basic ")?;
+  let decoded = base64::engine::general_purpose::STANDARD
+    .decode(encoded.trim())
+    .ok()?;
+  let decoded = String::from_utf8(decoded).ok()?;
+  let (name, pwd) = decoded.split_once(':')?;
+  Some((name.to_owned(), pwd.to_owned()))
+}
+
 pub fn verify_otp(user: &str, code: &str) -> Result<bool, AppError> {
   use crate::schema::users::dsl::*;
 
@@ -111,6 +181,66 @@ pub fn disbale_otp(user: &str) -> Result<bool, AppError> {
   return Ok(effected > 0);
 }
 
+/// disable or enable a user, sessions of a disabled user are revoked
+pub fn set_disabled(name: &str, value: bool) -> Result<bool, AppError> {
+  let effected = {
+    use crate::schema::users::dsl::*;
+    let mut db_mutex = SHARED_DB_CONN.lock().unwrap();
+    let db = &mut *db_mutex;
+    diesel::update(users.filter(username.eq(name)))
+      .set(disabled.eq(value))
+      .execute(db)?
+  };
+  if value {
+    revoke_devices(name, None)?;
+  }
+  Ok(effected > 0)
+}
+
+pub fn find_user(name: &str) -> Result<Option<User>, AppError> {
+  use crate::schema::users::dsl::*;
+  let mut db_mutex = SHARED_DB_CONN.lock().unwrap();
+  let db = &mut *db_mutex;
+  let r = users
+    .filter(username.eq(name))
+    .first::<User>(db)
+    .optional()?;
+  Ok(r)
+}
+
+/// the user a password reset link may be sent to, found by name or verified email,
+/// users of the directory and disabled users can not reset their password
+pub fn find_resettable_user(name_or_email: &str) -> Result<Option<User>, AppError> {
+  use crate::schema::users::dsl::*;
+  let mut db_mutex = SHARED_DB_CONN.lock().unwrap();
+  let db = &mut *db_mutex;
+  let r = users
+    .filter(username.eq(name_or_email).or(email.eq(name_or_email)))
+    .filter(
+      email_verified
+        .eq(true)
+        .and(disabled.eq(false))
+        .and(ldap_dn.is_null()),
+    )
+    .load::<User>(db)?;
+  // an email shared by several users identifies none of them
+  if r.len() != 1 {
+    return Ok(None);
+  }
+  Ok(r.into_iter().next())
+}
+
+/// mark the email of a user verified, false if it has changed since the link was sent
+pub fn set_email_verified(name: &str, address: &str) -> Result<bool, AppError> {
+  use crate::schema::users::dsl::*;
+  let mut db_mutex = SHARED_DB_CONN.lock().unwrap();
+  let db = &mut *db_mutex;
+  let effected = diesel::update(users.filter(username.eq(name).and(email.eq(address))))
+    .set(email_verified.eq(true))
+    .execute(db)?;
+  Ok(effected > 0)
+}
+
 pub fn auto_create_user_group(db: &mut SqliteConnection) {
   use crate::schema::groups::dsl::*;
   let group = groups.first::<Group>(db);
@@ -127,7 +257,7 @@ pub fn auto_create_user_group(db: &mut SqliteConnection) {
       NewGroup {
         name: "guest".to_owned(),
         desc: "guest group".to_owned(),
-        permissions: "none".to_owned(),
+        permissions: "fs_read,fs_write,kv".to_owned(),
       },
     ])
     .execute(db)
@@ -148,17 +278,42 @@ pub fn auto_create_user(db: &mut SqliteConnection) {
   diesel::insert_into(schema::users::table)
     .values(NewUser {
       username: "admin",
-      password: &hash_pwd("admin"),
+      password: &hash_pwd("admin").unwrap(),
       email: "",
       user_type: 0,
       user_root: "",
       group_name: "admin",
+      // everybody knows the default password
+      must_change_password: true,
     })
     .execute(db)
     .unwrap();
   println!("create admin autmatically");
 }
 
+/// directory in `file_root` holding the roots of users created without an admin
+pub const USERS_DIR: &str = "users";
+
+/// create `users/<name>` in `file_root` as root of a new user, returns the root to store
+///
+/// users are only stored once their root exists, an empty root would be all of `file_root`
+pub fn create_user_root(name: &str) -> Result<String, AppError> {
+  if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+    return Err(
+      AppError::new(&format!("{name} can not be used as a username"))
+        .with_status(StatusCode::BAD_REQUEST),
+    );
+  }
+  let root = PathBuf::from(USERS_DIR).join(name);
+  std::fs::create_dir_all(PathBuf::from(config!(file_root)).join(&root)).map_err(|err| {
+    AppError::new(&format!(
+      "can not create root {} of {name}: {err}",
+      root.display()
+    ))
+  })?;
+  Ok(root.to_string_lossy().to_string())
+}
+
 pub fn create_one_time_token(
   user: &str,
   module_prefix: &str,
@@ -1,3 +1,77 @@
-pub fn hash_pwd(s: &str) -> String {
-  sha256::digest(s.to_string())
-}
\ No newline at end of file
+use argon2::{
+  password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+  Argon2,
+};
+
+use lazy_static::lazy_static;
+
+use crate::conv_err;
+
+use super::error::AppError;
+
+conv_err!(argon2::password_hash::Error);
+
+lazy_static! {
+  /// hash of a random password, verified when there is no hash of a user to verify
+  static ref DUMMY_HASH: String = hash_pwd(&uuid::Uuid::new_v4().to_string()).unwrap();
+}
+
+/// hash a password with argon2id and a random salt, in PHC string format
+pub fn hash_pwd(pwd: &str) -> Result<String, AppError> {
+  let salt = SaltString::generate(&mut OsRng);
+  let hashed = Argon2::default().hash_password(pwd.as_bytes(), &salt)?;
+  Ok(hashed.to_string())
+}
+
+/// passwords were hashed with unsalted sha256 before argon2id, PHC strings start with `$`
+pub fn is_legacy_hash(hashed: &str) -> bool {
+  !hashed.starts_with('$')
+}
+
+/// check `pwd` against a hash from `hash_pwd` or a legacy sha256 hash
+pub fn verify_pwd(pwd: &str, hashed: &str) -> bool {
+  if is_legacy_hash(hashed) {
+    return constant_time_eq(
+      sha256::digest(pwd.to_string()).as_bytes(),
+      hashed.as_bytes(),
+    );
+  }
+  match PasswordHash::new(hashed) {
+    Ok(parsed) => Argon2::default()
+      .verify_password(pwd.as_bytes(), &parsed)
+      .is_ok(),
+    Err(_) => false,
+  }
+}
+
+/// takes as long as checking a password of a user and never matches, so the time taken
+/// to refuse a login does not tell whether the user exists
+pub fn verify_dummy(pwd: &str) {
+  verify_pwd(pwd, &DUMMY_HASH);
+}
+
+/// compares every byte, the time taken does not tell how much of `a` matched `b`
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  if a.len() != b.len() {
+    return false;
+  }
+  a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn argon2_and_legacy_hashes_are_verified() {
+    let hashed = hash_pwd("secret").unwrap();
+    assert!(verify_pwd("secret", &hashed));
+    assert!(!verify_pwd("wrong", &hashed));
+
+    let legacy = sha256::digest("secret".to_string());
+    assert!(is_legacy_hash(&legacy));
+    assert!(verify_pwd("secret", &legacy));
+    assert!(!verify_pwd("wrong", &legacy));
+    assert!(!verify_pwd("secret", &legacy[..63]));
+  }
+}
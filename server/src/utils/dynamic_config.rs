@@ -0,0 +1,281 @@
+/// Runtime configuration
+///
+/// `DYNAMIC_CONFIG` starts from config.toml, keys set through the admin api are validated,
+/// stored in `dynamic_config` and override the file from then on. Subsystems listen to the
+/// keys they depend on to apply new values without a restart, others read them on use.
+use std::{
+  sync::{Arc, Mutex},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::http::StatusCode;
+use chrono::NaiveTime;
+use lazy_static::lazy_static;
+use serde_json::{Map, Value};
+
+use crate::{
+  config,
+  config::{DynamicConfig, DYNAMIC_CONFIG},
+  db::SHARED_DB_CONN,
+  models::DynamicConfigEntry,
+};
+
+use super::error::AppError;
+use super::eventbus::EventEmitter;
+
+/// shown instead of the smtp secret, setting it back keeps the stored secret
+const SECRET_MASK: &str = "********";
+
+lazy_static! {
+  static ref CONFIG_HOOK: Arc<Mutex<EventEmitter<String, ()>>> =
+    Arc::new(Mutex::new(EventEmitter::new()));
+}
+
+fn now_secs() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn bad_request(msg: &str) -> AppError {
+  AppError::new(msg).with_status(StatusCode::BAD_REQUEST)
+}
+
+fn to_map(conf: &DynamicConfig) -> Result<Map<String, Value>, AppError> {
+  match serde_json::to_value(conf) {
+    Ok(Value::Object(map)) => Ok(map),
+    _ => Err(AppError::new("dynamic config: can not serialize")),
+  }
+}
+
+fn from_map(map: Map<String, Value>) -> Result<DynamicConfig, AppError> {
+  serde_json::from_value(Value::Object(map)).map_err(|err| bad_request(&err.to_string()))
+}
+
+/// call `cb` after `key` has changed
+pub fn listen(key: &str, cb: impl Fn(()) + 'static) {
+  CONFIG_HOOK.lock().unwrap().listen(key.to_owned(), cb);
+}
+
+/// apply the values stored in the database over the ones of config.toml
+pub fn load() -> Result<(), AppError> {
+  let entries = {
+    use diesel::prelude::*;
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    crate::schema::dynamic_config::table.load::<DynamicConfigEntry>(&mut *conn)?
+  };
+  let mut map = to_map(&DYNAMIC_CONFIG.lock().unwrap())?;
+  for entry in entries {
+    if !map.contains_key(&entry.key) {
+      tracing::warn!("dynamic config: ignored unknown key {}", entry.key);
+      continue;
+    }
+    let mut merged = map.clone();
+    merged.insert(entry.key.clone(), serde_json::from_str(&entry.value)?);
+    // a stored value broken by an upgrade must not keep the server from starting
+    match from_map(merged.clone()).and_then(|conf| validate(&conf, &entry.key)) {
+      Ok(_) => map = merged,
+      Err(err) => tracing::warn!("dynamic config: ignored {}: {err}", entry.key),
+    }
+  }
+  *DYNAMIC_CONFIG.lock().unwrap() = from_map(map)?;
+  Ok(())
+}
+
+/// current config, secrets are masked
+pub fn get() -> Result<Map<String, Value>, AppError> {
+  let mut conf = DYNAMIC_CONFIG.lock().unwrap().clone();
+  if let Some(smtp) = conf.smtp.as_mut() {
+    if !smtp.secret.is_empty() {
+      smtp.secret = SECRET_MASK.to_owned();
+    }
+  }
+  to_map(&conf)
+}
+
+/// validate and store `changes`, listeners of changed keys are notified,
+/// returns the changed keys
+pub fn set(changes: Map<String, Value>, by: &str) -> Result<Vec<String>, AppError> {
+  let old = DYNAMIC_CONFIG.lock().unwrap().clone();
+  let old_map = to_map(&old)?;
+  let mut map = old_map.clone();
+  for (key, mut value) in changes {
+    if !map.contains_key(&key) {
+      return Err(bad_request(&format!("unknown config key: {key}")));
+    }
+    if key == "smtp" {
+      keep_masked_secret(&mut value, &old);
+    }
+    map.insert(key, value);
+  }
+  let conf = from_map(map.clone())?;
+  let changed: Vec<String> = map
+    .iter()
+    .filter(|(key, value)| old_map.get(*key) != Some(value))
+    .map(|(key, _)| key.to_owned())
+    .collect();
+  for key in &changed {
+    validate(&conf, key)?;
+  }
+
+  let now = now_secs();
+  let entries = changed
+    .iter()
+    .map(|key| DynamicConfigEntry {
+      key: key.to_owned(),
+      value: map[key].to_string(),
+      updated_at: now,
+      updated_by: by.to_owned(),
+    })
+    .collect::<Vec<_>>();
+  save(&entries)?;
+  *DYNAMIC_CONFIG.lock().unwrap() = conf;
+
+  let mut hook = CONFIG_HOOK.lock().unwrap();
+  for key in &changed {
+    tracing::info!("dynamic config: {key} changed by {by}");
+    hook.emit(key.to_owned(), ());
+  }
+  Ok(changed)
+}
+
+fn keep_masked_secret(value: &mut Value, old: &DynamicConfig) {
+  let old_secret = match &old.smtp {
+    Some(smtp) => smtp.secret.clone(),
+    None => return,
+  };
+  if let Some(secret) = value.get_mut("secret") {
+    if secret.as_str() == Some(SECRET_MASK) {
+      *secret = Value::String(old_secret);
+    }
+  }
+}
+
+fn save(entries: &[DynamicConfigEntry]) -> Result<(), AppError> {
+  use crate::schema::dynamic_config::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  conn.transaction::<_, diesel::result::Error, _>(|conn| {
+    for entry in entries {
+      diesel::replace_into(dynamic_config)
+        .values(entry)
+        .execute(conn)?;
+    }
+    Ok(())
+  })?;
+  Ok(())
+}
+
+fn group_exists(name: &str) -> Result<bool, AppError> {
+  use crate::schema::groups::dsl;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = dsl::groups
+    .filter(dsl::name.eq(name))
+    .count()
+    .get_result::<i64>(&mut *conn)?;
+  Ok(r > 0)
+}
+
+/// check the value of `key`, unchanged keys are not checked again, so a value of
+/// config.toml that became invalid does not block changing other keys
+pub fn validate(conf: &DynamicConfig, key: &str) -> Result<(), AppError> {
+  match key {
+    "smtp" => {
+      if let Some(smtp) = &conf.smtp {
+        super::mail::validate_config(smtp).map_err(|err| bad_request(&err.to_string()))?;
+      }
+    }
+    "public_url" => {
+      if let Some(url) = &conf.public_url {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+          return Err(bad_request(
+            "public_url must start with http:// or https://",
+          ));
+        }
+      }
+    }
+    "shell" => {
+      if let Some(shell) = &conf.shell {
+        if which::which(shell).is_err() {
+          return Err(bad_request(&format!("shell {shell} is not found")));
+        }
+      }
+    }
+    "transcode" => {
+      if let Some(transcode) = &conf.transcode {
+        if matches!(transcode.resize, Some(v) if v == 0 || v > 4320) {
+          return Err(bad_request("transcode resize must be between 1 and 4320"));
+        }
+        if matches!(transcode.bitrate, Some(v) if v == 0 || v > 100_000) {
+          return Err(bad_request(
+            "transcode bitrate must be between 1 and 100000",
+          ));
+        }
+      }
+    }
+    "index_update_time" => {
+      if let Some(time) = &conf.index_update_time {
+        NaiveTime::parse_from_str(time, "%H:%M")
+          .map_err(|_| bad_request("index_update_time must be HH:MM"))?;
+      }
+    }
+    "registration" => match conf.registration.as_deref() {
+      None | Some("admin") | Some("open") => {}
+      Some(other) => {
+        return Err(bad_request(&format!(
+          "unknown registration {other}, use admin or open"
+        )))
+      }
+    },
+    "registration_group" => {
+      if let Some(group) = &conf.registration_group {
+        if !group_exists(group)? {
+          return Err(bad_request(&format!("group {group} not found")));
+        }
+      }
+    }
+    _ => {}
+  }
+  Ok(())
+}
+
+/// shell started by terminals
+pub fn shell() -> String {
+  DYNAMIC_CONFIG
+    .lock()
+    .unwrap()
+    .shell
+    .clone()
+    .unwrap_or_else(|| config!(shell))
+}
+
+/// default height and bitrate of transcoded videos
+pub fn transcode_defaults() -> (u32, u32) {
+  let transcode = DYNAMIC_CONFIG.lock().unwrap().transcode.clone();
+  let transcode = transcode.as_ref();
+  (
+    transcode.and_then(|t| t.resize).unwrap_or(720),
+    transcode.and_then(|t| t.bitrate).unwrap_or(2000),
+  )
+}
+
+/// daily time of the file index update, None if it is not scheduled
+pub fn index_update_time() -> Option<NaiveTime> {
+  let time = DYNAMIC_CONFIG.lock().unwrap().index_update_time.clone()?;
+  NaiveTime::parse_from_str(&time, "%H:%M").ok()
+}
+
+/// anyone may sign up, into the returned group
+pub fn open_registration_group() -> Option<String> {
+  let conf = DYNAMIC_CONFIG.lock().unwrap();
+  if conf.registration.as_deref() != Some("open") {
+    return None;
+  }
+  Some(
+    conf
+      .registration_group
+      .clone()
+      .unwrap_or_else(|| "guest".to_owned()),
+  )
+}
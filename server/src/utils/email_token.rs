@@ -0,0 +1,88 @@
+/// Single use tokens sent in links by email, for password reset and email verification
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::Engine;
+
+use crate::{db::SHARED_DB_CONN, models::EmailToken};
+
+use super::error::AppError;
+
+pub const RESET_PASSWORD: &str = "reset_password";
+pub const VERIFY_EMAIL: &str = "verify_email";
+
+fn now_secs() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn hash_token(token: &str) -> String {
+  sha256::digest(token.to_string())
+}
+
+/// create a token, older tokens of the same user and purpose stop working
+pub fn create(
+  user: &str,
+  for_purpose: &str,
+  address: &str,
+  ttl_secs: i64,
+) -> Result<String, AppError> {
+  let mut bytes = [0u8; 32];
+  OsRng.fill_bytes(&mut bytes);
+  let token = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+  let now = now_secs();
+  let row = EmailToken {
+    id: hash_token(&token),
+    username: user.to_owned(),
+    purpose: for_purpose.to_owned(),
+    email: address.to_owned(),
+    expires_at: now + ttl_secs,
+    created_at: now,
+  };
+  use crate::schema::email_tokens::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::delete(
+    email_tokens.filter(
+      username
+        .eq(user)
+        .and(purpose.eq(for_purpose))
+        .or(expires_at.lt(now)),
+    ),
+  )
+  .execute(&mut *conn)?;
+  diesel::insert_into(email_tokens)
+    .values(&row)
+    .execute(&mut *conn)?;
+  Ok(token)
+}
+
+/// seconds since the last token of a user was created, None if it has none
+pub fn last_created(user: &str, for_purpose: &str) -> Result<Option<i64>, AppError> {
+  use crate::schema::email_tokens::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = email_tokens
+    .filter(username.eq(user).and(purpose.eq(for_purpose)))
+    .select(created_at)
+    .order(created_at.desc())
+    .first::<i64>(&mut *conn)
+    .optional()?;
+  Ok(r.map(|at| now_secs() - at))
+}
+
+/// the token if it is valid, it can not be used again
+pub fn consume(token: &str, for_purpose: &str) -> Result<Option<EmailToken>, AppError> {
+  use crate::schema::email_tokens::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let found = email_tokens
+    .filter(id.eq(hash_token(token)).and(purpose.eq(for_purpose)))
+    .first::<EmailToken>(&mut *conn)
+    .optional()?;
+  if let Some(found) = &found {
+    diesel::delete(email_tokens.filter(id.eq(&found.id))).execute(&mut *conn)?;
+  }
+  Ok(found.filter(|t| t.expires_at > now_secs()))
+}
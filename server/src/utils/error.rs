@@ -55,6 +55,7 @@ conv_err!(SessionGetError);
 conv_err!(SystemTimeError);
 conv_err!(std::io::Error);
 conv_err!(etag::ParseError);
+conv_err!(serde_json::Error);
 
 impl ResponseError for AppError {
   fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
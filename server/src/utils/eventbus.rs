@@ -1,5 +1,7 @@
 use std::{collections::HashMap, fmt::Debug, hash::Hash, sync::Mutex};
 
+use tracing::debug;
+
 pub type Listener<P> = Box<dyn Fn(P) -> ()>;
 
 pub struct EventEmitter<ET, P> {
@@ -18,14 +20,14 @@ impl<ET: PartialEq + Eq + Hash + Clone + Debug, P: Clone> EventEmitter<ET, P> {
   #[allow(unused)]
   pub fn emit(&mut self, event: ET, payload: P) {
     let mut v = self.events.lock().unwrap();
-    println!("emit: {:?}", v.keys());
+    debug!("emit: {:?}", event);
     let listeners = v.entry(event.clone()).or_insert(vec![]);
     for l in listeners {
       l(payload.clone());
     }
   }
   pub fn listen(&mut self, event: ET, cb: impl Fn(P) -> () + 'static) {
-    println!("listen: {:?}", event);
+    debug!("listen: {:?}", event);
     let b = Box::new(cb);
     let mut v = self.events.lock().unwrap();
     v.entry(event.clone()).or_insert(vec![]).push(b);
@@ -0,0 +1,299 @@
+/// LDAP authentication
+///
+/// With `authentication = "ldap"` a user logs in by binding as its directory entry. The
+/// entry is linked to a row of `users` by `ldap_dn`, created on first login, so user root,
+/// otp and everything else work as for local users. Directory groups are mapped to groups.
+/// A local user with the name of an entry is not taken over on login, an admin links it
+/// with `user link-ldap`.
+///
+/// `LdapConn` runs its own runtime, which can not be started from a worker of the server,
+/// so every directory operation runs on a thread of its own.
+use std::collections::HashMap;
+
+use actix_web::http::StatusCode;
+use ldap3::{ldap_escape, LdapConn, LdapConnSettings, Scope, SearchEntry};
+
+use crate::{
+  config,
+  config::{LdapConfig, APP_CONFIG},
+  conv_err,
+  db::SHARED_DB_CONN,
+  models::{NewUser, User},
+};
+
+use super::auth::create_user_root;
+use super::crypto::hash_pwd;
+use super::error::AppError;
+
+conv_err!(ldap3::LdapError);
+
+/// a user found in the directory
+struct DirectoryUser {
+  dn: String,
+  email: Option<String>,
+  groups: Vec<String>,
+}
+
+pub fn is_enabled() -> bool {
+  config!(authentication) == "ldap"
+}
+
+pub fn config() -> Result<LdapConfig, AppError> {
+  APP_CONFIG
+    .lock()
+    .unwrap()
+    .ldap
+    .clone()
+    .ok_or_else(|| AppError::new("ldap: authentication is ldap but [ldap] is not configured"))
+}
+
+fn in_thread<T: Send + 'static>(
+  f: impl FnOnce() -> Result<T, AppError> + Send + 'static,
+) -> Result<T, AppError> {
+  std::thread::spawn(f)
+    .join()
+    .unwrap_or_else(|_| Err(AppError::new("ldap: worker thread panicked")))
+}
+
+/// connection bound with the search account
+fn connect(conf: &LdapConfig) -> Result<LdapConn, AppError> {
+  let settings = LdapConnSettings::new().set_starttls(conf.starttls.unwrap_or(false));
+  let mut ldap = LdapConn::with_settings(settings, &conf.url)?;
+  ldap
+    .simple_bind(
+      conf.bind_dn.as_deref().unwrap_or(""),
+      conf.bind_password.as_deref().unwrap_or(""),
+    )?
+    .success()?;
+  Ok(ldap)
+}
+
+fn find_entry(
+  ldap: &mut LdapConn,
+  conf: &LdapConfig,
+  name: &str,
+) -> Result<Option<DirectoryUser>, AppError> {
+  let filter = conf
+    .user_filter
+    .as_deref()
+    .unwrap_or("(uid={username})")
+    .replace("{username}", &ldap_escape(name));
+  let email_attribute = conf.email_attribute.as_deref().unwrap_or("mail");
+  let group_attribute = conf.group_attribute.as_deref().unwrap_or("memberOf");
+  let (entries, _) = ldap
+    .search(
+      &conf.base_dn,
+      Scope::Subtree,
+      &filter,
+      vec![email_attribute, group_attribute],
+    )?
+    .success()?;
+  // an ambiguous filter must not pick one of several users
+  if entries.len() != 1 {
+    return Ok(None);
+  }
+  let mut entry = SearchEntry::construct(entries.into_iter().next().unwrap());
+  Ok(Some(DirectoryUser {
+    dn: entry.dn,
+    email: entry
+      .attrs
+      .remove(email_attribute)
+      .and_then(|v| v.into_iter().next()),
+    groups: entry.attrs.remove(group_attribute).unwrap_or_default(),
+  }))
+}
+
+/// `cn` of a group dn, the dn itself if it has none
+fn group_cn(dn: &str) -> &str {
+  dn.split(',')
+    .next()
+    .and_then(|rdn| rdn.split_once('='))
+    .filter(|(attr, _)| attr.trim().eq_ignore_ascii_case("cn"))
+    .map_or(dn, |(_, value)| value.trim())
+}
+
+fn mapped_group(conf: &LdapConfig, groups: &[String]) -> Option<String> {
+  let mapping: &HashMap<String, String> = conf.group_mapping.as_ref()?;
+  groups.iter().find_map(|dn| {
+    mapping
+      .iter()
+      .find(|(k, _)| k.eq_ignore_ascii_case(dn) || k.as_str() == group_cn(dn))
+      .map(|(_, group)| group.to_owned())
+  })
+}
+
+/// bind as `name`, returns the linked user, None if the directory refuses the password
+pub fn verify_password(name: &str, pwd: &str) -> Result<Option<User>, AppError> {
+  // an empty password would be an unauthenticated bind, which always succeeds
+  if pwd.is_empty() {
+    return Ok(None);
+  }
+  let conf = config()?;
+  let (name, pwd) = (name.to_owned(), pwd.to_owned());
+  let found = in_thread(move || {
+    let mut ldap = connect(&conf)?;
+    let entry = match find_entry(&mut ldap, &conf, &name)? {
+      Some(entry) => entry,
+      None => return Ok(None),
+    };
+    let bound = ldap.simple_bind(&entry.dn, &pwd)?.success().is_ok();
+    let _ = ldap.unbind();
+    if !bound {
+      return Ok(None);
+    }
+    Ok(Some((entry, conf, name)))
+  })?;
+  let (entry, conf, name) = match found {
+    Some(found) => found,
+    None => return Ok(None),
+  };
+  let user = link_user(&conf, &name, &entry)?;
+  Ok(Some(user).filter(|u| !u.disabled))
+}
+
+/// update the user linked to `entry`, creating it on first login
+fn link_user(conf: &LdapConfig, name: &str, entry: &DirectoryUser) -> Result<User, AppError> {
+  let group = mapped_group(conf, &entry.groups);
+  match find_user(name)? {
+    None => {
+      let pwd = hash_pwd(&uuid::Uuid::new_v4().to_string())?;
+      let default_group = conf.default_group.as_deref().unwrap_or("guest");
+      let root = create_user_root(name)?;
+      insert_user(NewUser {
+        username: name,
+        password: &pwd,
+        email: entry.email.as_deref().unwrap_or(""),
+        user_type: 1,
+        user_root: &root,
+        group_name: group.as_deref().unwrap_or(default_group),
+        must_change_password: false,
+      })?;
+      tracing::info!("ldap: created user {name} for {}", entry.dn);
+    }
+    Some(user) if user.ldap_dn.is_some() => (),
+    // whoever controls the entry would get the files of the local user
+    Some(_) => {
+      return Err(
+        AppError::new(&format!(
+          "ldap: local user {name} is not linked to {}, an admin links it with `user link-ldap`",
+          entry.dn
+        ))
+        .with_status(StatusCode::CONFLICT),
+      )
+    }
+  }
+  update_user(name, entry, group.as_deref())?;
+  find_user(name)?.ok_or_else(|| AppError::new(&format!("ldap: user {name} not found")))
+}
+
+/// link the local user `name` to its directory entry, returns the dn,
+/// the user logs in with its directory password from then on
+pub fn link_local_user(name: &str) -> Result<String, AppError> {
+  let user = find_user(name)?.ok_or_else(|| AppError::new(&format!("user {name} not found")))?;
+  if let Some(dn) = user.ldap_dn {
+    return Ok(dn);
+  }
+  let conf = config()?;
+  let search = name.to_owned();
+  let found = in_thread(move || {
+    let mut ldap = connect(&conf)?;
+    let entry = find_entry(&mut ldap, &conf, &search)?;
+    let _ = ldap.unbind();
+    Ok(entry.map(|entry| (entry, conf)))
+  })?;
+  let (entry, conf) =
+    found.ok_or_else(|| AppError::new(&format!("ldap: {name} is not in the directory")))?;
+  update_user(name, &entry, mapped_group(&conf, &entry.groups).as_deref())?;
+  tracing::info!("ldap: linked local user {name} to {}", entry.dn);
+  Ok(entry.dn)
+}
+
+fn find_user(name: &str) -> Result<Option<User>, AppError> {
+  use crate::schema::users::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = users
+    .filter(username.eq(name))
+    .first::<User>(&mut *conn)
+    .optional()?;
+  Ok(r)
+}
+
+fn insert_user(new_user: NewUser) -> Result<(), AppError> {
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::insert_into(crate::schema::users::table)
+    .values(new_user)
+    .execute(&mut *conn)?;
+  Ok(())
+}
+
+fn update_user(name: &str, entry: &DirectoryUser, group: Option<&str>) -> Result<(), AppError> {
+  use crate::schema::users::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::update(users.filter(username.eq(name)))
+    .set(ldap_dn.eq(&entry.dn))
+    .execute(&mut *conn)?;
+  if let Some(address) = &entry.email {
+    diesel::update(users.filter(username.eq(name)))
+      .set(email.eq(address))
+      .execute(&mut *conn)?;
+  }
+  if let Some(group) = group {
+    diesel::update(users.filter(username.eq(name)))
+      .set(group_name.eq(group))
+      .execute(&mut *conn)?;
+  }
+  Ok(())
+}
+
+fn linked_users() -> Result<Vec<String>, AppError> {
+  use crate::schema::users::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = users
+    .filter(ldap_dn.is_not_null().and(disabled.eq(false)))
+    .select(username)
+    .load::<String>(&mut *conn)?;
+  Ok(r)
+}
+
+#[derive(Debug, Default)]
+pub struct SyncResult {
+  pub updated: usize,
+  pub disabled: usize,
+}
+
+/// update groups of linked users and disable the ones removed from the directory,
+/// called from a scheduler thread
+pub fn sync() -> Result<SyncResult, AppError> {
+  let conf = config()?;
+  let names = linked_users()?;
+  let found = in_thread(move || {
+    let mut ldap = connect(&conf)?;
+    let mut found = vec![];
+    for name in names {
+      let entry = find_entry(&mut ldap, &conf, &name)?;
+      found.push((name, entry));
+    }
+    let _ = ldap.unbind();
+    Ok((conf, found))
+  })?;
+  let (conf, found) = found;
+  let mut result = SyncResult::default();
+  for (name, entry) in found {
+    match entry {
+      Some(entry) => {
+        update_user(&name, &entry, mapped_group(&conf, &entry.groups).as_deref())?;
+        result.updated += 1;
+      }
+      None => {
+        super::auth::set_disabled(&name, true)?;
+        tracing::info!("ldap: disabled user {name}, it is no longer in the directory");
+        result.disabled += 1;
+      }
+    }
+  }
+  Ok(result)
+}
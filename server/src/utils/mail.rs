@@ -0,0 +1,264 @@
+/// Outgoing mail
+///
+/// Mails are rendered from the templates below and written to `mail_queue`, the send mail
+/// job delivers them with the `[smtp]` config and retries failed ones with a growing delay.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::StatusCode;
+use lettre::{
+  message::{header::ContentType, Mailbox},
+  transport::smtp::authentication::Credentials,
+  Message, SmtpTransport, Transport,
+};
+
+use crate::{
+  config::{SMTPConfig, DYNAMIC_CONFIG},
+  conv_err,
+  db::SHARED_DB_CONN,
+  models::QueuedMail,
+};
+
+use super::error::AppError;
+
+conv_err!(lettre::error::Error);
+conv_err!(lettre::address::AddressError);
+conv_err!(lettre::transport::smtp::Error);
+
+/// a mail is given up after this many failed attempts
+const MAX_ATTEMPTS: i32 = 8;
+const RETRY_BASE_SECS: i64 = 60;
+/// sent mails are kept this long
+const KEEP_SENT_SECS: i64 = 7 * 24 * 60 * 60;
+
+pub enum Template {
+  ResetPassword,
+  VerifyEmail,
+  NewDevice,
+}
+
+impl Template {
+  /// subject and body, `{{name}}` is replaced by the value of `name`
+  fn source(&self) -> (&'static str, &'static str) {
+    match self {
+      Self::ResetPassword => (
+        "Reset your webby.os password",
+        "Hi {{username}},\n\n\
+         Somebody asked to reset the password of your webby.os account. \
+         Open the link below within an hour to choose a new password:\n\n\
+         {{link}}\n\n\
+         If it was not you, ignore this mail, your password stays the same.\n",
+      ),
+      Self::VerifyEmail => (
+        "Verify your email for webby.os",
+        "Hi {{username}},\n\n\
+         Open the link below to verify this email address for your webby.os account:\n\n\
+         {{link}}\n",
+      ),
+      Self::NewDevice => (
+        "New login to your webby.os account",
+        "Hi {{username}},\n\n\
+         Your webby.os account was logged in from a new device.\n\n\
+         Time: {{time}}\n\
+         IP: {{ip}}\n\
+         Device: {{user_agent}}\n\n\
+         If it was not you, change your password and revoke the session in your account settings.\n",
+      ),
+    }
+  }
+}
+
+fn now_secs() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn render(source: &str, vars: &[(&str, &str)]) -> String {
+  vars.iter().fold(source.to_owned(), |s, (name, value)| {
+    s.replace(&format!("{{{{{name}}}}}"), value)
+  })
+}
+
+fn smtp_config() -> Option<SMTPConfig> {
+  DYNAMIC_CONFIG.lock().unwrap().smtp.clone()
+}
+
+pub fn is_enabled() -> bool {
+  smtp_config().is_some()
+}
+
+/// link to `path` of the server, `public_url` must be configured
+pub fn link(path: &str) -> Result<String, AppError> {
+  let base = DYNAMIC_CONFIG
+    .lock()
+    .unwrap()
+    .public_url
+    .clone()
+    .ok_or_else(|| AppError::new("mail: public_url is not configured"))?;
+  Ok(format!("{}{path}", base.trim_end_matches('/')))
+}
+
+/// render a template and queue it for `recipient`
+pub fn queue(recipient: &str, template: Template, vars: &[(&str, &str)]) -> Result<(), AppError> {
+  if !is_enabled() {
+    return Err(
+      AppError::new("mail: smtp is not configured").with_status(StatusCode::SERVICE_UNAVAILABLE),
+    );
+  }
+  // a bad address would only fail later in the queue
+  recipient.parse::<Mailbox>()?;
+  let (subject, body) = template.source();
+  let now = now_secs();
+  insert(&QueuedMail {
+    id: uuid::Uuid::new_v4().to_string(),
+    recipient: recipient.to_owned(),
+    subject: render(subject, vars),
+    body: render(body, vars),
+    attempts: 0,
+    next_attempt_at: now,
+    last_error: None,
+    created_at: now,
+    sent_at: None,
+  })
+}
+
+fn insert(mail: &QueuedMail) -> Result<(), AppError> {
+  use crate::schema::mail_queue::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::insert_into(mail_queue)
+    .values(mail)
+    .execute(&mut *conn)?;
+  Ok(())
+}
+
+/// check a config before it is used, nothing is sent
+pub fn validate_config(conf: &SMTPConfig) -> Result<(), AppError> {
+  if conf.host.trim().is_empty() {
+    return Err(AppError::new("smtp host is empty"));
+  }
+  if conf.port == 0 || conf.port > u16::MAX as u32 {
+    return Err(AppError::new("smtp port must be between 1 and 65535"));
+  }
+  let from = conf.from.clone().unwrap_or_else(|| conf.user.clone());
+  from
+    .parse::<Mailbox>()
+    .map_err(|_| AppError::new("smtp from (or user) must be an email address"))?;
+  transport(conf)?;
+  Ok(())
+}
+
+fn transport(conf: &SMTPConfig) -> Result<SmtpTransport, AppError> {
+  let builder = match conf.security.as_deref().unwrap_or("starttls") {
+    "tls" => SmtpTransport::relay(&conf.host)?,
+    "starttls" => SmtpTransport::starttls_relay(&conf.host)?,
+    "none" => SmtpTransport::builder_dangerous(&conf.host),
+    other => {
+      return Err(AppError::new(&format!(
+        "mail: unknown smtp security {other}, use tls, starttls or none"
+      )))
+    }
+  };
+  let mut builder = builder.port(conf.port as u16);
+  if !conf.user.is_empty() {
+    builder = builder.credentials(Credentials::new(conf.user.clone(), conf.secret.clone()));
+  }
+  Ok(builder.build())
+}
+
+fn due() -> Result<Vec<QueuedMail>, AppError> {
+  use crate::schema::mail_queue::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = mail_queue
+    .filter(
+      sent_at
+        .is_null()
+        .and(attempts.lt(MAX_ATTEMPTS))
+        .and(next_attempt_at.le(now_secs())),
+    )
+    .order(next_attempt_at.asc())
+    .load::<QueuedMail>(&mut *conn)?;
+  Ok(r)
+}
+
+fn mark_sent(mail_id: &str) -> Result<(), AppError> {
+  use crate::schema::mail_queue::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::update(mail_queue.filter(id.eq(mail_id)))
+    .set(sent_at.eq(now_secs()))
+    .execute(&mut *conn)?;
+  Ok(())
+}
+
+fn mark_failed(mail: &QueuedMail, err: &str) -> Result<(), AppError> {
+  use crate::schema::mail_queue::dsl::*;
+  use diesel::prelude::*;
+  let tried = mail.attempts + 1;
+  let delay = RETRY_BASE_SECS << tried.min(10);
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::update(mail_queue.filter(id.eq(&mail.id)))
+    .set((
+      attempts.eq(tried),
+      next_attempt_at.eq(now_secs() + delay),
+      last_error.eq(err),
+    ))
+    .execute(&mut *conn)?;
+  Ok(())
+}
+
+fn purge_sent() -> Result<usize, AppError> {
+  use crate::schema::mail_queue::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = diesel::delete(mail_queue.filter(sent_at.lt(now_secs() - KEEP_SENT_SECS)))
+    .execute(&mut *conn)?;
+  Ok(r)
+}
+
+/// send due mails, returns the number of mails sent and failed
+pub fn send_due() -> Result<(usize, usize), AppError> {
+  let conf = match smtp_config() {
+    Some(conf) => conf,
+    None => return Ok((0, 0)),
+  };
+  purge_sent()?;
+  let mails = due()?;
+  if mails.is_empty() {
+    return Ok((0, 0));
+  }
+  let from = conf.from.clone().unwrap_or_else(|| conf.user.clone());
+  let from = from.parse::<Mailbox>()?;
+  let mailer = transport(&conf)?;
+  let (mut sent, mut failed) = (0, 0);
+  for mail in mails {
+    let result = mail
+      .recipient
+      .parse::<Mailbox>()
+      .map_err(AppError::from)
+      .and_then(|to| {
+        Ok(
+          Message::builder()
+            .from(from.clone())
+            .to(to)
+            .subject(&mail.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(mail.body.clone())?,
+        )
+      })
+      .and_then(|message| Ok(mailer.send(&message)?));
+    match result {
+      Ok(_) => {
+        mark_sent(&mail.id)?;
+        sent += 1;
+      }
+      Err(err) => {
+        tracing::warn!("mail: sending to {} failed: {err}", mail.recipient);
+        mark_failed(&mail, &err.to_string())?;
+        failed += 1;
+      }
+    }
+  }
+  Ok((sent, failed))
+}
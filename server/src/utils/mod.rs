@@ -1,10 +1,29 @@
 pub mod vfs;
+pub mod storage;
+pub mod mount;
+pub mod acl;
+pub mod trash;
+pub mod versions;
+pub mod permission;
+pub mod quota;
+pub mod archive;
+pub mod upload;
+pub mod share;
 pub mod response;
 pub mod error;
 pub mod parser;
 pub mod crypto;
 pub mod session;
 pub mod auth;
+pub mod tls;
+pub mod dynamic_config;
+pub mod mail;
+pub mod email_token;
+pub mod ldap;
+pub mod oidc;
+pub mod api_token;
+pub mod rate_limit;
+pub mod web_authn;
 pub mod transcode;
 pub mod stream;
 pub mod path;
@@ -16,3 +35,5 @@ pub mod log;
 pub mod system_info;
 #[cfg(debug_assertions)]
 pub mod performance;
+#[cfg(test)]
+pub mod test_utils;
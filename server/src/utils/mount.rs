@@ -0,0 +1,106 @@
+/// Per-user mount table
+///
+/// A mount maps a top level directory of a user, e.g. `photos`, to a directory
+/// on the host (`/mnt/disk2/photos`) or to a path in a configured storage backend (`minio://photos`).
+use std::{collections::HashMap, path::PathBuf, sync::RwLock};
+
+use actix_web::http::StatusCode;
+use lazy_static::lazy_static;
+
+use crate::{db::SHARED_DB_CONN, models::Mount};
+
+use super::acl::SHARED_DIR;
+use super::error::AppError;
+use super::trash::TRASH_DIR;
+use super::versions::VERSIONS_DIR;
+
+lazy_static! {
+  /// mounts of each user, loaded from database on first use
+  static ref MOUNT_TABLE: RwLock<HashMap<String, Vec<Mount>>> = RwLock::new(HashMap::new());
+}
+
+pub fn get_mounts(user: &str) -> Result<Vec<Mount>, AppError> {
+  if let Some(mounts) = MOUNT_TABLE.read().unwrap().get(user) {
+    return Ok(mounts.clone());
+  }
+  // loaded under the write lock, rows read before an invalidation can not be cached after it
+  let mut table = MOUNT_TABLE.write().unwrap();
+  if let Some(mounts) = table.get(user) {
+    return Ok(mounts.clone());
+  }
+  use crate::schema::mounts::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let result = mounts.filter(username.eq(user)).load::<Mount>(&mut *conn)?;
+  table.insert(user.to_owned(), result.clone());
+  Ok(result)
+}
+
+/// find the mount containing `file`, returns the mount and the path inside it
+pub fn find_mount(user: &str, file: &PathBuf) -> Result<Option<(Mount, PathBuf)>, AppError> {
+  let first = match file.components().next() {
+    Some(std::path::Component::Normal(first)) => first.to_string_lossy().to_string(),
+    _ => return Ok(None),
+  };
+  let mount = get_mounts(user)?.into_iter().find(|m| m.prefix == first);
+  Ok(mount.map(|m| {
+    let rest = file.strip_prefix(&m.prefix).unwrap().to_path_buf();
+    (m, rest)
+  }))
+}
+
+pub fn add_mount(mount: Mount) -> Result<(), AppError> {
+  if mount.prefix.is_empty()
+    || mount.prefix.contains(['/', '\\'])
+    || mount.prefix == "."
+    || mount.prefix == ".."
+  {
+    return Err(
+      AppError::new("mount prefix must be a single directory name")
+        .with_status(StatusCode::BAD_REQUEST),
+    );
+  }
+  if [TRASH_DIR, VERSIONS_DIR, SHARED_DIR].contains(&mount.prefix.as_str()) {
+    return Err(
+      AppError::new(&format!("{} is reserved", mount.prefix)).with_status(StatusCode::BAD_REQUEST),
+    );
+  }
+  if mount.target.is_empty() {
+    return Err(AppError::new("mount target is empty").with_status(StatusCode::BAD_REQUEST));
+  }
+  use crate::schema::mounts::dsl::*;
+  use diesel::prelude::*;
+  {
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    diesel::replace_into(mounts)
+      .values(&mount)
+      .execute(&mut *conn)?;
+  }
+  // the database is unlocked first, `get_mounts` locks the table before the database
+  MOUNT_TABLE.write().unwrap().remove(&mount.username);
+  Ok(())
+}
+
+pub fn remove_mount(user: &str, mount_prefix: &str) -> Result<bool, AppError> {
+  use crate::schema::mounts::dsl::*;
+  use diesel::prelude::*;
+  let r = {
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    diesel::delete(mounts.filter(username.eq(user).and(prefix.eq(mount_prefix))))
+      .execute(&mut *conn)?
+  };
+  MOUNT_TABLE.write().unwrap().remove(user);
+  Ok(r > 0)
+}
+
+/// remove every mount of a user
+pub fn remove_user(user: &str) -> Result<(), AppError> {
+  use crate::schema::mounts::dsl::*;
+  use diesel::prelude::*;
+  {
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    diesel::delete(mounts.filter(username.eq(user))).execute(&mut *conn)?;
+  }
+  MOUNT_TABLE.write().unwrap().remove(user);
+  Ok(())
+}
@@ -0,0 +1,732 @@
+/// OpenID Connect single sign-on
+///
+/// Users are sent to the provider with the authorization code flow and PKCE. The code is
+/// exchanged at the token endpoint directly over TLS, so the claims of the id token are
+/// trusted after checking issuer, audience, expiry and nonce. Accounts of the provider are
+/// linked to users in `oidc_identities`, found by email or provisioned on first login.
+use std::{
+  collections::HashMap,
+  sync::Mutex,
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::http::StatusCode;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::Engine;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{
+  config::{OidcConfig, APP_CONFIG},
+  conv_err,
+  db::SHARED_DB_CONN,
+  models::{NewUser, OidcIdentity, User},
+};
+
+use super::auth::create_user_root;
+use super::crypto::hash_pwd;
+use super::error::AppError;
+
+conv_err!(reqwest::Error);
+
+/// a login must be finished within this time
+const LOGIN_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Deserialize, Clone)]
+struct ProviderMetadata {
+  issuer: String,
+  authorization_endpoint: String,
+  token_endpoint: String,
+  userinfo_endpoint: Option<String>,
+}
+
+struct PendingLogin {
+  verifier: String,
+  nonce: String,
+  redirect: String,
+  started: Instant,
+}
+
+lazy_static! {
+  static ref METADATA: Mutex<Option<ProviderMetadata>> = Mutex::new(None);
+  /// logins in progress, keyed by state
+  static ref PENDING: Mutex<HashMap<String, PendingLogin>> = Mutex::new(HashMap::new());
+}
+
+fn now_secs() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn random_string() -> String {
+  let mut bytes = [0u8; 32];
+  OsRng.fill_bytes(&mut bytes);
+  base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn unauthorized(msg: &str) -> AppError {
+  AppError::new(&format!("oidc: {msg}")).with_status(StatusCode::UNAUTHORIZED)
+}
+
+pub fn config() -> Result<OidcConfig, AppError> {
+  APP_CONFIG
+    .lock()
+    .unwrap()
+    .oidc
+    .clone()
+    .ok_or_else(|| AppError::new("oidc is not configured").with_status(StatusCode::NOT_FOUND))
+}
+
+pub fn is_enabled() -> bool {
+  APP_CONFIG.lock().unwrap().oidc.is_some()
+}
+
+/// discovery document of the provider, fetched once
+async fn metadata(conf: &OidcConfig) -> Result<ProviderMetadata, AppError> {
+  if let Some(m) = METADATA.lock().unwrap().as_ref() {
+    return Ok(m.clone());
+  }
+  let url = format!(
+    "{}/.well-known/openid-configuration",
+    conf.issuer.trim_end_matches('/')
+  );
+  let body = reqwest::get(&url)
+    .await?
+    .error_for_status()?
+    .bytes()
+    .await?;
+  let m = serde_json::from_slice::<ProviderMetadata>(&body)?;
+  if m.issuer.trim_end_matches('/') != conf.issuer.trim_end_matches('/') {
+    return Err(AppError::new(&format!(
+      "oidc: provider issuer {} does not match {}",
+      m.issuer, conf.issuer
+    )));
+  }
+  *METADATA.lock().unwrap() = Some(m.clone());
+  Ok(m)
+}
+
+/// only paths of this server are followed after login
+fn safe_redirect(redirect: Option<&str>) -> String {
+  match redirect {
+    Some(r) if r.starts_with('/') && !r.starts_with("//") && !r.contains('\\') => r.to_owned(),
+    _ => "/".to_owned(),
+  }
+}
+
+/// url of the provider to send the browser to, and the state to bind to its session
+pub async fn start_login(redirect: Option<&str>) -> Result<(String, String), AppError> {
+  let conf = config()?;
+  let m = metadata(&conf).await?;
+  let state = random_string();
+  let nonce = random_string();
+  let verifier = random_string();
+  let challenge =
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+  let mut url = url::Url::parse(&m.authorization_endpoint)
+    .map_err(|err| AppError::new(&format!("oidc: authorization endpoint: {err}")))?;
+  url
+    .query_pairs_mut()
+    .append_pair("response_type", "code")
+    .append_pair("client_id", &conf.client_id)
+    .append_pair("redirect_uri", &conf.redirect_url)
+    .append_pair(
+      "scope",
+      conf.scopes.as_deref().unwrap_or("openid email profile"),
+    )
+    .append_pair("state", &state)
+    .append_pair("nonce", &nonce)
+    .append_pair("code_challenge", &challenge)
+    .append_pair("code_challenge_method", "S256");
+
+  let mut pending = PENDING.lock().unwrap();
+  pending.retain(|_, p| p.started.elapsed() < LOGIN_TTL);
+  pending.insert(
+    state.clone(),
+    PendingLogin {
+      verifier,
+      nonce,
+      redirect: safe_redirect(redirect),
+      started: Instant::now(),
+    },
+  );
+  Ok((url.to_string(), state))
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+  access_token: String,
+  id_token: String,
+}
+
+/// claims of an id token received from the token endpoint
+fn id_token_claims(id_token: &str) -> Result<serde_json::Map<String, Value>, AppError> {
+  let payload = id_token
+    .split('.')
+    .nth(1)
+    .ok_or_else(|| unauthorized("malformed id token"))?;
+  let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+    .decode(payload.trim_end_matches('='))
+    .map_err(|_| unauthorized("malformed id token"))?;
+  Ok(serde_json::from_slice(&payload)?)
+}
+
+fn validate_claims(
+  claims: &serde_json::Map<String, Value>,
+  conf: &OidcConfig,
+  m: &ProviderMetadata,
+  nonce: &str,
+) -> Result<(), AppError> {
+  if claims.get("iss").and_then(Value::as_str) != Some(m.issuer.as_str()) {
+    return Err(unauthorized("id token issuer mismatch"));
+  }
+  let audience_ok = match claims.get("aud") {
+    Some(Value::String(aud)) => aud == &conf.client_id,
+    Some(Value::Array(aud)) => aud
+      .iter()
+      .any(|a| a.as_str() == Some(conf.client_id.as_str())),
+    _ => false,
+  };
+  if !audience_ok {
+    return Err(unauthorized("id token audience mismatch"));
+  }
+  if claims.get("exp").and_then(Value::as_i64).unwrap_or(0) <= now_secs() {
+    return Err(unauthorized("id token expired"));
+  }
+  if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+    return Err(unauthorized("id token nonce mismatch"));
+  }
+  Ok(())
+}
+
+/// exchange the code of the callback, returns the user logged in and where to go next
+pub async fn finish_login(
+  state: &str,
+  session_state: Option<&str>,
+  code: &str,
+) -> Result<(User, String), AppError> {
+  // the state must come back to the browser that started the login
+  if session_state != Some(state) {
+    return Err(unauthorized("state mismatch"));
+  }
+  let pending = PENDING
+    .lock()
+    .unwrap()
+    .remove(state)
+    .filter(|p| p.started.elapsed() < LOGIN_TTL)
+    .ok_or_else(|| unauthorized("no login in progress"))?;
+  let conf = config()?;
+  let m = metadata(&conf).await?;
+
+  let mut form = vec![
+    ("grant_type", "authorization_code"),
+    ("code", code),
+    ("redirect_uri", conf.redirect_url.as_str()),
+    ("client_id", conf.client_id.as_str()),
+    ("code_verifier", pending.verifier.as_str()),
+  ];
+  if let Some(secret) = &conf.client_secret {
+    form.push(("client_secret", secret.as_str()));
+  }
+  let client = reqwest::Client::new();
+  let resp = client.post(&m.token_endpoint).form(&form).send().await?;
+  if !resp.status().is_success() {
+    let status = resp.status();
+    let body = resp.text().await.unwrap_or_default();
+    return Err(unauthorized(&format!(
+      "token endpoint returned {status}: {body}"
+    )));
+  }
+  let tokens = serde_json::from_slice::<TokenResponse>(&resp.bytes().await?)?;
+  let mut claims = id_token_claims(&tokens.id_token)?;
+  validate_claims(&claims, &conf, &m, &pending.nonce)?;
+
+  // providers often leave email and groups out of the id token
+  if let Some(userinfo_endpoint) = &m.userinfo_endpoint {
+    let resp = client
+      .get(userinfo_endpoint)
+      .bearer_auth(&tokens.access_token)
+      .send()
+      .await?;
+    if resp.status().is_success() {
+      let info = serde_json::from_slice::<serde_json::Map<String, Value>>(&resp.bytes().await?)?;
+      if info.get("sub") == claims.get("sub") {
+        for (k, v) in info {
+          claims.entry(k).or_insert(v);
+        }
+      }
+    }
+  }
+
+  let user = user_of_claims(&conf, &m.issuer, &claims)?;
+  Ok((user, pending.redirect))
+}
+
+fn claim_str<'a>(claims: &'a serde_json::Map<String, Value>, name: &str) -> Option<&'a str> {
+  claims
+    .get(name)
+    .and_then(Value::as_str)
+    .filter(|v| !v.is_empty())
+}
+
+/// group named by the first value of the group claim found in `group_mapping`
+fn mapped_group(conf: &OidcConfig, claims: &serde_json::Map<String, Value>) -> Option<String> {
+  let mapping = conf.group_mapping.as_ref()?;
+  let values = match claims.get(conf.group_claim.as_deref()?)? {
+    Value::String(v) => vec![v.as_str()],
+    Value::Array(vs) => vs.iter().filter_map(Value::as_str).collect(),
+    _ => vec![],
+  };
+  values
+    .into_iter()
+    .find_map(|v| mapping.get(v))
+    .map(|g| g.to_owned())
+}
+
+fn sanitize_username(name: &str) -> String {
+  name
+    .chars()
+    .map(|c| {
+      if c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.' {
+        c
+      } else {
+        '_'
+      }
+    })
+    .collect()
+}
+
+/// the linked user, linking or provisioning one on first login
+fn user_of_claims(
+  conf: &OidcConfig,
+  issuer: &str,
+  claims: &serde_json::Map<String, Value>,
+) -> Result<User, AppError> {
+  let sub = claim_str(claims, "sub").ok_or_else(|| unauthorized("id token without sub"))?;
+  let email = claim_str(claims, "email");
+  let email_verified = claims
+    .get("email_verified")
+    .and_then(Value::as_bool)
+    .unwrap_or(false);
+  let group = mapped_group(conf, claims);
+
+  let username = match find_identity(issuer, sub)? {
+    Some(identity) => identity.username,
+    None => {
+      let linked = match email {
+        Some(email) if email_verified && conf.link_by_email.unwrap_or(true) => {
+          find_user_by_email(email)?
+        }
+        _ => None,
+      };
+      let username = match linked {
+        Some(user) => user.username,
+        None => {
+          let name = claim_str(
+            claims,
+            conf
+              .username_claim
+              .as_deref()
+              .unwrap_or("preferred_username"),
+          )
+          .or_else(|| email.and_then(|e| e.split('@').next()))
+          .unwrap_or(sub);
+          let name = sanitize_username(name);
+          let default_group = conf.default_group.as_deref().unwrap_or("guest");
+          provision_user(
+            &name,
+            email.unwrap_or(""),
+            group.as_deref().unwrap_or(default_group),
+          )?;
+          name
+        }
+      };
+      insert_identity(&OidcIdentity {
+        issuer: issuer.to_owned(),
+        subject: sub.to_owned(),
+        username: username.clone(),
+        email: email.map(|e| e.to_owned()),
+        created_at: now_secs(),
+        last_login: now_secs(),
+      })?;
+      username
+    }
+  };
+  touch_identity(issuer, sub)?;
+  if let Some(group) = &group {
+    set_group(&username, group)?;
+  }
+  find_user(&username)?
+    .filter(|user| !user.disabled)
+    .ok_or_else(|| unauthorized(&format!("user {username} not found or disabled")))
+}
+
+fn find_identity(iss: &str, sub: &str) -> Result<Option<OidcIdentity>, AppError> {
+  use crate::schema::oidc_identities::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = oidc_identities
+    .filter(issuer.eq(iss).and(subject.eq(sub)))
+    .first::<OidcIdentity>(&mut *conn)
+    .optional()?;
+  Ok(r)
+}
+
+fn insert_identity(identity: &OidcIdentity) -> Result<(), AppError> {
+  use crate::schema::oidc_identities::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::insert_into(oidc_identities)
+    .values(identity)
+    .execute(&mut *conn)?;
+  Ok(())
+}
+
+fn touch_identity(iss: &str, sub: &str) -> Result<(), AppError> {
+  use crate::schema::oidc_identities::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::update(oidc_identities.filter(issuer.eq(iss).and(subject.eq(sub))))
+    .set(last_login.eq(now_secs()))
+    .execute(&mut *conn)?;
+  Ok(())
+}
+
+pub fn remove_user(user: &str) -> Result<(), AppError> {
+  use crate::schema::oidc_identities::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::delete(oidc_identities.filter(username.eq(user))).execute(&mut *conn)?;
+  Ok(())
+}
+
+fn find_user(name: &str) -> Result<Option<User>, AppError> {
+  use crate::schema::users::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = users
+    .filter(username.eq(name))
+    .first::<User>(&mut *conn)
+    .optional()?;
+  Ok(r)
+}
+
+fn find_user_by_email(address: &str) -> Result<Option<User>, AppError> {
+  use crate::schema::users::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = users
+    .filter(email.eq(address))
+    .first::<User>(&mut *conn)
+    .optional()?;
+  Ok(r)
+}
+
+fn set_group(name: &str, group: &str) -> Result<(), AppError> {
+  use crate::schema::users::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::update(users.filter(username.eq(name)))
+    .set(group_name.eq(group))
+    .execute(&mut *conn)?;
+  Ok(())
+}
+
+/// new user without a usable password, it can only log in through the provider
+fn provision_user(name: &str, address: &str, group: &str) -> Result<(), AppError> {
+  if find_user(name)?.is_some() {
+    return Err(
+      AppError::new(&format!(
+        "oidc: user {name} already exists and is not linked to this account"
+      ))
+      .with_status(StatusCode::CONFLICT),
+    );
+  }
+  use diesel::prelude::*;
+  let pwd = hash_pwd(&random_string())?;
+  let root = create_user_root(name)?;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::insert_into(crate::schema::users::table)
+    .values(NewUser {
+      username: name,
+      password: &pwd,
+      email: address,
+      user_type: 1,
+      user_root: &root,
+      group_name: group,
+      must_change_password: false,
+    })
+    .execute(&mut *conn)?;
+  tracing::info!("oidc: provisioned user {name} in group {group}");
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use actix_web::{http::header, web, App, HttpRequest, HttpResponse, HttpServer};
+  use serde_json::json;
+
+  use super::*;
+  use crate::models::NewGroup;
+  use crate::utils::test_utils::{init_db, temp_dir};
+
+  const CLIENT_ID: &str = "webbyos";
+  const GROUP: &str = "oidc-users";
+
+  /// what the provider knows of a login, by code and then by access token
+  #[derive(Clone)]
+  struct Grant {
+    challenge: String,
+    nonce: String,
+    sub: String,
+    email: String,
+    username: String,
+  }
+
+  type Grants = web::Data<Mutex<HashMap<String, Grant>>>;
+
+  fn base64url(data: &[u8]) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(data)
+  }
+
+  fn issuer_of(req: &HttpRequest) -> String {
+    format!("http://{}", req.connection_info().host())
+  }
+
+  async fn discovery(req: HttpRequest) -> HttpResponse {
+    let issuer = issuer_of(&req);
+    HttpResponse::Ok().json(json!({
+      "issuer": issuer,
+      "authorization_endpoint": format!("{issuer}/authorize"),
+      "token_endpoint": format!("{issuer}/token"),
+      "userinfo_endpoint": format!("{issuer}/userinfo"),
+    }))
+  }
+
+  /// logs in whoever is named by the extra `sub`, `email` and `preferred_username` params
+  async fn authorize(query: web::Query<HashMap<String, String>>, grants: Grants) -> HttpResponse {
+    let param = |name: &str| query.get(name).cloned().unwrap_or_default();
+    if param("client_id") != CLIENT_ID || param("code_challenge_method") != "S256" {
+      return HttpResponse::BadRequest().finish();
+    }
+    let code = random_string();
+    grants.lock().unwrap().insert(
+      code.clone(),
+      Grant {
+        challenge: param("code_challenge"),
+        nonce: param("nonce"),
+        sub: param("sub"),
+        email: param("email"),
+        username: param("preferred_username"),
+      },
+    );
+    let mut location = url::Url::parse(&param("redirect_uri")).unwrap();
+    location
+      .query_pairs_mut()
+      .append_pair("code", &code)
+      .append_pair("state", &param("state"));
+    HttpResponse::Found()
+      .insert_header((header::LOCATION, location.to_string()))
+      .finish()
+  }
+
+  /// codes are used once and only with the verifier of their challenge,
+  /// the email is left to the userinfo endpoint
+  async fn token(
+    req: HttpRequest,
+    form: web::Form<HashMap<String, String>>,
+    grants: Grants,
+  ) -> HttpResponse {
+    let param = |name: &str| form.get(name).cloned().unwrap_or_default();
+    let mut grants = grants.lock().unwrap();
+    let grant = match grants.remove(&param("code")) {
+      Some(grant) if param("grant_type") == "authorization_code" => grant,
+      _ => return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" })),
+    };
+    if base64url(&Sha256::digest(param("code_verifier").as_bytes())) != grant.challenge {
+      return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" }));
+    }
+    let claims = json!({
+      "iss": issuer_of(&req),
+      "aud": CLIENT_ID,
+      "exp": now_secs() + 60,
+      "nonce": grant.nonce,
+      "sub": grant.sub,
+      "preferred_username": grant.username,
+    });
+    let id_token = format!(
+      "{}.{}.",
+      base64url(br#"{"alg":"none"}"#),
+      base64url(claims.to_string().as_bytes())
+    );
+    let access_token = random_string();
+    grants.insert(access_token.clone(), grant);
+    HttpResponse::Ok().json(json!({
+      "access_token": access_token,
+      "token_type": "Bearer",
+      "id_token": id_token,
+    }))
+  }
+
+  async fn userinfo(req: HttpRequest, grants: Grants) -> HttpResponse {
+    let access_token = req
+      .headers()
+      .get(header::AUTHORIZATION)
+      .and_then(|v| v.to_str().ok())
+      .and_then(|v| v.strip_prefix("Bearer "))
+      .unwrap_or_default();
+    match grants.lock().unwrap().get(access_token) {
+      Some(grant) => HttpResponse::Ok().json(json!({
+        "sub": grant.sub,
+        "email": grant.email,
+        "email_verified": true,
+      })),
+      None => HttpResponse::Unauthorized().finish(),
+    }
+  }
+
+  /// a provider on a local port, returns its issuer
+  fn mock_provider() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let issuer = format!("http://{}", listener.local_addr().unwrap());
+    let grants: Grants = web::Data::new(Mutex::new(HashMap::new()));
+    let server = HttpServer::new(move || {
+      App::new()
+        .app_data(grants.clone())
+        .route(
+          "/.well-known/openid-configuration",
+          web::get().to(discovery),
+        )
+        .route("/authorize", web::get().to(authorize))
+        .route("/token", web::post().to(token))
+        .route("/userinfo", web::get().to(userinfo))
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    actix_web::rt::spawn(server);
+    issuer
+  }
+
+  /// the browser part of a login, returns the state and the code sent back to the callback
+  async fn authorize_at_provider(sub: &str, email: &str, username: &str) -> (String, String) {
+    let (url, state) = start_login(Some("/apps")).await.unwrap();
+    let url = format!("{url}&sub={sub}&email={email}&preferred_username={username}");
+    let client = reqwest::Client::builder()
+      .redirect(reqwest::redirect::Policy::none())
+      .build()
+      .unwrap();
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 302);
+    let location = resp.headers()["location"].to_str().unwrap();
+    let query = url::Url::parse(location)
+      .unwrap()
+      .query_pairs()
+      .into_owned()
+      .collect::<HashMap<_, _>>();
+    assert_eq!(query["state"], state);
+    (state, query["code"].clone())
+  }
+
+  fn add_user(name: &str, address: &str) {
+    use diesel::prelude::*;
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    diesel::insert_into(crate::schema::users::table)
+      .values(NewUser {
+        username: name,
+        password: &hash_pwd("secret").unwrap(),
+        email: address,
+        user_type: 0,
+        user_root: "",
+        group_name: GROUP,
+        must_change_password: false,
+      })
+      .execute(&mut *conn)
+      .unwrap();
+  }
+
+  #[actix_web::test]
+  async fn login_round_trip_provisions_and_links_users() {
+    init_db();
+    {
+      use diesel::prelude::*;
+      let mut conn = SHARED_DB_CONN.lock().unwrap();
+      diesel::insert_into(crate::schema::groups::table)
+        .values(NewGroup {
+          name: GROUP.to_owned(),
+          desc: String::new(),
+          permissions: String::new(),
+        })
+        .execute(&mut *conn)
+        .unwrap();
+    }
+    let issuer = mock_provider();
+    {
+      let mut conf = APP_CONFIG.lock().unwrap();
+      conf.file_root = Some(temp_dir("oidc").to_string_lossy().to_string());
+      conf.oidc = Some(OidcConfig {
+        display_name: None,
+        issuer: issuer.clone(),
+        client_id: CLIENT_ID.to_owned(),
+        client_secret: None,
+        redirect_url: "http://webby.test/auth/oidc/callback".to_owned(),
+        scopes: None,
+        username_claim: None,
+        default_group: Some(GROUP.to_owned()),
+        group_claim: None,
+        group_mapping: None,
+        link_by_email: None,
+      });
+    }
+
+    // the state must be the one bound to the browser, and of a login in progress
+    let (state, code) = authorize_at_provider("sub-new", "oidc-new@example.com", "oidc-new").await;
+    let err = finish_login(&state, Some("other"), &code)
+      .await
+      .unwrap_err();
+    assert_eq!(err.status_code, StatusCode::UNAUTHORIZED);
+    let err = finish_login("unknown", Some("unknown"), &code)
+      .await
+      .unwrap_err();
+    assert_eq!(err.status_code, StatusCode::UNAUTHORIZED);
+
+    // first login provisions a user, the email comes from the userinfo endpoint
+    let (user, redirect) = finish_login(&state, Some(&state), &code).await.unwrap();
+    assert_eq!(user.username, "oidc-new");
+    assert_eq!(user.email, "oidc-new@example.com");
+    assert_eq!(user.group_name, GROUP);
+    assert_eq!(redirect, "/apps");
+    assert_eq!(
+      find_identity(&issuer, "sub-new").unwrap().unwrap().username,
+      "oidc-new"
+    );
+    // a login is only finished once
+    let err = finish_login(&state, Some(&state), &code).await.unwrap_err();
+    assert_eq!(err.status_code, StatusCode::UNAUTHORIZED);
+
+    // the next login finds the linked identity, whatever the username claim says
+    let (state, code) = authorize_at_provider("sub-new", "oidc-new@example.com", "renamed").await;
+    let (user, _) = finish_login(&state, Some(&state), &code).await.unwrap();
+    assert_eq!(user.username, "oidc-new");
+
+    // an existing user with the verified email is linked instead of provisioning another
+    add_user("oidc-local", "oidc-local@example.com");
+    let (state, code) =
+      authorize_at_provider("sub-local", "oidc-local@example.com", "someone-else").await;
+    let (user, _) = finish_login(&state, Some(&state), &code).await.unwrap();
+    assert_eq!(user.username, "oidc-local");
+    assert_eq!(
+      find_identity(&issuer, "sub-local")
+        .unwrap()
+        .unwrap()
+        .username,
+      "oidc-local"
+    );
+    assert!(find_user("someone-else").unwrap().is_none());
+  }
+}
@@ -0,0 +1,152 @@
+/// Role based permissions
+///
+/// Permissions are granted to groups. `groups.permissions` holds either `all`, `none` or a
+/// comma separated list of the names below, e.g. `fs_read,fs_write,kv`.
+use std::collections::BTreeSet;
+
+use actix_web::http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::db::SHARED_DB_CONN;
+
+use super::error::AppError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+  /// open a shell on the server
+  Shell,
+  /// http tunnel and websockify
+  Tunnel,
+  /// manage users, groups, mounts and quotas
+  UserAdmin,
+  /// read server logs
+  LogRead,
+  /// read system information of the server
+  SystemInfo,
+  FsRead,
+  FsWrite,
+  /// kv storage
+  Kv,
+  /// change the runtime configuration of the server
+  Config,
+}
+
+pub const ALL_PERMISSIONS: [Permission; 9] = [
+  Permission::Shell,
+  Permission::Tunnel,
+  Permission::UserAdmin,
+  Permission::LogRead,
+  Permission::SystemInfo,
+  Permission::FsRead,
+  Permission::FsWrite,
+  Permission::Kv,
+  Permission::Config,
+];
+
+impl Permission {
+  pub fn name(self) -> &'static str {
+    match self {
+      Self::Shell => "shell",
+      Self::Tunnel => "tunnel",
+      Self::UserAdmin => "user_admin",
+      Self::LogRead => "log_read",
+      Self::SystemInfo => "system_info",
+      Self::FsRead => "fs_read",
+      Self::FsWrite => "fs_write",
+      Self::Kv => "kv",
+      Self::Config => "config",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Self> {
+    ALL_PERMISSIONS.into_iter().find(|p| p.name() == name)
+  }
+}
+
+/// parse the `permissions` column of a group
+pub fn parse(s: &str) -> Result<BTreeSet<Permission>, AppError> {
+  let s = s.trim();
+  if s == "all" {
+    return Ok(ALL_PERMISSIONS.into_iter().collect());
+  }
+  if s == "none" || s.is_empty() {
+    return Ok(BTreeSet::new());
+  }
+  s.split(',')
+    .map(|name| {
+      let name = name.trim();
+      Permission::from_name(name).ok_or_else(|| {
+        AppError::new(&format!("unknown permission: {name}")).with_status(StatusCode::BAD_REQUEST)
+      })
+    })
+    .collect()
+}
+
+/// format permissions for the `permissions` column of a group
+pub fn format(permissions: &BTreeSet<Permission>) -> String {
+  if permissions.len() == ALL_PERMISSIONS.len() {
+    return "all".to_owned();
+  }
+  if permissions.is_empty() {
+    return "none".to_owned();
+  }
+  permissions
+    .iter()
+    .map(|p| p.name())
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+/// permissions of the group of a user, a user without group has none
+pub fn of_user(name: &str) -> Result<BTreeSet<Permission>, AppError> {
+  use crate::schema::{groups, users};
+  use diesel::prelude::*;
+  let permissions = {
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    users::table
+      .inner_join(groups::table)
+      .filter(users::username.eq(name))
+      .select(groups::permissions)
+      .first::<String>(&mut *conn)
+      .optional()?
+  };
+  match permissions {
+    // a broken entry must not lock everyone out of the other permissions
+    Some(permissions) => Ok(parse(&permissions).unwrap_or_else(|err| {
+      tracing::warn!("permissions of user {name}: {err}");
+      permissions
+        .split(',')
+        .filter_map(|p| Permission::from_name(p.trim()))
+        .collect()
+    })),
+    None => Ok(BTreeSet::new()),
+  }
+}
+
+/// fails with 403 when user lacks `permission`
+pub fn require(name: &str, permission: Permission) -> Result<(), AppError> {
+  if of_user(name)?.contains(&permission) {
+    return Ok(());
+  }
+  Err(
+    AppError::new(&format!(
+      "permission denied: {} required",
+      permission.name()
+    ))
+    .with_status(StatusCode::FORBIDDEN),
+  )
+}
+
+pub fn set_group_permissions(
+  group: &str,
+  permissions_: &BTreeSet<Permission>,
+) -> Result<bool, AppError> {
+  use crate::schema::groups::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = diesel::update(groups.filter(name.eq(group)))
+    .set(permissions.eq(format(permissions_)))
+    .execute(&mut *conn)?;
+  Ok(r > 0)
+}
@@ -0,0 +1,488 @@
+/// Storage quotas
+///
+/// A user may store up to the quota set on the user, or else on its group, in its root.
+/// Writes are charged to the user whose root holds the file, so writes through
+/// `Shared with me` or a mount of another user root count for the owner of that root.
+/// Files in mounts outside of every user root are not counted. Usage is computed once by
+/// walking the user root and then kept up to date with the size changes made through `vfs`.
+use std::{
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::http::StatusCode;
+use serde::Serialize;
+
+use crate::{db::SHARED_DB_CONN, models::StorageUsage, UserSessionData};
+
+use super::error::AppError;
+use super::mount::find_mount;
+use super::path::secure_join;
+use super::storage::{resolve_internal, StorageBackend};
+
+#[derive(Debug, Serialize)]
+pub struct UserUsage {
+  pub username: String,
+  pub group_name: String,
+  pub used: i64,
+  pub quota: Option<i64>,
+}
+
+fn now_secs() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// quota of a user, the quota of the user wins over the one of its group
+pub fn limit(name: &str) -> Result<Option<i64>, AppError> {
+  use crate::schema::{groups, users};
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = users::table
+    .inner_join(groups::table)
+    .filter(users::username.eq(name))
+    .select((users::quota, groups::quota))
+    .first::<(Option<i64>, Option<i64>)>(&mut *conn)
+    .optional()?;
+  Ok(r.and_then(|(user_quota, group_quota)| user_quota.or(group_quota)))
+}
+
+/// size of a file, or of all files in a directory
+pub async fn tree_size(backend: &Arc<dyn StorageBackend>, path: &PathBuf) -> Result<u64, AppError> {
+  tree_size_except(backend, path, &[]).await
+}
+
+/// size of a file, or of all files in a directory except the subtrees in `except`
+#[async_recursion::async_recursion]
+async fn tree_size_except(
+  backend: &Arc<dyn StorageBackend>,
+  path: &PathBuf,
+  except: &[PathBuf],
+) -> Result<u64, AppError> {
+  if except.contains(path) {
+    return Ok(0);
+  }
+  let file_stat = match backend.stat(path).await {
+    Ok(file_stat) => file_stat,
+    Err(_) => return Ok(0),
+  };
+  if !file_stat.is_dir {
+    return Ok(file_stat.size);
+  }
+  let mut total = 0;
+  for f in backend.read_dir(path).await? {
+    total += tree_size_except(backend, &path.join(&f.name), except).await?;
+  }
+  Ok(total)
+}
+
+/// user names and roots, the roots with most components first so that nested roots win
+pub fn user_roots() -> Result<Vec<(String, String)>, AppError> {
+  use crate::schema::users::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let mut r = users
+    .select((username, user_root))
+    .load::<(String, String)>(&mut *conn)?;
+  r.sort_by_key(|(_, root)| std::cmp::Reverse(split_root(root).1.components().count()));
+  Ok(r)
+}
+
+/// storage name and path of a root written like `user_root`
+fn split_root(root: &str) -> (Option<&str>, PathBuf) {
+  match root.split_once("://") {
+    Some((name, path)) => (Some(name), PathBuf::from(path)),
+    None => (None, PathBuf::from(root)),
+  }
+}
+
+/// name of the user whose root contains `path`, `path` is written like a user root:
+/// relative to `file_root`, absolute on the host or `<storage>://<path>`
+pub fn find_owner<'a>(roots: &'a [(String, String)], path: &str) -> Option<&'a (String, String)> {
+  let (storage, path) = split_root(path);
+  roots.iter().find(|(_, root)| {
+    let (root_storage, root) = split_root(root);
+    root_storage == storage && root.has_root() == path.has_root() && path.starts_with(&root)
+  })
+}
+
+/// the user charged for writes to `file`, None for files in mounts outside of user roots
+pub fn owner(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+) -> Result<Option<UserSessionData>, AppError> {
+  let target = resolve_internal(file_root, user, file)?;
+  if let Some(origin) = target.shared {
+    // shares of shares are refused by `resolve`, the owner file is in its root or its mounts
+    return owner(file_root, &origin.owner(), &origin.file);
+  }
+  if target.mount.is_none() {
+    return Ok(Some(UserSessionData::new(&user.username, &user.user_root)));
+  }
+  let file = secure_join(&PathBuf::new(), &PathBuf::from(file))?;
+  let (mount, rest) = match find_mount(&user.username, &file)? {
+    Some(found) => found,
+    None => return Ok(None),
+  };
+  let path = if rest.as_os_str().is_empty() {
+    mount.target.clone()
+  } else {
+    format!(
+      "{}/{}",
+      mount.target.trim_end_matches('/'),
+      rest.to_string_lossy()
+    )
+  };
+  let roots = user_roots()?;
+  Ok(find_owner(&roots, &path).map(|(name, root)| UserSessionData::new(name, root)))
+}
+
+/// size of `file` counted in quota, 0 for files in mounts outside of user roots
+pub async fn size_of(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+) -> Result<u64, AppError> {
+  if owner(file_root, user, file)?.is_none() {
+    return Ok(0);
+  }
+  // trash and versions are counted too
+  let target = resolve_internal(file_root, user, file)?;
+  tree_size(&target.backend, &target.path).await
+}
+
+fn cached_usage(name: &str) -> Result<Option<i64>, AppError> {
+  use crate::schema::storage_usage::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = storage_usage
+    .filter(username.eq(name))
+    .select(used)
+    .first::<i64>(&mut *conn)
+    .optional()?;
+  Ok(r)
+}
+
+/// walk user root and store its size as current usage, roots of other users nested in it,
+/// like `users/<name>` in the root of admin, are counted for their own users
+pub async fn recalculate(file_root: &PathBuf, user: &UserSessionData) -> Result<i64, AppError> {
+  let target = resolve_internal(file_root, user, "")?;
+  let (storage, root) = split_root(&user.user_root);
+  let nested = user_roots()?
+    .into_iter()
+    .filter_map(|(_, other)| {
+      let (other_storage, other) = split_root(&other);
+      if other_storage != storage || other.has_root() != root.has_root() || other == root {
+        return None;
+      }
+      other
+        .strip_prefix(&root)
+        .ok()
+        .map(|rest| target.path.join(rest))
+    })
+    .collect::<Vec<_>>();
+  let total = tree_size_except(&target.backend, &target.path, &nested).await? as i64;
+  let row = StorageUsage {
+    username: user.username.clone(),
+    used: total,
+    updated_at: now_secs(),
+  };
+  use crate::schema::storage_usage::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::replace_into(storage_usage)
+    .values(&row)
+    .execute(&mut *conn)?;
+  Ok(total)
+}
+
+/// bytes used by a user, computed on first use
+pub async fn usage(file_root: &PathBuf, user: &UserSessionData) -> Result<i64, AppError> {
+  match cached_usage(&user.username)? {
+    Some(used_) => Ok(used_),
+    None => recalculate(file_root, user).await,
+  }
+}
+
+/// forget the usage of a removed user
+pub fn remove_user(name: &str) -> Result<(), AppError> {
+  use crate::schema::storage_usage::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::delete(storage_usage.filter(username.eq(name))).execute(&mut *conn)?;
+  Ok(())
+}
+
+/// add `delta` bytes to usage of a user, nothing is done before usage is computed
+pub fn add(name: &str, delta: i64) -> Result<(), AppError> {
+  if delta == 0 {
+    return Ok(());
+  }
+  use crate::schema::storage_usage::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::update(storage_usage.filter(username.eq(name)))
+    .set((used.eq(used + delta), updated_at.eq(now_secs())))
+    .execute(&mut *conn)?;
+  Ok(())
+}
+
+/// add `delta` bytes to usage of the user charged for `file`
+pub fn charge(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  delta: i64,
+) -> Result<(), AppError> {
+  match owner(file_root, user, file)? {
+    Some(owner) => add(&owner.username, delta),
+    None => Ok(()),
+  }
+}
+
+/// fails with 507 when `incoming` more bytes in `file` would exceed the quota of its owner
+pub async fn check(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  incoming: u64,
+) -> Result<(), AppError> {
+  match remaining(file_root, user, file).await? {
+    Some(left) if incoming as i64 > left => Err(exceeded(incoming, left)),
+    _ => Ok(()),
+  }
+}
+
+/// bytes the owner of `file` can still write, negative when it is over quota,
+/// None when it has no quota
+pub async fn remaining(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+) -> Result<Option<i64>, AppError> {
+  let owner = match owner(file_root, user, file)? {
+    Some(owner) => owner,
+    None => return Ok(None),
+  };
+  let quota = match limit(&owner.username)? {
+    Some(quota) => quota,
+    None => return Ok(None),
+  };
+  let used_ = usage(file_root, &owner).await?;
+  Ok(Some(quota - used_))
+}
+
+pub fn exceeded(incoming: u64, left: i64) -> AppError {
+  AppError::new(&format!(
+    "storage quota exceeded: {incoming} bytes requested, {} bytes left",
+    left.max(0)
+  ))
+  .with_status(StatusCode::INSUFFICIENT_STORAGE)
+}
+
+/// apply the size change of `file` after a write, `before` is its size before the write
+pub async fn track(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  before: u64,
+) -> Result<(), AppError> {
+  let after = size_of(file_root, user, file).await?;
+  charge(file_root, user, file, after as i64 - before as i64)
+}
+
+/// size of a file or directory on local disk, for jobs working on host paths
+pub fn local_tree_size(path: &Path) -> u64 {
+  walkdir::WalkDir::new(path)
+    .into_iter()
+    .filter_map(|e| e.ok())
+    .filter(|e| e.file_type().is_file())
+    .filter_map(|e| e.metadata().ok())
+    .map(|m| m.len())
+    .sum()
+}
+
+/// usage and quota of every user
+pub async fn list_usage(file_root: &PathBuf, refresh: bool) -> Result<Vec<UserUsage>, AppError> {
+  let rows = {
+    use crate::schema::{groups, users};
+    use diesel::prelude::*;
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    users::table
+      .inner_join(groups::table)
+      .select((
+        users::username,
+        users::user_root,
+        users::group_name,
+        users::quota,
+        groups::quota,
+      ))
+      .load::<(String, String, String, Option<i64>, Option<i64>)>(&mut *conn)?
+  };
+  let mut r = vec![];
+  for (name, user_root, group_name, user_quota, group_quota) in rows {
+    let user = UserSessionData::new(&name, &user_root);
+    let used_ = if refresh {
+      recalculate(file_root, &user).await?
+    } else {
+      usage(file_root, &user).await?
+    };
+    r.push(UserUsage {
+      username: name,
+      group_name,
+      used: used_,
+      quota: user_quota.or(group_quota),
+    });
+  }
+  Ok(r)
+}
+
+pub fn set_user_quota(name: &str, quota_: Option<i64>) -> Result<bool, AppError> {
+  use crate::schema::users::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = diesel::update(users.filter(username.eq(name)))
+    .set(quota.eq(quota_))
+    .execute(&mut *conn)?;
+  Ok(r > 0)
+}
+
+pub fn set_group_quota(group: &str, quota_: Option<i64>) -> Result<bool, AppError> {
+  use crate::schema::groups::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = diesel::update(groups.filter(name.eq(group)))
+    .set(quota.eq(quota_))
+    .execute(&mut *conn)?;
+  Ok(r > 0)
+}
+
+#[cfg(test)]
+mod tests {
+  use actix_web::web::Bytes;
+
+  use super::*;
+  use crate::models::{NewGroup, NewUser};
+  use crate::utils::test_utils::{init_db, temp_dir};
+  use crate::utils::vfs;
+
+  fn add_user(name: &str, root: &str, quota_: Option<i64>) -> UserSessionData {
+    use diesel::prelude::*;
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    diesel::insert_into(crate::schema::groups::table)
+      .values(NewGroup {
+        name: name.to_owned(),
+        desc: String::new(),
+        permissions: "fs_read,fs_write".to_owned(),
+      })
+      .execute(&mut *conn)
+      .unwrap();
+    diesel::insert_into(crate::schema::users::table)
+      .values(NewUser {
+        username: name,
+        password: "",
+        email: "",
+        user_type: 0,
+        user_root: root,
+        group_name: name,
+        must_change_password: false,
+      })
+      .execute(&mut *conn)
+      .unwrap();
+    {
+      use crate::schema::users::dsl::*;
+      diesel::update(users.filter(username.eq(name)))
+        .set(quota.eq(quota_))
+        .execute(&mut *conn)
+        .unwrap();
+    }
+    UserSessionData::new(name, root)
+  }
+
+  fn roots(list: &[(&str, &str)]) -> Vec<(String, String)> {
+    let mut r = list
+      .iter()
+      .map(|(name, root)| (name.to_string(), root.to_string()))
+      .collect::<Vec<_>>();
+    r.sort_by_key(|(_, root)| std::cmp::Reverse(split_root(root).1.components().count()));
+    r
+  }
+
+  fn owner_name(roots: &[(String, String)], path: &str) -> Option<String> {
+    find_owner(roots, path).map(|(name, _)| name.clone())
+  }
+
+  #[test]
+  fn nested_root_wins() {
+    let roots = roots(&[
+      ("admin", ""),
+      ("alice", "users/alice"),
+      ("bob", "minio://bob"),
+    ]);
+    assert_eq!(
+      owner_name(&roots, "users/alice/a.txt").as_deref(),
+      Some("alice")
+    );
+    assert_eq!(
+      owner_name(&roots, "users/alice2/a.txt").as_deref(),
+      Some("admin")
+    );
+    assert_eq!(
+      owner_name(&roots, "minio://bob/a.txt").as_deref(),
+      Some("bob")
+    );
+  }
+
+  #[test]
+  fn paths_outside_of_roots_are_not_charged() {
+    let roots = roots(&[("admin", ""), ("bob", "minio://bob")]);
+    assert_eq!(owner_name(&roots, "/mnt/disk/a.txt"), None);
+    assert_eq!(owner_name(&roots, "minio://shared/a.txt"), None);
+    assert_eq!(owner_name(&roots, "other://bob/a.txt"), None);
+  }
+
+  #[tokio::test]
+  async fn nested_roots_are_counted_for_their_users() {
+    init_db();
+    let file_root = temp_dir("quota-nested");
+    std::fs::create_dir_all(file_root.join("outer/users/inner")).unwrap();
+    std::fs::write(file_root.join("outer/a.txt"), "abc").unwrap();
+    std::fs::write(file_root.join("outer/users/inner/b.txt"), "defg").unwrap();
+    let outer = add_user("quota-nested-outer", "outer", None);
+    let inner = add_user("quota-nested-inner", "outer/users/inner", None);
+    assert_eq!(recalculate(&file_root, &outer).await.unwrap(), 3);
+    assert_eq!(recalculate(&file_root, &inner).await.unwrap(), 4);
+  }
+
+  #[tokio::test]
+  async fn stream_over_quota_fails_and_keeps_the_old_content() {
+    init_db();
+    let dir = temp_dir("quota-stream");
+    let user_root = dir.join("root");
+    std::fs::create_dir_all(&user_root).unwrap();
+    std::fs::write(user_root.join("a.txt"), "hello").unwrap();
+    let user = add_user("quota-stream", &user_root.to_string_lossy(), Some(10));
+    let file_root = dir.join("files");
+    let stream =
+      |n: usize| futures::stream::iter(vec![Ok::<_, std::io::Error>(Bytes::from(vec![b'x'; n]))]);
+
+    // the replaced 5 bytes are freed, 10 bytes fit
+    let err = vfs::write_stream(&file_root, &user, "a.txt", stream(11))
+      .await
+      .unwrap_err();
+    assert_eq!(err.status_code, StatusCode::INSUFFICIENT_STORAGE);
+    assert_eq!(
+      std::fs::read_to_string(user_root.join("a.txt")).unwrap(),
+      "hello"
+    );
+    assert_eq!(std::fs::read_dir(&user_root).unwrap().count(), 1);
+
+    vfs::write_stream(&file_root, &user, "a.txt", stream(10))
+      .await
+      .unwrap();
+    assert_eq!(usage(&file_root, &user).await.unwrap(), 10);
+  }
+}
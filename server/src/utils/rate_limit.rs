@@ -0,0 +1,285 @@
+/// Rate limiting of authentication attempts
+///
+/// Failures are counted per action for the client ip and for the username tried. A few
+/// failures are free, after that every failure locks the key twice as long as the one
+/// before, up to `MAX_LOCK_SECS`. Every failure is also written to `login_failures`, where it
+/// is kept as long as it counts.
+use std::{
+  collections::HashMap,
+  sync::Mutex,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::http::StatusCode;
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::{db::SHARED_DB_CONN, models::LoginFailure};
+
+use super::error::AppError;
+
+const USER_FREE_FAILURES: u32 = 5;
+/// users behind a NAT share an ip
+const IP_FREE_FAILURES: u32 = 20;
+const BASE_LOCK_SECS: i64 = 30;
+const MAX_LOCK_SECS: i64 = 60 * 60;
+/// failures are forgotten after this long without a new one
+const FORGET_AFTER_SECS: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+  Login,
+  EnableOtp,
+  OneTimeToken,
+  ResetPassword,
+  /// counted per link instead of per user
+  SharePassword,
+}
+
+impl Action {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Login => "login",
+      Self::EnableOtp => "enable_otp",
+      Self::OneTimeToken => "one_time_token",
+      Self::ResetPassword => "reset_password",
+      Self::SharePassword => "share_password",
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Lockout {
+  /// `<action>:ip:<ip>` or `<action>:user:<username>`
+  pub key: String,
+  pub failures: u32,
+  pub last_failure: i64,
+  /// 0 if the key has never been locked
+  pub locked_until: i64,
+}
+
+lazy_static! {
+  static ref LOCKOUTS: Mutex<HashMap<String, Lockout>> = Mutex::new(HashMap::new());
+}
+
+fn now_secs() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn user_key(action: Action, user: &str) -> String {
+  format!("{}:user:{user}", action.as_str())
+}
+
+/// keys counting an attempt, with the failures allowed before locking
+fn keys(action: Action, ip: &str, user: Option<&str>) -> Vec<(String, u32)> {
+  let mut r = vec![(format!("{}:ip:{ip}", action.as_str()), IP_FREE_FAILURES)];
+  if let Some(user) = user {
+    r.push((user_key(action, user), USER_FREE_FAILURES));
+  }
+  r
+}
+
+/// fails with 429 while the ip or the user is locked for `action`
+pub fn check(action: Action, ip: &str, user: Option<&str>) -> Result<(), AppError> {
+  let now = now_secs();
+  let lockouts = LOCKOUTS.lock().unwrap();
+  let locked_until = keys(action, ip, user)
+    .iter()
+    .filter_map(|(key, _)| lockouts.get(key))
+    .map(|l| l.locked_until)
+    .max()
+    .unwrap_or(0);
+  if locked_until > now {
+    return Err(
+      AppError::new(&format!(
+        "too many failed attempts, retry in {} seconds",
+        locked_until - now
+      ))
+      .with_status(StatusCode::TOO_MANY_REQUESTS),
+    );
+  }
+  Ok(())
+}
+
+/// count a failed attempt and write it to the audit trail
+pub fn fail(action: Action, ip: &str, user: Option<&str>, reason: &str) -> Result<(), AppError> {
+  let now = now_secs();
+  {
+    let mut lockouts = LOCKOUTS.lock().unwrap();
+    lockouts.retain(|_, l| now - l.last_failure < FORGET_AFTER_SECS);
+    for (key, free) in keys(action, ip, user) {
+      let l = lockouts.entry(key.clone()).or_insert(Lockout {
+        key,
+        failures: 0,
+        last_failure: now,
+        locked_until: 0,
+      });
+      l.failures += 1;
+      l.last_failure = now;
+      if l.failures > free {
+        let shift = (l.failures - free - 1).min(16);
+        l.locked_until = now + (BASE_LOCK_SECS << shift).min(MAX_LOCK_SECS);
+      }
+    }
+  }
+  tracing::warn!(
+    "{} failed - CLIENT IP: {ip}, USER: {}, {reason}",
+    action.as_str(),
+    user.unwrap_or("-")
+  );
+  insert_failure(&LoginFailure {
+    id: uuid::Uuid::new_v4().to_string(),
+    action: action.as_str().to_owned(),
+    username: user.map(|u| u.to_owned()),
+    ip: ip.to_owned(),
+    reason: reason.to_owned(),
+    created_at: now,
+  })
+}
+
+fn insert_failure(failure: &LoginFailure) -> Result<(), AppError> {
+  use crate::schema::login_failures::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::insert_into(login_failures)
+    .values(failure)
+    .execute(&mut *conn)?;
+  Ok(())
+}
+
+/// forget failures of `user` once it gets through, failures of the ip are kept
+pub fn succeed(action: Action, user: &str) {
+  LOCKOUTS.lock().unwrap().remove(&user_key(action, user));
+}
+
+/// counted failures, most recent first
+pub fn lockouts() -> Vec<Lockout> {
+  let now = now_secs();
+  let mut r = LOCKOUTS
+    .lock()
+    .unwrap()
+    .values()
+    .filter(|l| now - l.last_failure < FORGET_AFTER_SECS)
+    .cloned()
+    .collect::<Vec<_>>();
+  r.sort_by_key(|l| -l.last_failure);
+  r
+}
+
+/// clear one key, or every key if `key` is None, returns the number of keys cleared
+pub fn clear(key: Option<&str>) -> usize {
+  let mut lockouts = LOCKOUTS.lock().unwrap();
+  match key {
+    Some(key) => lockouts.remove(key).map_or(0, |_| 1),
+    None => {
+      let count = lockouts.len();
+      lockouts.clear();
+      count
+    }
+  }
+}
+
+/// forget failures older than `FORGET_AFTER_SECS`, returns how many were removed from the audit trail
+pub fn purge_expired() -> Result<usize, AppError> {
+  let cutoff = now_secs() - FORGET_AFTER_SECS;
+  LOCKOUTS
+    .lock()
+    .unwrap()
+    .retain(|_, l| l.last_failure > cutoff);
+  use crate::schema::login_failures::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = diesel::delete(login_failures.filter(created_at.le(cutoff))).execute(&mut *conn)?;
+  Ok(r)
+}
+
+/// latest failures in the audit trail, optionally of one user
+pub fn audit(user: Option<&str>, limit: i64) -> Result<Vec<LoginFailure>, AppError> {
+  use crate::schema::login_failures::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let mut query = login_failures
+    .order(created_at.desc())
+    .limit(limit)
+    .into_boxed();
+  if let Some(user) = user {
+    query = query.filter(username.eq(user));
+  }
+  Ok(query.load::<LoginFailure>(&mut *conn)?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::test_utils::init_db;
+
+  fn locked_for(key: &str) -> i64 {
+    let l = lockouts().into_iter().find(|l| l.key == key).unwrap();
+    l.locked_until - l.last_failure
+  }
+
+  #[test]
+  fn lock_doubles_after_free_failures() {
+    init_db();
+    let (ip, user) = ("10.0.0.1", "rate-limit-escalation");
+    let key = user_key(Action::Login, user);
+    for _ in 0..USER_FREE_FAILURES {
+      check(Action::Login, ip, Some(user)).unwrap();
+      fail(Action::Login, ip, Some(user), "test").unwrap();
+    }
+    assert_eq!(locked_for(&key), 0);
+
+    fail(Action::Login, ip, Some(user), "test").unwrap();
+    assert_eq!(locked_for(&key), BASE_LOCK_SECS);
+    let err = check(Action::Login, ip, Some(user)).unwrap_err();
+    assert_eq!(err.status_code, StatusCode::TOO_MANY_REQUESTS);
+    // another user from the same ip is not locked yet
+    check(Action::Login, ip, Some("rate-limit-other")).unwrap();
+
+    fail(Action::Login, ip, Some(user), "test").unwrap();
+    assert_eq!(locked_for(&key), BASE_LOCK_SECS * 2);
+    for _ in 0..20 {
+      fail(Action::Login, ip, Some(user), "test").unwrap();
+    }
+    assert_eq!(locked_for(&key), MAX_LOCK_SECS);
+  }
+
+  #[test]
+  fn success_forgets_the_user_but_not_the_ip() {
+    init_db();
+    let (ip, user) = ("10.0.0.2", "rate-limit-success");
+    for _ in 0..IP_FREE_FAILURES + 1 {
+      fail(Action::Login, ip, Some(user), "test").unwrap();
+    }
+    succeed(Action::Login, user);
+    assert!(lockouts()
+      .iter()
+      .all(|l| l.key != user_key(Action::Login, user)));
+    let err = check(Action::Login, ip, Some("rate-limit-success-other")).unwrap_err();
+    assert_eq!(err.status_code, StatusCode::TOO_MANY_REQUESTS);
+    // an action does not lock the others
+    check(Action::ResetPassword, ip, None).unwrap();
+  }
+
+  #[test]
+  fn old_failures_are_purged() {
+    init_db();
+    let user = "rate-limit-purge";
+    let old = LoginFailure {
+      id: uuid::Uuid::new_v4().to_string(),
+      action: Action::Login.as_str().to_owned(),
+      username: Some(user.to_owned()),
+      ip: "10.0.0.3".to_owned(),
+      reason: "test".to_owned(),
+      created_at: now_secs() - FORGET_AFTER_SECS - 1,
+    };
+    insert_failure(&old).unwrap();
+    fail(Action::Login, "10.0.0.3", Some(user), "test").unwrap();
+    assert!(purge_expired().unwrap() >= 1);
+    let left = audit(Some(user), 10).unwrap();
+    assert_eq!(left.len(), 1);
+    assert_ne!(left[0].id, old.id);
+  }
+}
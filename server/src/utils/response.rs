@@ -1,9 +1,10 @@
 use actix_web::{HttpResponse, http::header};
 use percent_encoding::{AsciiSet, CONTROLS};
 use serde::Serialize;
-use tokio::{fs::File, io::AsyncRead};
+use tokio::io::AsyncRead;
 use tokio_util::io::ReaderStream;
 
+use super::storage::VfsReader;
 use super::stream::RangeStream;
 
 pub enum AppResponseStatus {
@@ -77,7 +78,7 @@ pub fn create_binary_resp(data: Vec<u8>, mime_type: Option<String>, expires: Opt
 }
 
 pub fn create_stream_resp(
-  stream: RangeStream<ReaderStream<File>>,
+  stream: RangeStream<ReaderStream<VfsReader>>,
   mime_type: Option<String>,
   download_name: Option<&str>,
   range: (u64, u64),
@@ -183,6 +183,24 @@ pub fn delete(files: &Vec<String>) -> Result<(), AppError> {
   Ok(())
 }
 
+/// delete docs of `files` with a single commit, the path of a directory also matches its children
+pub fn delete_paths(files: &Vec<String>) -> Result<(), AppError> {
+  let index = SEARCH_INDEX.lock().unwrap();
+  let schema = index.schema();
+  let path = schema.get_field("path").unwrap();
+
+  let mut index_writer = index.writer(10_000_000)?;
+
+  let query_parser = QueryParser::for_index(&index, vec![path]);
+  for f in files {
+    let query = query_parser.parse_query(&format!(r#""{}""#, f.replace('"', " ")))?;
+    index_writer.delete_query(query)?;
+  }
+
+  index_writer.commit()?;
+  Ok(())
+}
+
 #[allow(unused)]
 pub fn cleanup_by_path(file_path: &str) -> Result<(), AppError> {
   let index = SEARCH_INDEX.lock().unwrap();
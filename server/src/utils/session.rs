@@ -1,9 +1,101 @@
 use actix_session::Session;
+use actix_web::{http::header, HttpRequest};
+use serde::{Deserialize, Serialize};
+use std::{
+  net::IpAddr,
+  time::{SystemTime, UNIX_EPOCH},
+};
 
-use crate::UserSessionData;
+use crate::{config, UserSessionData};
 
 use super::error::AppError;
 
+/// key of `DeviceInfo` in session state
+pub const DEVICE_KEY: &str = "device";
+
+/// set in the session of a request authenticated by its own credentials, e.g. DAV basic auth,
+/// such sessions are not stored
+pub const TRANSIENT_KEY: &str = "transient";
+
+/// last seen time is written at most once in this interval
+const LAST_SEEN_INTERVAL_SECS: i64 = 60;
+
+/// device a session is used from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceInfo {
+  /// public id of the session, the session key itself only lives in the cookie
+  pub id: String,
+  pub user_agent: String,
+  pub created_at: i64,
+  pub last_seen: i64,
+}
+
+impl DeviceInfo {
+  pub fn new(user_agent: &str) -> Self {
+    let now = now_secs();
+    Self {
+      id: uuid::Uuid::new_v4().to_string(),
+      user_agent: user_agent.to_owned(),
+      created_at: now,
+      last_seen: now,
+    }
+  }
+}
+
+fn now_secs() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs() as i64)
+}
+
+pub fn user_agent(req: &HttpRequest) -> String {
+  req
+    .headers()
+    .get(header::USER_AGENT)
+    .and_then(|v| v.to_str().ok())
+    .unwrap_or("unknown")
+    .to_owned()
+}
+
+fn is_trusted_proxy(ip: &IpAddr) -> bool {
+  config!(trusted_proxies)
+    .iter()
+    .any(|proxy| proxy.parse::<IpAddr>().map_or(false, |proxy| &proxy == ip))
+}
+
+/// address of the client, `X-Forwarded-For` is only believed when sent by a trusted proxy
+///
+/// the header is read from the right, proxies append the address they got the request from,
+/// the first address not of a trusted proxy is the client, the ones before it may be forged
+pub fn client_ip(req: &HttpRequest) -> String {
+  let peer = match req.peer_addr() {
+    Some(peer) => peer.ip(),
+    None => return "unknown".to_owned(),
+  };
+  if !is_trusted_proxy(&peer) {
+    return peer.to_string();
+  }
+  let forwarded = req
+    .headers()
+    .get_all("x-forwarded-for")
+    .filter_map(|v| v.to_str().ok())
+    .collect::<Vec<_>>()
+    .join(",");
+  let mut client = peer;
+  for hop in forwarded.rsplit(',') {
+    match hop.trim().parse::<IpAddr>() {
+      Ok(ip) => {
+        client = ip;
+        if !is_trusted_proxy(&ip) {
+          break;
+        }
+      }
+      Err(_) => break,
+    }
+  }
+  client.to_string()
+}
+
 pub fn is_login(sess: &Session) -> Result<bool, AppError> {
   let user_data = sess.get::<UserSessionData>("user")?;
   if let Some(user_data) = user_data {
@@ -26,6 +118,11 @@ pub trait SessionUtils {
   fn get_user_root(&self) -> Result<String, AppError>;
   fn is_csrf_token_valid(&self, csrf_token: &str) -> Result<bool, AppError>;
   fn set_csrf_token(&mut self, csrf_token: &str) -> Result<bool, AppError>;
+  fn get_device(&self) -> Result<Option<DeviceInfo>, AppError>;
+  /// record a new device for this session, called on login
+  fn start_device(&self, user_agent: &str) -> Result<DeviceInfo, AppError>;
+  /// update last seen time of the device, sessions from before devices were recorded get one
+  fn touch_device(&self, user_agent: &str) -> Result<(), AppError>;
 }
 
 impl SessionUtils for Session {
@@ -50,4 +147,27 @@ impl SessionUtils for Session {
     let data = self.get_user_data()?;
     Ok(data.user_root)
   }
+  fn get_device(&self) -> Result<Option<DeviceInfo>, AppError> {
+    Ok(self.get::<DeviceInfo>(DEVICE_KEY)?)
+  }
+  fn start_device(&self, user_agent: &str) -> Result<DeviceInfo, AppError> {
+    let device = DeviceInfo::new(user_agent);
+    self.insert(DEVICE_KEY, device.clone())?;
+    Ok(device)
+  }
+  fn touch_device(&self, user_agent: &str) -> Result<(), AppError> {
+    match self.get_device()? {
+      Some(mut device) => {
+        let now = now_secs();
+        if now - device.last_seen >= LAST_SEEN_INTERVAL_SECS {
+          device.last_seen = now;
+          self.insert(DEVICE_KEY, device)?;
+        }
+      }
+      None => {
+        self.start_device(user_agent)?;
+      }
+    }
+    Ok(())
+  }
 }
@@ -0,0 +1,321 @@
+/// Public share links
+///
+/// A share link exposes a file or folder of its owner at `/s/{id}` to anyone knowing the id,
+/// optionally protected by a password, an expiry time and a download limit.
+use std::{
+  path::{Component, PathBuf},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{http::StatusCode, web::block};
+
+use crate::{db::SHARED_DB_CONN, models::ShareLink, models::User, UserSessionData};
+
+use super::acl::SHARED_DIR;
+use super::crypto::{hash_pwd, verify_pwd};
+use super::error::AppError;
+use super::mount::get_mounts;
+use super::path::secure_join;
+use super::permission::{self, Permission};
+use super::rate_limit::{self, Action};
+use super::trash::TRASH_DIR;
+use super::versions::VERSIONS_DIR;
+use super::vfs;
+
+pub struct NewShareLink {
+  pub path: String,
+  pub password: Option<String>,
+  pub expires_at: Option<i64>,
+  pub max_downloads: Option<i32>,
+  pub allow_upload: bool,
+}
+
+fn now_secs() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn not_found() -> AppError {
+  AppError::new("share link not found or expired").with_status(StatusCode::NOT_FOUND)
+}
+
+/// a link can not expose the whole root, directories shared by other users, mounts,
+/// or the trash and versions of its owner
+fn check_path(owner: &str, path: &str) -> Result<(), AppError> {
+  let bad_request = |msg: &str| AppError::new(msg).with_status(StatusCode::BAD_REQUEST);
+  let first = match PathBuf::from(path).components().next() {
+    Some(Component::Normal(first)) => first.to_string_lossy().to_string(),
+    _ => return Err(bad_request("share link: can not share user root")),
+  };
+  if first == SHARED_DIR
+    || first == TRASH_DIR
+    || first == VERSIONS_DIR
+    || get_mounts(owner)?.iter().any(|m| m.prefix == first)
+  {
+    return Err(bad_request(&format!("share link: can not share {path}")));
+  }
+  Ok(())
+}
+
+pub async fn create(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  new_link: NewShareLink,
+) -> Result<ShareLink, AppError> {
+  let path = secure_join(&PathBuf::new(), &PathBuf::from(&new_link.path))?;
+  let path = path.to_string_lossy().to_string();
+  check_path(&user.username, &path)?;
+  let file_stat = vfs::stat(file_root, user, &path).await?;
+  if new_link.allow_upload && !file_stat.is_dir {
+    return Err(
+      AppError::new("share link: upload is only allowed for folders")
+        .with_status(StatusCode::BAD_REQUEST),
+    );
+  }
+  let link = ShareLink {
+    id: uuid::Uuid::new_v4().simple().to_string(),
+    username: user.username.clone(),
+    path,
+    password: new_link
+      .password
+      .filter(|p| !p.is_empty())
+      .map(|p| hash_pwd(&p))
+      .transpose()?,
+    expires_at: new_link.expires_at,
+    max_downloads: new_link.max_downloads,
+    download_count: 0,
+    allow_upload: new_link.allow_upload,
+    created_at: now_secs(),
+  };
+
+  use crate::schema::share_links::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::insert_into(share_links)
+    .values(&link)
+    .execute(&mut *conn)?;
+  Ok(link)
+}
+
+pub fn list(user: &str) -> Result<Vec<ShareLink>, AppError> {
+  use crate::schema::share_links::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let links = share_links
+    .filter(username.eq(user))
+    .order(created_at.desc())
+    .load::<ShareLink>(&mut *conn)?;
+  Ok(links)
+}
+
+pub fn delete(user: &str, link_id: &str) -> Result<bool, AppError> {
+  use crate::schema::share_links::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = diesel::delete(share_links.filter(username.eq(user).and(id.eq(link_id))))
+    .execute(&mut *conn)?;
+  Ok(r > 0)
+}
+
+/// the link and its owner
+fn load(link_id: &str) -> Result<(ShareLink, User), AppError> {
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let link = {
+    use crate::schema::share_links::dsl::*;
+    share_links
+      .filter(id.eq(link_id))
+      .first::<ShareLink>(&mut *conn)
+      .optional()?
+      .ok_or_else(not_found)?
+  };
+  let owner = {
+    use crate::schema::users::dsl::*;
+    users
+      .filter(username.eq(&link.username))
+      .first::<User>(&mut *conn)
+      .optional()?
+      .ok_or_else(not_found)?
+  };
+  Ok((link, owner))
+}
+
+/// check a link is usable with `pwd` sent from `ip`, returns the link and a session of its owner,
+/// wrong passwords are rate limited per ip and per link. Links of owners who were disabled
+/// or can no longer read files stop working.
+pub async fn open(
+  link_id: &str,
+  pwd: Option<&str>,
+  ip: &str,
+) -> Result<(ShareLink, UserSessionData), AppError> {
+  let (link, owner) = load(link_id)?;
+  if link.expires_at.map_or(false, |t| t <= now_secs()) {
+    return Err(not_found());
+  }
+  if owner.disabled || !permission::of_user(&owner.username)?.contains(&Permission::FsRead) {
+    return Err(not_found());
+  }
+  if let Some(hashed) = link.password.clone() {
+    let pwd = pwd.map(|p| p.to_owned()).ok_or_else(|| {
+      AppError::new("share link: password required").with_status(StatusCode::UNAUTHORIZED)
+    })?;
+    rate_limit::check(Action::SharePassword, ip, Some(&link.id))?;
+    // argon2 takes a while, it must not block the worker
+    if !block(move || verify_pwd(&pwd, &hashed)).await? {
+      rate_limit::fail(
+        Action::SharePassword,
+        ip,
+        Some(&link.id),
+        "wrong share password",
+      )?;
+      return Err(
+        AppError::new("share link: wrong password").with_status(StatusCode::UNAUTHORIZED),
+      );
+    }
+    rate_limit::succeed(Action::SharePassword, &link.id);
+  }
+  let owner = UserSessionData::new(&owner.username, &owner.user_root);
+  Ok((link, owner))
+}
+
+/// path in the owner root of `file`, which is relative to the shared path
+pub fn file_in_link(link: &ShareLink, file: &str) -> Result<String, AppError> {
+  let path = secure_join(&PathBuf::from(&link.path), &PathBuf::from(file))?;
+  let path = path.to_string_lossy().to_string();
+  // links created before paths were checked may point to the root
+  check_path(&link.username, &path)?;
+  Ok(path)
+}
+
+/// count a download of the link, fails when the download limit is reached
+pub fn count_download(link: &ShareLink) -> Result<(), AppError> {
+  use crate::schema::share_links::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = diesel::update(
+    share_links.filter(
+      id.eq(&link.id).and(
+        max_downloads
+          .is_null()
+          .or(download_count.lt(max_downloads.assume_not_null())),
+      ),
+    ),
+  )
+  .set(download_count.eq(download_count + 1))
+  .execute(&mut *conn)?;
+  if r == 0 {
+    return Err(AppError::new("share link: download limit reached").with_status(StatusCode::GONE));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::models::{Mount, NewGroup, NewUser};
+  use crate::utils::mount::add_mount;
+  use crate::utils::test_utils::{init_db, temp_dir};
+
+  fn add_user(name: &str, permissions: &str) {
+    use diesel::prelude::*;
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    diesel::insert_into(crate::schema::groups::table)
+      .values(NewGroup {
+        name: name.to_owned(),
+        desc: String::new(),
+        permissions: permissions.to_owned(),
+      })
+      .execute(&mut *conn)
+      .unwrap();
+    diesel::insert_into(crate::schema::users::table)
+      .values(NewUser {
+        username: name,
+        password: "",
+        email: "",
+        user_type: 0,
+        user_root: "",
+        group_name: name,
+        must_change_password: false,
+      })
+      .execute(&mut *conn)
+      .unwrap();
+  }
+
+  fn new_link(path: &str) -> NewShareLink {
+    NewShareLink {
+      path: path.to_owned(),
+      password: None,
+      expires_at: None,
+      max_downloads: None,
+      allow_upload: false,
+    }
+  }
+
+  #[tokio::test]
+  async fn root_and_reserved_directories_can_not_be_shared() {
+    init_db();
+    let dir = temp_dir("share-reserved");
+    let user_root = dir.join("root");
+    std::fs::create_dir_all(user_root.join("docs")).unwrap();
+    let user = UserSessionData::new("share-reserved", &user_root.to_string_lossy());
+    add_mount(Mount {
+      username: user.username.clone(),
+      prefix: "disk".to_owned(),
+      target: dir.join("disk").to_string_lossy().to_string(),
+      read_only: false,
+    })
+    .unwrap();
+    let file_root = dir.join("files");
+
+    for path in [
+      "",
+      ".",
+      SHARED_DIR,
+      TRASH_DIR,
+      ".versions/a.txt",
+      "disk",
+      "disk/a",
+    ] {
+      let err = create(&file_root, &user, new_link(path)).await.unwrap_err();
+      assert_eq!(err.status_code, StatusCode::BAD_REQUEST, "{path}");
+    }
+    let link = create(&file_root, &user, new_link("docs")).await.unwrap();
+    assert_eq!(file_in_link(&link, "a.txt").unwrap(), "docs/a.txt");
+  }
+
+  #[tokio::test]
+  async fn links_of_disabled_owners_stop_working() {
+    init_db();
+    add_user("share-disabled", "fs_read");
+    add_user("share-no-read", "kv");
+    for owner in ["share-disabled", "share-no-read"] {
+      let link = ShareLink {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        username: owner.to_owned(),
+        path: "docs".to_owned(),
+        password: None,
+        expires_at: None,
+        max_downloads: None,
+        download_count: 0,
+        allow_upload: false,
+        created_at: now_secs(),
+      };
+      {
+        use crate::schema::share_links::dsl::*;
+        use diesel::prelude::*;
+        let mut conn = SHARED_DB_CONN.lock().unwrap();
+        diesel::insert_into(share_links)
+          .values(&link)
+          .execute(&mut *conn)
+          .unwrap();
+      }
+      if owner == "share-disabled" {
+        assert!(open(&link.id, None, "10.6.0.1").await.is_ok());
+        crate::utils::auth::set_disabled(owner, true).unwrap();
+      }
+      let err = open(&link.id, None, "10.6.0.1").await.unwrap_err();
+      assert_eq!(err.status_code, StatusCode::NOT_FOUND, "{owner}");
+    }
+  }
+}
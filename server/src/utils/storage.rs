@@ -0,0 +1,358 @@
+/// Storage backends used by the virtual file system.
+///
+/// All paths passed to a backend are relative to the backend root and have
+/// already been checked by `secure_join`, so backends never see `..` or absolute paths.
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  pin::Pin,
+  sync::{Arc, RwLock},
+};
+
+use actix_web::http::StatusCode;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::config;
+use crate::config::StorageConfig;
+use crate::UserSessionData;
+
+use super::acl::resolve_shared;
+use super::error::AppError;
+use super::mount::find_mount;
+use super::path::secure_join;
+use super::trash::TRASH_DIR;
+use super::versions::VERSIONS_DIR;
+use super::vfs::{FileStat, FileStatWithName};
+
+pub mod local;
+pub mod memory;
+#[cfg(feature = "s3")]
+pub mod s3;
+
+pub type VfsReader = Pin<Box<dyn AsyncRead + Send>>;
+
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+  async fn read_dir(&self, dir: &Path) -> Result<Vec<FileStatWithName>, AppError>;
+  async fn stat(&self, file: &Path) -> Result<FileStat, AppError>;
+  async fn create_dir(&self, dir: &Path) -> Result<(), AppError>;
+  /// remove a file, or a directory with everything in it
+  async fn remove(&self, file: &Path) -> Result<(), AppError>;
+  async fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError>;
+  async fn copy(&self, from: &Path, to: &Path) -> Result<u64, AppError>;
+  /// read bytes in the inclusive `range`, the reader may yield more than requested
+  async fn read(&self, file: &Path, range: (u64, u64)) -> Result<VfsReader, AppError>;
+  /// create or replace a file, parent directories are created if needed
+  async fn write(&self, file: &Path, reader: VfsReader) -> Result<u64, AppError>;
+  /// path on the host file system, only available for local backends
+  fn local_path(&self, _file: &Path) -> Option<PathBuf> {
+    None
+  }
+}
+
+/// read the whole `size` bytes of a file, empty files are not requested at all,
+/// a range can not be empty and S3 answers 416 to `bytes=0-0` of an empty object
+pub async fn read_all(
+  backend: &Arc<dyn StorageBackend>,
+  file: &Path,
+  size: u64,
+) -> Result<VfsReader, AppError> {
+  if size == 0 {
+    return Ok(Box::pin(tokio::io::empty()));
+  }
+  let reader = backend.read(file, (0, size - 1)).await?;
+  Ok(Box::pin(reader.take(size)))
+}
+
+lazy_static! {
+  pub static ref STORAGE_BACKENDS: RwLock<HashMap<String, Arc<dyn StorageBackend>>> =
+    RwLock::new(HashMap::new());
+}
+
+pub fn create_backend(
+  name: &str,
+  conf: &StorageConfig,
+) -> Result<Arc<dyn StorageBackend>, AppError> {
+  let backend: Arc<dyn StorageBackend> = match conf.kind.as_str() {
+    "local" => {
+      let root = conf
+        .root
+        .clone()
+        .ok_or_else(|| AppError::new(&format!("storage {name}: root is required")))?;
+      Arc::new(local::LocalStorage::new(PathBuf::from(root)))
+    }
+    "memory" => Arc::new(memory::MemoryStorage::new()),
+    #[cfg(feature = "s3")]
+    "s3" => Arc::new(s3::S3Storage::new(name, conf)?),
+    #[cfg(not(feature = "s3"))]
+    "s3" => {
+      return Err(AppError::new(&format!(
+        "storage {name}: s3 support is not built in, build with --features s3"
+      )))
+    }
+    kind => {
+      return Err(AppError::new(&format!(
+        "storage {name}: unsupported storage type {kind}"
+      )))
+    }
+  };
+  Ok(backend)
+}
+
+/// register every backend declared in config
+pub fn init_storage_backends() -> Result<(), AppError> {
+  let mut backends = STORAGE_BACKENDS.write().unwrap();
+  for (name, conf) in config!(storage).iter() {
+    backends.insert(name.clone(), create_backend(name, conf)?);
+  }
+  Ok(())
+}
+
+pub fn get_backend(name: &str) -> Result<Arc<dyn StorageBackend>, AppError> {
+  STORAGE_BACKENDS
+    .read()
+    .unwrap()
+    .get(name)
+    .cloned()
+    .ok_or_else(|| AppError::new(&format!("storage backend not found: {name}")))
+}
+
+/// a user path resolved to the storage it lives in
+pub struct ResolvedPath {
+  pub backend: Arc<dyn StorageBackend>,
+  /// path relative to the backend root
+  pub path: PathBuf,
+  /// prefix of the mount containing the path, None for files in the user root
+  pub mount: Option<String>,
+  pub read_only: bool,
+  /// for paths in `Shared with me`, the owner of the shared directory
+  pub shared: Option<SharedOrigin>,
+}
+
+/// where a shared path lives from the view of its owner
+pub struct SharedOrigin {
+  pub owner: String,
+  pub owner_root: String,
+  /// path in the owner root
+  pub file: String,
+}
+
+impl SharedOrigin {
+  pub fn owner(&self) -> UserSessionData {
+    UserSessionData::new(&self.owner, &self.owner_root)
+  }
+}
+
+impl ResolvedPath {
+  pub fn writable(self) -> Result<Self, AppError> {
+    if self.read_only {
+      return Err(
+        AppError::new("permission denied: read only mount").with_status(StatusCode::FORBIDDEN),
+      );
+    }
+    Ok(self)
+  }
+
+  pub fn same_storage(&self, other: &ResolvedPath) -> bool {
+    self.mount == other.mount
+  }
+}
+
+/// open the backend of a root and return the backend relative path of the root
+///
+/// a root like `minio://alice` lives in the configured backend `minio`,
+/// an absolute root is a directory on the host, other roots are directories under `file_root`
+pub fn open_root(
+  file_root: &PathBuf,
+  root: &str,
+) -> Result<(Arc<dyn StorageBackend>, PathBuf), AppError> {
+  if let Some((name, root)) = root.split_once("://") {
+    let path = secure_join(&PathBuf::new(), &PathBuf::from(root))?;
+    return Ok((get_backend(name)?, path));
+  }
+  let root = PathBuf::from(root);
+  if root.has_root() {
+    return Ok((Arc::new(local::LocalStorage::new(root)), PathBuf::new()));
+  }
+  Ok((Arc::new(local::LocalStorage::new(file_root.clone())), root))
+}
+
+/// find the storage of a path sent by `user`, the trash and versions in user root and the
+/// trash of mounts are refused, they are only managed by their own modules
+pub fn resolve(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+) -> Result<ResolvedPath, AppError> {
+  let target = resolve_internal(file_root, user, file)?;
+  let file = secure_join(&PathBuf::new(), &PathBuf::from(file))?;
+  let mut names = file.components().map(|c| c.as_os_str());
+  let reserved = match (&target.shared, &target.mount) {
+    // the path in the owner root was checked when it was resolved
+    (Some(_), _) => false,
+    (None, Some(_)) => names.nth(1).map_or(false, |name| name == TRASH_DIR),
+    (None, None) => names
+      .next()
+      .map_or(false, |name| name == TRASH_DIR || name == VERSIONS_DIR),
+  };
+  if reserved {
+    return Err(
+      AppError::new(&format!("{} is reserved", file.to_string_lossy()))
+        .with_status(StatusCode::FORBIDDEN),
+    );
+  }
+  Ok(target)
+}
+
+/// find the storage of `file` through the acls, the mount table and the root of `user`,
+/// reserved directories are not refused
+pub fn resolve_internal(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+) -> Result<ResolvedPath, AppError> {
+  let file = secure_join(&PathBuf::new(), &PathBuf::from(file))?;
+  if let Some(target) = resolve_shared(file_root, user, &file)? {
+    return Ok(target);
+  }
+  if let Some((mount, rest)) = find_mount(&user.username, &file)? {
+    let (backend, root) = open_root(file_root, &mount.target)?;
+    return Ok(ResolvedPath {
+      backend,
+      path: root.join(rest),
+      mount: Some(mount.prefix),
+      read_only: mount.read_only,
+      shared: None,
+    });
+  }
+  let (backend, root) = open_root(file_root, &user.user_root)?;
+  Ok(ResolvedPath {
+    backend,
+    path: root.join(file),
+    mount: None,
+    read_only: false,
+    shared: None,
+  })
+}
+
+pub fn not_found(file: &Path) -> AppError {
+  AppError::new(&format!("file not found: {}", file.to_string_lossy()))
+    .with_status(StatusCode::NOT_FOUND)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn reader(content: &[u8]) -> VfsReader {
+    Box::pin(std::io::Cursor::new(content.to_vec()))
+  }
+
+  async fn read_string(backend: &Arc<dyn StorageBackend>, file: &str) -> String {
+    let file = Path::new(file);
+    let size = backend.stat(file).await.unwrap().size;
+    let mut buf = String::new();
+    read_all(backend, file, size)
+      .await
+      .unwrap()
+      .read_to_string(&mut buf)
+      .await
+      .unwrap();
+    buf
+  }
+
+  #[test]
+  fn relative_root_is_under_file_root() {
+    let file_root = PathBuf::from("/srv/files");
+    let (backend, path) = open_root(&file_root, "users/alice").unwrap();
+    assert_eq!(path, PathBuf::from("users/alice"));
+    assert_eq!(
+      backend.local_path(&path.join("a.txt")),
+      Some(PathBuf::from("/srv/files/users/alice/a.txt"))
+    );
+  }
+
+  #[test]
+  fn absolute_root_is_on_the_host() {
+    let (backend, path) = open_root(&PathBuf::from("/srv/files"), "/mnt/disk").unwrap();
+    assert_eq!(path, PathBuf::new());
+    assert_eq!(
+      backend.local_path(Path::new("a.txt")),
+      Some(PathBuf::from("/mnt/disk/a.txt"))
+    );
+  }
+
+  #[test]
+  fn named_root_uses_the_registered_backend() {
+    STORAGE_BACKENDS.write().unwrap().insert(
+      "test-open-root".to_owned(),
+      Arc::new(memory::MemoryStorage::new()),
+    );
+    let file_root = PathBuf::from("/srv/files");
+    let (backend, path) = open_root(&file_root, "test-open-root://alice").unwrap();
+    assert_eq!(path, PathBuf::from("alice"));
+    assert!(backend.local_path(&path).is_none());
+
+    assert!(open_root(&file_root, "test-open-root://../bob").is_err());
+    assert!(open_root(&file_root, "missing-backend://alice").is_err());
+  }
+
+  #[tokio::test]
+  async fn read_all_of_empty_file() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(memory::MemoryStorage::new());
+    backend
+      .write(Path::new("empty.txt"), reader(b""))
+      .await
+      .unwrap();
+    backend
+      .write(Path::new("a.txt"), reader(b"hello"))
+      .await
+      .unwrap();
+    assert_eq!(read_string(&backend, "empty.txt").await, "");
+    assert_eq!(read_string(&backend, "a.txt").await, "hello");
+  }
+
+  #[tokio::test]
+  async fn memory_rename_moves_the_subtree() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(memory::MemoryStorage::new());
+    backend
+      .write(Path::new("dir/sub/a.txt"), reader(b"a"))
+      .await
+      .unwrap();
+    backend
+      .write(Path::new("dir-other/b.txt"), reader(b"b"))
+      .await
+      .unwrap();
+    backend
+      .rename(Path::new("dir"), Path::new("moved"))
+      .await
+      .unwrap();
+    assert!(backend.stat(Path::new("dir")).await.is_err());
+    assert_eq!(read_string(&backend, "moved/sub/a.txt").await, "a");
+    assert_eq!(read_string(&backend, "dir-other/b.txt").await, "b");
+  }
+
+  #[test]
+  fn trash_and_versions_are_refused() {
+    crate::utils::test_utils::init_db();
+    let user = UserSessionData::new("storage-reserved", "/srv/root");
+    crate::utils::mount::add_mount(crate::models::Mount {
+      username: user.username.clone(),
+      prefix: "disk".to_owned(),
+      target: "/mnt/disk".to_owned(),
+      read_only: false,
+    })
+    .unwrap();
+    let file_root = PathBuf::from("/srv/files");
+    for file in [".trash", ".trash/a", ".versions/a", "disk/.trash/a"] {
+      let err = resolve(&file_root, &user, file).err().unwrap();
+      assert_eq!(err.status_code, StatusCode::FORBIDDEN, "{file}");
+      assert!(resolve_internal(&file_root, &user, file).is_ok(), "{file}");
+    }
+    for file in ["", "docs/.trash", "disk", "disk/docs/.versions"] {
+      assert!(resolve(&file_root, &user, file).is_ok(), "{file}");
+    }
+  }
+}
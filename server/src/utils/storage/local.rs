@@ -0,0 +1,101 @@
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use tokio::fs;
+use tokio::io::AsyncSeekExt;
+
+use crate::utils::error::AppError;
+use crate::utils::vfs::{convert_meta_to_struct, FileStat, FileStatWithName};
+
+use super::{StorageBackend, VfsReader};
+
+/// backend on the host file system, every path is joined under `root`
+pub struct LocalStorage {
+  root: PathBuf,
+}
+
+impl LocalStorage {
+  pub fn new(root: PathBuf) -> Self {
+    Self { root }
+  }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+  async fn read_dir(&self, dir: &Path) -> Result<Vec<FileStatWithName>, AppError> {
+    let mut result = fs::read_dir(self.root.join(dir)).await?;
+    let mut files_in_dir: Vec<FileStatWithName> = vec![];
+    while let Result::Ok(Option::Some(dir_entry)) = result.next_entry().await {
+      let filename = dir_entry.file_name().to_string_lossy().into_owned();
+      let file_stat = self.stat(&dir.join(&filename)).await?;
+      files_in_dir.push(FileStatWithName::new(&file_stat, &filename));
+    }
+    Ok(files_in_dir)
+  }
+
+  async fn stat(&self, file: &Path) -> Result<FileStat, AppError> {
+    let meta = fs::metadata(self.root.join(file)).await?;
+    convert_meta_to_struct(meta)
+  }
+
+  async fn create_dir(&self, dir: &Path) -> Result<(), AppError> {
+    Ok(fs::create_dir(self.root.join(dir)).await?)
+  }
+
+  async fn remove(&self, file: &Path) -> Result<(), AppError> {
+    let file = self.root.join(file);
+    if fs::metadata(&file).await?.is_dir() {
+      fs::remove_dir_all(&file).await?;
+    } else {
+      fs::remove_file(&file).await?;
+    }
+    Ok(())
+  }
+
+  async fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError> {
+    Ok(fs::rename(self.root.join(from), self.root.join(to)).await?)
+  }
+
+  async fn copy(&self, from: &Path, to: &Path) -> Result<u64, AppError> {
+    Ok(fs::copy(self.root.join(from), self.root.join(to)).await?)
+  }
+
+  async fn read(&self, file: &Path, range: (u64, u64)) -> Result<VfsReader, AppError> {
+    let mut f = fs::File::open(self.root.join(file)).await?;
+    f.seek(std::io::SeekFrom::Start(range.0)).await?;
+    Ok(Box::pin(f))
+  }
+
+  async fn write(&self, file: &Path, mut reader: VfsReader) -> Result<u64, AppError> {
+    let file = self.root.join(file);
+    if let Some(parent) = file.parent() {
+      fs::create_dir_all(parent).await?;
+    }
+    // written next to the file and renamed over it, a failed write keeps the old content
+    let name = file
+      .file_name()
+      .map_or(String::new(), |n| n.to_string_lossy().to_string());
+    let part = file.with_file_name(format!(".{name}.{}.part", uuid::Uuid::new_v4().simple()));
+    let written = async {
+      let mut f = fs::File::create(&part).await?;
+      let written = tokio::io::copy(&mut reader, &mut f).await?;
+      f.sync_all().await?;
+      Ok::<u64, std::io::Error>(written)
+    }
+    .await;
+    match written {
+      Ok(written) => {
+        fs::rename(&part, &file).await?;
+        Ok(written)
+      }
+      Err(err) => {
+        let _ = fs::remove_file(&part).await;
+        Err(err.into())
+      }
+    }
+  }
+
+  fn local_path(&self, file: &Path) -> Option<PathBuf> {
+    Some(self.root.join(file))
+  }
+}
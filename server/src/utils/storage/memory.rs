@@ -0,0 +1,212 @@
+use std::{
+  collections::BTreeMap,
+  io::Cursor,
+  path::{Path, PathBuf},
+  sync::RwLock,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::http::StatusCode;
+use async_trait::async_trait;
+use tokio::io::AsyncReadExt;
+
+use crate::utils::error::AppError;
+use crate::utils::vfs::{FileStat, FileStatWithName};
+
+use super::{not_found, StorageBackend, VfsReader};
+
+#[derive(Clone)]
+struct MemoryNode {
+  content: Option<Vec<u8>>, // None for directories
+  created: u128,
+  modified: u128,
+}
+
+impl MemoryNode {
+  fn dir() -> Self {
+    let now = now_millis();
+    Self {
+      content: None,
+      created: now,
+      modified: now,
+    }
+  }
+
+  fn stat(&self) -> FileStat {
+    FileStat {
+      is_dir: self.content.is_none(),
+      is_file: self.content.is_some(),
+      file_type: "".to_string(),
+      size: self.content.as_ref().map_or(0, |c| c.len() as u64),
+      created: self.created,
+      modified: self.modified,
+      accessed: self.modified,
+    }
+  }
+}
+
+/// volatile backend keeping everything in a map, content is lost on restart
+pub struct MemoryStorage {
+  nodes: RwLock<BTreeMap<PathBuf, MemoryNode>>,
+}
+
+impl MemoryStorage {
+  pub fn new() -> Self {
+    let mut nodes = BTreeMap::new();
+    nodes.insert(PathBuf::new(), MemoryNode::dir());
+    Self {
+      nodes: RwLock::new(nodes),
+    }
+  }
+
+  fn ensure_parent(nodes: &mut BTreeMap<PathBuf, MemoryNode>, file: &Path) -> Result<(), AppError> {
+    for parent in file.ancestors().skip(1) {
+      match nodes.get(parent) {
+        Some(node) if node.content.is_some() => {
+          return Err(
+            AppError::new(&format!("not a directory: {}", parent.to_string_lossy()))
+              .with_status(StatusCode::BAD_REQUEST),
+          )
+        }
+        Some(_) => (),
+        None => {
+          nodes.insert(parent.to_path_buf(), MemoryNode::dir());
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// the node at `file` and every node below it
+  fn subtree(nodes: &BTreeMap<PathBuf, MemoryNode>, file: &Path) -> Vec<(PathBuf, MemoryNode)> {
+    nodes
+      .range(file.to_path_buf()..)
+      .take_while(|(p, _)| p.starts_with(file))
+      .map(|(p, n)| (p.clone(), n.clone()))
+      .collect()
+  }
+}
+
+fn now_millis() -> u128 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_millis())
+}
+
+#[async_trait]
+impl StorageBackend for MemoryStorage {
+  async fn read_dir(&self, dir: &Path) -> Result<Vec<FileStatWithName>, AppError> {
+    let nodes = self.nodes.read().unwrap();
+    let node = nodes.get(dir).ok_or_else(|| not_found(dir))?;
+    if node.content.is_some() {
+      return Err(AppError::new("read_dir: not a directory").with_status(StatusCode::BAD_REQUEST));
+    }
+    let files = nodes
+      .iter()
+      .filter(|(p, _)| p.parent() == Some(dir))
+      .map(|(p, n)| {
+        let name = p.file_name().unwrap().to_string_lossy().to_string();
+        FileStatWithName::new(&n.stat(), &name)
+      })
+      .collect();
+    Ok(files)
+  }
+
+  async fn stat(&self, file: &Path) -> Result<FileStat, AppError> {
+    let nodes = self.nodes.read().unwrap();
+    let node = nodes.get(file).ok_or_else(|| not_found(file))?;
+    Ok(node.stat())
+  }
+
+  async fn create_dir(&self, dir: &Path) -> Result<(), AppError> {
+    let mut nodes = self.nodes.write().unwrap();
+    if nodes.contains_key(dir) {
+      return Err(AppError::new("create_dir: file exists").with_status(StatusCode::BAD_REQUEST));
+    }
+    Self::ensure_parent(&mut nodes, dir)?;
+    nodes.insert(dir.to_path_buf(), MemoryNode::dir());
+    Ok(())
+  }
+
+  async fn remove(&self, file: &Path) -> Result<(), AppError> {
+    let mut nodes = self.nodes.write().unwrap();
+    if !nodes.contains_key(file) {
+      return Err(not_found(file));
+    }
+    for (p, _) in Self::subtree(&nodes, file) {
+      nodes.remove(&p);
+    }
+    Ok(())
+  }
+
+  async fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError> {
+    let mut nodes = self.nodes.write().unwrap();
+    let moved = Self::subtree(&nodes, from);
+    if moved.is_empty() {
+      return Err(not_found(from));
+    }
+    Self::ensure_parent(&mut nodes, to)?;
+    for (p, _) in moved.iter() {
+      nodes.remove(p);
+    }
+    for (p, node) in moved {
+      let rest = p.strip_prefix(from).unwrap();
+      nodes.insert(to.join(rest), node);
+    }
+    Ok(())
+  }
+
+  async fn copy(&self, from: &Path, to: &Path) -> Result<u64, AppError> {
+    let mut nodes = self.nodes.write().unwrap();
+    let node = nodes.get(from).cloned().ok_or_else(|| not_found(from))?;
+    let content = node
+      .content
+      .ok_or_else(|| AppError::new("copy: not a file").with_status(StatusCode::BAD_REQUEST))?;
+    Self::ensure_parent(&mut nodes, to)?;
+    let size = content.len() as u64;
+    let now = now_millis();
+    nodes.insert(
+      to.to_path_buf(),
+      MemoryNode {
+        content: Some(content),
+        created: now,
+        modified: now,
+      },
+    );
+    Ok(size)
+  }
+
+  async fn read(&self, file: &Path, range: (u64, u64)) -> Result<VfsReader, AppError> {
+    let nodes = self.nodes.read().unwrap();
+    let node = nodes.get(file).ok_or_else(|| not_found(file))?;
+    let content = node
+      .content
+      .as_ref()
+      .ok_or_else(|| AppError::new("read: not a file").with_status(StatusCode::BAD_REQUEST))?;
+    let start = (range.0 as usize).min(content.len());
+    let end = (range.1 as usize)
+      .saturating_add(1)
+      .min(content.len())
+      .max(start);
+    Ok(Box::pin(Cursor::new(content[start..end].to_vec())))
+  }
+
+  async fn write(&self, file: &Path, mut reader: VfsReader) -> Result<u64, AppError> {
+    let mut content = vec![];
+    reader.read_to_end(&mut content).await?;
+    let size = content.len() as u64;
+    let mut nodes = self.nodes.write().unwrap();
+    Self::ensure_parent(&mut nodes, file)?;
+    let now = now_millis();
+    let created = nodes.get(file).map_or(now, |n| n.created);
+    nodes.insert(
+      file.to_path_buf(),
+      MemoryNode {
+        content: Some(content),
+        created,
+        modified: now,
+      },
+    );
+    Ok(size)
+  }
+}
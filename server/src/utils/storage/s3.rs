@@ -0,0 +1,577 @@
+use std::path::Path;
+
+use actix_web::http::StatusCode;
+use async_trait::async_trait;
+use aws_sdk_s3::{
+  config::{Credentials, Region},
+  primitives::{ByteStream, DateTime},
+  types::{CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier},
+  Client,
+};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tokio::io::AsyncReadExt;
+
+use crate::config::StorageConfig;
+use crate::utils::error::AppError;
+use crate::utils::vfs::{FileStat, FileStatWithName};
+
+use super::{not_found, StorageBackend, VfsReader};
+
+/// multipart upload part size, S3 requires at least 5MB for every part but the last
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// characters kept as they are in a segment of `x-amz-copy-source`, the unreserved ones of RFC 3986
+const COPY_SOURCE_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+  .remove(b'-')
+  .remove(b'.')
+  .remove(b'_')
+  .remove(b'~');
+
+/// backend for S3 compatible object stores (AWS S3, MinIO ...),
+/// directories are emulated with `/` delimited keys and empty `dir/` marker objects
+pub struct S3Storage {
+  client: Client,
+  bucket: String,
+}
+
+fn s3_err(e: impl std::fmt::Display) -> AppError {
+  AppError::new(&format!("s3 error: {e}"))
+}
+
+fn to_millis(t: Option<&DateTime>) -> u128 {
+  t.and_then(|t| t.to_millis().ok()).map_or(0, |t| t as u128)
+}
+
+fn file_key(file: &Path) -> String {
+  file
+    .components()
+    .map(|c| c.as_os_str().to_string_lossy().to_string())
+    .collect::<Vec<_>>()
+    .join("/")
+}
+
+/// `<bucket>/<key>` with every segment percent encoded, keys may contain `%`, `+`, `?` or `#`
+fn copy_source(bucket: &str, key: &str) -> String {
+  std::iter::once(bucket)
+    .chain(key.split('/'))
+    .map(|segment| utf8_percent_encode(segment, COPY_SOURCE_SEGMENT).to_string())
+    .collect::<Vec<_>>()
+    .join("/")
+}
+
+fn dir_key(dir: &Path) -> String {
+  let key = file_key(dir);
+  if key.is_empty() {
+    key
+  } else {
+    key + "/"
+  }
+}
+
+impl S3Storage {
+  pub fn new(name: &str, conf: &StorageConfig) -> Result<Self, AppError> {
+    let required = |v: &Option<String>, key: &str| {
+      v.clone()
+        .ok_or_else(|| AppError::new(&format!("storage {name}: {key} is required")))
+    };
+    let bucket = required(&conf.bucket, "bucket")?;
+    let access_key = required(&conf.access_key, "access_key")?;
+    let secret_key = required(&conf.secret_key, "secret_key")?;
+    let region = conf.region.clone().unwrap_or("us-east-1".to_owned());
+
+    let mut builder = aws_sdk_s3::config::Builder::new()
+      .region(Region::new(region))
+      .credentials_provider(Credentials::new(
+        access_key, secret_key, None, None, "webbyos",
+      ))
+      .force_path_style(true);
+    if let Some(ref endpoint) = conf.endpoint {
+      builder = builder.endpoint_url(endpoint);
+    }
+    let client = Client::from_conf(builder.build());
+    Ok(Self { client, bucket })
+  }
+
+  /// every key under `prefix`, including directory markers
+  async fn list_all(&self, prefix: &str) -> Result<Vec<String>, AppError> {
+    let mut keys = vec![];
+    let mut token = None;
+    loop {
+      let output = self
+        .client
+        .list_objects_v2()
+        .bucket(&self.bucket)
+        .prefix(prefix)
+        .set_continuation_token(token)
+        .send()
+        .await
+        .map_err(s3_err)?;
+      for obj in output.contents().unwrap_or_default() {
+        if let Some(key) = obj.key() {
+          keys.push(key.to_owned());
+        }
+      }
+      token = output.next_continuation_token().map(|t| t.to_owned());
+      if token.is_none() {
+        break;
+      }
+    }
+    Ok(keys)
+  }
+
+  async fn delete_keys(&self, keys: Vec<String>) -> Result<(), AppError> {
+    // DeleteObjects accepts at most 1000 keys per request
+    for chunk in keys.chunks(1000) {
+      let objects = chunk
+        .iter()
+        .map(|k| ObjectIdentifier::builder().key(k).build())
+        .collect::<Vec<_>>();
+      self
+        .client
+        .delete_objects()
+        .bucket(&self.bucket)
+        .delete(Delete::builder().set_objects(Some(objects)).build())
+        .send()
+        .await
+        .map_err(s3_err)?;
+    }
+    Ok(())
+  }
+
+  async fn copy_key(&self, from: &str, to: &str) -> Result<(), AppError> {
+    self
+      .client
+      .copy_object()
+      .bucket(&self.bucket)
+      .copy_source(copy_source(&self.bucket, from))
+      .key(to)
+      .send()
+      .await
+      .map_err(s3_err)?;
+    Ok(())
+  }
+
+  /// upload `buf` and the rest of `reader` as parts of `upload_id` and complete the upload,
+  /// returns the bytes read from `reader`
+  async fn upload_parts(
+    &self,
+    key: &str,
+    upload_id: &str,
+    mut buf: Vec<u8>,
+    reader: &mut VfsReader,
+  ) -> Result<u64, AppError> {
+    let mut total = 0;
+    let mut parts = vec![];
+    let mut part_number = 1;
+    while !buf.is_empty() {
+      let part = self
+        .client
+        .upload_part()
+        .bucket(&self.bucket)
+        .key(key)
+        .upload_id(upload_id)
+        .part_number(part_number)
+        .body(ByteStream::from(std::mem::take(&mut buf)))
+        .send()
+        .await
+        .map_err(s3_err)?;
+      parts.push(
+        CompletedPart::builder()
+          .set_e_tag(part.e_tag().map(|t| t.to_owned()))
+          .part_number(part_number)
+          .build(),
+      );
+      part_number += 1;
+      total += (&mut *reader)
+        .take(PART_SIZE as u64)
+        .read_to_end(&mut buf)
+        .await? as u64;
+    }
+
+    self
+      .client
+      .complete_multipart_upload()
+      .bucket(&self.bucket)
+      .key(key)
+      .upload_id(upload_id)
+      .multipart_upload(
+        CompletedMultipartUpload::builder()
+          .set_parts(Some(parts))
+          .build(),
+      )
+      .send()
+      .await
+      .map_err(s3_err)?;
+    Ok(total)
+  }
+
+  async fn is_dir(&self, dir: &Path) -> Result<bool, AppError> {
+    let prefix = dir_key(dir);
+    if prefix.is_empty() {
+      return Ok(true);
+    }
+    let output = self
+      .client
+      .list_objects_v2()
+      .bucket(&self.bucket)
+      .prefix(prefix)
+      .max_keys(1)
+      .send()
+      .await
+      .map_err(s3_err)?;
+    Ok(output.key_count() > 0)
+  }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+  async fn read_dir(&self, dir: &Path) -> Result<Vec<FileStatWithName>, AppError> {
+    let prefix = dir_key(dir);
+    let mut files = vec![];
+    let mut token = None;
+    loop {
+      let output = self
+        .client
+        .list_objects_v2()
+        .bucket(&self.bucket)
+        .prefix(&prefix)
+        .delimiter("/")
+        .set_continuation_token(token)
+        .send()
+        .await
+        .map_err(s3_err)?;
+      for p in output.common_prefixes().unwrap_or_default() {
+        let name = p.prefix().unwrap_or_default()[prefix.len()..].trim_end_matches('/');
+        let stat = FileStat {
+          is_dir: true,
+          is_file: false,
+          file_type: "".to_string(),
+          size: 0,
+          created: 0,
+          modified: 0,
+          accessed: 0,
+        };
+        files.push(FileStatWithName::new(&stat, name));
+      }
+      for obj in output.contents().unwrap_or_default() {
+        let key = obj.key().unwrap_or_default();
+        if key == prefix {
+          continue;
+        }
+        let modified = to_millis(obj.last_modified());
+        let stat = FileStat {
+          is_dir: false,
+          is_file: true,
+          file_type: "".to_string(),
+          size: obj.size() as u64,
+          created: modified,
+          modified,
+          accessed: modified,
+        };
+        files.push(FileStatWithName::new(&stat, &key[prefix.len()..]));
+      }
+      token = output.next_continuation_token().map(|t| t.to_owned());
+      if token.is_none() {
+        break;
+      }
+    }
+    Ok(files)
+  }
+
+  async fn stat(&self, file: &Path) -> Result<FileStat, AppError> {
+    let key = file_key(file);
+    if !key.is_empty() {
+      let head = self
+        .client
+        .head_object()
+        .bucket(&self.bucket)
+        .key(&key)
+        .send()
+        .await;
+      match head {
+        Ok(head) => {
+          let modified = to_millis(head.last_modified());
+          return Ok(FileStat {
+            is_dir: false,
+            is_file: true,
+            file_type: "".to_string(),
+            size: head.content_length() as u64,
+            created: modified,
+            modified,
+            accessed: modified,
+          });
+        }
+        Err(err) => {
+          let err = err.into_service_error();
+          if !err.is_not_found() {
+            return Err(s3_err(err));
+          }
+        }
+      }
+    }
+    if self.is_dir(file).await? {
+      return Ok(FileStat {
+        is_dir: true,
+        is_file: false,
+        file_type: "".to_string(),
+        size: 0,
+        created: 0,
+        modified: 0,
+        accessed: 0,
+      });
+    }
+    Err(not_found(file))
+  }
+
+  async fn create_dir(&self, dir: &Path) -> Result<(), AppError> {
+    self
+      .client
+      .put_object()
+      .bucket(&self.bucket)
+      .key(dir_key(dir))
+      .body(ByteStream::from(vec![]))
+      .send()
+      .await
+      .map_err(s3_err)?;
+    Ok(())
+  }
+
+  async fn remove(&self, file: &Path) -> Result<(), AppError> {
+    let stat = self.stat(file).await?;
+    let keys = if stat.is_dir {
+      self.list_all(&dir_key(file)).await?
+    } else {
+      vec![file_key(file)]
+    };
+    self.delete_keys(keys).await
+  }
+
+  async fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError> {
+    let stat = self.stat(from).await?;
+    if !stat.is_dir {
+      self.copy_key(&file_key(from), &file_key(to)).await?;
+      return self.delete_keys(vec![file_key(from)]).await;
+    }
+    let from_prefix = dir_key(from);
+    let to_prefix = dir_key(to);
+    let keys = self.list_all(&from_prefix).await?;
+    for key in keys.iter() {
+      let dest = format!("{}{}", to_prefix, &key[from_prefix.len()..]);
+      self.copy_key(key, &dest).await?;
+    }
+    self.delete_keys(keys).await
+  }
+
+  async fn copy(&self, from: &Path, to: &Path) -> Result<u64, AppError> {
+    let stat = self.stat(from).await?;
+    if stat.is_dir {
+      return Err(AppError::new("copy: not a file").with_status(StatusCode::BAD_REQUEST));
+    }
+    self.copy_key(&file_key(from), &file_key(to)).await?;
+    Ok(stat.size)
+  }
+
+  async fn read(&self, file: &Path, range: (u64, u64)) -> Result<VfsReader, AppError> {
+    let output = self
+      .client
+      .get_object()
+      .bucket(&self.bucket)
+      .key(file_key(file))
+      .range(format!("bytes={}-{}", range.0, range.1))
+      .send()
+      .await
+      .map_err(s3_err)?;
+    Ok(Box::pin(output.body.into_async_read()))
+  }
+
+  async fn write(&self, file: &Path, mut reader: VfsReader) -> Result<u64, AppError> {
+    let key = file_key(file);
+    let mut buf = Vec::with_capacity(PART_SIZE);
+    let total = (&mut reader)
+      .take(PART_SIZE as u64)
+      .read_to_end(&mut buf)
+      .await? as u64;
+
+    if buf.len() < PART_SIZE {
+      self
+        .client
+        .put_object()
+        .bucket(&self.bucket)
+        .key(&key)
+        .body(ByteStream::from(buf))
+        .send()
+        .await
+        .map_err(s3_err)?;
+      return Ok(total);
+    }
+
+    let upload = self
+      .client
+      .create_multipart_upload()
+      .bucket(&self.bucket)
+      .key(&key)
+      .send()
+      .await
+      .map_err(s3_err)?;
+    let upload_id = upload
+      .upload_id()
+      .ok_or_else(|| AppError::new("s3 error: no upload id"))?
+      .to_owned();
+
+    let completed = self.upload_parts(&key, &upload_id, buf, &mut reader).await;
+    match completed {
+      Ok(written) => Ok(total + written),
+      Err(err) => {
+        // parts of an upload that is neither completed nor aborted are kept and billed
+        if let Err(abort_err) = self
+          .client
+          .abort_multipart_upload()
+          .bucket(&self.bucket)
+          .key(&key)
+          .upload_id(&upload_id)
+          .send()
+          .await
+        {
+          tracing::error!("s3: abort upload {upload_id} of {key} failed: {abort_err}");
+        }
+        Err(err)
+      }
+    }
+  }
+}
+
+/// The round trips run against an S3 compatible server and are skipped unless
+/// `S3_TEST_ENDPOINT` is set, e.g. with a local MinIO:
+///
+/// ```sh
+/// docker run -p 9000:9000 minio/minio server /data
+/// S3_TEST_ENDPOINT=http://127.0.0.1:9000 cargo test --features s3 storage::s3
+/// ```
+///
+/// `S3_TEST_BUCKET`, `S3_TEST_ACCESS_KEY` and `S3_TEST_SECRET_KEY` default to the MinIO defaults.
+#[cfg(test)]
+mod tests {
+  use std::{path::PathBuf, sync::Arc};
+
+  use super::*;
+  use crate::utils::storage::read_all;
+
+  /// a backend working under a prefix of its own, None without a server
+  async fn storage() -> Option<(Arc<dyn StorageBackend>, PathBuf)> {
+    let endpoint = std::env::var("S3_TEST_ENDPOINT").ok()?;
+    let env = |key: &str, default: &str| std::env::var(key).unwrap_or(default.to_owned());
+    let conf = StorageConfig {
+      kind: "s3".to_owned(),
+      root: None,
+      endpoint: Some(endpoint),
+      region: None,
+      bucket: Some(env("S3_TEST_BUCKET", "webbyos-test")),
+      access_key: Some(env("S3_TEST_ACCESS_KEY", "minioadmin")),
+      secret_key: Some(env("S3_TEST_SECRET_KEY", "minioadmin")),
+    };
+    let storage = S3Storage::new("test", &conf).unwrap();
+    // fails when the bucket exists already
+    let _ = storage
+      .client
+      .create_bucket()
+      .bucket(&storage.bucket)
+      .send()
+      .await;
+    let prefix = PathBuf::from(uuid::Uuid::new_v4().to_string());
+    Some((Arc::new(storage), prefix))
+  }
+
+  fn reader(content: Vec<u8>) -> VfsReader {
+    Box::pin(std::io::Cursor::new(content))
+  }
+
+  async fn read(backend: &Arc<dyn StorageBackend>, file: &Path) -> Vec<u8> {
+    let size = backend.stat(file).await.unwrap().size;
+    let mut buf = vec![];
+    read_all(backend, file, size)
+      .await
+      .unwrap()
+      .read_to_end(&mut buf)
+      .await
+      .unwrap();
+    buf
+  }
+
+  #[test]
+  fn copy_source_encodes_every_segment() {
+    assert_eq!(
+      copy_source("bucket", "a b/x+%?#.txt"),
+      "bucket/a%20b/x%2B%25%3F%23.txt"
+    );
+    assert_eq!(copy_source("bucket", "dir/a-b_c.~"), "bucket/dir/a-b_c.~");
+  }
+
+  #[tokio::test]
+  async fn small_empty_and_multipart_files() {
+    let (backend, dir) = match storage().await {
+      Some(storage) => storage,
+      None => return,
+    };
+    let small = dir.join("small.txt");
+    backend
+      .write(&small, reader(b"hello".to_vec()))
+      .await
+      .unwrap();
+    assert_eq!(read(&backend, &small).await, b"hello");
+
+    let empty = dir.join("empty.txt");
+    backend.write(&empty, reader(vec![])).await.unwrap();
+    assert_eq!(read(&backend, &empty).await, b"");
+
+    let content = (0..PART_SIZE * 2 + 1)
+      .map(|i| (i % 251) as u8)
+      .collect::<Vec<_>>();
+    let large = dir.join("large.bin");
+    let written = backend
+      .write(&large, reader(content.clone()))
+      .await
+      .unwrap();
+    assert_eq!(written, content.len() as u64);
+    assert_eq!(read(&backend, &large).await, content);
+
+    let mut names = backend
+      .read_dir(&dir)
+      .await
+      .unwrap()
+      .into_iter()
+      .map(|f| f.name)
+      .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["empty.txt", "large.bin", "small.txt"]);
+    backend.remove(&dir).await.unwrap();
+    assert!(backend.stat(&small).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn rename_and_copy_keys_with_reserved_characters() {
+    let (backend, dir) = match storage().await {
+      Some(storage) => storage,
+      None => return,
+    };
+    let from = dir.join("a b").join("x+%?#.txt");
+    backend
+      .write(&from, reader(b"data".to_vec()))
+      .await
+      .unwrap();
+
+    let copied = dir.join("copy 100%.txt");
+    assert_eq!(backend.copy(&from, &copied).await.unwrap(), 4);
+    assert_eq!(read(&backend, &copied).await, b"data");
+
+    backend
+      .rename(&dir.join("a b"), &dir.join("c+d"))
+      .await
+      .unwrap();
+    assert!(backend.stat(&from).await.is_err());
+    assert_eq!(
+      read(&backend, &dir.join("c+d").join("x+%?#.txt")).await,
+      b"data"
+    );
+    backend.remove(&dir).await.unwrap();
+  }
+}
@@ -0,0 +1,22 @@
+/// Setup shared by tests touching the database or the file system
+use std::{path::PathBuf, sync::Once};
+
+use crate::{config::APP_CONFIG, db::SHARED_DB_CONN, run_migrations};
+
+static INIT_DB: Once = Once::new();
+
+/// point the shared connection to an in-memory database with the current schema,
+/// tests run in parallel on it and use their own user names
+pub fn init_db() {
+  INIT_DB.call_once(|| {
+    APP_CONFIG.lock().unwrap().database_url = Some(":memory:".to_owned());
+    run_migrations(&mut SHARED_DB_CONN.lock().unwrap());
+  });
+}
+
+/// an empty directory for a single test
+pub fn temp_dir(name: &str) -> PathBuf {
+  let dir = std::env::temp_dir().join(format!("webbyos-test-{name}-{}", uuid::Uuid::new_v4()));
+  std::fs::create_dir_all(&dir).unwrap();
+  dir
+}
@@ -0,0 +1,112 @@
+/// HTTPS
+///
+/// Connections get their certificate from a resolver instead of a fixed config, so a
+/// renewed certificate written over `cert_path` and `key_path` is used by new connections
+/// without a restart. The `reload_cert` job watches the files.
+use std::{
+  fs::File,
+  io::BufReader,
+  sync::{Arc, RwLock},
+  time::SystemTime,
+};
+
+use lazy_static::lazy_static;
+use rustls::{
+  server::{ClientHello, ResolvesServerCert},
+  sign::{self, CertifiedKey},
+  Certificate, PrivateKey, ServerConfig,
+};
+
+use crate::config::{TlsConfig, APP_CONFIG};
+
+use super::error::AppError;
+
+pub const DEFAULT_HSTS_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+lazy_static! {
+  static ref CERTIFIED_KEY: RwLock<Option<Arc<CertifiedKey>>> = RwLock::new(None);
+  /// modification times of the loaded cert and key files
+  static ref LOADED_AT: RwLock<Option<(SystemTime, SystemTime)>> = RwLock::new(None);
+}
+
+pub fn config() -> Option<TlsConfig> {
+  APP_CONFIG.lock().unwrap().tls.clone()
+}
+
+pub fn is_enabled() -> bool {
+  config().is_some()
+}
+
+fn open(path: &str) -> Result<BufReader<File>, AppError> {
+  let file = File::open(path).map_err(|err| AppError::new(&format!("tls: {path}: {err}")))?;
+  Ok(BufReader::new(file))
+}
+
+/// read and check the certificate and key of `conf`
+pub fn load_certified_key(conf: &TlsConfig) -> Result<CertifiedKey, AppError> {
+  let certs = rustls_pemfile::certs(&mut open(&conf.cert_path)?)?;
+  if certs.is_empty() {
+    return Err(AppError::new(&format!(
+      "tls: no certificate in {}",
+      conf.cert_path
+    )));
+  }
+  let key = rustls_pemfile::read_all(&mut open(&conf.key_path)?)?
+    .into_iter()
+    .find_map(|item| match item {
+      rustls_pemfile::Item::PKCS8Key(key)
+      | rustls_pemfile::Item::RSAKey(key)
+      | rustls_pemfile::Item::ECKey(key) => Some(key),
+      _ => None,
+    })
+    .ok_or_else(|| AppError::new(&format!("tls: no private key in {}", conf.key_path)))?;
+  let key = sign::any_supported_type(&PrivateKey(key))
+    .map_err(|err| AppError::new(&format!("tls: {}: {err}", conf.key_path)))?;
+  Ok(CertifiedKey::new(
+    certs.into_iter().map(Certificate).collect(),
+    key,
+  ))
+}
+
+fn modified_times(conf: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+  let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+  Some((modified(&conf.cert_path)?, modified(&conf.key_path)?))
+}
+
+/// load the certificate again if its files changed, returns whether it was replaced,
+/// a broken new certificate keeps the old one in use
+pub fn reload_if_changed() -> Result<bool, AppError> {
+  let conf = match config() {
+    Some(conf) => conf,
+    None => return Ok(false),
+  };
+  let times = modified_times(&conf);
+  if times.is_none() || *LOADED_AT.read().unwrap() == times {
+    return Ok(false);
+  }
+  let key = load_certified_key(&conf)?;
+  *CERTIFIED_KEY.write().unwrap() = Some(Arc::new(key));
+  *LOADED_AT.write().unwrap() = times;
+  Ok(true)
+}
+
+struct CertResolver;
+
+impl ResolvesServerCert for CertResolver {
+  fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+    CERTIFIED_KEY.read().unwrap().clone()
+  }
+}
+
+/// rustls config of the https listener, None if tls is not configured
+pub fn server_config() -> Result<Option<ServerConfig>, AppError> {
+  if !is_enabled() {
+    return Ok(None);
+  }
+  reload_if_changed()?;
+  let config = ServerConfig::builder()
+    .with_safe_defaults()
+    .with_no_client_auth()
+    .with_cert_resolver(Arc::new(CertResolver));
+  Ok(Some(config))
+}
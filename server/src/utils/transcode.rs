@@ -3,6 +3,8 @@ use image::{ImageFormat};
 use tokio::io::AsyncRead;
 use std::{path::PathBuf, io::Cursor};
 
+use crate::config;
+
 use super::error::AppError;
 
 pub async fn ffmpeg_scale(file: &PathBuf, size: u32, bitrate: u32) -> impl AsyncRead {
@@ -12,7 +14,7 @@ pub async fn ffmpeg_scale(file: &PathBuf, size: u32, bitrate: u32) -> impl Async
 
 fn scale(file: &PathBuf, size: u32, bitrate: u32) -> impl AsyncRead {
   let file = file.clone().canonicalize().unwrap();
-  FFMpeg::set_ffmpeg_bin("./ffmpeg");
+  FFMpeg::set_ffmpeg_bin(&config!(ffmpeg_bin_path));
   let stream = FFMpeg::input(&file.to_string_lossy().to_string())
     .output()
     .resize(-2, size as i32)
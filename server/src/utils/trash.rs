@@ -0,0 +1,351 @@
+/// Recycle bin of users
+///
+/// Deleted files are moved to `.trash/<id>` in the root of their owner, or in the root of
+/// their mount, and recorded in the `trash` table, they are removed for good when the trash
+/// is emptied or the retention period has passed. Trash is not indexed, a deleted file keeps
+/// its index entry until it is purged.
+use std::{
+  collections::HashMap,
+  path::{Component, PathBuf},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::http::StatusCode;
+
+use crate::{config, db::SHARED_DB_CONN, models::TrashItem, UserSessionData};
+
+use super::error::AppError;
+use super::mount::get_mounts;
+use super::path::secure_join;
+use super::quota;
+use super::storage::{resolve, resolve_internal};
+use super::vfs::{self, index_path, FSHookPayload, FSHookType, FS_HOOK};
+
+pub const TRASH_DIR: &str = ".trash";
+
+fn now_secs() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn trash_dir(mount: Option<&str>) -> String {
+  match mount {
+    Some(mount) => format!("{mount}/{TRASH_DIR}"),
+    None => TRASH_DIR.to_owned(),
+  }
+}
+
+fn trash_path(item: &TrashItem) -> String {
+  format!("{}/{}", trash_dir(item.mount.as_deref()), item.id)
+}
+
+/// create `dir` and its missing parents, `dir` may be in the trash
+async fn ensure_dir(file_root: &PathBuf, user: &UserSessionData, dir: &PathBuf) -> Result<(), AppError> {
+  let mut current = PathBuf::new();
+  for c in dir.components() {
+    current.push(c);
+    let target = resolve_internal(file_root, user, &current.to_string_lossy())?;
+    if target.backend.stat(&target.path).await.is_err() {
+      target.writable()?.backend.create_dir(&target.path).await?;
+    }
+  }
+  Ok(())
+}
+
+/// the trash of a file is in the same storage, moving to and from it is a rename
+async fn rename(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  from_file: &str,
+  to_file: &str,
+) -> Result<(), AppError> {
+  let from = resolve_internal(file_root, user, from_file)?.writable()?;
+  let to = resolve_internal(file_root, user, to_file)?.writable()?;
+  if !from.same_storage(&to) {
+    return Err(AppError::new(&format!(
+      "{from_file} and {to_file} are not in the same storage"
+    )));
+  }
+  from.backend.rename(&from.path, &to.path).await
+}
+
+/// move `file` to the trash of the user owning it, files in `Shared with me` go to the trash
+/// of their owner, files in a mount go to the trash in the root of that mount
+#[async_recursion::async_recursion]
+pub async fn move_to_trash(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+) -> Result<TrashItem, AppError> {
+  let file = secure_join(&PathBuf::new(), &PathBuf::from(file))?;
+  let original_path = file.to_string_lossy().to_string();
+  match file.components().next() {
+    None => {
+      return Err(AppError::new("can not delete user root").with_status(StatusCode::BAD_REQUEST))
+    }
+    Some(Component::Normal(first)) if first == TRASH_DIR => {
+      return Err(AppError::new("file is already in trash").with_status(StatusCode::BAD_REQUEST))
+    }
+    _ => (),
+  }
+  if get_mounts(&user.username)?.iter().any(|m| m.prefix == original_path) {
+    return Err(AppError::new("can not delete a mount point").with_status(StatusCode::BAD_REQUEST));
+  }
+
+  let target = resolve(file_root, user, &original_path)?.writable()?;
+  if let Some(origin) = &target.shared {
+    return move_to_trash(file_root, &origin.owner(), &origin.file).await;
+  }
+  let file_stat = target.backend.stat(&target.path).await?;
+  let dir = trash_dir(target.mount.as_deref());
+  ensure_dir(file_root, user, &PathBuf::from(dir)).await?;
+  let item = TrashItem {
+    id: uuid::Uuid::new_v4().to_string(),
+    username: user.username.clone(),
+    original_path,
+    deleted_at: now_secs(),
+    is_dir: file_stat.is_dir,
+    size: file_stat.size as i64,
+    indexed_path: index_path(file_root, &target),
+    mount: target.mount.clone(),
+  };
+  rename(file_root, user, &item.original_path, &trash_path(&item)).await?;
+
+  use crate::schema::trash::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::insert_into(trash).values(&item).execute(&mut *conn)?;
+  Ok(item)
+}
+
+pub fn list(user: &str) -> Result<Vec<TrashItem>, AppError> {
+  use crate::schema::trash::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let items = trash
+    .filter(username.eq(user))
+    .order(deleted_at.desc())
+    .load::<TrashItem>(&mut *conn)?;
+  Ok(items)
+}
+
+fn get_item(user: &str, item_id: &str) -> Result<TrashItem, AppError> {
+  use crate::schema::trash::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  trash
+    .filter(username.eq(user).and(id.eq(item_id)))
+    .first::<TrashItem>(&mut *conn)
+    .optional()?
+    .ok_or_else(|| AppError::new("trash item not found").with_status(StatusCode::NOT_FOUND))
+}
+
+fn remove_item(item_id: &str) -> Result<(), AppError> {
+  use crate::schema::trash::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::delete(trash.filter(id.eq(item_id))).execute(&mut *conn)?;
+  Ok(())
+}
+
+/// forget the trash of a removed user, its files are left in its root
+pub fn remove_user(user: &str) -> Result<(), AppError> {
+  use crate::schema::trash::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::delete(trash.filter(username.eq(user))).execute(&mut *conn)?;
+  Ok(())
+}
+
+/// move a file in trash back to its original path
+pub async fn restore(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  item_id: &str,
+) -> Result<TrashItem, AppError> {
+  let item = get_item(&user.username, item_id)?;
+  if vfs::stat(file_root, user, &item.original_path).await.is_ok() {
+    return Err(
+      AppError::new(&format!("file exists: {}", item.original_path))
+        .with_status(StatusCode::CONFLICT),
+    );
+  }
+  if let Some(parent) = PathBuf::from(&item.original_path).parent() {
+    ensure_dir(file_root, user, &parent.to_path_buf()).await?;
+  }
+  rename(file_root, user, &trash_path(&item), &item.original_path).await?;
+  remove_item(&item.id)?;
+  vfs::emit_fs_hook(file_root, user, FSHookType::AddFile, &item.original_path)?;
+  Ok(item)
+}
+
+/// remove a file in trash permanently
+pub async fn purge(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  item: &TrashItem,
+) -> Result<(), AppError> {
+  let target = resolve_internal(file_root, user, &trash_path(item))?;
+  if target.backend.stat(&target.path).await.is_ok() {
+    let size = quota::size_of(file_root, user, &trash_path(item)).await?;
+    target.backend.remove(&target.path).await?;
+    quota::add(&user.username, -(size as i64))?;
+  }
+  remove_item(&item.id)?;
+  if let Some(file) = &item.indexed_path {
+    FS_HOOK
+      .lock()
+      .unwrap()
+      .emit(FSHookType::DeleteFile, FSHookPayload(vec![file.clone()]));
+  }
+  Ok(())
+}
+
+pub async fn empty(file_root: &PathBuf, user: &UserSessionData) -> Result<usize, AppError> {
+  let items = list(&user.username)?;
+  for item in items.iter() {
+    purge(file_root, user, item).await?;
+  }
+  Ok(items.len())
+}
+
+/// purge files deleted before the retention period of every user, a file failing to purge
+/// is logged and tried again on the next run
+pub async fn purge_expired(file_root: &PathBuf) -> Result<usize, AppError> {
+  let retention_days = config!(trash_retention_days);
+  if retention_days <= 0 {
+    return Ok(0);
+  }
+  let expired_at = now_secs() - retention_days as i64 * 24 * 3600;
+
+  let (items, user_roots) = {
+    use crate::schema::users;
+    use diesel::prelude::*;
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    let items = crate::schema::trash::table
+      .filter(crate::schema::trash::deleted_at.lt(expired_at))
+      .load::<TrashItem>(&mut *conn)?;
+    let user_roots = users::table
+      .select((users::username, users::user_root))
+      .load::<(String, String)>(&mut *conn)?
+      .into_iter()
+      .collect::<HashMap<_, _>>();
+    (items, user_roots)
+  };
+
+  let mut purged = 0;
+  for item in items {
+    let user_root = match user_roots.get(&item.username) {
+      Some(user_root) => user_root,
+      None => {
+        remove_item(&item.id)?;
+        continue;
+      }
+    };
+    let user = UserSessionData::new(&item.username, user_root);
+    match purge(file_root, &user, &item).await {
+      Ok(_) => purged += 1,
+      Err(err) => tracing::error!(
+        "purge trash item {} of {} failed: {err}",
+        item.id,
+        item.username
+      ),
+    }
+  }
+  Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::test_utils::{init_db, temp_dir};
+
+  /// the user root is a host path outside of `file_root`, so nothing is sent to the file index
+  fn setup(name: &str) -> (PathBuf, PathBuf, UserSessionData) {
+    init_db();
+    let dir = temp_dir(name);
+    let user_root = dir.join("root");
+    std::fs::create_dir_all(user_root.join("docs")).unwrap();
+    std::fs::write(user_root.join("docs/a.txt"), "hello").unwrap();
+    let user = UserSessionData::new(name, &user_root.to_string_lossy());
+    (dir.join("files"), user_root, user)
+  }
+
+  #[tokio::test]
+  async fn deleted_file_is_restored() {
+    let (file_root, user_root, user) = setup("trash-restore");
+    let item = move_to_trash(&file_root, &user, "docs/a.txt")
+      .await
+      .unwrap();
+    assert!(!user_root.join("docs/a.txt").exists());
+    assert!(user_root.join(trash_path(&item)).exists());
+    assert_eq!(list(&user.username).unwrap().len(), 1);
+
+    restore(&file_root, &user, &item.id).await.unwrap();
+    assert_eq!(
+      std::fs::read_to_string(user_root.join("docs/a.txt")).unwrap(),
+      "hello"
+    );
+    assert!(!user_root.join(trash_path(&item)).exists());
+    assert!(list(&user.username).unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn restore_does_not_replace_a_new_file() {
+    let (file_root, user_root, user) = setup("trash-conflict");
+    let item = move_to_trash(&file_root, &user, "docs/a.txt")
+      .await
+      .unwrap();
+    std::fs::write(user_root.join("docs/a.txt"), "new").unwrap();
+    let err = restore(&file_root, &user, &item.id).await.unwrap_err();
+    assert_eq!(err.status_code, StatusCode::CONFLICT);
+    assert_eq!(
+      std::fs::read_to_string(user_root.join("docs/a.txt")).unwrap(),
+      "new"
+    );
+  }
+
+  #[tokio::test]
+  async fn trash_and_root_can_not_be_deleted() {
+    let (file_root, _, user) = setup("trash-invalid");
+    let item = move_to_trash(&file_root, &user, "docs/a.txt")
+      .await
+      .unwrap();
+    assert!(move_to_trash(&file_root, &user, "").await.is_err());
+    assert!(move_to_trash(&file_root, &user, &trash_path(&item))
+      .await
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn file_in_mount_goes_to_the_trash_of_the_mount() {
+    let (file_root, user_root, user) = setup("trash-mount");
+    let disk = user_root.parent().unwrap().join("disk");
+    std::fs::create_dir_all(&disk).unwrap();
+    std::fs::write(disk.join("b.txt"), "mounted").unwrap();
+    crate::utils::mount::add_mount(crate::models::Mount {
+      username: user.username.clone(),
+      prefix: "disk".to_owned(),
+      target: disk.to_string_lossy().to_string(),
+      read_only: false,
+    })
+    .unwrap();
+
+    let item = move_to_trash(&file_root, &user, "disk/b.txt")
+      .await
+      .unwrap();
+    assert_eq!(item.mount.as_deref(), Some("disk"));
+    assert!(!disk.join("b.txt").exists());
+    assert!(disk.join(TRASH_DIR).join(&item.id).exists());
+    assert!(move_to_trash(&file_root, &user, &trash_path(&item))
+      .await
+      .is_err());
+
+    restore(&file_root, &user, &item.id).await.unwrap();
+    assert_eq!(
+      std::fs::read_to_string(disk.join("b.txt")).unwrap(),
+      "mounted"
+    );
+  }
+}
@@ -0,0 +1,312 @@
+/// Resumable uploads
+///
+/// An upload session lives in `<upload_temp_dir>/resumable` as `<id>.json` with its metadata and
+/// `<id>.part` with the bytes received so far. The offset of a session is the length of its part
+/// file, so an interrupted upload can be continued even after the server restarts. Sessions
+/// receiving no chunk for a day are removed by `purge_stale`.
+use std::{
+  collections::HashSet,
+  path::PathBuf,
+  sync::Mutex,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+  error::PayloadError,
+  http::StatusCode,
+  web::{block, Bytes},
+};
+use futures::{Stream, StreamExt};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt};
+
+use crate::{config, conv_err, UserSessionData};
+
+use super::error::AppError;
+use super::path::secure_join;
+use super::quota;
+use super::storage::resolve;
+use super::versions;
+use super::vfs::{
+  ensure_dir_sync, ensure_parent_dir_sync, index_path, FSHookPayload, FSHookType, FS_HOOK,
+};
+
+conv_err!(PayloadError);
+
+/// sessions without a chunk for this long are abandoned
+const STALE_AFTER_SECS: u64 = 24 * 3600;
+
+lazy_static! {
+  /// sessions receiving a chunk, a session accepts only one chunk at a time
+  static ref UPLOADING: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UploadSession {
+  pub id: String,
+  pub username: String,
+  /// destination path in user root
+  pub file: String,
+  pub size: u64,
+  /// expected hex encoded SHA-256 of the whole file
+  pub sha256: Option<String>,
+  pub created_at: u64,
+  /// time of the last received chunk
+  #[serde(default)]
+  pub updated_at: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UploadOffset {
+  pub id: String,
+  pub offset: u64,
+  pub size: u64,
+}
+
+struct UploadingGuard(String);
+
+impl UploadingGuard {
+  fn lock(id: &str) -> Result<Self, AppError> {
+    if !UPLOADING.lock().unwrap().insert(id.to_owned()) {
+      return Err(
+        AppError::new("another chunk of this upload is in progress")
+          .with_status(StatusCode::CONFLICT),
+      );
+    }
+    Ok(Self(id.to_owned()))
+  }
+}
+
+impl Drop for UploadingGuard {
+  fn drop(&mut self) {
+    UPLOADING.lock().unwrap().remove(&self.0);
+  }
+}
+
+fn session_dir() -> PathBuf {
+  PathBuf::from(config!(upload_temp_dir)).join("resumable")
+}
+
+fn meta_path(id: &str) -> PathBuf {
+  session_dir().join(format!("{id}.json"))
+}
+
+fn part_path(id: &str) -> PathBuf {
+  session_dir().join(format!("{id}.part"))
+}
+
+fn now_secs() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs())
+}
+
+async fn save_session(session: &UploadSession) -> Result<(), AppError> {
+  fs::write(meta_path(&session.id), serde_json::to_vec(session)?).await?;
+  Ok(())
+}
+
+fn not_found() -> AppError {
+  AppError::new("upload session not found").with_status(StatusCode::NOT_FOUND)
+}
+
+pub async fn create_session(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  size: u64,
+  sha256: Option<String>,
+) -> Result<UploadSession, AppError> {
+  let file = secure_join(&PathBuf::new(), &PathBuf::from(file))?;
+  if file.components().next().is_none() {
+    return Err(AppError::new("upload: file name is empty").with_status(StatusCode::BAD_REQUEST));
+  }
+  // fail early, quota is checked again when the upload is finalized
+  let file_str = file.to_string_lossy();
+  let before = quota::size_of(file_root, user, &file_str).await?;
+  quota::check(file_root, user, &file_str, size.saturating_sub(before)).await?;
+  let session = UploadSession {
+    id: uuid::Uuid::new_v4().to_string(),
+    username: user.username.clone(),
+    file: file.to_string_lossy().to_string(),
+    size,
+    sha256: sha256.map(|s| s.to_lowercase()),
+    created_at: now_secs(),
+    updated_at: now_secs(),
+  };
+  ensure_dir_sync(session_dir())?;
+  fs::File::create(part_path(&session.id)).await?;
+  save_session(&session).await?;
+  Ok(session)
+}
+
+/// load a session of user and its current offset
+pub async fn get_session(
+  user: &UserSessionData,
+  id: &str,
+) -> Result<(UploadSession, u64), AppError> {
+  // ids are generated by us, anything else must not be joined into a path
+  uuid::Uuid::parse_str(id).map_err(|_| not_found())?;
+  let content = fs::read(meta_path(id)).await.map_err(|_| not_found())?;
+  let session: UploadSession = serde_json::from_slice(&content)?;
+  if session.username != user.username {
+    return Err(not_found());
+  }
+  let offset = fs::metadata(part_path(id)).await?.len();
+  Ok((session, offset))
+}
+
+/// append a chunk starting at `offset`, returns the new offset
+pub async fn write_chunk(
+  user: &UserSessionData,
+  id: &str,
+  offset: u64,
+  mut chunk: impl Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+) -> Result<u64, AppError> {
+  let (mut session, current) = get_session(user, id).await?;
+  let _guard = UploadingGuard::lock(id)?;
+  session.updated_at = now_secs();
+  save_session(&session).await?;
+  if offset != current {
+    return Err(
+      AppError::new(&format!("upload offset mismatch, expected {current}"))
+        .with_status(StatusCode::CONFLICT),
+    );
+  }
+  let mut f = fs::OpenOptions::new()
+    .append(true)
+    .open(part_path(id))
+    .await?;
+  let mut offset = current;
+  while let Some(bytes) = chunk.next().await {
+    let bytes = bytes?;
+    if offset + bytes.len() as u64 > session.size {
+      f.flush().await?;
+      return Err(
+        AppError::new("upload exceeds the declared size").with_status(StatusCode::BAD_REQUEST),
+      );
+    }
+    f.write_all(&bytes).await?;
+    offset += bytes.len() as u64;
+  }
+  f.flush().await?;
+  Ok(offset)
+}
+
+fn sha256_of_file(file: PathBuf) -> Result<String, AppError> {
+  let mut f = std::fs::File::open(file)?;
+  let mut hasher = Sha256::new();
+  std::io::copy(&mut f, &mut hasher)?;
+  Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// move a complete upload to its destination, `sha256` overrides the checksum given on creation
+pub async fn finalize(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  id: &str,
+  sha256: Option<String>,
+) -> Result<UploadSession, AppError> {
+  let (session, offset) = get_session(user, id).await?;
+  let _guard = UploadingGuard::lock(id)?;
+  if offset != session.size {
+    return Err(
+      AppError::new(&format!("upload is incomplete: {offset}/{}", session.size))
+        .with_status(StatusCode::BAD_REQUEST),
+    );
+  }
+  let part = part_path(id);
+  if let Some(expected) = sha256.or(session.sha256.clone()) {
+    let part = part.clone();
+    let actual = block(move || sha256_of_file(part)).await??;
+    if actual != expected.to_lowercase() {
+      return Err(AppError::new("upload checksum mismatch").with_status(StatusCode::BAD_REQUEST));
+    }
+  }
+
+  let target = resolve(file_root, user, &session.file)?.writable()?;
+  let before = quota::size_of(file_root, user, &session.file).await?;
+  quota::check(file_root, user, &session.file, session.size.saturating_sub(before)).await?;
+  versions::snapshot(file_root, user, &session.file).await?;
+  if let Some(dest) = target.backend.local_path(&target.path) {
+    ensure_parent_dir_sync(&dest)?;
+    // rename fails when upload_temp_dir is on another device
+    if fs::rename(&part, &dest).await.is_err() {
+      fs::copy(&part, &dest).await?;
+      fs::remove_file(&part).await?;
+    }
+  } else {
+    let reader = fs::File::open(&part).await?;
+    target.backend.write(&target.path, Box::pin(reader)).await?;
+    fs::remove_file(&part).await?;
+  }
+  fs::remove_file(meta_path(id)).await?;
+  quota::track(file_root, user, &session.file, before).await?;
+
+  if let Some(file) = index_path(file_root, &target) {
+    FS_HOOK
+      .lock()
+      .unwrap()
+      .emit(FSHookType::AddFile, FSHookPayload(vec![file]));
+  }
+  Ok(session)
+}
+
+pub async fn abort(user: &UserSessionData, id: &str) -> Result<(), AppError> {
+  get_session(user, id).await?;
+  let _guard = UploadingGuard::lock(id)?;
+  fs::remove_file(part_path(id)).await?;
+  fs::remove_file(meta_path(id)).await?;
+  Ok(())
+}
+
+/// remove sessions which received no chunk for `STALE_AFTER_SECS` and part files left without
+/// a session, returns the number of removed sessions
+pub fn purge_stale() -> Result<usize, AppError> {
+  let dir = session_dir();
+  if !dir.exists() {
+    return Ok(0);
+  }
+  let expired_at = now_secs().saturating_sub(STALE_AFTER_SECS);
+  let mut purged = 0;
+  for entry in std::fs::read_dir(&dir)? {
+    let path = entry?.path();
+    let id = match path.file_stem() {
+      Some(id) => id.to_string_lossy().to_string(),
+      None => continue,
+    };
+    match path.extension().and_then(|ext| ext.to_str()) {
+      Some("json") => {
+        let session = std::fs::read(&path)
+          .ok()
+          .and_then(|content| serde_json::from_slice::<UploadSession>(&content).ok());
+        let last_active = session.map_or(0, |s| s.created_at.max(s.updated_at));
+        if last_active >= expired_at {
+          continue;
+        }
+        // a chunk being received keeps its session
+        let _guard = match UploadingGuard::lock(&id) {
+          Ok(guard) => guard,
+          Err(_) => continue,
+        };
+        std::fs::remove_file(part_path(&id)).ok();
+        std::fs::remove_file(&path)?;
+        purged += 1;
+      }
+      Some("part") if !meta_path(&id).exists() => {
+        let modified = path
+          .metadata()?
+          .modified()?
+          .duration_since(UNIX_EPOCH)?
+          .as_secs();
+        if modified < expired_at {
+          std::fs::remove_file(&path)?;
+        }
+      }
+      _ => (),
+    }
+  }
+  Ok(purged)
+}
@@ -0,0 +1,408 @@
+/// File versioning
+///
+/// In directories with a versioning policy, the old content of a file is kept before it is
+/// replaced. Contents are stored once per user in `.versions/<sha256>` and referenced by rows
+/// of the `file_versions` table, retention is applied with the policy of the directory.
+use std::{
+  collections::HashSet,
+  path::{Component, PathBuf},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::http::StatusCode;
+use diesel::sql_types::{BigInt, Text};
+use diesel::QueryableByName;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use tokio_util::io::ReaderStream;
+
+use crate::{
+  db::SHARED_DB_CONN,
+  models::{FileVersion, VersioningPolicy},
+  UserSessionData,
+};
+
+use super::error::AppError;
+use super::path::secure_join;
+use super::quota;
+use super::storage::{self, resolve, resolve_internal, ResolvedPath, VfsReader};
+use super::stream::RangeStream;
+use super::trash::TRASH_DIR;
+use super::vfs::{self, FSHookType};
+
+pub const VERSIONS_DIR: &str = ".versions";
+
+fn now_secs() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn object_path(hash: &str) -> String {
+  format!("{VERSIONS_DIR}/{hash}")
+}
+
+fn normalize(file: &str) -> Result<String, AppError> {
+  let file = secure_join(&PathBuf::new(), &PathBuf::from(file))?;
+  Ok(file.to_string_lossy().to_string())
+}
+
+/// policy of the closest directory containing `file`
+fn find_policy(user: &str, file: &str) -> Result<Option<VersioningPolicy>, AppError> {
+  let policies = list_policies(user)?;
+  let file = PathBuf::from(file);
+  let policy = policies
+    .into_iter()
+    .filter(|p| file.starts_with(&p.dir))
+    .max_by_key(|p| PathBuf::from(&p.dir).components().count());
+  Ok(policy)
+}
+
+pub fn list_policies(user: &str) -> Result<Vec<VersioningPolicy>, AppError> {
+  use crate::schema::versioning_policies::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let policies = versioning_policies
+    .filter(username.eq(user))
+    .load::<VersioningPolicy>(&mut *conn)?;
+  Ok(policies)
+}
+
+/// enable versioning for a directory or update its retention,
+/// `None` keeps versions without limit of count or age
+pub fn set_policy(policy: VersioningPolicy) -> Result<VersioningPolicy, AppError> {
+  let policy = VersioningPolicy {
+    dir: normalize(&policy.dir)?,
+    ..policy
+  };
+  use crate::schema::versioning_policies::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::replace_into(versioning_policies)
+    .values(&policy)
+    .execute(&mut *conn)?;
+  Ok(policy)
+}
+
+/// disable versioning for a directory, existing versions are kept
+pub fn remove_policy(user: &str, dir_: &str) -> Result<bool, AppError> {
+  let dir_ = normalize(dir_)?;
+  use crate::schema::versioning_policies::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = diesel::delete(versioning_policies.filter(username.eq(user).and(dir.eq(dir_))))
+    .execute(&mut *conn)?;
+  Ok(r > 0)
+}
+
+/// forget the versions and policies of a removed user, stored contents are left in its root
+pub fn remove_user(user: &str) -> Result<(), AppError> {
+  use crate::schema::{file_versions, versioning_policies};
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::delete(file_versions::table.filter(file_versions::username.eq(user)))
+    .execute(&mut *conn)?;
+  diesel::delete(versioning_policies::table.filter(versioning_policies::username.eq(user)))
+    .execute(&mut *conn)?;
+  Ok(())
+}
+
+async fn sha256_of(target: &ResolvedPath, size: u64) -> Result<String, AppError> {
+  let mut hasher = Sha256::new();
+  if size > 0 {
+    let mut reader = target.backend.read(&target.path, (0, size - 1)).await?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+      let n = reader.read(&mut buf).await?;
+      if n == 0 {
+        break;
+      }
+      hasher.update(&buf[..n]);
+    }
+  }
+  Ok(format!("{:x}", hasher.finalize()))
+}
+
+async fn copy_content(from: &ResolvedPath, to: &ResolvedPath, size: u64) -> Result<(), AppError> {
+  if from.same_storage(to) {
+    from.backend.copy(&from.path, &to.path).await?;
+    return Ok(());
+  }
+  let reader = storage::read_all(&from.backend, &from.path, size).await?;
+  to.backend.write(&to.path, reader).await?;
+  Ok(())
+}
+
+/// keep current content of `file` before it is replaced,
+/// nothing is done when the file does not exist or versioning is off for its directory
+pub async fn snapshot(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+) -> Result<Option<FileVersion>, AppError> {
+  match record(file_root, user, file).await? {
+    Some((version, policy)) => {
+      apply_retention(file_root, user, &version.path, &policy).await?;
+      Ok(Some(version))
+    }
+    None => Ok(None),
+  }
+}
+
+/// add a version of `file` without applying retention
+async fn record(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+) -> Result<Option<(FileVersion, VersioningPolicy)>, AppError> {
+  let file = normalize(file)?;
+  match PathBuf::from(&file).components().next() {
+    Some(Component::Normal(first)) if first == VERSIONS_DIR || first == TRASH_DIR => {
+      return Ok(None)
+    }
+    None => return Ok(None),
+    _ => (),
+  }
+  let policy = match find_policy(&user.username, &file)? {
+    Some(policy) => policy,
+    None => return Ok(None),
+  };
+  let from = resolve(file_root, user, &file)?;
+  let file_stat = match from.backend.stat(&from.path).await {
+    Ok(file_stat) if !file_stat.is_dir => file_stat,
+    _ => return Ok(None),
+  };
+
+  let hash = sha256_of(&from, file_stat.size).await?;
+  let dir = resolve_internal(file_root, user, VERSIONS_DIR)?;
+  if dir.backend.stat(&dir.path).await.is_err() {
+    dir.backend.create_dir(&dir.path).await?;
+  }
+  let to = resolve_internal(file_root, user, &object_path(&hash))?;
+  // same content is stored once
+  if to.backend.stat(&to.path).await.is_err() {
+    copy_content(&from, &to, file_stat.size).await?;
+    if to.mount.is_none() {
+      quota::add(&user.username, file_stat.size as i64)?;
+    }
+  }
+
+  let version = FileVersion {
+    id: uuid::Uuid::new_v4().to_string(),
+    username: user.username.clone(),
+    path: file,
+    hash,
+    size: file_stat.size as i64,
+    created_at: now_secs(),
+  };
+  {
+    use crate::schema::file_versions::dsl::*;
+    use diesel::prelude::*;
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    diesel::insert_into(file_versions)
+      .values(&version)
+      .execute(&mut *conn)?;
+  }
+  Ok(Some((version, policy)))
+}
+
+/// versions of a file, newest first
+pub fn list(user: &str, file: &str) -> Result<Vec<FileVersion>, AppError> {
+  let file = normalize(file)?;
+  use crate::schema::file_versions::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let versions = file_versions
+    .filter(username.eq(user).and(path.eq(file)))
+    .order(created_at.desc())
+    .load::<FileVersion>(&mut *conn)?;
+  Ok(versions)
+}
+
+pub fn get(user: &str, version_id: &str) -> Result<FileVersion, AppError> {
+  use crate::schema::file_versions::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  file_versions
+    .filter(username.eq(user).and(id.eq(version_id)))
+    .first::<FileVersion>(&mut *conn)
+    .optional()?
+    .ok_or_else(|| AppError::new("file version not found").with_status(StatusCode::NOT_FOUND))
+}
+
+/// path of the stored content of a version in user root
+fn content_path(version: &FileVersion) -> String {
+  object_path(&version.hash)
+}
+
+/// read `range` of the stored content of a version, `vfs` refuses paths in `.versions`
+pub async fn read_stream(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  version: &FileVersion,
+  range: (u64, u64),
+) -> Result<RangeStream<ReaderStream<VfsReader>>, AppError> {
+  let target = resolve_internal(file_root, user, &content_path(version))?;
+  let f = target.backend.read(&target.path, range).await?;
+  let reader = ReaderStream::new(f);
+  Ok(RangeStream::new(range.1 - range.0 + 1, reader))
+}
+
+/// replace a file with one of its versions, the replaced content becomes a new version
+pub async fn restore(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  version_id: &str,
+) -> Result<FileVersion, AppError> {
+  let version = get(&user.username, version_id)?;
+  let from = resolve_internal(file_root, user, &content_path(&version))?;
+  let to = resolve(file_root, user, &version.path)?.writable()?;
+  // retention is applied after the copy, it may drop the version being restored
+  let before = quota::size_of(file_root, user, &version.path).await?;
+  quota::check(
+    file_root,
+    user,
+    &version.path,
+    (version.size as u64).saturating_sub(before),
+  )
+  .await?;
+  let recorded = record(file_root, user, &version.path).await?;
+  copy_content(&from, &to, version.size as u64).await?;
+  quota::track(file_root, user, &version.path, before).await?;
+  if let Some((current, policy)) = recorded {
+    apply_retention(file_root, user, &current.path, &policy).await?;
+  }
+  vfs::emit_fs_hook(file_root, user, FSHookType::AddFile, &version.path)?;
+  Ok(version)
+}
+
+/// drop versions of `file` exceeding the count or age of `policy`
+async fn apply_retention(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  policy: &VersioningPolicy,
+) -> Result<(), AppError> {
+  let expired_at = policy
+    .keep_days
+    .map(|days| now_secs() - days as i64 * 24 * 3600);
+  let keep = policy.keep_versions.map(|n| n.max(0) as usize);
+  let expired = list(&user.username, file)?
+    .into_iter()
+    .enumerate()
+    .filter(|(i, v)| {
+      keep.map_or(false, |keep| *i >= keep) || expired_at.map_or(false, |t| v.created_at < t)
+    })
+    .map(|(_, v)| v)
+    .collect::<Vec<_>>();
+  remove_versions(file_root, user, expired).await
+}
+
+/// delete version rows and the stored contents no longer referenced
+async fn remove_versions(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  versions: Vec<FileVersion>,
+) -> Result<(), AppError> {
+  if versions.is_empty() {
+    return Ok(());
+  }
+  let hashes = versions
+    .iter()
+    .map(|v| v.hash.clone())
+    .collect::<HashSet<_>>()
+    .into_iter()
+    .collect::<Vec<_>>();
+  let unused = {
+    use crate::schema::file_versions::dsl::*;
+    use diesel::prelude::*;
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    let ids = versions.iter().map(|v| v.id.clone()).collect::<Vec<_>>();
+    diesel::delete(file_versions.filter(id.eq_any(&ids))).execute(&mut *conn)?;
+    let used = file_versions
+      .filter(username.eq(&user.username).and(hash.eq_any(&hashes)))
+      .select(hash)
+      .load::<String>(&mut *conn)?
+      .into_iter()
+      .collect::<HashSet<_>>();
+    hashes
+      .into_iter()
+      .filter(|h| !used.contains(h))
+      .collect::<Vec<_>>()
+  };
+  for h in unused {
+    let target = resolve_internal(file_root, user, &object_path(&h))?;
+    if let Ok(file_stat) = target.backend.stat(&target.path).await {
+      target.backend.remove(&target.path).await?;
+      if target.mount.is_none() {
+        quota::add(&user.username, -(file_stat.size as i64))?;
+      }
+    }
+  }
+  Ok(())
+}
+
+/// apply age retention to versions of every user, returns count of removed versions
+pub async fn purge_expired(file_root: &PathBuf) -> Result<usize, AppError> {
+  let (policies, user_roots) = {
+    use crate::schema::users;
+    use diesel::prelude::*;
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    let policies = crate::schema::versioning_policies::table
+      .filter(crate::schema::versioning_policies::keep_days.is_not_null())
+      .load::<VersioningPolicy>(&mut *conn)?;
+    let user_roots = users::table
+      .select((users::username, users::user_root))
+      .load::<(String, String)>(&mut *conn)?;
+    (policies, user_roots)
+  };
+
+  let mut purged = 0;
+  for (name, user_root) in user_roots {
+    let user = UserSessionData::new(&name, &user_root);
+    let versions = {
+      use crate::schema::file_versions::dsl::*;
+      use diesel::prelude::*;
+      let mut conn = SHARED_DB_CONN.lock().unwrap();
+      file_versions
+        .filter(username.eq(&name))
+        .load::<FileVersion>(&mut *conn)?
+    };
+    let expired = versions
+      .into_iter()
+      .filter(|v| {
+        let file = PathBuf::from(&v.path);
+        let policy = policies
+          .iter()
+          .filter(|p| p.username == name && file.starts_with(&p.dir))
+          .max_by_key(|p| PathBuf::from(&p.dir).components().count());
+        match policy.and_then(|p| p.keep_days) {
+          Some(days) => v.created_at < now_secs() - days as i64 * 24 * 3600,
+          None => false,
+        }
+      })
+      .collect::<Vec<_>>();
+    purged += expired.len();
+    remove_versions(file_root, &user, expired).await?;
+  }
+  Ok(purged)
+}
+
+#[derive(QueryableByName)]
+struct VersionsUsage {
+  #[diesel(sql_type = BigInt)]
+  size: i64,
+}
+
+/// bytes taken by stored versions of a user, each content is counted once
+pub fn usage(user: &str) -> Result<i64, AppError> {
+  use diesel::{sql_query, RunQueryDsl};
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = sql_query(
+    "select coalesce(sum(size), 0) as size from \
+     (select distinct hash, size from file_versions where username = ?)",
+  )
+  .bind::<Text, _>(user)
+  .get_result::<VersionsUsage>(&mut *conn)?;
+  Ok(r.size)
+}
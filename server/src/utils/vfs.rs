@@ -1,6 +1,6 @@
 /// virtual file system backend
 
-use actix_web::web::block;
+use actix_web::web::{block, Bytes};
 use async_zip::error::ZipError;
 use async_zip::write::ZipFileWriter;
 use async_zip::{Compression, ZipEntryBuilder};
@@ -13,28 +13,42 @@ use std::collections::HashMap;
 use std::hash::Hash;
 use std::io::Cursor;
 use std::path::Path;
+use std::pin::Pin;
 use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 use std::thread;
 use std::time::UNIX_EPOCH;
 use std::{fs::Metadata, io, path::PathBuf};
+use actix_web::http::StatusCode;
+use etag::EntityTag;
+use futures::{Stream, StreamExt};
 use tantivy::Document;
-use tokio::fs::{self, File};
-use tokio::io::{duplex, AsyncRead, AsyncSeekExt, DuplexStream};
+use tokio::fs::File;
+use tokio::io::{
+  duplex, AsyncRead, AsyncReadExt, AsyncSeek, AsyncWriteExt, DuplexStream, ReadBuf,
+};
 use tokio_util::io::ReaderStream;
 
 use crate::db::SHARED_DB_CONN;
 use crate::models::{FileIndex, FileIndexSizeCount};
 use crate::schedulers::update_file_index::UpdateGalleryJob;
-use crate::{config, conv_err};
+use crate::{config, conv_err, UserSessionData};
 
+use super::acl::{self, SHARED_DIR};
 use super::error::AppError;
 use super::eventbus::EventEmitter;
-use super::path::secure_join;
 use super::search_engine::search_docs;
+use super::mount::get_mounts;
+use super::path::secure_join;
+use super::quota;
+use super::storage::{self, resolve, ResolvedPath, StorageBackend, VfsReader};
 use super::stream::RangeStream;
+use super::trash::{move_to_trash, TRASH_DIR};
 use super::transcode::{ffmpeg_scale, self};
+use super::versions::{self, VERSIONS_DIR};
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum FSHookType {
@@ -65,34 +79,66 @@ lazy_static! {
 
 pub async fn read_dir(
   file_root: &PathBuf,
-  user_root: &str,
+  user: &UserSessionData,
   dir: &str,
 ) -> Result<Vec<FileStatWithName>, AppError> {
-  let odir = PathBuf::from(dir);
-  let dir = normailze_path(file_root, user_root, dir)?;
-  let mut result = fs::read_dir(&dir).await?;
-  let mut files_in_dir: Vec<FileStatWithName> = vec![];
-  while let Result::Ok(Option::Some(dir_entry)) = result.next_entry().await {
-    let filename = dir_entry.file_name().to_string_lossy().into_owned();
-    let file_path = odir.join(&filename);
-    let file_path = file_path.to_str().map_or("", |v| v);
-    let file_stat = stat(file_root, user_root, file_path).await?;
-    let file_stat_with_name = FileStatWithName::new(&file_stat, &filename);
-    files_in_dir.push(file_stat_with_name);
+  let dir_path = secure_join(&PathBuf::new(), &PathBuf::from(dir))?;
+  if let Some(files) = acl::read_virtual_dir(file_root, user, &dir_path).await? {
+    return Ok(files);
+  }
+  let target = resolve(file_root, user, dir)?;
+  let mut files = target.backend.read_dir(&target.path).await?;
+  let is_root = !dir_path
+    .components()
+    .any(|c| matches!(c, std::path::Component::Normal(_)));
+  if target.mount.is_none() && is_root {
+    // trash and versions are hidden, mounts are shown as directories in the root of user
+    let mounts = get_mounts(&user.username)?;
+    files.retain(|f| {
+      f.name != TRASH_DIR
+        && f.name != VERSIONS_DIR
+        && f.name != SHARED_DIR
+        && !mounts.iter().any(|m| m.prefix == f.name)
+    });
+    for m in mounts {
+      let file_stat = stat(file_root, user, &m.prefix).await;
+      if let Ok(file_stat) = file_stat {
+        files.push(FileStatWithName::new(&file_stat, &m.prefix));
+      }
+    }
+    // directories shared by other users
+    if !acl::grants_for(&user.username)?.is_empty() {
+      if let Ok(file_stat) = stat(file_root, user, SHARED_DIR).await {
+        files.push(FileStatWithName::new(&file_stat, SHARED_DIR));
+      }
+    }
+  } else if target.mount.is_some() && dir_path.components().count() == 1 {
+    // a mount keeps the trash of its files in its root
+    files.retain(|f| f.name != TRASH_DIR);
   }
-  Ok(files_in_dir)
+  Ok(files)
+}
+
+/// read whole content of a file into memory
+pub async fn read_to_end(file_root: &PathBuf, user: &UserSessionData, file: &str) -> Result<Vec<u8>, AppError> {
+  let target = resolve(file_root, user, file)?;
+  let size = target.backend.stat(&target.path).await?.size;
+  let mut result = Vec::with_capacity(size as usize);
+  storage::read_all(&target.backend, &target.path, size)
+    .await?
+    .read_to_end(&mut result)
+    .await?;
+  Ok(result)
 }
 
 #[allow(unused)]
 pub async fn read_image(
-  file_root: PathBuf,
-  user_root: String,
-  file: String,
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
   resize: Option<u32>,
 ) -> Result<Vec<u8>, AppError> {
-  let dir = normailze_path(&file_root, &user_root, &file)?;
-
-  let result = fs::read(dir).await?;
+  let result = read_to_end(file_root, user, file).await?;
   if let Some(resize) = resize {
     let buf = block(move || {
       let img = image::io::Reader::new(Cursor::new(&result))
@@ -111,100 +157,240 @@ pub async fn read_image(
   Ok(result)
 }
 
-pub async fn metadata(file_root: &PathBuf, user_root: &str, file: &str) -> Result<Metadata, AppError> {
-  let dir = normailze_path(file_root, user_root, file)?;
-  let meta = fs::metadata(dir).await?;
-  Ok(meta)
+pub async fn stat(file_root: &PathBuf, user: &UserSessionData, file: &str) -> Result<FileStat, AppError> {
+  let file_path = secure_join(&PathBuf::new(), &PathBuf::from(file))?;
+  if let Some(file_stat) = acl::stat_virtual(user, &file_path)? {
+    return Ok(file_stat);
+  }
+  let target = resolve(file_root, user, file)?;
+  target.backend.stat(&target.path).await
+}
+
+/// path of a file in file index, only files stored under `file_root` are indexed
+pub fn index_path(file_root: &PathBuf, target: &ResolvedPath) -> Option<String> {
+  let file = target.backend.local_path(&target.path)?;
+  let file = file.strip_prefix(file_root).ok()?;
+  Some(file.to_string_lossy().to_string())
+}
+
+/// fire a file system hook for `file` if it is in file index
+pub fn emit_fs_hook(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  hook: FSHookType,
+  file: &str,
+) -> Result<(), AppError> {
+  let target = resolve(file_root, user, file)?;
+  if let Some(file) = index_path(file_root, &target) {
+    FS_HOOK.lock().unwrap().emit(hook, FSHookPayload(vec![file]));
+  }
+  Ok(())
 }
 
-pub async fn stat(file_root: &PathBuf, user_root: &str, file: &str) -> Result<FileStat, AppError> {
-  let meta = metadata(file_root, user_root, file).await?;
-  convert_meta_to_struct(meta)
+/// weak entity tag of a file, derived from its size and modified time
+pub async fn etag(file_root: &PathBuf, user: &UserSessionData, file: &str) -> Result<EntityTag, AppError> {
+  let file_stat = stat(file_root, user, file).await?;
+  Ok(EntityTag::weak(&format!(
+    "{:x}-{:x}",
+    file_stat.modified, file_stat.size
+  )))
 }
 
 #[allow(unused)]
 pub async fn create(
-  file_root: PathBuf,
-  user_root: String,
-  file: String,
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
   buffer: Vec<u8>,
 ) -> Result<(), AppError> {
-  let dir = normailze_path(&file_root, &user_root, &file)?;
-  let parent = Path::new(&file).parent().unwrap_or(dir.as_path());
-  fs::create_dir_all(parent).await?;
-  Ok(fs::write(file, buffer).await?)
+  let target = resolve(file_root, user, file)?.writable()?;
+  let before = quota::size_of(file_root, user, file).await?;
+  quota::check(file_root, user, file, (buffer.len() as u64).saturating_sub(before)).await?;
+  versions::snapshot(file_root, user, file).await?;
+  target
+    .backend
+    .write(&target.path, Box::pin(Cursor::new(buffer)))
+    .await?;
+  quota::track(file_root, user, file, before).await?;
+  Ok(())
 }
 
-pub async fn delete(file_root: &PathBuf, user_root: &str, file: &str) -> Result<(), AppError> {
-  let dir = normailze_path(file_root, &user_root, &file)?;
-  let path_stat = stat(file_root, user_root, file).await?;
-  if path_stat.is_dir {
-    fs::remove_dir_all(&dir).await?;
-  } else {
-    fs::remove_file(&dir).await?;
+/// counts bytes read through it and fails once more than `allowed` were read,
+/// so a backend write fails instead of storing a file over quota
+struct QuotaReader {
+  inner: DuplexStream,
+  allowed: Option<u64>,
+  read: Arc<AtomicU64>,
+}
+
+impl AsyncRead for QuotaReader {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    let filled = buf.filled().len();
+    match Pin::new(&mut self.inner).poll_read(cx, buf) {
+      Poll::Ready(Ok(())) => (),
+      other => return other,
+    }
+    let n = (buf.filled().len() - filled) as u64;
+    let read = self.read.fetch_add(n, Ordering::Relaxed) + n;
+    if self.allowed.map_or(false, |allowed| read > allowed) {
+      return Poll::Ready(Err(io::Error::new(
+        io::ErrorKind::Other,
+        "storage quota exceeded",
+      )));
+    }
+    Poll::Ready(Ok(()))
+  }
+}
+
+/// create or replace a file with the content of `stream`, the stream does not need to be `Send`
+pub async fn write_stream<E: std::fmt::Display>(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+  mut stream: impl Stream<Item = Result<Bytes, E>> + Unpin,
+) -> Result<u64, AppError> {
+  let target = resolve(file_root, user, file)?.writable()?;
+  let before = quota::size_of(file_root, user, file).await?;
+  quota::check(file_root, user, file, 0).await?;
+  // size of a stream is unknown, it is counted while it is written, the replaced content is freed
+  let left = quota::remaining(file_root, user, file).await?;
+  let allowed = left.map(|left| (left + before as i64).max(0) as u64);
+  versions::snapshot(file_root, user, file).await?;
+  let (mut w, r) = duplex(512 * 1024);
+  let feed = async move {
+    while let Some(bytes) = stream.next().await {
+      let bytes = bytes.map_err(|e| AppError::new(&e.to_string()))?;
+      w.write_all(&bytes).await?;
+    }
+    w.shutdown().await?;
+    Ok::<(), AppError>(())
+  };
+  let read = Arc::new(AtomicU64::new(0));
+  let r = QuotaReader {
+    inner: r,
+    allowed,
+    read: read.clone(),
+  };
+  let (written, fed) = tokio::join!(target.backend.write(&target.path, Box::pin(r)), feed);
+  if let (Some(allowed), Some(left)) = (allowed, left) {
+    let read = read.load(Ordering::Relaxed);
+    if read > allowed {
+      return Err(quota::exceeded(read.saturating_sub(before), left));
+    }
   }
+  let written = written?;
+  fed?;
+  quota::track(file_root, user, file, before).await?;
+  Ok(written)
+}
+
+/// move a file to trash of its owner, see `trash::move_to_trash` and `trash::purge`
+pub async fn delete(file_root: &PathBuf, user: &UserSessionData, file: &str) -> Result<(), AppError> {
+  move_to_trash(file_root, user, file).await?;
   Ok(())
 }
 
 pub async fn delete_batch(
   file_root: &PathBuf,
-  user_root: &str,
+  user: &UserSessionData,
   files: Vec<String>,
 ) -> Result<(), AppError> {
   for file in files {
-    let dir = normailze_path(&file_root, &user_root, &file)?;
-    let path_stat = stat(file_root, user_root, &file).await?;
-    if path_stat.is_dir {
-      fs::remove_dir_all(&dir).await?;
-    } else {
-      fs::remove_file(&dir).await?;
-    }
+    delete(file_root, user, &file).await?;
   }
   Ok(())
 }
 
 pub async fn read_video_transform_stream(
-  file_root: PathBuf,
-  user_root: String,
-  file: String,
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
   resize: Option<u32>,
   bitrate: Option<u32>,
 ) -> Result<impl AsyncRead, AppError> {
-  let dir = normailze_path(&file_root, &user_root, &file)?;
-  let resize = resize.map_or(720, |v| v);
-  let bitrate = bitrate.map_or(2000, |v| v);
+  let dir = normailze_path(file_root, user, file)?;
+  let (default_resize, default_bitrate) = super::dynamic_config::transcode_defaults();
+  let resize = resize.map_or(default_resize, |v| v);
+  let bitrate = bitrate.map_or(default_bitrate, |v| v);
   let stream = ffmpeg_scale(&dir, resize, bitrate).await;
   Ok(stream)
 }
 
-pub async fn create_dir(file_root: &PathBuf, user_root: &str, file: &str) -> Result<(), AppError> {
-  let dir = normailze_path(&file_root, &user_root, &file)?;
-  let result = fs::create_dir(&dir).await?;
-  Ok(result)
+pub async fn create_dir(file_root: &PathBuf, user: &UserSessionData, file: &str) -> Result<(), AppError> {
+  let target = resolve(file_root, user, file)?.writable()?;
+  target.backend.create_dir(&target.path).await
 }
 
 pub async fn move_file(
   file_root: &PathBuf,
-  user_root: &str,
+  user: &UserSessionData,
   from_file: &str,
   to_file: &str,
 ) -> Result<(), AppError> {
-  let from_file = normailze_path(&file_root, &user_root, &from_file)?;
-  let to_file = normailze_path(&file_root, &user_root, &to_file)?;
-  let result = fs::rename(from_file, to_file).await?;
-  Ok(result)
+  let from = resolve(file_root, user, from_file)?.writable()?;
+  let to = resolve(file_root, user, to_file)?.writable()?;
+  let before_to = quota::size_of(file_root, user, to_file).await?;
+  if from.same_storage(&to) {
+    versions::snapshot(file_root, user, to_file).await?;
+    from.backend.rename(&from.path, &to.path).await?;
+    // only the replaced file is freed when moving inside a storage
+    return quota::charge(file_root, user, to_file, -(before_to as i64));
+  }
+  let before_from = quota::size_of(file_root, user, from_file).await?;
+  let size = quota::tree_size(&from.backend, &from.path).await?;
+  quota::check(file_root, user, to_file, size.saturating_sub(before_to)).await?;
+  versions::snapshot(file_root, user, to_file).await?;
+  transfer(&from, &from.path, &to, &to.path).await?;
+  from.backend.remove(&from.path).await?;
+  quota::track(file_root, user, to_file, before_to).await?;
+  quota::track(file_root, user, from_file, before_from).await
 }
 
 pub async fn copy_file(
   file_root: &PathBuf,
-  user_root: &str,
+  user: &UserSessionData,
   from_file: &str,
   to_file: &str,
 ) -> Result<u64, AppError> {
-  let from_file = normailze_path(&file_root, &user_root, &from_file)?;
-  let to_file = normailze_path(&file_root, &user_root, &to_file)?;
-  let result = fs::copy(from_file, to_file).await?;
-  Ok(result)
+  let from = resolve(file_root, user, from_file)?;
+  let to = resolve(file_root, user, to_file)?.writable()?;
+  let before = quota::size_of(file_root, user, to_file).await?;
+  let size = quota::tree_size(&from.backend, &from.path).await?;
+  quota::check(file_root, user, to_file, size.saturating_sub(before)).await?;
+  versions::snapshot(file_root, user, to_file).await?;
+  let copied = if from.same_storage(&to) {
+    from.backend.copy(&from.path, &to.path).await?
+  } else {
+    transfer(&from, &from.path, &to, &to.path).await?
+  };
+  quota::track(file_root, user, to_file, before).await?;
+  Ok(copied)
+}
+
+/// copy a file or directory between two storages, returns bytes copied
+#[async_recursion::async_recursion]
+async fn transfer(
+  from: &ResolvedPath,
+  from_path: &PathBuf,
+  to: &ResolvedPath,
+  to_path: &PathBuf,
+) -> Result<u64, AppError> {
+  let file_stat = from.backend.stat(from_path).await?;
+  if file_stat.is_dir {
+    to.backend.create_dir(to_path).await?;
+    let mut total = 0;
+    for f in from.backend.read_dir(from_path).await? {
+      total += transfer(from, &from_path.join(&f.name), to, &to_path.join(&f.name)).await?;
+    }
+    return Ok(total);
+  }
+  let size = file_stat.size;
+  let reader = storage::read_all(&from.backend, from_path, size).await?;
+  to.backend.write(to_path, reader).await
 }
 
 pub async fn search_in_index(kw: &str, limit: i64) -> Result<Vec<FileIndex>, AppError> {
@@ -240,11 +426,11 @@ pub struct FileStatNameDir {
 
 pub fn search_files(
   file_root: &PathBuf,
-  user_root: &str,
+  user: &UserSessionData,
   dir: &str,
   keyword: &str,
 ) -> Result<Vec<FileStatNameDir>, AppError> {
-  let search_root = normailze_path(file_root, user_root, dir)?;
+  let search_root = normailze_path(file_root, user, dir)?;
   let root_str = search_root.canonicalize()?.to_string_lossy().to_string();
   let files: Vec<String> = rust_search::SearchBuilder::default()
     .location(search_root)
@@ -290,7 +476,7 @@ pub fn search_files(
   Ok(file_stats)
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Debug, Clone)]
 #[mixin::declare]
 pub struct FileStat {
   pub is_dir: bool,
@@ -309,7 +495,7 @@ pub struct FileStatWithName {
 }
 
 impl FileStatWithName {
-  fn new(file_stat: &FileStat, name: &str) -> Self {
+  pub fn new(file_stat: &FileStat, name: &str) -> Self {
     let FileStat {
       is_dir,
       is_file,
@@ -346,13 +532,12 @@ pub fn convert_meta_to_struct(meta: Metadata) -> Result<FileStat, AppError> {
 
 pub async fn read_file_stream(
   file_root: &PathBuf,
-  user_root: &str,
+  user: &UserSessionData,
   file: &str,
   range: (u64, u64),
-) -> Result<RangeStream<ReaderStream<File>>, AppError> {
-  let dir = normailze_path(&file_root, &user_root, &file)?;
-  let mut f = tokio::fs::File::open(dir).await?;
-  f.seek(io::SeekFrom::Start(range.0)).await.unwrap();
+) -> Result<RangeStream<ReaderStream<VfsReader>>, AppError> {
+  let target = resolve(file_root, user, file)?;
+  let f = target.backend.read(&target.path, range).await?;
   let reader = ReaderStream::new(f);
   let reader = RangeStream::new(range.1 - range.0 + 1, reader);
   Ok(reader)
@@ -385,10 +570,11 @@ pub async fn file_index_last_updated_time(username_: &str) -> Result<String, App
 
 pub async fn read_to_zip_stream(
   file_root: &PathBuf,
-  user_root: &str,
+  user: &UserSessionData,
   file: &str,
 ) -> Result<ReaderStream<DuplexStream>, AppError> {
-  let f = zip_path_to_stream(&file_root.join(user_root), &PathBuf::from_str(&file)?).await?;
+  let target = resolve(file_root, user, file)?;
+  let f = zip_path_to_stream(target.backend, &target.path, &PathBuf::from_str(&file)?).await?;
   let reader = ReaderStream::new(f);
   Ok(reader)
 }
@@ -401,38 +587,51 @@ pub fn ensure_parent_dir_sync(file: &PathBuf) -> Result<(), AppError> {
   Ok(())
 }
 
+/// absolute path of a file on the host file system,
+/// fails for files in storages that are not on local disk
 pub fn normailze_path(
   file_root: &PathBuf,
-  user_root: &str,
+  user: &UserSessionData,
   file: &str,
 ) -> Result<PathBuf, AppError> {
-  let user_abs_root = file_root.join(user_root);
-  Ok(secure_join(&user_abs_root, &PathBuf::from(file))?)
+  let target = resolve(file_root, user, file)?;
+  target.backend.local_path(&target.path).ok_or_else(|| {
+    AppError::new("path error: file is not in local storage").with_status(StatusCode::BAD_REQUEST)
+  })
 }
 
+/// stream `path` of `backend` as a zip file, entries are named under `name`
 pub async fn zip_path_to_stream(
-  base: &PathBuf,
-  file: &PathBuf,
-) -> Result<DuplexStream, std::io::Error> {
+  backend: Arc<dyn StorageBackend>,
+  path: &PathBuf,
+  name: &PathBuf,
+) -> Result<DuplexStream, AppError> {
   #[async_recursion::async_recursion]
   async fn walk(
-    base: &PathBuf,
-    file: &PathBuf,
+    backend: &Arc<dyn StorageBackend>,
+    path: &PathBuf,
+    name: &PathBuf,
     writer: &mut ZipFileWriter<DuplexStream>,
-  ) -> Result<(), std::io::Error> {
-    let meta = tokio::fs::metadata(base.join(file)).await?;
-    if meta.is_file() {
-      let s = file.to_str().unwrap().to_string();
+  ) -> Result<(), AppError> {
+    let file_stat = backend.stat(path).await?;
+    if file_stat.is_file {
+      let s = name.to_str().unwrap().to_string();
       let entry = ZipEntryBuilder::new(s, Compression::Stored).build();
-      let mut w = writer.write_entry_stream(entry).await.unwrap();
-      let mut f = tokio::fs::File::open(base.join(file)).await?;
+      let mut w = writer.write_entry_stream(entry).await?;
+      let size = file_stat.size;
+      let mut f = storage::read_all(backend, path, size).await?;
       tokio::io::copy(&mut f, &mut w).await?;
-      w.close().await.unwrap();
-    } else if meta.is_dir() {
-      let mut files = tokio::fs::read_dir(base.join(file)).await?;
-      while let Ok(Some(inner_file)) = files.next_entry().await {
-        let filename = inner_file.file_name();
-        walk(&base, &file.join(filename), writer).await?;
+      w.close().await?;
+    } else if file_stat.is_dir {
+      let files = backend.read_dir(path).await?;
+      for inner_file in files {
+        walk(
+          backend,
+          &path.join(&inner_file.name),
+          &name.join(&inner_file.name),
+          writer,
+        )
+        .await?;
       }
     }
     Ok(())
@@ -440,11 +639,15 @@ pub async fn zip_path_to_stream(
 
   let (w, r) = duplex(512 * 1024);
 
-  let base = base.clone();
-  let file = file.clone();
+  let path = path.clone();
+  let name = name.clone();
   tokio::spawn(async move {
     let mut writer = ZipFileWriter::new(w);
-    walk(&base, &file, &mut writer).await.unwrap();
+    if let Err(err) = walk(&backend, &path, &name, &mut writer).await {
+      tracing::error!("zip stream error: {}", err);
+      return;
+    }
+    writer.close().await.ok();
   });
 
   Ok(r)
@@ -465,11 +668,10 @@ pub struct FileStatTreeInner {
 
 pub async fn read_entries_in_zip(
   file_root: &PathBuf,
-  user_root: &str,
+  user: &UserSessionData,
   file: &str,
 ) -> Result<Rc<RefCell<FileStatTreeInner>>, AppError> {
-  let file: PathBuf = normailze_path(file_root, user_root, file)?;
-  let mut file = File::open(file).await?;
+  let mut file = open_seekable(file_root, user, file).await?;
   let zip_file = async_zip::read::seek::ZipFileReader::new(&mut file).await?;
   let mut file_map = HashMap::<String, Rc<RefCell<FileStatTreeInner>>>::new();
   let mut root = None;
@@ -552,8 +754,56 @@ pub async fn read_entries_in_zip(
   Ok(root)
 }
 
-pub fn create_thumbnail(file_root: &PathBuf, user_root: &str, file: &str, size: u32) -> Result<Vec<u8>, AppError> {
-  let file: PathBuf = normailze_path(file_root, user_root, file)?;
+/// open a file for random access, files in remote storages are buffered in memory
+pub async fn open_seekable(
+  file_root: &PathBuf,
+  user: &UserSessionData,
+  file: &str,
+) -> Result<SeekableFile, AppError> {
+  let target = resolve(file_root, user, file)?;
+  if let Some(local) = target.backend.local_path(&target.path) {
+    return Ok(SeekableFile::Local(File::open(local).await?));
+  }
+  let buf = read_to_end(file_root, user, file).await?;
+  Ok(SeekableFile::Memory(Cursor::new(buf)))
+}
+
+pub enum SeekableFile {
+  Local(File),
+  Memory(Cursor<Vec<u8>>),
+}
+
+impl AsyncRead for SeekableFile {
+  fn poll_read(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      SeekableFile::Local(f) => Pin::new(f).poll_read(cx, buf),
+      SeekableFile::Memory(c) => Pin::new(c).poll_read(cx, buf),
+    }
+  }
+}
+
+impl AsyncSeek for SeekableFile {
+  fn start_seek(self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+    match self.get_mut() {
+      SeekableFile::Local(f) => Pin::new(f).start_seek(position),
+      SeekableFile::Memory(c) => Pin::new(c).start_seek(position),
+    }
+  }
+
+  fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+    match self.get_mut() {
+      SeekableFile::Local(f) => Pin::new(f).poll_complete(cx),
+      SeekableFile::Memory(c) => Pin::new(c).poll_complete(cx),
+    }
+  }
+}
+
+pub fn create_thumbnail(file_root: &PathBuf, user: &UserSessionData, file: &str, size: u32) -> Result<Vec<u8>, AppError> {
+  let file: PathBuf = normailze_path(file_root, user, file)?;
   let thumbnail = transcode::create_image_thumbnail(&file, size);
   thumbnail
 }
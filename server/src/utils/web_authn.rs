@@ -0,0 +1,235 @@
+/// WebAuthn passkeys
+///
+/// Challenge states of registrations and logins are kept in memory for a few minutes,
+/// registered passkeys are stored in `web_authn_credentials`, a user may have many.
+use std::{
+  collections::HashMap,
+  sync::Mutex,
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::http::StatusCode;
+use base64::Engine;
+use lazy_static::lazy_static;
+use webauthn_rs::prelude::*;
+
+use crate::{conv_err, db::SHARED_DB_CONN, models::WebAuthnCredential};
+
+use super::error::AppError;
+
+conv_err!(WebauthnError);
+
+/// a challenge must be answered within this time
+const CHALLENGE_TTL: Duration = Duration::from_secs(5 * 60);
+
+lazy_static! {
+  /// registrations in progress, keyed by username
+  static ref REGISTRATIONS: Mutex<HashMap<String, (PasskeyRegistration, Instant)>> =
+    Mutex::new(HashMap::new());
+  /// logins in progress, keyed by login id
+  static ref AUTHENTICATIONS: Mutex<HashMap<String, (String, PasskeyAuthentication, Instant)>> =
+    Mutex::new(HashMap::new());
+}
+
+fn now_secs() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn bad_request(msg: &str) -> AppError {
+  AppError::new(msg).with_status(StatusCode::BAD_REQUEST)
+}
+
+/// relying party for the site at `url`, whose host must be the host of the request
+pub fn build(url: &str, host: &str) -> Result<Webauthn, AppError> {
+  let rp_origin = Url::parse(url).map_err(|_| bad_request(&format!("invalid url: {url}")))?;
+  let rp_id = rp_origin
+    .domain()
+    .ok_or_else(|| bad_request(&format!("invalid url: {url}")))?;
+  let origin_host = match rp_origin.port() {
+    Some(port) => format!("{rp_id}:{port}"),
+    None => rp_id.to_owned(),
+  };
+  if origin_host != host {
+    return Err(bad_request(&format!(
+      "url {url} does not match host {host}"
+    )));
+  }
+  let web_authn = WebauthnBuilder::new(rp_id, &rp_origin)?
+    .rp_name("webby_os")
+    .build()?;
+  Ok(web_authn)
+}
+
+fn encode_id(id: &CredentialID) -> String {
+  base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&id.0)
+}
+
+pub fn list(user: &str) -> Result<Vec<WebAuthnCredential>, AppError> {
+  use crate::schema::web_authn_credentials::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = web_authn_credentials
+    .filter(username.eq(user))
+    .order(created_at.asc())
+    .load::<WebAuthnCredential>(&mut *conn)?;
+  Ok(r)
+}
+
+fn passkeys(user: &str) -> Result<Vec<Passkey>, AppError> {
+  list(user)?
+    .iter()
+    .map(|c| Ok(serde_json::from_str::<Passkey>(&c.passkey)?))
+    .collect()
+}
+
+pub fn delete(user: &str, credential_id: &str) -> Result<bool, AppError> {
+  use crate::schema::web_authn_credentials::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let r = diesel::delete(web_authn_credentials.filter(username.eq(user).and(id.eq(credential_id))))
+    .execute(&mut *conn)?;
+  Ok(r > 0)
+}
+
+pub fn remove_user(user: &str) -> Result<(), AppError> {
+  use crate::schema::web_authn_credentials::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::delete(web_authn_credentials.filter(username.eq(user))).execute(&mut *conn)?;
+  Ok(())
+}
+
+/// webauthn handle of a user, created on first registration
+fn user_handle(user: &str) -> Result<Uuid, AppError> {
+  use crate::schema::users::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let handle = users
+    .filter(username.eq(user))
+    .select(web_authn_id)
+    .first::<Option<String>>(&mut *conn)?;
+  if let Some(handle) = handle.and_then(|h| Uuid::parse_str(&h).ok()) {
+    return Ok(handle);
+  }
+  let handle = Uuid::new_v4();
+  diesel::update(users.filter(username.eq(user)))
+    .set(web_authn_id.eq(handle.to_string()))
+    .execute(&mut *conn)?;
+  Ok(handle)
+}
+
+pub fn start_register(
+  web_authn: &Webauthn,
+  user: &str,
+) -> Result<CreationChallengeResponse, AppError> {
+  let handle = user_handle(user)?;
+  // a key registered twice would only confuse the user
+  let exclude = passkeys(user)?
+    .iter()
+    .map(|p| p.cred_id().clone())
+    .collect::<Vec<_>>();
+  let (ccr, state) = web_authn.start_passkey_registration(handle, user, user, Some(exclude))?;
+  let mut registrations = REGISTRATIONS.lock().unwrap();
+  registrations.retain(|_, (_, at)| at.elapsed() < CHALLENGE_TTL);
+  registrations.insert(user.to_owned(), (state, Instant::now()));
+  Ok(ccr)
+}
+
+pub fn finish_register(
+  web_authn: &Webauthn,
+  user: &str,
+  key_name: &str,
+  credential: &RegisterPublicKeyCredential,
+) -> Result<WebAuthnCredential, AppError> {
+  let state = REGISTRATIONS
+    .lock()
+    .unwrap()
+    .remove(user)
+    .filter(|(_, at)| at.elapsed() < CHALLENGE_TTL)
+    .map(|(state, _)| state)
+    .ok_or_else(|| bad_request("no registration in progress"))?;
+  let key = web_authn.finish_passkey_registration(credential, &state)?;
+  let credential = WebAuthnCredential {
+    id: encode_id(key.cred_id()),
+    username: user.to_owned(),
+    name: key_name.to_owned(),
+    passkey: serde_json::to_string(&key)?,
+    created_at: now_secs(),
+    last_used_at: None,
+  };
+  insert(&credential)?;
+  Ok(credential)
+}
+
+fn insert(credential: &WebAuthnCredential) -> Result<(), AppError> {
+  use crate::schema::web_authn_credentials::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::insert_into(web_authn_credentials)
+    .values(credential)
+    .execute(&mut *conn)?;
+  Ok(())
+}
+
+/// returns the challenge and the id to finish the login with
+pub fn start_login(
+  web_authn: &Webauthn,
+  user: &str,
+) -> Result<(RequestChallengeResponse, String), AppError> {
+  let keys = passkeys(user)?;
+  if keys.is_empty() {
+    return Err(bad_request("no passkey registered"));
+  }
+  let (rcr, state) = web_authn.start_passkey_authentication(&keys)?;
+  let login_id = Uuid::new_v4().to_string();
+  let mut authentications = AUTHENTICATIONS.lock().unwrap();
+  authentications.retain(|_, (_, _, at)| at.elapsed() < CHALLENGE_TTL);
+  authentications.insert(login_id.clone(), (user.to_owned(), state, Instant::now()));
+  Ok((rcr, login_id))
+}
+
+/// verify the answer to a login challenge, returns the user logged in
+pub fn finish_login(
+  web_authn: &Webauthn,
+  login_id: &str,
+  credential: &PublicKeyCredential,
+) -> Result<String, AppError> {
+  let (user, state) = AUTHENTICATIONS
+    .lock()
+    .unwrap()
+    .remove(login_id)
+    .filter(|(_, _, at)| at.elapsed() < CHALLENGE_TTL)
+    .map(|(user, state, _)| (user, state))
+    .ok_or_else(|| bad_request("no login in progress"))?;
+  let result = web_authn
+    .finish_passkey_authentication(credential, &state)
+    .map_err(|err| {
+      AppError::new(&format!("passkey login failed: {err}")).with_status(StatusCode::UNAUTHORIZED)
+    })?;
+
+  // keep the signature counter up to date, it detects cloned authenticators
+  let cred_id = encode_id(result.cred_id());
+  let stored = list(&user)?
+    .into_iter()
+    .find(|c| c.id == cred_id)
+    .ok_or_else(|| AppError::new("passkey not found").with_status(StatusCode::UNAUTHORIZED))?;
+  let mut key = serde_json::from_str::<Passkey>(&stored.passkey)?;
+  key.update_credential(&result);
+  mark_used(&cred_id, &key)?;
+  Ok(user)
+}
+
+fn mark_used(cred_id: &str, key: &Passkey) -> Result<(), AppError> {
+  use crate::schema::web_authn_credentials::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::update(web_authn_credentials.filter(id.eq(cred_id)))
+    .set((
+      passkey.eq(serde_json::to_string(key)?),
+      last_used_at.eq(now_secs()),
+    ))
+    .execute(&mut *conn)?;
+  Ok(())
+}